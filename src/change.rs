@@ -1,6 +1,6 @@
 use std::{fmt::Display, str::FromStr};
 
-use anyhow::bail;
+use anyhow::{anyhow, bail};
 use chrono::{DateTime, Utc};
 use sha1::{Digest, Sha1};
 
@@ -9,85 +9,370 @@ pub struct Change {
     pub name: String,
     pub note: String,
     pub date: DateTime<Utc>,
-    pub planner: String,
+    /// Date as written in the plan when it isn't in UTC, such as
+    /// `2024-03-07T05:19:34+0200`, kept when writing the plan back
+    pub written_date: Option<String>,
+    pub planner: Planner,
+    /// Changes that must be deployed before this one
+    pub requires: Vec<String>,
+    /// Changes that must not be deployed together with this one
+    pub conflicts: Vec<String>,
 }
 
 impl Change {
-    pub fn format(&self, project: &str, parent: Option<String>) -> Result<String, std::fmt::Error> {
+    pub fn format(
+        &self,
+        project: &str,
+        uri: Option<&str>,
+        parent: Option<String>,
+    ) -> Result<String, std::fmt::Error> {
         use std::fmt::Write;
 
         let mut s = String::new();
         writeln!(&mut s, "project {}", project)?;
+        if let Some(uri) = uri {
+            writeln!(&mut s, "uri {}", canonical_uri(uri))?;
+        }
         writeln!(&mut s, "change {}", self.name)?;
         if let Some(parent) = parent {
             writeln!(&mut s, "parent {}", parent)?;
         }
         writeln!(&mut s, "planner {}", self.planner)?;
-        writeln!(&mut s, "date {}", format_line_date(self.date))?;
-        writeln!(&mut s)?;
-        write!(&mut s, "{}", self.note)?;
+        write!(&mut s, "date {}", format_line_date(self.date))?;
+        if !self.requires.is_empty() {
+            write!(&mut s, "\nrequires")?;
+            for name in &self.requires {
+                write!(&mut s, "\n  + {name}")?;
+            }
+        }
+        if !self.conflicts.is_empty() {
+            write!(&mut s, "\nconflicts")?;
+            for name in &self.conflicts {
+                write!(&mut s, "\n  - {name}")?;
+            }
+        }
+        if !self.note.is_empty() {
+            write!(&mut s, "\n\n{}", self.note)?;
+        }
         Ok(s)
     }
 
-    pub fn id(&self, project: &str, parent_id: Option<String>) -> String {
-        let change_str = self.format(project, parent_id).expect("always succeeds");
-        let bytes = format!("change {}\0{change_str}", change_str.len());
-        let mut hasher = Sha1::new();
-        hasher.update(bytes);
-        let hash = hasher.finalize();
-        base16ct::lower::encode_string(&hash)
+    pub fn id(&self, project: &str, uri: Option<&str>, parent_id: Option<String>) -> String {
+        let change_str = self
+            .format(project, uri, parent_id)
+            .expect("always succeeds");
+        object_id("change", &change_str)
     }
 
-    pub fn parse_line(mut change: &str) -> anyhow::Result<Self> {
-        fn index_of(s: &str, ch: char) -> Option<usize> {
-            s.char_indices()
-                .find_map(|(idx, ch2)| (ch2 == ch).then_some(idx))
-        }
-
-        let Some(name_end_idx) = index_of(change, ' ') else {
-            bail!("missing space after name");
-        };
-        let name = change[..name_end_idx].to_string();
-        change = change[name_end_idx..].trim_start();
-
-        let Some(date_end_idx) = index_of(change, ' ') else {
-            bail!("missing space after date");
-        };
-        let date = DateTime::from_str(&change[..date_end_idx])?;
-        change = change[date_end_idx..].trim_start();
-
-        let (planner, note) = match index_of(change, '#') {
-            Some(planner_end_idx) => (
-                change[..planner_end_idx].trim().to_string(),
-                change[planner_end_idx + 1..].trim().replace("\\n", "\n"),
-            ),
-            None => (change.trim().to_string(), String::new()),
-        };
-
+    pub fn parse_line(change: &str) -> anyhow::Result<Self> {
+        let LineFields {
+            name,
+            requires,
+            conflicts,
+            date,
+            written_date,
+            planner,
+            note,
+        } = LineFields::parse(change)?;
         Ok(Self {
             name,
             note,
             date,
+            written_date,
             planner,
+            requires,
+            conflicts,
         })
     }
 
-    #[cfg(test)]
     pub fn format_line(&self) -> String {
+        let dependencies = self
+            .requires
+            .iter()
+            .cloned()
+            .chain(self.conflicts.iter().map(|name| format!("!{name}")))
+            .collect::<Vec<_>>();
+        let dependencies = if dependencies.is_empty() {
+            String::new()
+        } else {
+            format!(" [{}]", dependencies.join(" "))
+        };
         format!(
-            "{} {} {} # {}",
+            "{}{dependencies} {} {}{}",
             self.name,
-            format_line_date(self.date),
+            written_date(self.date, self.written_date.as_deref()),
             self.planner,
-            self.note.replace('\n', "\\n"),
+            format_line_note(&self.note),
+        )
+    }
+}
+
+/// Why a change or tag name breaks the sqitch naming rules, if it does.
+///
+/// Sqitch refuses to read plans with such names, so `add` and `tag` reject
+/// them and `plan lint` reports them.
+pub fn invalid_name(name: &str, is_tag: bool) -> Option<&'static str> {
+    let (Some(first), Some(last)) = (name.chars().next(), name.chars().last()) else {
+        return Some("is empty");
+    };
+    if name.chars().any(char::is_whitespace) {
+        return Some("contains whitespace");
+    }
+    if name.contains([':', '@', '#', '\\']) {
+        return Some("contains one of : @ # \\");
+    }
+    if is_tag && name.contains('/') {
+        return Some("contains /");
+    }
+    if first.is_ascii_punctuation() {
+        return Some("starts with punctuation");
+    }
+    if last.is_ascii_punctuation() && last != '_' {
+        return Some("ends with punctuation other than _");
+    }
+    if name.len() == 40 && name.chars().all(|ch| ch.is_ascii_hexdigit()) {
+        return Some("looks like a change ID");
+    }
+    // These would read as a change reference like `name^2`
+    let without_digits = name.trim_end_matches(|c: char| c.is_ascii_digit());
+    if without_digits.len() < name.len() && without_digits.ends_with(['~', '^', '/', '=', '%']) {
+        return Some("ends with one of ~ ^ / = % followed by digits");
+    }
+    None
+}
+
+/// A project URI normalized the way sqitch does before hashing it into IDs,
+/// e.g. with the scheme and host lowercased
+pub(crate) fn canonical_uri(uri: &str) -> String {
+    url::Url::parse(uri).map_or_else(|_| uri.to_string(), String::from)
+}
+
+/// SHA-1 of a plan object, computed the same way as git object IDs
+pub(crate) fn object_id(kind: &str, content: &str) -> String {
+    let bytes = format!("{kind} {}\0{content}", content.len());
+    let mut hasher = Sha1::new();
+    hasher.update(bytes);
+    let hash = hasher.finalize();
+    base16ct::lower::encode_string(&hash)
+}
+
+/// Who planned a change or tag, written as `Name <email>`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Planner {
+    pub name: String,
+    /// Empty when the plan gives only a name
+    pub email: String,
+}
+
+impl Planner {
+    pub fn new(name: impl Into<String>, email: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            email: email.into(),
+        }
+    }
+}
+
+impl FromStr for Planner {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        if s.is_empty() {
+            bail!("missing planner");
+        }
+        Ok(
+            match s.strip_suffix('>').and_then(|rest| rest.rsplit_once('<')) {
+                Some((name, email)) => Self::new(name.trim_end(), email),
+                None => Self::new(s, ""),
+            },
         )
     }
 }
 
+impl Display for Planner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.email.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{} <{}>", self.name, self.email)
+        }
+    }
+}
+
+/// Fields of change and tag lines: `name [dependencies] date planner # note`
+pub(crate) struct LineFields {
+    pub name: String,
+    /// Changes in the optional `[required !conflicting]` list after the name
+    pub requires: Vec<String>,
+    pub conflicts: Vec<String>,
+    pub date: DateTime<Utc>,
+    /// The date as written, if not in UTC
+    pub written_date: Option<String>,
+    pub planner: Planner,
+    pub note: String,
+}
+
+impl LineFields {
+    /// Split a line into its fields, separated by any amount of spaces and
+    /// tabs. Errors point at the column where the line goes wrong.
+    pub fn parse(line: &str) -> anyhow::Result<Self> {
+        let mut tokens = Tokens { line, pos: 0 };
+
+        let name = tokens.take_while(|ch| !ch.is_whitespace() && ch != '[');
+        if name.is_empty() {
+            return Err(tokens.error("missing name"));
+        }
+        let mut separated = tokens.skip_whitespace();
+
+        let mut requires = Vec::new();
+        let mut conflicts = Vec::new();
+        if tokens.eat('[') {
+            loop {
+                tokens.skip_whitespace();
+                if tokens.eat(']') {
+                    break;
+                }
+                if tokens.rest().is_empty() {
+                    return Err(tokens.error(format!("missing ] after the dependencies of {name}")));
+                }
+                let column = tokens.column();
+                let dependency = tokens.take_while(|ch| !ch.is_whitespace() && ch != ']');
+                match dependency.strip_prefix('!') {
+                    Some("") => {
+                        return Err(anyhow!(
+                            "column {column}: missing change name after ! in the dependencies of {name}"
+                        ))
+                    }
+                    Some(conflict) => conflicts.push(conflict.to_string()),
+                    None => requires.push(dependency.to_string()),
+                }
+            }
+            separated = tokens.skip_whitespace();
+        }
+        if !separated {
+            return Err(tokens.error(format!("missing space after {name}")));
+        }
+
+        let column = tokens.column();
+        let date_text = tokens.take_while(|ch| !ch.is_whitespace());
+        let date = parse_line_date(date_text).map_err(|e| anyhow!("column {column}: {e}"))?;
+        let written_date =
+            (date_text != format_line_date(date).to_string()).then(|| date_text.to_string());
+        if !tokens.skip_whitespace() {
+            return Err(tokens.error("missing planner after the date"));
+        }
+
+        // `#` may appear in the name or email, so the note starts after the
+        // closing `>` of the email. Planners without an email end at the `#`.
+        let column = tokens.column();
+        let planner_end = match tokens.rest().find('<') {
+            Some(email_start) => match tokens.rest()[email_start..].find('>') {
+                Some(email_len) => email_start + email_len + 1,
+                None => {
+                    tokens.pos += email_start;
+                    return Err(tokens.error("missing > after the planner email"));
+                }
+            },
+            None => tokens.rest().find('#').unwrap_or(tokens.rest().len()),
+        };
+        let planner = tokens.rest()[..planner_end]
+            .parse()
+            .map_err(|e| anyhow!("column {column}: {e}"))?;
+        tokens.pos += planner_end;
+        tokens.skip_whitespace();
+
+        let note = if tokens.eat('#') {
+            tokens.rest().trim().replace("\\n", "\n")
+        } else if tokens.rest().is_empty() {
+            String::new()
+        } else {
+            return Err(tokens.error("expected # before the note"));
+        };
+
+        Ok(Self {
+            name: name.to_string(),
+            requires,
+            conflicts,
+            date,
+            written_date,
+            planner,
+            note,
+        })
+    }
+}
+
+/// A plan line being split into fields, up to `pos`
+struct Tokens<'a> {
+    line: &'a str,
+    pos: usize,
+}
+
+impl<'a> Tokens<'a> {
+    fn rest(&self) -> &'a str {
+        &self.line[self.pos..]
+    }
+
+    /// Column of the next character, counting from 1
+    fn column(&self) -> usize {
+        self.line[..self.pos].chars().count() + 1
+    }
+
+    fn error(&self, message: impl Display) -> anyhow::Error {
+        anyhow!("column {}: {message}", self.column())
+    }
+
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> &'a str {
+        let rest = self.rest();
+        let len = rest.find(|ch| !predicate(ch)).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    /// Skip spaces and tabs, telling whether there were any
+    fn skip_whitespace(&mut self) -> bool {
+        !self.take_while(char::is_whitespace).is_empty()
+    }
+
+    fn eat(&mut self, ch: char) -> bool {
+        let found = self.rest().starts_with(ch);
+        if found {
+            self.pos += ch.len_utf8();
+        }
+        found
+    }
+}
+
+/// The ` # note` at the end of a plan line, left out when there is no note
+pub(crate) fn format_line_note(note: &str) -> String {
+    if note.is_empty() {
+        String::new()
+    } else {
+        format!(" # {}", note.replace('\n', "\\n"))
+    }
+}
+
 pub fn format_line_date(date: DateTime<Utc>) -> impl Display {
     date.format("%FT%TZ")
 }
 
+/// Parse the date of a plan line, in UTC or with an offset like `+02:00` or
+/// the `+0200` sqitch also accepts
+fn parse_line_date(text: &str) -> anyhow::Result<DateTime<Utc>> {
+    let date = DateTime::parse_from_rfc3339(text)
+        .or_else(|_| DateTime::parse_from_str(text, "%FT%T%z"))
+        .map_err(|e| anyhow::anyhow!("invalid date {text}: {e}"))?;
+    Ok(date.with_timezone(&Utc))
+}
+
+/// The date of a plan line as it was written if it still stands for `date`,
+/// otherwise in UTC
+pub(crate) fn written_date(date: DateTime<Utc>, written: Option<&str>) -> String {
+    written
+        .filter(|written| parse_line_date(written).is_ok_and(|parsed| parsed == date))
+        .map_or_else(|| format_line_date(date).to_string(), str::to_string)
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
@@ -95,9 +380,12 @@ pub mod tests {
     pub fn example() -> Change {
         Change {
             date: DateTime::from_str("2024-03-07T03:19:34Z").unwrap(),
+            written_date: None,
             name: "change_name".into(),
             note: "A description of the change".into(),
-            planner: "Ruslan Fadeev <github@kinrany.dev>".into(),
+            planner: Planner::new("Ruslan Fadeev", "github@kinrany.dev"),
+            requires: Vec::new(),
+            conflicts: Vec::new(),
         }
     }
 
@@ -115,14 +403,14 @@ pub mod tests {
 
     #[test]
     fn test_format() {
-        let formatted_change = example().format("quitch", None).unwrap();
+        let formatted_change = example().format("quitch", None, None).unwrap();
         assert_eq!(formatted_change, EXAMPLE_STRING);
     }
 
     #[test]
     fn test_id_without_parent() {
         assert_eq!(
-            example().id("quitch", None),
+            example().id("quitch", None, None),
             "da41a550b0cba5bd3dffbf645032a98ae1136da5",
         );
     }
@@ -132,18 +420,31 @@ pub mod tests {
         assert_eq!(
             example().id(
                 "quitch",
+                None,
                 Some("da41a550b0cba5bd3dffbf645032a98ae1136da5".to_string())
             ),
             "7b6b9ba12694a34a5445e1d847d36d2344d61bcb"
         );
     }
 
+    #[test]
+    fn test_format_with_uri() {
+        let formatted = example()
+            .format("quitch", Some("HTTPS://Example.com"), None)
+            .unwrap();
+        assert!(formatted.starts_with("project quitch\nuri https://example.com/\nchange "));
+        assert_ne!(
+            example().id("quitch", Some("https://example.com/"), None),
+            example().id("quitch", None, None)
+        );
+    }
+
     #[test]
     fn test_id_with_unicode_note() {
         let mut change = example();
         change.note = "🤦🏼‍♂️".into();
         assert_eq!(
-            change.id("quitch", None),
+            change.id("quitch", None, None),
             "fb29c4f840ce9cd266d983a2c90d7ddf745c1711"
         );
     }
@@ -161,6 +462,163 @@ pub mod tests {
         assert_eq!(change, example());
     }
 
+    #[test]
+    fn test_parse_line_with_dependencies() {
+        let change = Change::parse_line(
+            "change_name [users @v1.0 platform:roles !old_users] \
+            2024-03-07T03:19:34Z Ruslan Fadeev <github@kinrany.dev> # A description of the change",
+        )
+        .unwrap();
+        assert_eq!(change.requires, ["users", "@v1.0", "platform:roles"]);
+        assert_eq!(change.conflicts, ["old_users"]);
+        assert_eq!(
+            Change {
+                requires: Vec::new(),
+                conflicts: Vec::new(),
+                ..change.clone()
+            },
+            example()
+        );
+        assert_eq!(Change::parse_line(&change.format_line()).unwrap(), change);
+
+        let change =
+            Change::parse_line("change_name[users] 2024-03-07T03:19:34Z Ruslan Fadeev").unwrap();
+        assert_eq!(change.requires, ["users"]);
+        assert!(Change::parse_line("change_name [users 2024-03-07T03:19:34Z Ruslan").is_err());
+        assert!(Change::parse_line("change_name [!] 2024-03-07T03:19:34Z Ruslan").is_err());
+    }
+
+    #[test]
+    fn test_format_with_dependencies() {
+        let change = Change {
+            requires: vec!["users".into(), "flips@v1.0".into()],
+            conflicts: vec!["old_users".into()],
+            ..example()
+        };
+        assert_eq!(
+            change.format("quitch", None, None).unwrap(),
+            "project quitch\n\
+            change change_name\n\
+            planner Ruslan Fadeev <github@kinrany.dev>\n\
+            date 2024-03-07T03:19:34Z\n\
+            requires\n  + users\n  + flips@v1.0\n\
+            conflicts\n  - old_users\n\
+            \n\
+            A description of the change"
+        );
+        assert_ne!(
+            change.id("quitch", None, None),
+            example().id("quitch", None, None)
+        );
+    }
+
+    #[test]
+    fn test_parse_line_with_offset() {
+        for written in ["2024-03-07T05:19:34+02:00", "2024-03-07T05:19:34+0200"] {
+            let line = EXAMPLE_LINE.replace("2024-03-07T03:19:34Z", written);
+            let change = Change::parse_line(&line).unwrap();
+            assert_eq!(change.date, example().date);
+            assert_eq!(change.written_date.as_deref(), Some(written));
+            assert_eq!(
+                change.id("quitch", None, None),
+                example().id("quitch", None, None)
+            );
+            assert_eq!(change.format_line(), line);
+        }
+
+        // A changed date is written in UTC
+        let mut change = Change::parse_line(
+            &EXAMPLE_LINE.replace("2024-03-07T03:19:34Z", "2024-03-07T05:19:34+0200"),
+        )
+        .unwrap();
+        change.date = DateTime::from_str("2024-03-08T00:00:00Z").unwrap();
+        assert!(change.format_line().contains(" 2024-03-08T00:00:00Z "));
+
+        assert!(Change::parse_line("name 2024-03-07 planner").is_err());
+    }
+
+    #[test]
+    fn test_parse_line_spacing() {
+        let spaced = EXAMPLE_LINE.replace(' ', "  ").replacen(
+            "2024-03-07T03:19:34Z",
+            "\t2024-03-07T03:19:34Z\t",
+            1,
+        );
+        let change = Change::parse_line(&spaced).unwrap();
+        assert_eq!(change.name, "change_name");
+        assert_eq!(
+            change.planner,
+            Planner::new("Ruslan  Fadeev", "github@kinrany.dev")
+        );
+        assert_eq!(change.note, "A  description  of  the  change");
+
+        let change =
+            Change::parse_line("users[accounts] 2024-03-07T03:19:34Z C# Fan <c#@example.com>#Note")
+                .unwrap();
+        assert_eq!(change.requires, ["accounts"]);
+        assert_eq!(change.planner, Planner::new("C# Fan", "c#@example.com"));
+        assert_eq!(change.note, "Note");
+    }
+
+    #[test]
+    fn test_parse_line_errors() {
+        let error = |line: &str| Change::parse_line(line).unwrap_err().to_string();
+        assert_eq!(error("users"), "column 6: missing space after users");
+        assert_eq!(
+            error("users [accounts 2024-03-07T03:19:34Z Jane"),
+            "column 42: missing ] after the dependencies of users"
+        );
+        assert_eq!(
+            error("users [!] 2024-03-07T03:19:34Z Jane"),
+            "column 8: missing change name after ! in the dependencies of users"
+        );
+        assert!(error("users 2024-03-07 Jane").starts_with("column 7: invalid date 2024-03-07"));
+        assert_eq!(
+            error("users 2024-03-07T03:19:34Z"),
+            "column 27: missing planner after the date"
+        );
+        assert_eq!(
+            error("users 2024-03-07T03:19:34Z Jane <jane@example.com"),
+            "column 33: missing > after the planner email"
+        );
+        assert_eq!(
+            error("users 2024-03-07T03:19:34Z Jane <jane@example.com> note"),
+            "column 52: expected # before the note"
+        );
+    }
+
+    #[test]
+    fn test_parse_planner() {
+        let change = Change::parse_line(EXAMPLE_LINE).unwrap();
+        assert_eq!(change.planner.name, "Ruslan Fadeev");
+        assert_eq!(change.planner.email, "github@kinrany.dev");
+
+        let planner: Planner = "Jane".parse().unwrap();
+        assert_eq!(planner, Planner::new("Jane", ""));
+        assert_eq!(planner.to_string(), "Jane");
+        assert!(" ".parse::<Planner>().is_err());
+    }
+
+    #[test]
+    fn test_invalid_name() {
+        for name in ["users", "add_users", "v1.0", "users_", "a/b"] {
+            assert_eq!(invalid_name(name, false), None, "{name}");
+        }
+        assert!(invalid_name("a/b", true).is_some());
+        for name in [
+            ".users",
+            "users.",
+            "us:ers",
+            "us@ers",
+            "users^2",
+            "users~",
+            "us\\ers",
+            "da41a550b0cba5bd3dffbf645032a98ae1136da5",
+        ] {
+            assert!(invalid_name(name, false).is_some(), "{name}");
+        }
+    }
+
     #[test]
     fn test_parse_line_with_newlines() {
         let note = "a\\nb";
@@ -0,0 +1,283 @@
+//! Sqitch-style references to changes in the plan
+//!
+//! A reference starts with one of:
+//! - `@HEAD` (or `@LAST`): the last change
+//! - `@ROOT` (or `@FIRST`): the first change
+//! - `@tag`: the change the tag is applied to
+//! - `name@tag`: the instance of a reworked change as of a tag
+//! - a change name, a change ID or an unambiguous prefix of a change ID at
+//!   least four characters long
+//!
+//! It can be followed by any number of `^` (one change earlier) and `~` (one
+//! change later), optionally with a count: `@HEAD^2` is two changes before the
+//! last one, `@ROOT~` is the second change.
+
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::{anyhow, bail};
+
+use crate::plan::FullChange;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangeRef {
+    base: Base,
+    /// Number of changes to move from the base, negative means earlier
+    offset: i64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Base {
+    Head,
+    Root,
+    Tag(String),
+    /// A change name or ID, optionally as of a tag
+    Change {
+        name_or_id: String,
+        tag: Option<String>,
+    },
+}
+
+impl FromStr for ChangeRef {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (base, suffix) = s.split_at(s.find(['^', '~']).unwrap_or(s.len()));
+        let base = match base {
+            "" => bail!("empty change reference {s:?}"),
+            "@HEAD" | "@LAST" => Base::Head,
+            "@ROOT" | "@FIRST" => Base::Root,
+            _ => match base.split_once('@') {
+                Some(("", tag)) => Base::Tag(tag.to_string()),
+                Some((name, tag)) => Base::Change {
+                    name_or_id: name.to_string(),
+                    tag: Some(tag.to_string()),
+                },
+                None => Base::Change {
+                    name_or_id: base.to_string(),
+                    tag: None,
+                },
+            },
+        };
+
+        let mut offset: i64 = 0;
+        let mut rest = suffix;
+        while let Some(direction) = rest.chars().next() {
+            rest = &rest[1..];
+            let digits_end = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let count = match &rest[..digits_end] {
+                "" => 1,
+                digits => digits
+                    .parse::<i64>()
+                    .map_err(|e| anyhow!("invalid count in change reference {s:?}: {e}"))?,
+            };
+            rest = &rest[digits_end..];
+            offset = match direction {
+                '^' => offset.checked_sub(count),
+                '~' => offset.checked_add(count),
+                _ => bail!("unexpected {direction:?} in change reference {s:?}"),
+            }
+            .ok_or_else(|| anyhow!("offset out of range in change reference {s:?}"))?;
+        }
+
+        Ok(Self { base, offset })
+    }
+}
+
+impl Display for ChangeRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.base {
+            Base::Head => write!(f, "@HEAD")?,
+            Base::Root => write!(f, "@ROOT")?,
+            Base::Tag(tag) => write!(f, "@{tag}")?,
+            Base::Change {
+                name_or_id,
+                tag: None,
+            } => write!(f, "{name_or_id}")?,
+            Base::Change {
+                name_or_id,
+                tag: Some(tag),
+            } => write!(f, "{name_or_id}@{tag}")?,
+        }
+        match self.offset {
+            0 => Ok(()),
+            offset if offset < 0 => write!(f, "^{}", offset.unsigned_abs()),
+            offset => write!(f, "~{offset}"),
+        }
+    }
+}
+
+impl ChangeRef {
+    /// Index of the referenced change in a list of changes in plan order.
+    ///
+    /// Names of reworked changes refer to the latest instance in the list.
+    pub fn resolve_index(&self, changes: &[FullChange]) -> anyhow::Result<usize> {
+        let base_idx = match &self.base {
+            Base::Head => changes.len().checked_sub(1),
+            Base::Root => (!changes.is_empty()).then_some(0),
+            Base::Tag(tag) => changes
+                .iter()
+                .position(|c| c.tags.iter().any(|t| t.name == *tag)),
+            Base::Change {
+                name_or_id,
+                tag: None,
+            } => find_change(changes, name_or_id)?,
+            Base::Change {
+                name_or_id,
+                tag: Some(tag),
+            } => match changes
+                .iter()
+                .position(|c| c.tags.iter().any(|t| t.name == *tag))
+            {
+                Some(tag_idx) => find_change(&changes[..=tag_idx], name_or_id)?,
+                None => None,
+            },
+        };
+        let Some(base_idx) = base_idx else {
+            bail!("no change matches {self}");
+        };
+        i64::try_from(base_idx)?
+            .checked_add(self.offset)
+            .and_then(|idx| usize::try_from(idx).ok())
+            .filter(|idx| *idx < changes.len())
+            .ok_or_else(|| anyhow!("{self} is out of range"))
+    }
+
+    pub fn resolve<'a>(&self, changes: &'a [FullChange]) -> anyhow::Result<&'a FullChange> {
+        Ok(&changes[self.resolve_index(changes)?])
+    }
+}
+
+/// Shortest ID prefix that is looked up, so that short change names that
+/// happen to be hex like `add` don't match IDs by accident
+const MIN_ID_PREFIX_LEN: usize = 4;
+
+/// Index of the last change with this name or ID, or else the only change
+/// whose ID starts with it
+fn find_change(changes: &[FullChange], name_or_id: &str) -> anyhow::Result<Option<usize>> {
+    if let Some(idx) = changes
+        .iter()
+        .rposition(|c| c.id == name_or_id || c.name() == name_or_id)
+    {
+        return Ok(Some(idx));
+    }
+    if name_or_id.len() < MIN_ID_PREFIX_LEN || !name_or_id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Ok(None);
+    }
+    let prefix = name_or_id.to_ascii_lowercase();
+    let matches: Vec<_> = changes
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| c.id.starts_with(&prefix))
+        .collect();
+    match matches[..] {
+        [] => Ok(None),
+        [(idx, _)] => Ok(Some(idx)),
+        _ => {
+            let candidates: Vec<_> = matches
+                .iter()
+                .map(|(_, c)| format!("{} ({})", c.name(), c.id))
+                .collect();
+            bail!(
+                "ambiguous prefix {name_or_id} matches {} changes: {}",
+                matches.len(),
+                candidates.join(", ")
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::tests::example_with_rework;
+
+    fn resolve(reference: &str) -> anyhow::Result<usize> {
+        let changes: Vec<_> = example_with_rework().full_changes().to_vec();
+        ChangeRef::from_str(reference)?.resolve_index(&changes)
+    }
+
+    #[test]
+    fn test_parse_and_display() {
+        for reference in ["@HEAD", "@ROOT~2", "@v1.0^1", "users@v1.0", "users"] {
+            assert_eq!(
+                ChangeRef::from_str(reference).unwrap().to_string(),
+                reference
+            );
+        }
+        assert_eq!(
+            ChangeRef::from_str("@HEAD^^~").unwrap().to_string(),
+            "@HEAD^1"
+        );
+        assert!(ChangeRef::from_str("@HEAD^9223372036854775807^9223372036854775807").is_err());
+        assert!(ChangeRef::from_str("@ROOT~9223372036854775807~").is_err());
+    }
+
+    #[test]
+    fn test_resolve_symbolic() {
+        assert_eq!(resolve("@HEAD").unwrap(), 2);
+        assert_eq!(resolve("@LAST").unwrap(), 2);
+        assert_eq!(resolve("@ROOT").unwrap(), 0);
+        assert_eq!(resolve("@HEAD^").unwrap(), 1);
+        assert_eq!(resolve("@HEAD^2").unwrap(), 0);
+        assert_eq!(resolve("@ROOT~").unwrap(), 1);
+        assert_eq!(resolve("@ROOT~~").unwrap(), 2);
+        assert!(resolve("@HEAD~").is_err());
+        assert!(resolve("@ROOT^").is_err());
+    }
+
+    #[test]
+    fn test_resolve_names_and_tags() {
+        assert_eq!(resolve("change_num2").unwrap(), 1);
+        assert_eq!(resolve("change_num2^").unwrap(), 0);
+        // Names of reworked changes refer to the latest instance
+        assert_eq!(resolve("change_name").unwrap(), 2);
+        assert_eq!(resolve("change_name@v1.0").unwrap(), 0);
+        assert_eq!(resolve("@v1.0").unwrap(), 0);
+        assert_eq!(resolve("@v1.0~").unwrap(), 1);
+        assert_eq!(
+            resolve("da41a550b0cba5bd3dffbf645032a98ae1136da5").unwrap(),
+            0
+        );
+        assert!(resolve("@v2.0").is_err());
+        assert!(resolve("unknown").is_err());
+    }
+
+    #[test]
+    fn test_resolve_id_prefix() {
+        assert_eq!(resolve("da41a550").unwrap(), 0);
+        assert_eq!(resolve("2959").unwrap(), 1);
+        assert_eq!(resolve("B85ABC3D").unwrap(), 2);
+        assert_eq!(resolve("da41@v1.0").unwrap(), 0);
+        assert_eq!(resolve("da41~").unwrap(), 1);
+        // Too short to be looked up as a prefix
+        assert!(resolve("da4").is_err());
+        assert!(resolve("da41x").is_err());
+
+        let mut changes: Vec<_> = example_with_rework().full_changes().to_vec();
+        changes[1].id = format!("da41{}", &changes[1].id[4..]);
+        let error = ChangeRef::from_str("da41")
+            .unwrap()
+            .resolve_index(&changes)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.starts_with("ambiguous prefix da41 matches 2 changes: change_name (da41"),
+            "{error}"
+        );
+        assert_eq!(
+            ChangeRef::from_str("da41a")
+                .unwrap()
+                .resolve_index(&changes)
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_resolve_empty() {
+        let reference = ChangeRef::from_str("@HEAD").unwrap();
+        assert!(reference.resolve_index(&[]).is_err());
+    }
+}
@@ -0,0 +1,139 @@
+//! Shell completion scripts.
+//!
+//! The static part comes from clap. Change names can't be known in advance, so
+//! the scripts complete options taking a change by calling the hidden
+//! `quitch __complete-changes`, which lists the changes in the plan.
+
+use clap_complete::Shell;
+use itertools::Itertools;
+
+use quitch::plan::Plan;
+
+/// Name of the helper command called by the completion scripts
+pub const HELPER: &str = "__complete-changes";
+
+/// Completion script for `shell`, with change names completed from the plan
+pub fn script(shell: Shell, command: &mut clap::Command) -> String {
+    let mut generated = Vec::new();
+    clap_complete::generate(shell, command, "quitch", &mut generated);
+    let generated = String::from_utf8(generated).expect("completion scripts are UTF-8");
+    match shell {
+        Shell::Bash => generated + BASH,
+        Shell::Fish => generated + FISH,
+        Shell::Zsh => {
+            // Options taking a change complete with the function defined below,
+            // which has to exist before the script calls `_quitch`
+            let generated =
+                ["TO_CHANGE", "AFTER_CHANGE", "ONTO"]
+                    .iter()
+                    .fold(generated, |script, value| {
+                        script.replace(
+                            &format!(":{value}:_default'"),
+                            &format!(":{value}:_quitch_changes'"),
+                        )
+                    });
+            match generated.split_once('\n') {
+                Some((compdef, rest)) => format!("{compdef}\n{ZSH}{rest}"),
+                None => generated,
+            }
+        }
+        _ => generated,
+    }
+}
+
+/// Names, tags and symbolic references of the changes in a plan that start
+/// with `prefix`
+pub fn change_candidates(plan: &Plan, prefix: &str) -> Vec<String> {
+    let changes = plan.full_changes();
+    let names = changes.iter().map(|change| change.name().to_string());
+    let tags = changes
+        .iter()
+        .flat_map(|change| &change.tags)
+        .map(|tag| format!("@{}", tag.name));
+    let symbolic = ["@HEAD", "@ROOT"].map(String::from);
+    names
+        .chain(tags)
+        .chain(symbolic)
+        .unique()
+        .filter(|candidate| candidate.starts_with(prefix))
+        .collect()
+}
+
+const BASH: &str = r#"
+_quitch_with_changes() {
+    local cur="${COMP_WORDS[COMP_CWORD]}" prev="${COMP_WORDS[COMP_CWORD-1]}"
+    case "${prev}" in
+        --to-change|--after-change|--onto)
+            local i plan_file=()
+            for ((i = 1; i < COMP_CWORD - 1; i++)); do
+                if [[ "${COMP_WORDS[i]}" == --plan-file ]]; then
+                    plan_file=(--plan-file "${COMP_WORDS[i+1]}")
+                fi
+            done
+            COMPREPLY=($(quitch __complete-changes "${plan_file[@]}" -- "${cur}" 2>/dev/null))
+            return 0
+            ;;
+    esac
+    _quitch "$@"
+}
+
+complete -F _quitch_with_changes -o nosort -o bashdefault -o default quitch
+"#;
+
+const FISH: &str = r#"
+function __quitch_changes
+    set -l tokens (commandline -opc)
+    set -l plan_file
+    if set -l i (contains -i -- --plan-file $tokens)
+        set plan_file --plan-file $tokens[(math $i + 1)]
+    end
+    quitch __complete-changes $plan_file -- (commandline -ct) 2>/dev/null
+end
+complete -c quitch -l to-change -l after-change -l onto -x -a '(__quitch_changes)'
+"#;
+
+const ZSH: &str = r#"
+_quitch_changes() {
+    local -a plan_file changes
+    local i=${words[(I)--plan-file]}
+    (( i )) && plan_file=(--plan-file "${words[i+1]}")
+    changes=(${(f)"$(quitch __complete-changes $plan_file -- "$PREFIX" 2>/dev/null)"})
+    compadd -a changes
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+    use crate::Cli;
+
+    #[test]
+    fn test_change_candidates() {
+        let plan = Plan::parse(
+            "%syntax-version=1.0.0\n\
+            %project=quitch\n\
+            \n\
+            change_name 2024-03-07T03:19:34Z Ruslan Fadeev <github@kinrany.dev> # First\n\
+            @v1.0 2024-03-07T03:19:34Z Ruslan Fadeev <github@kinrany.dev> # Tag\n",
+        )
+        .unwrap();
+        let all = change_candidates(&plan, "");
+        assert!(all.contains(&"change_name".to_string()));
+        assert!(all.contains(&"@v1.0".to_string()));
+        assert!(all.contains(&"@HEAD".to_string()));
+        assert_eq!(change_candidates(&plan, "@v"), ["@v1.0"]);
+    }
+
+    #[test]
+    fn test_scripts_call_the_helper() {
+        for shell in [Shell::Bash, Shell::Fish, Shell::Zsh] {
+            let script = script(shell, &mut Cli::command());
+            assert!(script.contains(HELPER), "{shell}");
+        }
+        let zsh = script(Shell::Zsh, &mut Cli::command());
+        assert!(zsh.starts_with("#compdef quitch\n\n_quitch_changes() {"));
+        assert!(zsh.contains(":TO_CHANGE:_quitch_changes'"));
+    }
+}
@@ -0,0 +1,943 @@
+//! Sqitch configuration files, `sqitch.conf`
+//!
+//! The format is the one git uses for its config: `[section]` and
+//! `[section "subsection"]` headers followed by `key = value` lines, read as
+//! `section.subsection.key`.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    change::Planner,
+    engine::{parse_seconds, EngineKind, Timeouts},
+    notify::{NotifyOn, Webhook},
+    script::ScriptDirs,
+    tunnel::SshOptions,
+};
+
+/// Name of the project config file, looked up in the current directory
+pub const LOCAL_CONFIG_FILE: &str = "sqitch.conf";
+
+/// Registry used when neither the command line nor the config names one
+pub const DEFAULT_REGISTRY: &str = "sqitch";
+
+/// Plan file used when neither the command line nor the config names one
+pub const DEFAULT_PLAN_FILE: &str = "sqitch.plan";
+
+/// A config file in the hierarchy, from the least to the most specific
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigScope {
+    System,
+    User,
+    /// The project's `sqitch.conf`
+    Local,
+}
+
+impl ConfigScope {
+    pub const ALL: [Self; 3] = [Self::System, Self::User, Self::Local];
+
+    /// Path of the config file of this scope, which environment variables can
+    /// move elsewhere. `local` replaces the project's `sqitch.conf`.
+    ///
+    /// None for the user config without a home directory.
+    pub fn path(
+        self,
+        local: Option<&Path>,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Option<PathBuf> {
+        let (vars, default) = match self {
+            Self::System => (
+                ["QUITCH_SYSTEM_CONFIG", "SQITCH_SYSTEM_CONFIG"],
+                Some(PathBuf::from("/etc/sqitch/sqitch.conf")),
+            ),
+            Self::User => (
+                ["QUITCH_USER_CONFIG", "SQITCH_USER_CONFIG"],
+                var("HOME").map(|home| Path::new(&home).join(".sqitch").join("sqitch.conf")),
+            ),
+            Self::Local => {
+                if let Some(local) = local {
+                    return Some(local.to_path_buf());
+                }
+                (
+                    ["QUITCH_CONFIG", "SQITCH_CONFIG"],
+                    Some(PathBuf::from(LOCAL_CONFIG_FILE)),
+                )
+            }
+        };
+        vars.into_iter()
+            .find_map(var)
+            .map(PathBuf::from)
+            .or(default)
+    }
+}
+
+/// Values of config files, in the order they were read
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Config {
+    entries: Vec<(String, String)>,
+}
+
+/// Where a target is and which registry, plan and scripts it uses, after
+/// looking up named targets and engine defaults in the config
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TargetConfig {
+    /// URI of the target database, if the config or command line has one
+    pub uri: Option<String>,
+    pub registry: String,
+    pub plan_file: String,
+    /// Directory containing the script directories, if set
+    pub top_dir: Option<String>,
+    /// `deploy_dir`, `revert_dir` and `verify_dir`, if set
+    pub deploy_dir: Option<String>,
+    pub revert_dir: Option<String>,
+    pub verify_dir: Option<String>,
+    /// `connect_timeout`, `statement_timeout` and `lock_wait_timeout`, if set
+    pub timeouts: Timeouts,
+    /// `ssh_host`, `ssh_user` and `ssh_key`, if set
+    pub ssh: SshOptions,
+}
+
+/// Settings from the command line that take precedence over the config
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Overrides<'a> {
+    /// Engine to use instead of `core.engine` when there is no target
+    pub engine: Option<&'a str>,
+    pub top_dir: Option<&'a str>,
+    /// Directory to look for `sqitch.plan` in, then in its parents, when the
+    /// config doesn't say where the plan is
+    pub search_from: Option<&'a Path>,
+}
+
+impl TargetConfig {
+    /// Where the scripts are: `deploy_dir` and the like, then their default
+    /// subdirectories of `top_dir`, which defaults to the directory of the
+    /// plan file
+    pub fn script_dirs(&self) -> ScriptDirs {
+        let defaults = match &self.top_dir {
+            Some(top_dir) => ScriptDirs::in_top_dir(Path::new(top_dir)),
+            None => ScriptDirs::next_to_plan(&self.plan_file),
+        };
+        let dir = |set: &Option<String>, default| set.as_ref().map_or(default, PathBuf::from);
+        ScriptDirs {
+            deploy: dir(&self.deploy_dir, defaults.deploy),
+            revert: dir(&self.revert_dir, defaults.revert),
+            verify: dir(&self.verify_dir, defaults.verify),
+            hooks: defaults.hooks,
+        }
+    }
+}
+
+impl Config {
+    /// Read a config file, treating a missing file as an empty config
+    pub async fn load(path: &Path) -> anyhow::Result<Self> {
+        match tokio::fs::read_to_string(path).await {
+            Ok(contents) => {
+                Self::parse(&contents).with_context(|| format!("invalid {}", path.display()))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("failed to read {}", path.display())),
+        }
+    }
+
+    pub fn parse(contents: &str) -> anyhow::Result<Self> {
+        let mut entries = Vec::new();
+        let mut section = None;
+        for (i, line) in contents.lines().enumerate() {
+            let line_no = i + 1;
+            match parse_line(line).with_context(|| format!("line {line_no}"))? {
+                Line::Blank => {}
+                Line::Section(name) => section = Some(name),
+                Line::Entry(key, value) => {
+                    let Some(section) = &section else {
+                        bail!("line {line_no}: value outside of a section");
+                    };
+                    entries.push((format!("{section}.{key}"), value));
+                }
+            }
+        }
+        Ok(Self { entries })
+    }
+
+    /// Read the config files of all scopes, more specific ones overriding the
+    /// others. `local` replaces the project's `sqitch.conf`.
+    pub async fn load_all(local: Option<&Path>) -> anyhow::Result<Self> {
+        let mut config = Self::default();
+        for scope in ConfigScope::ALL {
+            if let Some(path) = scope.path(local, |var| std::env::var(var).ok()) {
+                config.entries.extend(Self::load(&path).await?.entries);
+            }
+        }
+        Ok(config)
+    }
+
+    /// All keys and values, in the order they were read
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Value of a key such as `core.engine` or `target.prod.uri`.
+    ///
+    /// Section and key names are case-insensitive, subsection names are not.
+    /// The last value wins if a key is set more than once.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let key = normalize_key(key);
+        self.entries
+            .iter()
+            .rev()
+            .find(|(k, _)| *k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Names of the subsections of a section, such as the targets in
+    /// `[target "name"]` sections, in the order they first appear
+    pub fn subsections(&self, section: &str) -> Vec<&str> {
+        let prefix = format!("{}.", section.to_lowercase());
+        let mut names = Vec::new();
+        for (key, _) in &self.entries {
+            let Some((name, _)) = key
+                .strip_prefix(&prefix)
+                .and_then(|rest| rest.rsplit_once('.'))
+            else {
+                continue;
+            };
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        names
+    }
+
+    /// Identity of the user from `QUITCH_FULLNAME` or `SQITCH_FULLNAME` and
+    /// `QUITCH_EMAIL` or `SQITCH_EMAIL`, falling back to `user.name` and
+    /// `user.email`
+    pub fn user_identity(&self, var: impl Fn(&str) -> Option<String>) -> Option<Planner> {
+        let value = |vars: [&str; 2], key| {
+            vars.into_iter()
+                .find_map(&var)
+                .or_else(|| self.get(key).map(str::to_string))
+        };
+        Some(Planner::new(
+            value(["QUITCH_FULLNAME", "SQITCH_FULLNAME"], "user.name")?,
+            value(["QUITCH_EMAIL", "SQITCH_EMAIL"], "user.email")?,
+        ))
+    }
+
+    /// Webhooks told about deploys, reverts and rebases, from `[webhook]`
+    /// sections with a `url` and optionally `on`
+    pub fn webhooks(&self) -> anyhow::Result<Vec<Webhook>> {
+        self.subsections("webhook")
+            .into_iter()
+            .map(|name| {
+                let Some(url) = self.get(&format!("webhook.{name}.url")) else {
+                    bail!("webhook.{name}.url is not set");
+                };
+                let on = match self.get(&format!("webhook.{name}.on")) {
+                    Some(on) => on
+                        .parse()
+                        .with_context(|| format!("invalid webhook.{name}.on"))?,
+                    None => NotifyOn::default(),
+                };
+                Ok(Webhook {
+                    name: name.to_string(),
+                    url: url.to_string(),
+                    on,
+                })
+            })
+            .collect()
+    }
+
+    /// Resolve the target given on the command line, either a URI or the name
+    /// of a `[target]` section, falling back to the target of `core.engine`.
+    ///
+    /// The registry, plan file and script directories come from the target's
+    /// section, then from its engine's section, then from `core`, like in
+    /// sqitch. Without a `plan_file`, the plan is `sqitch.plan` in `top_dir`.
+    /// Projects with a plan per engine set them in each `[engine]` section.
+    pub fn target(&self, target: Option<&str>) -> anyhow::Result<TargetConfig> {
+        self.target_with(target, Overrides::default())
+    }
+
+    /// Same as [`Config::target`], with settings from the command line
+    /// overriding the config
+    pub fn target_with(
+        &self,
+        target: Option<&str>,
+        overrides: Overrides,
+    ) -> anyhow::Result<TargetConfig> {
+        let core_engine = overrides
+            .engine
+            .or_else(|| self.get("core.engine"))
+            .map(engine_name);
+        let target = target.or_else(|| {
+            core_engine.and_then(|engine| self.get(&format!("engine.{engine}.target")))
+        });
+        let (name, uri) = match target {
+            // Target names can't contain colons, URIs always do
+            Some(uri) if uri.contains(':') => (None, Some(uri)),
+            Some(name) => match self.get(&format!("target.{name}.uri")) {
+                Some(uri) => (Some(name), Some(uri)),
+                None => bail!("unknown target {name}, expected a URI or a target from the config"),
+            },
+            None => (None, None),
+        };
+        let engine = uri.and_then(uri_engine).or(core_engine);
+        // A URI like `db:mysql:` only names the engine, sqitch writes it as the
+        // target of engines added without one
+        let uri = uri.filter(|uri| !uri.ends_with(':'));
+
+        let lookup = |key: &str| {
+            name.and_then(|name| self.get(&format!("target.{name}.{key}")))
+                .or_else(|| engine.and_then(|engine| self.get(&format!("engine.{engine}.{key}"))))
+                .or_else(|| self.get(&format!("core.{key}")))
+        };
+        let top_dir = overrides.top_dir.or_else(|| lookup("top_dir"));
+        let plan_file = match (lookup("plan_file"), top_dir) {
+            (Some(plan_file), _) => plan_file.to_string(),
+            (None, Some(top_dir)) => Path::new(top_dir)
+                .join(DEFAULT_PLAN_FILE)
+                .to_string_lossy()
+                .into_owned(),
+            (None, None) => overrides
+                .search_from
+                .and_then(find_plan_file)
+                .map_or(DEFAULT_PLAN_FILE.to_string(), |path| {
+                    path.to_string_lossy().into_owned()
+                }),
+        };
+        let seconds = |key: &str| {
+            lookup(key)
+                .map(|value| parse_seconds(key, value))
+                .transpose()
+        };
+        let timeouts = Timeouts {
+            connect_timeout: seconds("connect_timeout")?,
+            statement_timeout: seconds("statement_timeout")?,
+            lock_wait_timeout: seconds("lock_wait_timeout")?,
+        };
+        let ssh = SshOptions {
+            ssh_host: lookup("ssh_host").map(str::to_string),
+            ssh_user: lookup("ssh_user").map(str::to_string),
+            ssh_key: lookup("ssh_key").map(PathBuf::from),
+        };
+        Ok(TargetConfig {
+            uri: uri.map(str::to_string),
+            registry: lookup("registry").unwrap_or(DEFAULT_REGISTRY).to_string(),
+            plan_file,
+            top_dir: top_dir.map(str::to_string),
+            deploy_dir: lookup("deploy_dir").map(str::to_string),
+            revert_dir: lookup("revert_dir").map(str::to_string),
+            verify_dir: lookup("verify_dir").map(str::to_string),
+            timeouts,
+            ssh,
+        })
+    }
+}
+
+/// Path of the nearest `sqitch.plan` in `dir` or its parents relative to `dir`,
+/// such as `../../sqitch.plan`, the way git finds `.git`
+pub fn find_plan_file(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .position(|ancestor| ancestor.join(DEFAULT_PLAN_FILE).is_file())
+        .map(|depth| {
+            std::iter::repeat_n("..", depth)
+                .collect::<PathBuf>()
+                .join(DEFAULT_PLAN_FILE)
+        })
+}
+
+/// Engine name of a target URI, such as `pg` in `db:pg://localhost/app`
+fn uri_engine(uri: &str) -> Option<&str> {
+    let uri = uri.strip_prefix("db:").unwrap_or(uri);
+    uri.split_once(':').map(|(scheme, _)| engine_name(scheme))
+}
+
+/// Name sqitch uses for an engine, so that `postgres://` targets find the
+/// settings in `[engine "pg"]`
+pub fn engine_name(engine: &str) -> &str {
+    match EngineKind::from_scheme(engine) {
+        Ok(kind) => kind.sqitch_name(),
+        Err(_) => engine,
+    }
+}
+
+/// Lowercase the section and key of a full key name, keeping the subsection
+fn normalize_key(key: &str) -> String {
+    match (key.split_once('.'), key.rsplit_once('.')) {
+        (Some((section, rest)), Some((_, name))) if rest.contains('.') => {
+            let subsection = &rest[..rest.len() - name.len() - 1];
+            format!(
+                "{}.{subsection}.{}",
+                section.to_lowercase(),
+                name.to_lowercase()
+            )
+        }
+        _ => key.to_lowercase(),
+    }
+}
+
+/// Rewrite a config file with `edit`, starting from an empty file if it
+/// doesn't exist yet
+pub async fn edit_file(
+    path: &Path,
+    edit: impl FnOnce(&str) -> anyhow::Result<String>,
+) -> anyhow::Result<()> {
+    let contents = match tokio::fs::read_to_string(path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+    };
+    let contents =
+        edit(&contents).with_context(|| format!("failed to update {}", path.display()))?;
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    tokio::fs::write(path, contents)
+        .await
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// Set a key in the contents of a config file, or remove it without a value,
+/// keeping the rest of the file as is.
+///
+/// A new key goes at the end of the last section it belongs to, a new section
+/// at the end of the file.
+pub fn edit(contents: &str, key: &str, value: Option<&str>) -> anyhow::Result<String> {
+    let key = normalize_key(key);
+    let Some((section, name)) = key.rsplit_once('.') else {
+        bail!("key {key} has no section, expected e.g. core.{key}");
+    };
+    let mut lines: Vec<String> = contents.lines().map(str::to_string).collect();
+    let mut current = None;
+    // Lines setting the key, and the line after the last one in its section
+    let mut found = Vec::new();
+    let mut section_end = None;
+    for (i, line) in lines.iter().enumerate() {
+        match parse_line(line).with_context(|| format!("line {}", i + 1))? {
+            Line::Blank => continue,
+            Line::Section(name) => current = Some(name),
+            Line::Entry(entry_key, _) => {
+                if current.as_deref() == Some(section) && entry_key == name {
+                    found.push(i);
+                }
+            }
+        }
+        if current.as_deref() == Some(section) {
+            section_end = Some(i + 1);
+        }
+    }
+
+    match value {
+        Some(value) => {
+            let entry = format!("\t{name} = {}", format_value(value));
+            match (found.last(), section_end) {
+                (Some(&i), _) => lines[i] = entry,
+                (None, Some(end)) => lines.insert(end, entry),
+                (None, None) => {
+                    lines.push(format_section(section));
+                    lines.push(entry);
+                }
+            }
+        }
+        None => {
+            if found.is_empty() {
+                bail!("{key} is not set");
+            }
+            for i in found.into_iter().rev() {
+                lines.remove(i);
+            }
+        }
+    }
+    Ok(lines.into_iter().map(|line| line + "\n").collect())
+}
+
+/// Remove every section of this name, such as `target.prod`, along with its
+/// values from the contents of a config file
+pub fn remove_section(contents: &str, section: &str) -> anyhow::Result<String> {
+    let section = normalize_key(&format!("{section}.x"));
+    let section = section
+        .strip_suffix(".x")
+        .expect("normalized with the suffix");
+    let mut removed = false;
+    let mut in_section = false;
+    let mut kept = String::new();
+    for (i, line) in contents.lines().enumerate() {
+        if let Line::Section(name) = parse_line(line).with_context(|| format!("line {}", i + 1))? {
+            in_section = name == section;
+            removed |= in_section;
+        }
+        if !in_section {
+            kept.push_str(line);
+            kept.push('\n');
+        }
+    }
+    if !removed {
+        bail!("no {} section", format_section(section));
+    }
+    Ok(kept)
+}
+
+/// A line of a config file
+enum Line {
+    /// Empty or a comment
+    Blank,
+    Section(String),
+    /// Lowercase key within the section and its value
+    Entry(String, String),
+}
+
+fn parse_line(line: &str) -> anyhow::Result<Line> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with(['#', ';']) {
+        return Ok(Line::Blank);
+    }
+    if let Some(header) = line.strip_prefix('[') {
+        let Some((header, _)) = header.split_once(']') else {
+            bail!("unterminated section header");
+        };
+        return Ok(Line::Section(parse_section(header)?));
+    }
+    let (key, value) = match line.split_once('=') {
+        Some((key, value)) => (key.trim(), parse_value(value)),
+        // A key without a value is a boolean flag, like in git
+        None => (line, "true".to_string()),
+    };
+    if key.is_empty() || !key.chars().all(|c| c.is_alphanumeric() || "-_".contains(c)) {
+        bail!("invalid key {key}");
+    }
+    Ok(Line::Entry(key.to_lowercase(), value))
+}
+
+/// Section header of a section name such as `target.prod`
+fn format_section(section: &str) -> String {
+    match section.split_once('.') {
+        Some((name, subsection)) => {
+            let subsection = subsection.replace('\\', "\\\\").replace('"', "\\\"");
+            format!("[{name} \"{subsection}\"]")
+        }
+        None => format!("[{section}]"),
+    }
+}
+
+/// Value as written to a config file, quoted if it wouldn't read back as is
+fn format_value(value: &str) -> String {
+    let needs_quotes = value.trim() != value
+        || value
+            .chars()
+            .any(|c| matches!(c, '#' | ';' | '"' | '\\' | '\n' | '\t'));
+    if !needs_quotes {
+        return value.to_string();
+    }
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t");
+    format!("\"{escaped}\"")
+}
+
+/// Parse the inside of a section header, `core` or `target "prod"`
+fn parse_section(header: &str) -> anyhow::Result<String> {
+    let header = header.trim();
+    let (name, subsection) = match header.split_once(char::is_whitespace) {
+        Some((name, subsection)) => (name, Some(subsection.trim())),
+        None => (header, None),
+    };
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_alphanumeric() || "-_".contains(c))
+    {
+        bail!("invalid section name {name}");
+    }
+    let name = name.to_lowercase();
+    match subsection {
+        None => Ok(name),
+        Some(subsection) => {
+            let Some(subsection) = subsection
+                .strip_prefix('"')
+                .and_then(|s| s.strip_suffix('"'))
+            else {
+                bail!("subsection of {name} must be quoted");
+            };
+            let subsection = subsection.replace("\\\"", "\"").replace("\\\\", "\\");
+            Ok(format!("{name}.{subsection}"))
+        }
+    }
+}
+
+/// Parse a value, removing quotes and trailing comments
+fn parse_value(raw: &str) -> String {
+    let mut value = String::new();
+    // Length of the value up to the last quoted character, which is kept even
+    // if it is whitespace
+    let mut quoted_len = 0;
+    let mut in_quotes = false;
+    let mut chars = raw.trim().chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '\\' => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some(c) => value.push(c),
+                None => {}
+            },
+            '#' | ';' if !in_quotes => break,
+            c => value.push(c),
+        }
+        if in_quotes {
+            quoted_len = value.len();
+        }
+    }
+    let unquoted_len = value.trim_end().len();
+    value.truncate(unquoted_len.max(quoted_len));
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static EXAMPLE: &str = r#"
+# Written by sqitch init
+[core]
+	engine = mysql
+	plan_file = db/sqitch.plan
+	; top_dir = db
+[engine "mysql"]
+	target = dev
+	registry = sqitch_registry
+[target "dev"]
+	uri = db:mysql://root@localhost/app_dev
+[target "Prod"]
+	uri = "db:mysql://deploy@db.example.com/app" # the primary
+	registry = meta
+	plan_file = prod.plan
+[deploy]
+	verify
+[core "variables"]
+	schema = " app "
+"#;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(EXAMPLE).unwrap();
+        assert_eq!(config.get("core.engine"), Some("mysql"));
+        assert_eq!(config.get("Core.Plan_File"), Some("db/sqitch.plan"));
+        assert_eq!(config.get("core.top_dir"), None);
+        assert_eq!(
+            config.get("target.Prod.uri"),
+            Some("db:mysql://deploy@db.example.com/app")
+        );
+        assert_eq!(config.get("target.prod.uri"), None);
+        assert_eq!(config.get("deploy.verify"), Some("true"));
+        assert_eq!(config.get("core.variables.schema"), Some(" app "));
+
+        let config = Config::parse("[core]\nengine = pg\nengine = mysql\n").unwrap();
+        assert_eq!(config.get("core.engine"), Some("mysql"));
+
+        assert!(Config::parse("engine = mysql").is_err());
+        assert!(Config::parse("[core\nengine = mysql").is_err());
+        assert!(Config::parse("[target prod]\nuri = x").is_err());
+    }
+
+    #[test]
+    fn test_target() {
+        let config = Config::parse(EXAMPLE).unwrap();
+        // The default target of the core engine
+        assert_eq!(
+            config.target(None).unwrap(),
+            TargetConfig {
+                uri: Some("db:mysql://root@localhost/app_dev".into()),
+                registry: "sqitch_registry".into(),
+                plan_file: "db/sqitch.plan".into(),
+                ..TargetConfig::default()
+            }
+        );
+        // A named target overrides the engine and core settings
+        assert_eq!(
+            config.target(Some("Prod")).unwrap(),
+            TargetConfig {
+                uri: Some("db:mysql://deploy@db.example.com/app".into()),
+                registry: "meta".into(),
+                plan_file: "prod.plan".into(),
+                ..TargetConfig::default()
+            }
+        );
+        // A URI only picks up the settings of its engine
+        let target = config.target(Some("pg://localhost/app")).unwrap();
+        assert_eq!(target.registry, DEFAULT_REGISTRY);
+        assert_eq!(target.plan_file, "db/sqitch.plan");
+        assert!(config.target(Some("staging")).is_err());
+
+        // No config at all
+        assert_eq!(
+            Config::default().target(None).unwrap(),
+            TargetConfig {
+                uri: None,
+                registry: DEFAULT_REGISTRY.into(),
+                plan_file: DEFAULT_PLAN_FILE.into(),
+                ..TargetConfig::default()
+            }
+        );
+        let config =
+            Config::parse("[core]\nengine = pg\n[engine \"pg\"]\ntarget = db:pg:\nregistry = meta")
+                .unwrap();
+        let target = config.target(None).unwrap();
+        assert_eq!(target.uri, None);
+        assert_eq!(target.registry, "meta");
+        let config = Config::parse("[core]\ntop_dir = migrations").unwrap();
+        assert_eq!(
+            config.target(None).unwrap().plan_file,
+            "migrations/sqitch.plan"
+        );
+    }
+
+    #[test]
+    fn test_plan_per_engine() {
+        let config = Config::parse(
+            "[core]\n\
+            engine = mysql\n\
+            [engine \"mysql\"]\n\
+            top_dir = mysql\n\
+            [engine \"pg\"]\n\
+            top_dir = pg\n",
+        )
+        .unwrap();
+        // The engine of the target picks the plan, whatever its scheme is called
+        for uri in ["db:pg://localhost/app", "postgres://localhost/app"] {
+            let target = config.target(Some(uri)).unwrap();
+            assert_eq!(target.plan_file, "pg/sqitch.plan");
+            assert_eq!(target.script_dirs().deploy, Path::new("pg/deploy"));
+        }
+        assert_eq!(config.target(None).unwrap().plan_file, "mysql/sqitch.plan");
+        let overrides = Overrides {
+            engine: Some("postgresql"),
+            ..Overrides::default()
+        };
+        assert_eq!(
+            config.target_with(None, overrides).unwrap().plan_file,
+            "pg/sqitch.plan"
+        );
+    }
+
+    #[test]
+    fn test_find_plan_file() {
+        let root = std::env::temp_dir().join(format!("quitch-find-plan-{}", std::process::id()));
+        let nested = root.join("db").join("deploy");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::write(root.join(DEFAULT_PLAN_FILE), "").unwrap();
+
+        assert_eq!(
+            find_plan_file(&root),
+            Some(PathBuf::from(DEFAULT_PLAN_FILE))
+        );
+        assert_eq!(
+            find_plan_file(&nested),
+            Some(PathBuf::from("../../sqitch.plan"))
+        );
+        // Only when the config doesn't name a plan
+        let overrides = Overrides {
+            search_from: Some(&nested),
+            ..Overrides::default()
+        };
+        assert_eq!(
+            Config::default()
+                .target_with(None, overrides)
+                .unwrap()
+                .plan_file,
+            "../../sqitch.plan"
+        );
+        let config = Config::parse("[core]\nplan_file = app.plan").unwrap();
+        assert_eq!(
+            config.target_with(None, overrides).unwrap().plan_file,
+            "app.plan"
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_script_dirs() {
+        let config = Config::parse(
+            "[core]
+            engine = pg
+            top_dir = db
+            revert_dir = db/undo
+            [engine \"pg\"]
+            deploy_dir = db/pg/deploy
+",
+        )
+        .unwrap();
+        let target = config.target(None).unwrap();
+        assert_eq!(target.plan_file, "db/sqitch.plan");
+        assert_eq!(
+            target.script_dirs(),
+            ScriptDirs {
+                deploy: "db/pg/deploy".into(),
+                revert: "db/undo".into(),
+                verify: "db/verify".into(),
+                hooks: "db/hooks".into(),
+            }
+        );
+        // Other engines only get the core settings
+        let target = config.target(Some("db:mysql://localhost/app")).unwrap();
+        assert_eq!(target.script_dirs().deploy, Path::new("db/deploy"));
+
+        // The command line overrides `top_dir`, but not the directories of one kind
+        let overrides = Overrides {
+            top_dir: Some("migrations"),
+            ..Overrides::default()
+        };
+        let target = config.target_with(None, overrides).unwrap();
+        assert_eq!(target.plan_file, "migrations/sqitch.plan");
+        assert_eq!(target.script_dirs().verify, Path::new("migrations/verify"));
+        assert_eq!(target.script_dirs().revert, Path::new("db/undo"));
+
+        // Without `top_dir`, scripts are next to the plan file
+        let config = Config::parse(
+            "[core]
+plan_file = app/sqitch.plan",
+        )
+        .unwrap();
+        assert_eq!(
+            config.target(None).unwrap().script_dirs(),
+            ScriptDirs::in_top_dir(Path::new("app"))
+        );
+    }
+
+    #[test]
+    fn test_edit() {
+        let contents = "# Project config\n[core]\n\tengine = mysql\n\n[deploy]\n\tverify\n";
+
+        // Replace a value
+        let edited = edit(contents, "core.engine", Some("pg")).unwrap();
+        assert_eq!(
+            edited,
+            "# Project config\n[core]\n\tengine = pg\n\n[deploy]\n\tverify\n"
+        );
+
+        // Add a key to an existing section, before the blank line
+        let edited = edit(contents, "core.plan_file", Some("db/sqitch.plan")).unwrap();
+        assert_eq!(
+            edited,
+            "# Project config\n[core]\n\tengine = mysql\n\tplan_file = db/sqitch.plan\n\n[deploy]\n\tverify\n"
+        );
+
+        // Add a section with a value that needs quoting
+        let edited = edit(contents, "target.Prod.uri", Some("db:mysql://x/app # main")).unwrap();
+        assert!(edited.ends_with("[target \"Prod\"]\n\turi = \"db:mysql://x/app # main\"\n"));
+        let config = Config::parse(&edited).unwrap();
+        assert_eq!(
+            config.get("target.Prod.uri"),
+            Some("db:mysql://x/app # main")
+        );
+
+        // Remove a key
+        let edited = edit(contents, "deploy.verify", None).unwrap();
+        assert_eq!(
+            edited,
+            "# Project config\n[core]\n\tengine = mysql\n\n[deploy]\n"
+        );
+        assert!(edit(contents, "deploy.mode", None).is_err());
+        assert!(edit(contents, "engine", Some("mysql")).is_err());
+
+        // Start an empty file
+        assert_eq!(
+            edit("", "user.name", Some("Jane Doe")).unwrap(),
+            "[user]\n\tname = Jane Doe\n"
+        );
+    }
+
+    #[test]
+    fn test_user_identity() {
+        let config = Config::parse("[user]\nname = Jane Doe\nemail = jane@example.com").unwrap();
+        assert_eq!(
+            config.user_identity(|_| None),
+            Some(Planner::new("Jane Doe", "jane@example.com"))
+        );
+        let env = |var: &str| (var == "SQITCH_EMAIL").then(|| "jane@work.example.com".to_string());
+        assert_eq!(
+            config.user_identity(env),
+            Some(Planner::new("Jane Doe", "jane@work.example.com"))
+        );
+        let config = Config::parse("[user]\nname = Jane Doe").unwrap();
+        assert_eq!(config.user_identity(|_| None), None);
+        assert_eq!(
+            config.user_identity(env),
+            Some(Planner::new("Jane Doe", "jane@work.example.com"))
+        );
+    }
+
+    #[test]
+    fn test_webhooks() {
+        let config = Config::parse(
+            "[webhook \"slack\"]
+            url = https://hooks.slack.com/services/T000/B000/XXXX
+            on = failure
+            [webhook \"ci\"]
+            url = https://ci.example.com/{project}/{status}
+",
+        )
+        .unwrap();
+        assert_eq!(
+            config.webhooks().unwrap(),
+            [
+                Webhook {
+                    name: "slack".to_string(),
+                    url: "https://hooks.slack.com/services/T000/B000/XXXX".to_string(),
+                    on: NotifyOn::Failure,
+                },
+                Webhook {
+                    name: "ci".to_string(),
+                    url: "https://ci.example.com/{project}/{status}".to_string(),
+                    on: NotifyOn::Always,
+                },
+            ]
+        );
+        let config = Config::parse("[webhook \"slack\"]\non = sometimes").unwrap();
+        assert!(config.webhooks().is_err());
+    }
+
+    #[test]
+    fn test_scope_path() {
+        let env = |var: &str| match var {
+            "HOME" => Some("/home/jane".to_string()),
+            "SQITCH_SYSTEM_CONFIG" => Some("/opt/sqitch.conf".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            ConfigScope::System.path(None, env),
+            Some("/opt/sqitch.conf".into())
+        );
+        assert_eq!(
+            ConfigScope::User.path(None, env),
+            Some("/home/jane/.sqitch/sqitch.conf".into())
+        );
+        assert_eq!(
+            ConfigScope::Local.path(None, env),
+            Some(LOCAL_CONFIG_FILE.into())
+        );
+        assert_eq!(
+            ConfigScope::Local.path(Some(Path::new("db/sqitch.conf")), env),
+            Some("db/sqitch.conf".into())
+        );
+        assert_eq!(ConfigScope::User.path(None, |_| None), None);
+    }
+
+    #[test]
+    fn test_sections() {
+        let config = Config::parse(EXAMPLE).unwrap();
+        assert_eq!(config.subsections("target"), ["dev", "Prod"]);
+        assert_eq!(config.subsections("engine"), ["mysql"]);
+        assert_eq!(config.subsections("core"), ["variables"]);
+        assert!(config.subsections("user").is_empty());
+
+        let contents = "[core]\n\tengine = mysql\n[target \"dev\"]\n\turi = db:mysql://x/dev\n[target \"prod\"]\n\turi = db:mysql://x/prod\n";
+        assert_eq!(
+            remove_section(contents, "target.dev").unwrap(),
+            "[core]\n\tengine = mysql\n[target \"prod\"]\n\turi = db:mysql://x/prod\n"
+        );
+        assert!(remove_section(contents, "target.staging").is_err());
+    }
+}
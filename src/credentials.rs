@@ -0,0 +1,243 @@
+//! Credentials that are not part of the target URL
+
+use std::{
+    collections::BTreeMap,
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Context;
+
+use crate::engine::{ClientConfig, EngineKind};
+
+/// Environment variables checked for a password, in order
+const PASSWORD_VARS: [&str; 2] = ["QUITCH_PASSWORD", "SQITCH_PASSWORD"];
+
+/// Password from the first set variable of [`PASSWORD_VARS`]
+fn env_password(var: impl Fn(&str) -> Option<String>) -> Option<String> {
+    PASSWORD_VARS.into_iter().find_map(var)
+}
+
+/// Environment variables checked for a username, in order
+const USERNAME_VARS: [&str; 2] = ["QUITCH_USERNAME", "SQITCH_USERNAME"];
+
+/// Username from the first set variable of [`USERNAME_VARS`]
+fn env_username(var: impl Fn(&str) -> Option<String>) -> Option<String> {
+    USERNAME_VARS.into_iter().find_map(var)
+}
+
+/// Fill in the username and password missing from the target URL.
+///
+/// Both come from the environment first. For MySQL both then come
+/// from the option file, `defaults_file` or `~/.my.cnf`, like in the `mysql`
+/// client. Without any of these the password stays empty, leaving
+/// authentication to the server, e.g. for socket or trust authentication, and
+/// it is only asked for with [`ask_password`] if the server refuses.
+pub fn fill_in(config: &mut ClientConfig, defaults_file: Option<&Path>) -> anyhow::Result<()> {
+    if config.username.is_empty() {
+        if let Some(username) = env_username(|var| std::env::var(var).ok()) {
+            config.username = username;
+        }
+    }
+    if config.password.is_none() {
+        config.password = env_password(|var| std::env::var(var).ok());
+    }
+    if config.engine == EngineKind::Mysql
+        && (config.username.is_empty() || config.password.is_none())
+    {
+        let options = read_option_file(defaults_file)?;
+        if config.username.is_empty() {
+            config.username = options.user.unwrap_or_default();
+        }
+        if config.password.is_none() {
+            config.password = options.password;
+        }
+    }
+    Ok(())
+}
+
+/// Passwords typed in by user and host, none where the answer was empty
+static TYPED_PASSWORDS: Mutex<BTreeMap<(String, String), Option<String>>> =
+    Mutex::new(BTreeMap::new());
+
+/// Ask for the password of the user of `config` if stdin is a terminal, once
+/// per user and host. An empty answer means no password.
+pub fn ask_password(config: &ClientConfig) -> anyhow::Result<Option<String>> {
+    if !std::io::stdin().is_terminal() {
+        return Ok(None);
+    }
+    let host = format!("{}:{}", config.hostname, config.port);
+    let mut typed = TYPED_PASSWORDS.lock().unwrap();
+    if let Some(password) = typed.get(&(config.username.clone(), host.clone())) {
+        return Ok(password.clone());
+    }
+    let password = prompt_password(&format!("Password for {}@{host}: ", config.username))?;
+    let password = Some(password).filter(|password| !password.is_empty());
+    typed.insert((config.username.clone(), host), password.clone());
+    Ok(password)
+}
+
+/// Credentials from a MySQL option file
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct OptionFile {
+    user: Option<String>,
+    password: Option<String>,
+}
+
+/// Read `defaults_file`, which must exist, or `~/.my.cnf` if it exists
+fn read_option_file(defaults_file: Option<&Path>) -> anyhow::Result<OptionFile> {
+    let path = match defaults_file {
+        Some(path) => path.to_path_buf(),
+        None => match std::env::var_os("HOME") {
+            Some(home) if Path::new(&home).join(".my.cnf").exists() => {
+                PathBuf::from(home).join(".my.cnf")
+            }
+            _ => return Ok(OptionFile::default()),
+        },
+    };
+    let contents = std::fs::read_to_string(&path)
+        .with_context(|| format!("failed to read option file {}", path.display()))?;
+    Ok(parse_option_file(&contents))
+}
+
+/// Parse the `[client]` and `[mysql]` groups of a MySQL option file.
+///
+/// Later values override earlier ones, other groups and options are ignored.
+fn parse_option_file(contents: &str) -> OptionFile {
+    let mut options = OptionFile::default();
+    let mut in_client_group = false;
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(['#', ';', '!']) {
+            continue;
+        }
+        if let Some(group) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            in_client_group = matches!(group.trim(), "client" | "mysql");
+            continue;
+        }
+        if !in_client_group {
+            continue;
+        }
+        let (key, value) = line.split_once('=').unwrap_or((line, ""));
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+            .unwrap_or(value)
+            .to_string();
+        match key.trim() {
+            "user" => options.user = Some(value),
+            "password" => options.password = Some(value),
+            _ => {}
+        }
+    }
+    options
+}
+
+/// Read a line from the terminal without echoing it
+fn prompt_password(prompt: &str) -> anyhow::Result<String> {
+    eprint!("{prompt}");
+    std::io::stderr().flush()?;
+    let mut password = String::new();
+    {
+        #[cfg(unix)]
+        let _echo = unix::EchoOff::new();
+        std::io::stdin().read_line(&mut password)?;
+    }
+    eprintln!();
+    Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
+
+#[cfg(unix)]
+mod unix {
+    /// Turns off terminal echo on stdin until dropped
+    pub struct EchoOff {
+        original: Option<libc::termios>,
+    }
+
+    impl EchoOff {
+        pub fn new() -> Self {
+            // SAFETY: termios is plain data and both calls only touch stdin
+            unsafe {
+                let mut termios = std::mem::zeroed::<libc::termios>();
+                if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+                    return Self { original: None };
+                }
+                let original = termios;
+                termios.c_lflag &= !libc::ECHO;
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios);
+                Self {
+                    original: Some(original),
+                }
+            }
+        }
+    }
+
+    impl Drop for EchoOff {
+        fn drop(&mut self) {
+            if let Some(original) = &self.original {
+                // SAFETY: restores the settings read in `new`
+                unsafe {
+                    libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, original);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_option_file() {
+        let contents = "\
+            # Written by hand\n\
+            [mysqld]\n\
+            user = mysql\n\
+            \n\
+            [client]\n\
+            user = app\n\
+            password = \"p#ss word\"\n\
+            host = localhost\n\
+            \n\
+            [mysql]\n\
+            user='admin'\n";
+        assert_eq!(
+            parse_option_file(contents),
+            OptionFile {
+                user: Some("admin".into()),
+                password: Some("p#ss word".into()),
+            }
+        );
+    }
+
+    #[test]
+    fn quitch_password_takes_precedence() {
+        let env = |vars: &'static [(&str, &str)]| {
+            move |var: &str| {
+                vars.iter()
+                    .find(|(name, _)| *name == var)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+        assert_eq!(
+            env_password(env(&[
+                ("SQITCH_PASSWORD", "sqitch"),
+                ("QUITCH_PASSWORD", "quitch")
+            ])),
+            Some("quitch".into())
+        );
+        assert_eq!(
+            env_password(env(&[("SQITCH_PASSWORD", "sqitch")])),
+            Some("sqitch".into())
+        );
+        assert_eq!(env_password(env(&[])), None);
+        assert_eq!(
+            env_username(env(&[("SQITCH_USERNAME", "deploy")])),
+            Some("deploy".into())
+        );
+    }
+}
@@ -0,0 +1,1471 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context};
+use futures::TryStreamExt;
+use tracing::{error, info, warn};
+
+use crate::{
+    change::Planner,
+    engine::Engine,
+    failure::{Classify, Failure},
+    git::GitCommit,
+    hook::{is_executable, is_sql, Hook},
+    interrupt,
+    output::{Action, ChangeOutcome},
+    plan::{FullChange, Plan},
+    registry::{
+        dependency_rows, events, fail_note, requires_other_projects, ChangeRow, Event, EventQuery,
+        RegistryStore, EVENT_PAGE_SIZE,
+    },
+    script::{script_hash, substitute_variables, ScriptDirs, ScriptKind},
+};
+
+/// Times the registry updates of a change are attempted before a transient
+/// failure is reported
+const REGISTRY_ATTEMPTS: u32 = 5;
+
+/// Wait before the first retry of registry updates, doubled after each attempt
+const FIRST_REGISTRY_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// How long to wait after `attempt` failed attempts to update the registry:
+/// between half and all of the doubled delay, so that instances that
+/// deadlocked on each other don't retry in lockstep
+fn registry_retry_delay(attempt: u32) -> Duration {
+    let delay = FIRST_REGISTRY_RETRY_DELAY * 2u32.pow(attempt - 1);
+    delay / 2 + delay.mul_f64(fastrand::f64() / 2.0)
+}
+
+/// Where a [`Deployer`] reads change scripts from
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Scripts {
+    /// Files in the script directories
+    #[default]
+    Files,
+    /// Scripts held in memory, by path relative to the plan directory such as
+    /// `deploy/users.sql`
+    Embedded(HashMap<PathBuf, String>),
+}
+
+impl Scripts {
+    async fn read(&self, path: &Path) -> anyhow::Result<String> {
+        match self {
+            Self::Files => Ok(tokio::fs::read_to_string(path).await?),
+            Self::Embedded(scripts) => match scripts.get(path) {
+                Some(script) => Ok(script.clone()),
+                None => bail!("{} is not embedded", path.display()),
+            },
+        }
+    }
+
+    /// Paths of the files in `dir` in order of their names, none if it
+    /// doesn't exist
+    async fn list(&self, dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        let mut paths = match self {
+            Self::Files => {
+                let mut entries = match tokio::fs::read_dir(dir).await {
+                    Ok(entries) => entries,
+                    Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                        return Ok(Vec::new())
+                    }
+                    Err(error) => return Err(error.into()),
+                };
+                let mut paths = Vec::new();
+                while let Some(entry) = entries.next_entry().await? {
+                    paths.push(entry.path());
+                }
+                paths
+            }
+            Self::Embedded(scripts) => scripts
+                .keys()
+                .filter(|path| path.parent() == Some(dir))
+                .cloned()
+                .collect(),
+        };
+        paths.sort();
+        Ok(paths)
+    }
+}
+
+/// How much of a deploy to undo when one of its changes fails
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DeployMode {
+    /// Keep the changes deployed before the one that failed
+    #[default]
+    Change,
+    /// Revert the changes deployed after the last tagged one
+    Tag,
+    /// Revert every change the deploy deployed
+    All,
+}
+
+/// Deploys and reverts changes of a plan, keeping the registry up to date
+pub struct Deployer {
+    /// Where to find the scripts of [`Scripts::Files`], and the paths of
+    /// embedded scripts
+    pub script_dirs: ScriptDirs,
+    pub plan: Plan,
+    pub scripts: Scripts,
+    pub db: Box<dyn Engine>,
+    pub registry: Box<dyn RegistryStore>,
+    /// Only print what would be done
+    pub log_only: bool,
+    /// Record changes as deployed or reverted without running their scripts
+    /// or hooks
+    pub registry_only: bool,
+    /// Refuse to revert changes whose deploy script was modified since deploying
+    pub strict: bool,
+    /// What to revert when a change fails to deploy
+    pub mode: DeployMode,
+    /// Revert changes that other deployed changes still require
+    pub force: bool,
+    /// Retry changes whose last deploy failed on a target that can't roll back
+    /// schema changes
+    pub resume: bool,
+    /// How long to wait for another instance working on the target to finish
+    pub lock_timeout: Duration,
+    /// Values of the variables used in scripts
+    pub variables: HashMap<String, String>,
+    /// Recorded as the committer of changes, tags and events
+    pub committer: Planner,
+    /// Recorded at the end of the notes of events, see [`crate::git`]
+    pub git_commit: Option<GitCommit>,
+    /// Changes deployed or reverted so far, including the one that failed
+    pub outcomes: Mutex<Vec<ChangeOutcome>>,
+}
+
+impl Deployer {
+    pub async fn deploy_change(&self, change: &FullChange) -> anyhow::Result<()> {
+        let deploy_path = self
+            .script_dirs
+            .change_script_path(ScriptKind::Deploy, change);
+        if self.log_only {
+            info!(
+                change = change.name(),
+                "Would deploy {} ({})",
+                change.name(),
+                deploy_path.display()
+            );
+            self.record_outcome(change, Action::Deploy, None, None);
+            return Ok(());
+        }
+        if self.registry_only {
+            info!(
+                change = change.name(),
+                "Recording {} as deployed",
+                change.name()
+            );
+        } else {
+            info!(change = change.name(), "Deploying {}", change.name());
+        }
+        let deploy_sql = self.scripts.read(&deploy_path).await?;
+        self.run_hooks(Hook::BeforeDeploy, &[change]).await?;
+
+        let deploy_the_change = self.atomically(
+            async || self.run_script(&deploy_sql).await,
+            async || {
+                self.record_deploy(change, &script_hash(deploy_sql.as_bytes()))
+                    .await
+            },
+        );
+        if let Err(error) = deploy_the_change.await {
+            error!(change = change.name(), "Failed to deploy {}", change.name());
+            self.record_failure(change, "deploy", &error).await;
+            self.record_outcome(change, Action::Deploy, Some(Event::Fail), Some(&error));
+            return Err(error);
+        }
+        self.record_outcome(change, Action::Deploy, Some(Event::Deploy), None);
+        self.run_hooks(Hook::AfterDeploy, &[change]).await
+    }
+
+    pub async fn revert_change(&self, change: &FullChange) -> anyhow::Result<()> {
+        let revert_path = self
+            .script_dirs
+            .change_script_path(ScriptKind::Revert, change);
+        if self.log_only {
+            info!(
+                change = change.name(),
+                "Would revert {} ({})",
+                change.name(),
+                revert_path.display()
+            );
+            self.record_outcome(change, Action::Revert, None, None);
+            return Ok(());
+        }
+        if self.registry_only {
+            info!(
+                change = change.name(),
+                "Recording {} as reverted",
+                change.name()
+            );
+        } else {
+            info!(change = change.name(), "Reverting {}", change.name());
+        }
+        let revert_sql = self.scripts.read(&revert_path).await?;
+        self.run_hooks(Hook::BeforeRevert, &[change]).await?;
+
+        let revert_the_change = self.atomically(
+            async || self.run_script(&revert_sql).await,
+            async || {
+                self.registry.delete_change(&change.id).await?;
+                self.registry
+                    .add_event(
+                        Event::Revert,
+                        change,
+                        self.event_note(change).as_deref(),
+                        self.plan.project(),
+                        &self.committer,
+                    )
+                    .await
+            },
+        );
+        if let Err(error) = revert_the_change.await {
+            error!(change = change.name(), "Failed to revert {}", change.name());
+            self.record_failure(change, "revert", &error).await;
+            self.record_outcome(change, Action::Revert, Some(Event::Fail), Some(&error));
+            return Err(error);
+        }
+        self.record_outcome(change, Action::Revert, Some(Event::Revert), None);
+        self.run_hooks(Hook::AfterRevert, &[change]).await
+    }
+
+    /// Record a change as deployed, with the hash of its deploy script, its
+    /// dependencies, its tags and a `deploy` event
+    async fn record_deploy(&self, change: &FullChange, hash: &str) -> anyhow::Result<()> {
+        self.registry
+            .insert_change(change, hash, self.plan.project(), &self.committer)
+            .await?;
+        let deployed = if requires_other_projects(&self.plan, change) {
+            self.registry.fetch_changes().await?
+        } else {
+            Vec::new()
+        };
+        let dependencies = dependency_rows(&self.plan, change, &deployed);
+        self.registry
+            .insert_dependencies(&change.id, &dependencies)
+            .await?;
+        self.registry
+            .insert_tags(
+                change,
+                self.plan.project(),
+                self.plan.uri(),
+                &self.committer,
+            )
+            .await?;
+        self.registry
+            .add_event(
+                Event::Deploy,
+                change,
+                self.event_note(change).as_deref(),
+                self.plan.project(),
+                &self.committer,
+            )
+            .await
+    }
+
+    /// Record `replaced`, changes deployed from the plan before it was
+    /// squashed, as reverted, and `replacements` from the plan after it, with
+    /// the hashes of their deploy scripts, as deployed in their place. Nothing
+    /// runs on the target, which already has them all.
+    pub async fn record_squash(
+        &self,
+        replaced: &[&FullChange],
+        replacements: &[(&FullChange, String)],
+    ) -> anyhow::Result<()> {
+        let baseline = self.plan.full_changes().first().expect("squashed plan");
+        let note = format!("Replaced by the squash into {}", baseline.name());
+        self.record_with_retries(async || {
+            for change in replaced.iter().rev() {
+                self.registry.delete_change(&change.id).await?;
+                self.registry
+                    .add_event(
+                        Event::Revert,
+                        change,
+                        Some(&note),
+                        self.plan.project(),
+                        &self.committer,
+                    )
+                    .await?;
+            }
+            for (change, hash) in replacements {
+                self.record_deploy(change, hash).await?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Run a deploy or revert script, unless only the registry is updated
+    async fn run_script(&self, script: &str) -> anyhow::Result<()> {
+        if self.registry_only {
+            return Ok(());
+        }
+        self.db
+            .run_script(&self.substitute(script))
+            .await
+            .classify(Failure::Script)
+    }
+
+    fn substitute(&self, script: &str) -> String {
+        substitute_variables(script, &self.variables, self.db.identifier_quote())
+    }
+
+    /// Run the scripts of a hook, stopping at the first one that fails.
+    ///
+    /// Executable hooks only run from the script directories, as embedded
+    /// scripts can't be executed.
+    async fn run_hooks(&self, hook: Hook, changes: &[&FullChange]) -> anyhow::Result<()> {
+        if self.log_only || self.registry_only {
+            return Ok(());
+        }
+        let dir = self.script_dirs.hooks.join(hook.dir_name());
+        for path in self.scripts.list(&dir).await? {
+            let result = if is_sql(&path) {
+                info!("Running {} hook {}", hook.dir_name(), path.display());
+                let sql = self.scripts.read(&path).await?;
+                self.db.run_script(&self.substitute(&sql)).await
+            } else if self.scripts == Scripts::Files && is_executable(&path) {
+                info!("Running {} hook {}", hook.dir_name(), path.display());
+                // Keep stdout for the output of quitch, e.g. with `--format json`
+                let status = tokio::process::Command::new(&path)
+                    .envs(hook.env(self.plan.project(), changes))
+                    .stdout(std::io::stderr())
+                    .status()
+                    .await;
+                match status {
+                    Ok(status) if status.success() => Ok(()),
+                    Ok(status) => Err(anyhow!("exited with {status}")),
+                    Err(error) => Err(error.into()),
+                }
+            } else {
+                warn!(
+                    "Skipping {}, which is neither a .sql file nor executable",
+                    path.display()
+                );
+                continue;
+            };
+            result
+                .with_context(|| format!("{} hook {} failed", hook.dir_name(), path.display()))
+                .classify(Failure::Script)?;
+        }
+        Ok(())
+    }
+
+    /// Note of a `deploy` or `revert` event, when it isn't just the note of
+    /// the change
+    fn event_note(&self, change: &FullChange) -> Option<String> {
+        self.git_commit
+            .as_ref()
+            .map(|commit| commit.annotate(&change.change.note))
+    }
+
+    /// Add a `fail` event whose note says what went wrong. Failing to add it
+    /// is only logged, so that the caller can still return `error`.
+    async fn record_failure(&self, change: &FullChange, action: &str, error: &anyhow::Error) {
+        let mut note = fail_note(&change.change.note, action, error);
+        if let Some(commit) = &self.git_commit {
+            note = commit.annotate(&note);
+        }
+        let recorded = self
+            .registry
+            .add_event(
+                Event::Fail,
+                change,
+                Some(&note),
+                self.plan.project(),
+                &self.committer,
+            )
+            .await;
+        if let Err(record_error) = recorded {
+            warn!(
+                change = change.name(),
+                "Could not record the failure of {}: {record_error:#}",
+                change.name()
+            );
+        }
+    }
+
+    fn record_outcome(
+        &self,
+        change: &FullChange,
+        action: Action,
+        event: Option<Event>,
+        error: Option<&anyhow::Error>,
+    ) {
+        self.outcomes.lock().unwrap().push(ChangeOutcome {
+            id: change.id.clone(),
+            name: change.name().to_string(),
+            action,
+            event,
+            error: error.map(|error| format!("{error:#}")),
+        });
+    }
+
+    /// Run the script of a change with `script`, then record it in the
+    /// registry with `record`, as a unit.
+    ///
+    /// If the target can roll back schema changes, the script runs in a
+    /// transaction that is rolled back if anything fails. A registry used from
+    /// the target's session is updated in that same transaction. Otherwise the
+    /// registry transaction commits before the script's, so a failure to
+    /// commit the script leaves it recorded without having taken effect.
+    ///
+    /// If the target can't roll back schema changes, the script has taken
+    /// effect by the time the registry is updated. Separate registry
+    /// transactions are run again after transient failures such as deadlocks.
+    async fn atomically(
+        &self,
+        script: impl AsyncFnOnce() -> anyhow::Result<()>,
+        record: impl AsyncFn() -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        if !self.db.transactional_ddl() {
+            script().await?;
+            return self.record_with_retries(record).await;
+        }
+        self.db.begin().await?;
+        let result = async {
+            script().await?;
+            if self.registry.in_target_session() {
+                record().await?;
+            } else {
+                self.record_with_retries(record).await?;
+            }
+            self.db.commit().await
+        }
+        .await;
+        if result.is_err() {
+            // Report the original error even if rolling back fails too
+            let _ = self.db.rollback().await;
+        }
+        result
+    }
+
+    /// Run `record` in a registry transaction, running it again up to
+    /// [`REGISTRY_ATTEMPTS`] times in all while it fails transiently
+    async fn record_with_retries(
+        &self,
+        record: impl AsyncFn() -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let mut attempt = 1;
+        loop {
+            let result = async {
+                self.registry.begin().await?;
+                record().await?;
+                self.registry.commit().await
+            }
+            .await;
+            let Err(error) = result else {
+                return Ok(());
+            };
+            let _ = self.registry.rollback().await;
+            if attempt == REGISTRY_ATTEMPTS || !self.registry.is_transient(&error) {
+                return Err(error);
+            }
+            let delay = registry_retry_delay(attempt);
+            warn!(
+                "Updating the registry failed, retrying in {} ms: {error:#}",
+                delay.as_millis()
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Run `f` while keeping other instances of quitch or sqitch from changing
+    /// the target.
+    ///
+    /// Ctrl-C stops `f` after the statement it is running, still releasing
+    /// the lock.
+    pub async fn locked<T>(&self, f: impl AsyncFnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+        self.lock().await?;
+        let _interrupts = interrupt::Guard::new();
+        let result = f().await;
+        let unlocked = self.unlock().await;
+        match result {
+            Ok(value) => unlocked.map(|()| value),
+            Err(error) => {
+                // The run's own failure says more than failing to release the
+                // lock after it
+                if let Err(unlock_error) = unlocked {
+                    warn!("Could not release the lock on the target: {unlock_error:#}");
+                }
+                Err(error)
+            }
+        }
+    }
+
+    async fn lock(&self) -> anyhow::Result<()> {
+        if self.log_only || self.db.lock(Duration::ZERO).await? {
+            return Ok(());
+        }
+        info!(
+            "Waiting up to {} seconds for another instance working on the target",
+            self.lock_timeout.as_secs()
+        );
+        if !self.db.lock(self.lock_timeout).await? {
+            return Err(anyhow!(
+                "timed out waiting for another instance working on the target"
+            ))
+            .classify(Failure::Locked);
+        }
+        Ok(())
+    }
+
+    async fn unlock(&self) -> anyhow::Result<()> {
+        if self.log_only {
+            return Ok(());
+        }
+        self.db.unlock().await
+    }
+
+    pub async fn deploy_changes(&self, changes: &[FullChange]) -> anyhow::Result<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        self.check_failed_deploys(changes).await?;
+        let run: Vec<_> = changes.iter().collect();
+        self.run_hooks(Hook::BeforeDeployRun, &run).await?;
+        for (idx, change) in changes.iter().enumerate() {
+            let deployed = match interrupt::check() {
+                Ok(()) => self.deploy_change(change).await,
+                Err(error) => Err(error),
+            };
+            if let Err(error) = deployed {
+                self.revert_failed_deploy(&changes[..idx], &error).await;
+                return Err(error);
+            }
+        }
+        self.run_hooks(Hook::AfterDeployRun, &run).await
+    }
+
+    /// Look for changes whose latest event is a failed deploy.
+    ///
+    /// Targets that roll back schema changes along with the rest of a failed
+    /// change can just retry it. Elsewhere the deploy script may have stopped
+    /// halfway, so running it again needs `--resume` once it is known to be
+    /// safe, or `--force`.
+    async fn check_failed_deploys(&self, changes: &[FullChange]) -> anyhow::Result<()> {
+        if self.log_only {
+            return Ok(());
+        }
+        let mut events = events(
+            self.registry.as_ref(),
+            EventQuery::project(self.plan.project()),
+            EVENT_PAGE_SIZE,
+        );
+        let mut latest = HashMap::new();
+        while let Some(event) = events.try_next().await? {
+            // Newest first
+            latest.entry(event.change_id).or_insert(event.event);
+        }
+        let failed: Vec<_> = changes
+            .iter()
+            .filter(|change| latest.get(&change.id) == Some(&Event::Fail))
+            .map(|change| change.name())
+            .collect();
+        if failed.is_empty() {
+            return Ok(());
+        }
+        let failed = failed.join(", ");
+        if self.db.transactional_ddl() {
+            info!("Retrying {failed}, whose last deploy failed and was rolled back");
+        } else if self.resume || self.force {
+            warn!("Retrying {failed}, whose last deploy failed and may have been partly applied");
+        } else {
+            bail!(
+                "the last deploy of {failed} failed, and the target can't roll back schema \
+                changes, so it may have been partly applied. Undo what it did or make sure its \
+                deploy script can run again, then retry with --resume"
+            );
+        }
+        Ok(())
+    }
+
+    /// Revert changes deployed before one that failed, as far back as the
+    /// mode says. Failing to revert them is only logged, as the error that
+    /// matters is the one of the deploy.
+    async fn revert_failed_deploy(&self, deployed: &[FullChange], error: &anyhow::Error) {
+        if Failure::of(error) == Some(Failure::Interrupted) {
+            return;
+        }
+        let kept = match self.mode {
+            DeployMode::Change => deployed.len(),
+            DeployMode::Tag => deployed
+                .iter()
+                .rposition(|change| !change.tags.is_empty())
+                .map_or(0, |idx| idx + 1),
+            DeployMode::All => 0,
+        };
+        let to_revert = &deployed[kept..];
+        if to_revert.is_empty() {
+            return;
+        }
+        warn!(
+            "Reverting the {} changes deployed before the failure",
+            to_revert.len()
+        );
+        if let Err(revert_error) = self.revert_changes(to_revert).await {
+            error!("Could not revert the changes deployed before the failure: {revert_error:#}");
+        }
+    }
+
+    /// Compare deploy scripts of changes about to be reverted with the hashes
+    /// stored when they were deployed.
+    ///
+    /// A modified deploy script usually means the revert script no longer
+    /// matches what is in the database either.
+    pub async fn check_deploy_scripts(
+        &self,
+        deployed: &[(FullChange, ChangeRow)],
+    ) -> anyhow::Result<()> {
+        let mut modified = Vec::new();
+        for (change, row) in deployed {
+            let Some(stored_hash) = &row.script_hash else {
+                continue;
+            };
+            let deploy_path = self
+                .script_dirs
+                .change_script_path(ScriptKind::Deploy, change);
+            let deploy_script = self.scripts.read(&deploy_path).await?;
+            if script_hash(deploy_script.as_bytes()) != *stored_hash {
+                modified.push(change.name());
+            }
+        }
+        if modified.is_empty() {
+            return Ok(());
+        }
+        let modified = modified.join(", ");
+        if self.strict {
+            bail!("deploy scripts of {modified} changed since they were deployed, their revert scripts may not match");
+        }
+        warn!(
+            "deploy scripts of {modified} changed since they were deployed, their revert scripts may not match"
+        );
+        Ok(())
+    }
+
+    /// Refuse to revert changes that deployed changes staying behind still
+    /// require, unless forced
+    pub async fn check_dependents(&self, changes: &[FullChange]) -> anyhow::Result<()> {
+        let reverted: HashSet<_> = changes.iter().map(|change| change.id.as_str()).collect();
+        let mut required = Vec::new();
+        for change in changes {
+            for dependent in self.registry.changes_requiring(&change.id).await? {
+                if !reverted.contains(dependent.change_id.as_str()) {
+                    required.push(format!(
+                        "{} (required by {}:{})",
+                        change.name(),
+                        dependent.project,
+                        dependent.change
+                    ));
+                }
+            }
+        }
+        if required.is_empty() {
+            return Ok(());
+        }
+        let required = required.join(", ");
+        if self.force {
+            warn!("reverting {required} anyway");
+            return Ok(());
+        }
+        bail!("cannot revert {required}, use --force to revert anyway");
+    }
+
+    /// Revert changes in reverse order
+    pub async fn revert_changes(&self, changes: &[FullChange]) -> anyhow::Result<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let run: Vec<_> = changes.iter().rev().collect();
+        self.run_hooks(Hook::BeforeRevertRun, &run).await?;
+        for &change in &run {
+            interrupt::check()?;
+            self.revert_change(change).await?;
+        }
+        self.run_hooks(Hook::AfterRevertRun, &run).await
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::{borrow::Cow, sync::Arc};
+
+    use futures::{future::BoxFuture, FutureExt};
+
+    use super::*;
+    use crate::registry::{
+        memory::MemoryRegistry, DependencyRow, DeployedDependencyRow, EventRow, ProjectRow,
+        RegistryDump, TagRow,
+    };
+
+    /// Records registry calls and fails scripts containing `fail`
+    #[derive(Default)]
+    pub struct MockEngine {
+        pub calls: Arc<Mutex<Vec<String>>>,
+        /// Every script run
+        pub scripts: Arc<Mutex<Vec<String>>>,
+        /// Without a recorded release, like registries from before releases
+        pub unreleased: bool,
+        /// Events to fail adding with a deadlock before succeeding
+        pub deadlocks: Mutex<u32>,
+        pub transactional_ddl: bool,
+        pub in_target_session: bool,
+        pub unlock_fails: bool,
+    }
+
+    impl MockEngine {
+        fn record(&self, call: String) {
+            self.calls.lock().unwrap().push(call);
+        }
+    }
+
+    impl RegistryStore for MockEngine {
+        fn registry_version(&self) -> BoxFuture<'_, anyhow::Result<Option<f32>>> {
+            let version = (!self.unreleased).then_some(crate::registry::REGISTRY_VERSION);
+            async move { Ok(version) }.boxed()
+        }
+
+        fn insert_release<'a>(
+            &'a self,
+            version: f32,
+            installer: &'a Planner,
+        ) -> BoxFuture<'a, anyhow::Result<()>> {
+            self.record(format!("release {version} by {}", installer.name));
+            async { Ok(()) }.boxed()
+        }
+
+        fn begin(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+            self.record("begin".to_string());
+            async { Ok(()) }.boxed()
+        }
+
+        fn commit(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+            self.record("commit".to_string());
+            async { Ok(()) }.boxed()
+        }
+
+        fn rollback(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+            self.record("rollback".to_string());
+            async { Ok(()) }.boxed()
+        }
+
+        fn is_transient(&self, error: &anyhow::Error) -> bool {
+            error.to_string() == "deadlock"
+        }
+
+        fn in_target_session(&self) -> bool {
+            self.in_target_session
+        }
+
+        fn fetch_projects(&self) -> BoxFuture<'_, anyhow::Result<Vec<ProjectRow>>> {
+            async { Ok(vec![]) }.boxed()
+        }
+
+        fn insert_project<'a>(
+            &'a self,
+            _: &'a str,
+            _: Option<&'a str>,
+            _: &'a Planner,
+        ) -> BoxFuture<'a, anyhow::Result<()>> {
+            async { Ok(()) }.boxed()
+        }
+
+        fn fetch_changes(&self) -> BoxFuture<'_, anyhow::Result<Vec<ChangeRow>>> {
+            async { Ok(vec![]) }.boxed()
+        }
+
+        fn insert_change<'a>(
+            &'a self,
+            change: &'a FullChange,
+            script_hash: &'a str,
+            _: &'a str,
+            _: &'a Planner,
+        ) -> BoxFuture<'a, anyhow::Result<()>> {
+            self.record(format!("insert {} {script_hash}", change.name()));
+            async { Ok(()) }.boxed()
+        }
+
+        fn fetch_tags(&self) -> BoxFuture<'_, anyhow::Result<Vec<TagRow>>> {
+            async { Ok(vec![]) }.boxed()
+        }
+
+        fn insert_tags<'a>(
+            &'a self,
+            change: &'a FullChange,
+            _: &'a str,
+            _: Option<&'a str>,
+            _: &'a Planner,
+        ) -> BoxFuture<'a, anyhow::Result<()>> {
+            for tag in &change.tags {
+                self.record(format!("tag @{}", tag.name));
+            }
+            async { Ok(()) }.boxed()
+        }
+
+        fn insert_dependencies<'a>(
+            &'a self,
+            _: &'a str,
+            _: &'a [DependencyRow],
+        ) -> BoxFuture<'a, anyhow::Result<()>> {
+            async { Ok(()) }.boxed()
+        }
+
+        fn fetch_dependencies(&self) -> BoxFuture<'_, anyhow::Result<Vec<DeployedDependencyRow>>> {
+            async { Ok(vec![]) }.boxed()
+        }
+
+        fn changes_requiring<'a>(
+            &'a self,
+            _: &'a str,
+        ) -> BoxFuture<'a, anyhow::Result<Vec<ChangeRow>>> {
+            async { Ok(vec![]) }.boxed()
+        }
+
+        fn delete_change<'a>(&'a self, change_id: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+            self.record(format!("delete {change_id}"));
+            async { Ok(()) }.boxed()
+        }
+
+        fn delete_project<'a>(&'a self, _: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+            async { Ok(()) }.boxed()
+        }
+
+        fn add_event<'a>(
+            &'a self,
+            event_type: Event,
+            change: &'a FullChange,
+            note: Option<&'a str>,
+            _: &'a str,
+            _: &'a Planner,
+        ) -> BoxFuture<'a, anyhow::Result<()>> {
+            let mut deadlocks = self.deadlocks.lock().unwrap();
+            if *deadlocks > 0 {
+                *deadlocks -= 1;
+                return async { bail!("deadlock") }.boxed();
+            }
+            let note = note.map(|note| format!(": {note}")).unwrap_or_default();
+            self.record(format!("{event_type} {}{note}", change.name()));
+            async { Ok(()) }.boxed()
+        }
+
+        fn fetch_events<'a>(
+            &'a self,
+            _: &'a EventQuery,
+        ) -> BoxFuture<'a, anyhow::Result<Vec<EventRow>>> {
+            async { Ok(vec![]) }.boxed()
+        }
+
+        fn deployed_change_tags<'a>(&'a self, _: &'a str) -> BoxFuture<'a, anyhow::Result<String>> {
+            async { Ok(String::new()) }.boxed()
+        }
+
+        fn import_rows<'a>(&'a self, _: &'a RegistryDump) -> BoxFuture<'a, anyhow::Result<()>> {
+            async { Ok(()) }.boxed()
+        }
+    }
+
+    impl Engine for MockEngine {
+        fn identifier_quote(&self) -> char {
+            '"'
+        }
+
+        fn registry_schema(&self) -> Cow<'static, str> {
+            "registry schema".into()
+        }
+
+        fn releases_schema(&self) -> Cow<'static, str> {
+            "releases schema".into()
+        }
+
+        fn registry_upgrades(&self) -> Vec<(f32, Cow<'static, str>)> {
+            vec![(crate::registry::REGISTRY_VERSION, "latest upgrade".into())]
+        }
+
+        fn registry_teardown(&self) -> Cow<'static, str> {
+            "".into()
+        }
+
+        fn lock(&self, _timeout: Duration) -> BoxFuture<'_, anyhow::Result<bool>> {
+            async { Ok(true) }.boxed()
+        }
+
+        fn unlock(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+            let fails = self.unlock_fails;
+            async move {
+                if fails {
+                    bail!("connection lost");
+                }
+                Ok(())
+            }
+            .boxed()
+        }
+
+        fn transactional_ddl(&self) -> bool {
+            self.transactional_ddl
+        }
+
+        fn registry_in_session(&self, _: &str) -> Option<Box<dyn Engine>> {
+            None
+        }
+
+        fn run_script<'a>(&'a self, sql: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+            self.scripts.lock().unwrap().push(sql.to_string());
+            async move {
+                if sql.contains("fail") {
+                    bail!("script failed");
+                }
+                Ok(())
+            }
+            .boxed()
+        }
+
+        fn registry_exists(&self) -> BoxFuture<'_, anyhow::Result<bool>> {
+            async { Ok(true) }.boxed()
+        }
+
+        fn schema_exists<'a>(&'a self, _: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+            async { Ok(true) }.boxed()
+        }
+
+        fn create_schema<'a>(&'a self, _: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+            async { Ok(()) }.boxed()
+        }
+
+        fn drop_schema<'a>(&'a self, _: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+            async { Ok(()) }.boxed()
+        }
+
+        fn fetch_strings<'a>(&'a self, _: &'a str) -> BoxFuture<'a, anyhow::Result<Vec<String>>> {
+            async { Ok(vec![]) }.boxed()
+        }
+    }
+
+    /// Directory of a test's plan and scripts, removed when dropped
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn plan_file(&self) -> String {
+            self.0.join("sqitch.plan").to_str().unwrap().to_string()
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Plan file in a fresh directory with the given deploy scripts
+    fn write_scripts(test_name: &str, scripts: &[(&str, &str)]) -> TestDir {
+        let dir = std::env::temp_dir().join(format!("quitch-{test_name}-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("deploy")).unwrap();
+        for (name, sql) in scripts {
+            std::fs::write(dir.join("deploy").join(format!("{name}.sql")), sql).unwrap();
+        }
+        TestDir(dir)
+    }
+
+    type Calls = Arc<Mutex<Vec<String>>>;
+
+    fn deployer(plan_file: String) -> (Deployer, Vec<FullChange>, Calls) {
+        let plan = crate::plan::tests::example();
+        let changes = plan.full_changes().to_vec();
+        let registry = MockEngine::default();
+        let calls = registry.calls.clone();
+        let deployer = Deployer {
+            script_dirs: ScriptDirs::next_to_plan(&plan_file),
+            plan,
+            scripts: Scripts::Files,
+            db: Box::<MockEngine>::default(),
+            registry: Box::new(registry),
+            log_only: false,
+            registry_only: false,
+            strict: false,
+            mode: DeployMode::Change,
+            force: false,
+            resume: false,
+            lock_timeout: Duration::from_secs(60),
+            variables: HashMap::new(),
+            committer: Planner::new("Jane", "jane@example.com"),
+            git_commit: None,
+            outcomes: Mutex::default(),
+        };
+        (deployer, changes, calls)
+    }
+
+    #[tokio::test]
+    async fn deploy_records_change_and_event() {
+        let dir = write_scripts("deploy-ok", &[("change_name", "select 1;\n")]);
+        let plan_file = dir.plan_file();
+        let (deployer, changes, calls) = deployer(plan_file);
+        deployer.deploy_change(&changes[0]).await.unwrap();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                "begin",
+                "insert change_name 005c6eb7364156e6b0d158d8b2767a24f1ce6611",
+                "Deploy change_name",
+                "commit"
+            ]
+        );
+        let outcomes = deployer.outcomes.lock().unwrap();
+        assert_eq!(outcomes[0].action, Action::Deploy);
+        assert_eq!(outcomes[0].event, Some(Event::Deploy));
+    }
+
+    #[tokio::test]
+    async fn script_commits_after_its_record() {
+        let dir = write_scripts("deploy-transactional", &[("change_name", "select 1;\n")]);
+        for (in_target_session, expected) in [
+            (
+                false,
+                &[
+                    "begin",
+                    "begin",
+                    "insert change_name 005c6eb7364156e6b0d158d8b2767a24f1ce6611",
+                    "Deploy change_name",
+                    "commit",
+                    "commit",
+                ][..],
+            ),
+            // One transaction covers both
+            (
+                true,
+                &[
+                    "begin",
+                    "insert change_name 005c6eb7364156e6b0d158d8b2767a24f1ce6611",
+                    "Deploy change_name",
+                    "commit",
+                ],
+            ),
+        ] {
+            let (mut deployer, changes, calls) = deployer(dir.plan_file());
+            deployer.db = Box::new(MockEngine {
+                calls: calls.clone(),
+                transactional_ddl: true,
+                ..MockEngine::default()
+            });
+            deployer.registry = Box::new(MockEngine {
+                calls: calls.clone(),
+                in_target_session,
+                ..MockEngine::default()
+            });
+            deployer.deploy_change(&changes[0]).await.unwrap();
+            assert_eq!(*calls.lock().unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn deploy_reads_embedded_scripts() {
+        let (mut deployer, changes, calls) = deployer("sqitch.plan".to_string());
+        deployer.scripts = Scripts::Embedded(HashMap::from([(
+            "deploy/change_name.sql".into(),
+            "select 1;\n".to_string(),
+        )]));
+        deployer.deploy_change(&changes[0]).await.unwrap();
+        assert!(calls
+            .lock()
+            .unwrap()
+            .contains(&"insert change_name 005c6eb7364156e6b0d158d8b2767a24f1ce6611".to_string()));
+
+        assert!(deployer.revert_change(&changes[0]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_deploy_records_fail_event() {
+        let dir = write_scripts("deploy-fail", &[("change_name", "select fail;")]);
+        let plan_file = dir.plan_file();
+        let (deployer, changes, calls) = deployer(plan_file);
+        let error = deployer.deploy_change(&changes[0]).await.unwrap_err();
+        assert_eq!(Failure::of(&error), Some(Failure::Script));
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["Fail change_name: A description of the change\n\nFailed to deploy: script failed"]
+        );
+        let outcomes = deployer.outcomes.lock().unwrap();
+        assert_eq!(outcomes[0].event, Some(Event::Fail));
+        assert_eq!(outcomes[0].error.as_deref(), Some("script failed"));
+    }
+
+    #[tokio::test]
+    async fn failed_fail_event_keeps_the_script_error() {
+        let dir = write_scripts("deploy-fail-unrecorded", &[("change_name", "select fail;")]);
+        let (mut deployer, changes, calls) = deployer(dir.plan_file());
+        deployer.registry = Box::new(MockEngine {
+            calls: calls.clone(),
+            deadlocks: Mutex::new(1),
+            ..MockEngine::default()
+        });
+        let error = deployer.deploy_change(&changes[0]).await.unwrap_err();
+        assert_eq!(error.to_string(), "script failed");
+        assert_eq!(Failure::of(&error), Some(Failure::Script));
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn registry_only_records_without_running_scripts() {
+        let dir = write_scripts("registry-only", &[("change_name", "select fail;")]);
+        let plan_file = dir.plan_file();
+        let (mut deployer, changes, calls) = deployer(plan_file);
+        deployer.registry_only = true;
+        deployer.deploy_change(&changes[0]).await.unwrap();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                "begin",
+                "insert change_name 0060d7ec2cdc2f212042aa76d464d346a63b285d",
+                "Deploy change_name",
+                "commit"
+            ]
+        );
+        assert_eq!(
+            deployer.outcomes.lock().unwrap()[0].event,
+            Some(Event::Deploy)
+        );
+    }
+
+    #[tokio::test]
+    async fn squash_replaces_deployed_changes() {
+        let dir = write_scripts("squash", &[]);
+        let (mut deployer, old, calls) = deployer(dir.plan_file());
+        let baseline = crate::change::Change {
+            name: "baseline".into(),
+            ..crate::change::tests::example()
+        };
+        deployer.plan = crate::squash::squash(&deployer.plan, 0, baseline).unwrap();
+        let new = deployer.plan.full_changes();
+        deployer
+            .record_squash(
+                &[&old[0], &old[1]],
+                &[(&new[0], "hash1".into()), (&new[1], "hash2".into())],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                "begin".to_string(),
+                format!("delete {}", old[1].id),
+                "Revert change_num2: Replaced by the squash into baseline".to_string(),
+                format!("delete {}", old[0].id),
+                "Revert change_name: Replaced by the squash into baseline".to_string(),
+                "insert baseline hash1".to_string(),
+                "Deploy baseline".to_string(),
+                "insert change_num2 hash2".to_string(),
+                "Deploy change_num2".to_string(),
+                "commit".to_string(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn transient_registry_failures_are_retried() {
+        let dir = write_scripts("deploy-deadlock", &[("change_name", "select 1;\n")]);
+        let plan_file = dir.plan_file();
+        let (mut deployer, changes, calls) = deployer(plan_file);
+        deployer.registry = Box::new(MockEngine {
+            calls: calls.clone(),
+            deadlocks: Mutex::new(1),
+            ..MockEngine::default()
+        });
+        deployer.deploy_change(&changes[0]).await.unwrap();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                "begin",
+                "insert change_name 005c6eb7364156e6b0d158d8b2767a24f1ce6611",
+                "rollback",
+                "begin",
+                "insert change_name 005c6eb7364156e6b0d158d8b2767a24f1ce6611",
+                "Deploy change_name",
+                "commit"
+            ]
+        );
+
+        // Until the attempts run out
+        calls.lock().unwrap().clear();
+        deployer.registry = Box::new(MockEngine {
+            calls: calls.clone(),
+            deadlocks: Mutex::new(REGISTRY_ATTEMPTS),
+            ..MockEngine::default()
+        });
+        let error = deployer.deploy_change(&changes[0]).await.unwrap_err();
+        assert_eq!(error.to_string(), "deadlock");
+        let calls = calls.lock().unwrap();
+        let begins = calls.iter().filter(|call| *call == "begin").count();
+        assert_eq!(begins, REGISTRY_ATTEMPTS as usize);
+        assert!(calls
+            .last()
+            .unwrap()
+            .ends_with("Failed to deploy: deadlock"));
+    }
+
+    #[tokio::test]
+    async fn failed_unlock_keeps_the_run_error() {
+        let dir = write_scripts("unlock-fail", &[]);
+        let (mut deployer, _, _) = deployer(dir.plan_file());
+        deployer.db = Box::new(MockEngine {
+            unlock_fails: true,
+            ..MockEngine::default()
+        });
+        let error = deployer
+            .locked(async || -> anyhow::Result<()> { bail!("script failed") })
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "script failed");
+        let error = deployer.locked(async || Ok(())).await.unwrap_err();
+        assert_eq!(error.to_string(), "connection lost");
+    }
+
+    #[test]
+    fn registry_retry_delays() {
+        for attempt in 1..REGISTRY_ATTEMPTS {
+            let full = FIRST_REGISTRY_RETRY_DELAY * 2u32.pow(attempt - 1);
+            let delay = registry_retry_delay(attempt);
+            assert!(full / 2 <= delay && delay <= full, "{delay:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_deploy_needs_resume() {
+        let dir = write_scripts("deploy-resume", &[("change_name", "select fail;")]);
+        let plan_file = dir.plan_file();
+        let (mut deployer, changes, _) = deployer(plan_file.clone());
+        deployer.registry = Box::new(MemoryRegistry::new());
+        let error = deployer.deploy_changes(&changes[..1]).await.unwrap_err();
+        assert_eq!(Failure::of(&error), Some(Failure::Script));
+
+        let error = deployer.deploy_changes(&changes[..1]).await.unwrap_err();
+        assert_eq!(Failure::of(&error), None);
+        assert!(error
+            .to_string()
+            .starts_with("the last deploy of change_name failed, "));
+
+        std::fs::write(dir.0.join("deploy").join("change_name.sql"), "select 1;\n").unwrap();
+        deployer.resume = true;
+        deployer.deploy_changes(&changes[..1]).await.unwrap();
+        // Deployed since, so nothing to resume
+        deployer.resume = false;
+        deployer.check_failed_deploys(&changes[..1]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn events_record_git_commit() {
+        let dir = write_scripts("git-commit", &[("change_name", "select 1;\n")]);
+        let plan_file = dir.plan_file();
+        let (mut deployer, changes, calls) = deployer(plan_file);
+        deployer.git_commit = Some(GitCommit {
+            sha: "0123abcd".to_string(),
+            branch: Some("main".to_string()),
+        });
+        deployer.deploy_change(&changes[0]).await.unwrap();
+        assert!(calls.lock().unwrap().contains(
+            &"Deploy change_name: A description of the change\n\nGit-Commit: 0123abcd\nGit-Branch: main"
+                .to_string()
+        ));
+    }
+
+    #[tokio::test]
+    async fn hooks_run_around_changes() {
+        let dir = write_scripts("hooks", &[("change_name", "select 1;\n")]);
+        let plan_file = dir.plan_file();
+        let hooks = Path::new(&plan_file).with_file_name("hooks");
+        let after_deploy = hooks.join("after_deploy");
+        std::fs::create_dir_all(&after_deploy).unwrap();
+        let log = hooks.join("log");
+        let script = after_deploy.join("log.sh");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho \"$QUITCH_HOOK $QUITCH_CHANGE\" >> {}\n",
+                log.display()
+            ),
+        )
+        .unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        std::fs::write(after_deploy.join("README"), "not a hook").unwrap();
+        let (deployer, changes, calls) = deployer(plan_file);
+        deployer.deploy_change(&changes[0]).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            "after_deploy change_name\n"
+        );
+
+        // A failed hook before the change stops it from being deployed
+        let before_deploy = hooks.join("before_deploy");
+        std::fs::create_dir_all(&before_deploy).unwrap();
+        std::fs::write(before_deploy.join("check.sql"), "select fail;").unwrap();
+        calls.lock().unwrap().clear();
+        let error = deployer.deploy_change(&changes[0]).await.unwrap_err();
+        assert_eq!(Failure::of(&error), Some(Failure::Script));
+        assert!(format!("{error:#}").starts_with("before_deploy hook "));
+        assert!(calls.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn modified_deploy_script_fails_revert_check_when_strict() {
+        let dir = write_scripts("revert-check", &[("change_name", "select 2;\n")]);
+        let plan_file = dir.plan_file();
+        let (mut deployer, changes, _) = deployer(plan_file);
+        let row = |script_hash: &str| ChangeRow {
+            change_id: changes[0].id.clone(),
+            script_hash: Some(script_hash.to_string()),
+            change: changes[0].name().to_string(),
+            project: "quitch".to_string(),
+            note: String::new(),
+            committed_at: changes[0].change.date,
+            committer_name: "quitch".to_string(),
+            committer_email: "quitch@quitch".to_string(),
+            planned_at: changes[0].change.date,
+            planner_name: changes[0].change.planner.name.clone(),
+            planner_email: changes[0].change.planner.email.clone(),
+        };
+        let deployed = [(changes[0].clone(), row(&script_hash(b"select 1;\n")))];
+
+        assert!(deployer.check_deploy_scripts(&deployed).await.is_ok());
+        deployer.strict = true;
+        assert!(deployer.check_deploy_scripts(&deployed).await.is_err());
+
+        let unmodified = [(changes[0].clone(), row(&script_hash(b"select 2;\n")))];
+        assert!(deployer.check_deploy_scripts(&unmodified).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn failed_revert_records_fail_event() {
+        let dir = write_scripts("revert-fail", &[]);
+        let plan_file = dir.plan_file();
+        let revert_dir = std::path::Path::new(&plan_file).with_file_name("revert");
+        std::fs::create_dir_all(&revert_dir).unwrap();
+        std::fs::write(revert_dir.join("change_name.sql"), "select fail;").unwrap();
+        let (deployer, changes, calls) = deployer(plan_file);
+        assert!(deployer.revert_change(&changes[0]).await.is_err());
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["Fail change_name: A description of the change\n\nFailed to revert: script failed"]
+        );
+    }
+
+    #[tokio::test]
+    async fn revert_changes_in_reverse_order() {
+        let (mut deployer, changes, _) = deployer("sqitch.plan".to_string());
+        deployer.scripts = Scripts::Embedded(
+            ["deploy", "revert"]
+                .into_iter()
+                .flat_map(|dir| {
+                    changes.iter().map(move |change| {
+                        (
+                            format!("{dir}/{}.sql", change.name()).into(),
+                            "select 1;\n".to_string(),
+                        )
+                    })
+                })
+                .collect(),
+        );
+        deployer.registry = Box::new(MemoryRegistry::new());
+        deployer.deploy_changes(&changes).await.unwrap();
+        assert_eq!(deployer.registry.fetch_changes().await.unwrap().len(), 2);
+
+        deployer.revert_changes(&changes).await.unwrap();
+        assert!(deployer.registry.fetch_changes().await.unwrap().is_empty());
+        let events = deployer
+            .registry
+            .fetch_events(&EventQuery {
+                reverse: true,
+                ..EventQuery::project("quitch")
+            })
+            .await
+            .unwrap();
+        let events: Vec<_> = events
+            .iter()
+            .map(|event| format!("{} {}", event.event, event.change))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "Deploy change_name",
+                "Deploy change_num2",
+                "Revert change_num2",
+                "Revert change_name"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_deploy_reverts_according_to_mode() {
+        let dir = write_scripts(
+            "deploy-mode",
+            &[
+                ("change_name", "select 1;\n"),
+                ("change_num2", "select fail;"),
+            ],
+        );
+        let plan_file = dir.plan_file();
+        let revert_dir = Path::new(&plan_file).with_file_name("revert");
+        std::fs::create_dir_all(&revert_dir).unwrap();
+        std::fs::write(revert_dir.join("change_name.sql"), "select 1;\n").unwrap();
+        for (mode, reverted) in [
+            (DeployMode::Change, false),
+            (DeployMode::Tag, true),
+            (DeployMode::All, true),
+        ] {
+            let (mut deployer, changes, calls) = deployer(plan_file.clone());
+            deployer.mode = mode;
+            assert!(deployer.deploy_changes(&changes).await.is_err());
+            let calls = calls.lock().unwrap();
+            assert_eq!(
+                calls.contains(&"Revert change_name".to_string()),
+                reverted,
+                "{mode:?}: {calls:?}"
+            );
+        }
+
+        // Changes up to the last tag stay deployed
+        let (mut deployer, _, calls) = deployer(plan_file);
+        deployer.plan = crate::plan::tests::example_with_tag();
+        let changes = deployer.plan.full_changes().to_vec();
+        deployer.mode = DeployMode::Tag;
+        assert!(deployer.deploy_changes(&changes).await.is_err());
+        assert!(!calls
+            .lock()
+            .unwrap()
+            .contains(&"Revert change_name".to_string()));
+    }
+
+    #[tokio::test]
+    async fn revert_refuses_changes_still_required() {
+        let (mut deployer, _, _) = deployer("sqitch.plan".to_string());
+        deployer.plan = Plan::parse(
+            "%syntax-version=1.0.0\n\
+            %project=quitch\n\
+            \n\
+            users 2024-03-07T03:19:34Z Ruslan Fadeev <github@kinrany.dev> # Users\n\
+            flips [users] 2024-03-10T00:04:24Z Ruslan Fadeev <github@kinrany.dev> # Flips\n",
+        )
+        .unwrap();
+        let changes: Vec<_> = deployer.plan.full_changes().to_vec();
+        deployer.scripts = Scripts::Embedded(HashMap::from([
+            ("deploy/users.sql".into(), "select 1;\n".to_string()),
+            ("deploy/flips.sql".into(), "select 1;\n".to_string()),
+        ]));
+        deployer.registry = Box::new(MemoryRegistry::new());
+        deployer.deploy_changes(&changes).await.unwrap();
+
+        let error = deployer.check_dependents(&changes[..1]).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "cannot revert users (required by quitch:flips), use --force to revert anyway"
+        );
+        assert!(deployer.check_dependents(&changes).await.is_ok());
+        deployer.force = true;
+        assert!(deployer.check_dependents(&changes[..1]).await.is_ok());
+    }
+}
@@ -0,0 +1,417 @@
+//! Consistency checks of the registry tables, run by `quitch registry doctor`.
+//!
+//! quitch and sqitch keep the tables consistent with each other, but rows
+//! edited by hand may not be. Change and tag IDs are computed again from their
+//! rows, the way they were computed from the plan, with the parent of a change
+//! being the change of the project deployed before it.
+//!
+//! Only tags and dependencies of changes that are no longer deployed can be
+//! fixed, by deleting them: nothing uses them, but they keep those changes from
+//! being deployed again. Anything else needs a person to decide what is right.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+
+use crate::{
+    change::{Change, Planner},
+    registry::{ChangeRow, Event, EventQuery, EventRow, RegistryStore},
+    tag::Tag,
+};
+
+/// A problem found in the registry
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Problem {
+    /// Short name of the check that failed, such as `dangling-tag`
+    pub code: &'static str,
+    pub message: String,
+    /// Whether `--fix` repairs it
+    pub fixable: bool,
+    /// ID of the missing change whose rows the fix deletes
+    #[serde(skip)]
+    leftover_of: Option<String>,
+}
+
+impl Problem {
+    fn new(code: &'static str, message: String) -> Self {
+        Self {
+            code,
+            message,
+            fixable: false,
+            leftover_of: None,
+        }
+    }
+
+    fn leftover(code: &'static str, message: String, change_id: &str) -> Self {
+        Self {
+            code,
+            message,
+            fixable: true,
+            leftover_of: Some(change_id.to_string()),
+        }
+    }
+}
+
+/// Check the registry tables against each other
+pub async fn diagnose(registry: &dyn RegistryStore) -> anyhow::Result<Vec<Problem>> {
+    let projects = registry.fetch_projects().await?;
+    let changes = registry.fetch_changes().await?;
+    let tags = registry.fetch_tags().await?;
+    let dependencies = registry.fetch_dependencies().await?;
+    let mut events = Vec::new();
+    for project in &projects {
+        events.extend(
+            registry
+                .fetch_events(&EventQuery {
+                    reverse: true,
+                    ..EventQuery::project(&project.project)
+                })
+                .await?,
+        );
+    }
+
+    let uris: HashMap<&str, Option<&str>> = projects
+        .iter()
+        .map(|row| (row.project.as_str(), row.uri.as_deref()))
+        .collect();
+    let deployed: HashMap<&str, &ChangeRow> = changes
+        .iter()
+        .map(|row| (row.change_id.as_str(), row))
+        .collect();
+    // Latest deploy or revert event of each change, oldest first
+    let mut latest: HashMap<&str, &EventRow> = HashMap::new();
+    for event in &events {
+        if matches!(event.event, Event::Deploy | Event::Revert) {
+            latest.insert(&event.change_id, event);
+        }
+    }
+
+    let mut problems = Vec::new();
+    for project in &projects {
+        let project = project.project.as_str();
+        let mut project_changes: Vec<_> = changes
+            .iter()
+            .filter(|row| row.project == project)
+            .collect();
+        project_changes.sort_by_key(|row| row.committed_at);
+        let mut parent: Option<&ChangeRow> = None;
+        for row in project_changes {
+            if let Some(event) = latest.get(row.change_id.as_str()) {
+                let expected = change_id(row, event, uris[project], parent);
+                if expected != row.change_id {
+                    let after = match parent {
+                        Some(parent) => format!("after {}", parent.change),
+                        None => "as the first change".to_string(),
+                    };
+                    problems.push(Problem::new(
+                        "change-id",
+                        format!(
+                            "{project}:{} has ID {}, but deployed {after} it would have ID \
+                            {expected}",
+                            row.change, row.change_id
+                        ),
+                    ));
+                }
+            }
+            parent = Some(row);
+        }
+    }
+    for row in &changes {
+        match latest.get(row.change_id.as_str()) {
+            Some(event) if event.event == Event::Deploy => {}
+            Some(_) => problems.push(Problem::new(
+                "reverted-change",
+                format!(
+                    "{}:{} is deployed, but its latest event reverted it",
+                    row.project, row.change
+                ),
+            )),
+            None => problems.push(Problem::new(
+                "change-without-events",
+                format!(
+                    "{}:{} is deployed, but has no deploy event",
+                    row.project, row.change
+                ),
+            )),
+        }
+    }
+    for event in &events {
+        let change_id = event.change_id.as_str();
+        let is_latest = latest
+            .get(change_id)
+            .is_some_and(|latest| std::ptr::eq(*latest, event));
+        if is_latest && event.event == Event::Deploy && !deployed.contains_key(change_id) {
+            problems.push(Problem::new(
+                "unknown-change",
+                format!(
+                    "events say {}:{} ({change_id}) is deployed, but it isn't in the changes \
+                    table",
+                    event.project, event.change
+                ),
+            ));
+        }
+    }
+
+    let mut tag_names = HashSet::new();
+    for tag in &tags {
+        if !tag_names.insert((&tag.project, &tag.tag)) {
+            problems.push(Problem::new(
+                "duplicate-tag",
+                format!(
+                    "{}:{} is in the tags table more than once",
+                    tag.project, tag.tag
+                ),
+            ));
+        }
+        if !deployed.contains_key(tag.change_id.as_str()) {
+            problems.push(Problem::leftover(
+                "dangling-tag",
+                format!(
+                    "{}:{} tags change {}, which isn't deployed",
+                    tag.project, tag.tag, tag.change_id
+                ),
+                &tag.change_id,
+            ));
+            continue;
+        }
+        let Some(&uri) = uris.get(tag.project.as_str()) else {
+            continue;
+        };
+        let expected = Tag {
+            name: tag.tag.trim_start_matches('@').to_string(),
+            note: tag.note.clone(),
+            date: tag.planned_at,
+            written_date: None,
+            planner: Planner::new(&tag.planner_name, &tag.planner_email),
+        }
+        .id(&tag.project, uri, &tag.change_id);
+        if expected != tag.tag_id {
+            problems.push(Problem::new(
+                "tag-id",
+                format!(
+                    "{}:{} has ID {}, but its fields give {expected}",
+                    tag.project, tag.tag, tag.tag_id
+                ),
+            ));
+        }
+    }
+
+    for dependency in &dependencies {
+        let Some(change) = deployed.get(dependency.change_id.as_str()) else {
+            problems.push(Problem::leftover(
+                "dangling-dependency",
+                format!(
+                    "change {}, which isn't deployed, has a dependency on {}",
+                    dependency.change_id, dependency.dependency
+                ),
+                &dependency.change_id,
+            ));
+            continue;
+        };
+        if let Some(dependency_id) = &dependency.dependency_id {
+            if !deployed.contains_key(dependency_id.as_str()) {
+                problems.push(Problem::new(
+                    "missing-dependency",
+                    format!(
+                        "{}:{} requires {} ({dependency_id}), which isn't deployed",
+                        change.project, change.change, dependency.dependency
+                    ),
+                ));
+            }
+        }
+    }
+    Ok(problems)
+}
+
+/// ID of a deployed change computed from its row, the dependencies recorded
+/// by its deploy event and the change deployed before it
+fn change_id(
+    row: &ChangeRow,
+    event: &EventRow,
+    uri: Option<&str>,
+    parent: Option<&ChangeRow>,
+) -> String {
+    let list = |names: &str| -> Vec<String> {
+        names
+            .split([',', ' '])
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+            .collect()
+    };
+    let change = Change {
+        name: row.change.clone(),
+        note: row.note.clone(),
+        date: row.planned_at,
+        written_date: None,
+        planner: Planner::new(&row.planner_name, &row.planner_email),
+        requires: list(&event.requires),
+        conflicts: list(&event.conflicts),
+    };
+    change.id(&row.project, uri, parent.map(|row| row.change_id.clone()))
+}
+
+/// Delete the rows that `problems` found left over from changes that are no
+/// longer deployed, returning how many problems that fixed
+pub async fn fix(registry: &dyn RegistryStore, problems: &[Problem]) -> anyhow::Result<usize> {
+    let leftovers: HashSet<_> = problems
+        .iter()
+        .filter_map(|problem| problem.leftover_of.as_deref())
+        .collect();
+    if leftovers.is_empty() {
+        return Ok(0);
+    }
+    registry.begin().await?;
+    for change_id in &leftovers {
+        // The change itself is gone, so only its tags and dependencies are left
+        if let Err(error) = registry.delete_change(change_id).await {
+            let _ = registry.rollback().await;
+            return Err(error);
+        }
+    }
+    registry.commit().await?;
+    Ok(problems.iter().filter(|problem| problem.fixable).count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        plan::{FullChange, Plan},
+        registry::{dependency_rows, memory::MemoryRegistry},
+    };
+
+    const PLAN: &str = "\
+%syntax-version=1.0.0
+%project=flipr
+%uri=https://example.com/flipr
+
+users 2024-03-07T03:19:34Z Jane <jane@example.com> # Add users
+@v1.0 2024-03-08T00:00:00Z Jane <jane@example.com> # Release
+flips [users] 2024-03-09T00:00:00Z Jane <jane@example.com> # Add flips
+";
+
+    async fn deploy(registry: &MemoryRegistry, plan: &Plan, change: &FullChange) {
+        let committer = Planner::new("Joe", "joe@example.com");
+        let deployed = registry.fetch_changes().await.unwrap();
+        let dependencies = dependency_rows(plan, change, &deployed);
+        registry
+            .insert_change(change, &change.id, plan.project(), &committer)
+            .await
+            .unwrap();
+        registry
+            .insert_tags(change, plan.project(), plan.uri(), &committer)
+            .await
+            .unwrap();
+        registry
+            .insert_dependencies(&change.id, &dependencies)
+            .await
+            .unwrap();
+        registry
+            .add_event(Event::Deploy, change, None, plan.project(), &committer)
+            .await
+            .unwrap();
+    }
+
+    async fn deployed_registry() -> (MemoryRegistry, Plan) {
+        let plan = Plan::parse(PLAN).unwrap();
+        let registry = MemoryRegistry::new();
+        registry
+            .insert_project(
+                plan.project(),
+                plan.uri(),
+                &Planner::new("Joe", "joe@example.com"),
+            )
+            .await
+            .unwrap();
+        for change in plan.full_changes() {
+            deploy(&registry, &plan, change).await;
+        }
+        (registry, plan)
+    }
+
+    fn codes(problems: &[Problem]) -> Vec<&str> {
+        let mut codes: Vec<_> = problems.iter().map(|problem| problem.code).collect();
+        codes.sort();
+        codes
+    }
+
+    #[tokio::test]
+    async fn test_consistent_registry() {
+        let (registry, _) = deployed_registry().await;
+        assert_eq!(diagnose(&registry).await.unwrap(), []);
+    }
+
+    #[tokio::test]
+    async fn test_deleted_change() {
+        let (registry, plan) = deployed_registry().await;
+        let users = &plan.full_changes()[0];
+        // Only the change row, as a manual `delete from changes` would
+        registry.delete_change(&users.id).await.unwrap();
+        let committer = Planner::new("Joe", "joe@example.com");
+        registry
+            .insert_tags(users, plan.project(), plan.uri(), &committer)
+            .await
+            .unwrap();
+
+        let problems = diagnose(&registry).await.unwrap();
+        assert_eq!(
+            codes(&problems),
+            [
+                "change-id",
+                "dangling-tag",
+                "missing-dependency",
+                "unknown-change"
+            ]
+        );
+        let change_id = problems.iter().find(|p| p.code == "change-id").unwrap();
+        assert_eq!(
+            change_id.message,
+            format!(
+                "flipr:flips has ID {}, but deployed as the first change it would have ID {}",
+                plan.full_changes()[1].id,
+                plan.full_changes()[1].change.id("flipr", plan.uri(), None)
+            )
+        );
+
+        assert_eq!(fix(&registry, &problems).await.unwrap(), 1);
+        let problems = diagnose(&registry).await.unwrap();
+        assert_eq!(
+            codes(&problems),
+            ["change-id", "missing-dependency", "unknown-change"]
+        );
+        assert!(problems.iter().all(|problem| !problem.fixable));
+        assert_eq!(fix(&registry, &problems).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_change_without_events() {
+        let plan = Plan::parse(PLAN).unwrap();
+        let registry = MemoryRegistry::new();
+        let users = &plan.full_changes()[0];
+        registry
+            .insert_change(
+                users,
+                "hash",
+                "flipr",
+                &Planner::new("Joe", "joe@example.com"),
+            )
+            .await
+            .unwrap();
+        registry
+            .insert_dependencies(
+                "0123abcd",
+                &[crate::registry::DependencyRow {
+                    kind: "require",
+                    dependency: "users".to_string(),
+                    dependency_id: Some(users.id.clone()),
+                }],
+            )
+            .await
+            .unwrap();
+        let problems = diagnose(&registry).await.unwrap();
+        assert_eq!(
+            codes(&problems),
+            ["change-without-events", "dangling-dependency"]
+        );
+    }
+}
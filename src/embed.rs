@@ -0,0 +1,156 @@
+//! Plans compiled into an application along with their scripts, so that it can
+//! deploy its changes at startup without shipping the plan directory.
+//!
+//! ```no_run
+//! use quitch::{commands::{parse_connection_string, RegistryLocation}, embed::EmbeddedPlan};
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! let target = parse_connection_string("db:pg://app:secret@localhost/app")?;
+//! EmbeddedPlan::new("%syntax-version=1.0.0\n%project=app\n\nusers 2024-03-07T03:19:34Z Jane <jane@example.com>\n")
+//!     .script("deploy/users.sql", "create table users (id int);")
+//!     .deploy(target, RegistryLocation::Name("sqitch".into()))
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Or embed the whole project directory, with the plan and every script in it:
+//!
+//! ```ignore
+//! quitch::embed!("$CARGO_MANIFEST_DIR/db")?
+//!     .deploy(target, RegistryLocation::Name("sqitch".into()))
+//!     .await?;
+//! ```
+
+use std::{collections::HashMap, path::PathBuf};
+
+use anyhow::anyhow;
+
+pub use include_dir;
+
+use crate::{
+    commands::{deploy_pending, setup_deployer_for, CommonArgs, ExecutionArgs, RegistryLocation},
+    config::DEFAULT_PLAN_FILE,
+    deployer::Scripts,
+    engine::ClientConfig,
+    failure::Failure,
+    plan::Plan,
+    script::ScriptDirs,
+};
+
+/// Embed a project directory holding `sqitch.plan` and the `deploy`, `revert`
+/// and `verify` directories, giving an [`EmbeddedPlan`].
+///
+/// Relative paths are resolved from where the compiler runs, so start the path
+/// with `$CARGO_MANIFEST_DIR/`.
+#[macro_export]
+macro_rules! embed {
+    // A `literal` fragment would reach `include_dir!` wrapped in a group it rejects
+    ($dir:tt) => {{
+        // The generated code refers to `include_dir` by name
+        use $crate::embed::include_dir;
+        $crate::embed::EmbeddedPlan::from_dir(&include_dir::include_dir!($dir))
+    }};
+}
+
+/// A plan with its scripts, read from memory instead of the filesystem
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EmbeddedPlan {
+    plan: String,
+    scripts: HashMap<PathBuf, String>,
+}
+
+impl EmbeddedPlan {
+    /// A plan given as the contents of its plan file, such as from `include_str!`
+    pub fn new(plan: impl Into<String>) -> Self {
+        Self {
+            plan: plan.into(),
+            scripts: HashMap::new(),
+        }
+    }
+
+    /// Add a script, with its path relative to the plan directory such as
+    /// `deploy/users.sql`
+    pub fn script(mut self, path: impl Into<PathBuf>, contents: impl Into<String>) -> Self {
+        self.scripts.insert(path.into(), contents.into());
+        self
+    }
+
+    /// The plan file and scripts of a directory embedded with `include_dir`
+    pub fn from_dir(dir: &include_dir::Dir) -> anyhow::Result<Self> {
+        let plan = dir
+            .get_file(DEFAULT_PLAN_FILE)
+            .and_then(|file| file.contents_utf8())
+            .ok_or_else(|| anyhow!("no {DEFAULT_PLAN_FILE} in {}", dir.path().display()))?;
+        let mut embedded = Self::new(plan);
+        let mut dirs = vec![dir];
+        while let Some(dir) = dirs.pop() {
+            for entry in dir.entries() {
+                match entry {
+                    include_dir::DirEntry::Dir(dir) => dirs.push(dir),
+                    include_dir::DirEntry::File(file) => {
+                        if file.path().extension().is_some_and(|ext| ext == "sql") {
+                            let contents = file.contents_utf8().ok_or_else(|| {
+                                anyhow!("{} is not valid UTF-8", file.path().display())
+                            })?;
+                            embedded = embedded.script(file.path(), contents);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(embedded)
+    }
+
+    pub fn plan(&self) -> anyhow::Result<Plan> {
+        Plan::parse(&self.plan)
+    }
+
+    /// Deploy the changes that aren't deployed yet, doing nothing when the
+    /// target is up to date
+    pub async fn deploy(
+        &self,
+        target: ClientConfig,
+        registry: RegistryLocation,
+    ) -> anyhow::Result<()> {
+        let common_args = CommonArgs {
+            registry,
+            plan_file: DEFAULT_PLAN_FILE.to_string(),
+            // Script paths are relative to the plan directory
+            script_dirs: ScriptDirs::next_to_plan(DEFAULT_PLAN_FILE),
+            connection_options: target,
+        };
+        let deployer = setup_deployer_for(
+            self.plan()?,
+            Scripts::Embedded(self.scripts.clone()),
+            common_args,
+            ExecutionArgs::default(),
+        )
+        .await?;
+        match deploy_pending(&deployer, None).await {
+            Err(error) if Failure::of(&error) == Some(Failure::NothingToDo) => Ok(()),
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embed_dir() {
+        static DIR: include_dir::Dir =
+            include_dir::include_dir!("$CARGO_MANIFEST_DIR/testdata/embed");
+        let embedded = EmbeddedPlan::from_dir(&DIR).unwrap();
+        assert_eq!(embedded.plan().unwrap().project(), "embedded");
+        assert_eq!(
+            embedded.scripts.get(&PathBuf::from("deploy/users.sql")),
+            Some(&"create table users (id int);\n".to_string())
+        );
+        assert_eq!(embedded.scripts.len(), 2);
+
+        let from_macro = crate::embed!("$CARGO_MANIFEST_DIR/testdata/embed").unwrap();
+        assert_eq!(from_macro, embedded);
+    }
+}
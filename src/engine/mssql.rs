@@ -0,0 +1,1082 @@
+use std::{borrow::Cow, collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, bail};
+use chrono::{DateTime, Utc};
+use futures::{future::BoxFuture, FutureExt};
+use regex::Regex;
+use tiberius::{AuthMethod, Client, EncryptionLevel, FromSql, Query, Row};
+use tokio::{net::TcpStream, sync::Mutex};
+use tokio_util::compat::{Compat, TokioAsyncWriteCompatExt};
+
+use super::{postgres::qualify_tables, ClientConfig, Engine, SslMode};
+use crate::{
+    change::Planner,
+    error::Error,
+    interrupt,
+    plan::FullChange,
+    registry::{
+        self, ChangeRow, DependencyRow, DeployedDependencyRow, Event, EventQuery, EventRow,
+        ProjectRow, RegistryDump, RegistryStore, TagRow,
+    },
+    script::{split_batches, substitute_variables},
+    tunnel::Tunnel,
+};
+
+/// Application lock taken while changing a database, scoped to the database
+const LOCK_RESOURCE: &str = "quitch";
+
+/// Error numbers after which the registry transaction can be run again:
+/// deadlock and lock request timeout
+const TRANSIENT_ERRORS: [u32; 2] = [1205, 1222];
+
+/// Schema of the registry tables on a connection made without one, the
+/// default schema of new users
+const DEFAULT_SCHEMA: &str = "dbo";
+
+type Connection = Client<Compat<TcpStream>>;
+
+/// SQL Server keeps the registry in a schema of the target database, like
+/// PostgreSQL.
+///
+/// The default schema of a session can't be changed, so the registry tables
+/// are always qualified with their schema.
+pub struct SqlServer {
+    /// The driver needs exclusive access to run a query, and the registry used
+    /// from the target's session shares the connection
+    client: Arc<Mutex<Connection>>,
+    /// Schema of the registry tables
+    registry_schema: Option<String>,
+    /// Whether the registry shares the connection of the target
+    in_target_session: bool,
+    /// SSH tunnel the connection goes through, open as long as this is
+    _tunnel: Option<Arc<Tunnel>>,
+}
+
+impl SqlServer {
+    pub async fn connect(config: &ClientConfig, schema: Option<&str>) -> anyhow::Result<Self> {
+        if config.socket.is_some() {
+            bail!("SQL Server targets can't be reached through a socket");
+        }
+        if config.charset.is_some() {
+            bail!("charset is not supported on SQL Server, which sends text as UTF-16");
+        }
+        if config.timeouts.statement_timeout.is_some() {
+            bail!("statement-timeout is not supported on SQL Server");
+        }
+        let mut options = tiberius::Config::new();
+        options.host(&config.hostname);
+        options.port(config.port);
+        if !config.db.is_empty() {
+            options.database(&config.db);
+        }
+        options.application_name("quitch");
+        options.authentication(AuthMethod::sql_server(
+            &config.username,
+            config.password.as_deref().unwrap_or_default(),
+        ));
+        let tls = &config.tls;
+        if tls.ssl_cert.is_some() || tls.ssl_key.is_some() {
+            bail!("client certificates are not supported on SQL Server");
+        }
+        // Servers usually have self-signed certificates, which are only
+        // checked when asked to, as by the `mysql` client
+        match tls.ssl_mode {
+            Some(SslMode::Disabled) => options.encryption(EncryptionLevel::NotSupported),
+            None | Some(SslMode::Preferred) => {
+                options.encryption(EncryptionLevel::On);
+                options.trust_cert();
+            }
+            Some(SslMode::Required) => {
+                options.encryption(EncryptionLevel::Required);
+                options.trust_cert();
+            }
+            // The driver always checks the hostname along with the CA
+            Some(SslMode::VerifyCa | SslMode::VerifyIdentity) => {
+                options.encryption(EncryptionLevel::Required);
+            }
+        }
+        if let Some(ssl_ca) = &tls.ssl_ca {
+            options.trust_cert_ca(ssl_ca.to_string_lossy());
+        }
+        let connecting = open(options);
+        let mut client = match config.timeouts.connect_timeout {
+            Some(seconds) => {
+                tokio::time::timeout(Duration::from_secs(seconds), connecting).await??
+            }
+            None => connecting.await?,
+        };
+        // Abort a failed script's batch and transaction like other engines
+        // do, and quote identifiers with double quotes like `sqlcmd`
+        let mut setup = "set xact_abort on; set quoted_identifier on;".to_string();
+        if let Some(seconds) = config.timeouts.lock_wait_timeout {
+            setup.push_str(&format!(" set lock_timeout {};", seconds * 1000));
+        }
+        client.simple_query(setup).await?.into_results().await?;
+        Ok(Self {
+            client: Arc::new(Mutex::new(client)),
+            registry_schema: schema.map(str::to_string),
+            in_target_session: false,
+            _tunnel: None,
+        })
+    }
+
+    /// Keep `tunnel` open while connected through it
+    pub fn with_tunnel(self, tunnel: Option<Arc<Tunnel>>) -> Self {
+        Self {
+            _tunnel: tunnel,
+            ..self
+        }
+    }
+
+    /// Whether the registry table `name` exists in the registry schema
+    async fn table_exists(&self, name: &str) -> anyhow::Result<bool> {
+        let mut query = Query::new(
+            "
+            select table_name
+            from information_schema.tables
+            where table_schema = coalesce(@P1, schema_name()) and table_name = @P2",
+        );
+        query.bind(self.registry_schema.as_deref());
+        query.bind(name);
+        Ok(!self.fetch(query).await?.is_empty())
+    }
+
+    /// Registry SQL with the table names qualified
+    fn qualified<'a>(&self, sql: &'a str) -> Cow<'a, str> {
+        match &self.registry_schema {
+            Some(schema) => qualify_tables(sql, schema),
+            None => Cow::Borrowed(sql),
+        }
+    }
+
+    /// Registry DDL for the registry schema of the connection
+    fn registry_ddl(&self, sql: &str) -> Cow<'static, str> {
+        registry_ddl(
+            sql,
+            self.registry_schema.as_deref().unwrap_or(DEFAULT_SCHEMA),
+        )
+        .into()
+    }
+
+    async fn fetch(&self, query: Query<'_>) -> anyhow::Result<Vec<Row>> {
+        let mut client = self.client.lock().await;
+        let rows = query.query(&mut client).await?.into_first_result().await?;
+        Ok(rows)
+    }
+
+    async fn execute(&self, query: Query<'_>) -> anyhow::Result<()> {
+        let mut client = self.client.lock().await;
+        query.execute(&mut client).await?;
+        Ok(())
+    }
+
+    /// Run SQL without parameters, such as transaction control
+    async fn batch(&self, sql: &str) -> anyhow::Result<()> {
+        let mut client = self.client.lock().await;
+        client.simple_query(sql).await?.into_results().await?;
+        Ok(())
+    }
+
+    async fn insert_rows(&self, sql: &str, rows: Vec<Vec<Param<'_>>>) -> anyhow::Result<()> {
+        for params in rows {
+            let mut query = Query::new(self.qualified(sql).into_owned());
+            for param in params {
+                param.bind_to(&mut query);
+            }
+            self.execute(query).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Connect, following the server to another address if it redirects, as
+/// Azure SQL does
+async fn open(mut options: tiberius::Config) -> anyhow::Result<Connection> {
+    let tcp = TcpStream::connect(options.get_addr()).await?;
+    tcp.set_nodelay(true)?;
+    match Client::connect(options.clone(), tcp.compat_write()).await {
+        Err(tiberius::error::Error::Routing { host, port }) => {
+            options.host(host);
+            options.port(port);
+            let tcp = TcpStream::connect(options.get_addr()).await?;
+            tcp.set_nodelay(true)?;
+            Ok(Client::connect(options, tcp.compat_write()).await?)
+        }
+        connected => Ok(connected?),
+    }
+}
+
+/// Registry DDL with its `:"registry"` and `:'registry'` placeholders filled
+/// in with `schema`
+fn registry_ddl(sql: &str, schema: &str) -> String {
+    let variables = HashMap::from([("registry".to_string(), schema.to_string())]);
+    substitute_variables(sql, &variables, '"')
+}
+
+/// A query parameter whose type depends on the column
+enum Param<'a> {
+    Text(Option<&'a str>),
+    Time(DateTime<Utc>),
+}
+
+impl<'a> Param<'a> {
+    fn text(text: &'a str) -> Self {
+        Self::Text(Some(text))
+    }
+
+    fn bind_to(self, query: &mut Query<'a>) {
+        match self {
+            Self::Text(text) => query.bind(text),
+            Self::Time(time) => query.bind(time),
+        }
+    }
+}
+
+/// Text of a value of `Event`, as stored in the `events` table
+fn event_name(event: Event) -> String {
+    event.to_string().to_lowercase()
+}
+
+/// Value of a column that can't be null
+fn get<'a, T: FromSql<'a>>(row: &'a Row, column: &str) -> anyhow::Result<T> {
+    row.try_get(column)?
+        .ok_or_else(|| anyhow!("{column} is null in the registry"))
+}
+
+fn text(row: &Row, column: &str) -> anyhow::Result<String> {
+    get::<&str>(row, column).map(str::to_string)
+}
+
+fn optional_text(row: &Row, column: &str) -> anyhow::Result<Option<String>> {
+    Ok(row.try_get::<&str, _>(column)?.map(str::to_string))
+}
+
+fn project_row(row: &Row) -> anyhow::Result<ProjectRow> {
+    Ok(ProjectRow {
+        project: text(row, "project")?,
+        uri: optional_text(row, "uri")?,
+        created_at: get(row, "created_at")?,
+        creator_name: text(row, "creator_name")?,
+        creator_email: text(row, "creator_email")?,
+    })
+}
+
+fn change_row(row: &Row) -> anyhow::Result<ChangeRow> {
+    Ok(ChangeRow {
+        change_id: text(row, "change_id")?,
+        script_hash: optional_text(row, "script_hash")?,
+        change: text(row, "change")?,
+        project: text(row, "project")?,
+        note: text(row, "note")?,
+        committed_at: get(row, "committed_at")?,
+        committer_name: text(row, "committer_name")?,
+        committer_email: text(row, "committer_email")?,
+        planned_at: get(row, "planned_at")?,
+        planner_name: text(row, "planner_name")?,
+        planner_email: text(row, "planner_email")?,
+    })
+}
+
+fn tag_row(row: &Row) -> anyhow::Result<TagRow> {
+    Ok(TagRow {
+        tag_id: text(row, "tag_id")?,
+        tag: text(row, "tag")?,
+        project: text(row, "project")?,
+        change_id: text(row, "change_id")?,
+        note: text(row, "note")?,
+        committed_at: get(row, "committed_at")?,
+        committer_name: text(row, "committer_name")?,
+        committer_email: text(row, "committer_email")?,
+        planned_at: get(row, "planned_at")?,
+        planner_name: text(row, "planner_name")?,
+        planner_email: text(row, "planner_email")?,
+    })
+}
+
+fn dependency_row(row: &Row) -> anyhow::Result<DeployedDependencyRow> {
+    Ok(DeployedDependencyRow {
+        change_id: text(row, "change_id")?,
+        kind: text(row, "type")?,
+        dependency: text(row, "dependency")?,
+        dependency_id: optional_text(row, "dependency_id")?,
+    })
+}
+
+fn event_row(row: &Row) -> anyhow::Result<EventRow> {
+    let event: &str = get(row, "event")?;
+    Ok(EventRow {
+        event: clap::ValueEnum::from_str(event, false)
+            .map_err(|_| anyhow!("unknown event {event} in the registry"))?,
+        change_id: text(row, "change_id")?,
+        change: text(row, "change")?,
+        project: text(row, "project")?,
+        note: text(row, "note")?,
+        requires: text(row, "requires")?,
+        conflicts: text(row, "conflicts")?,
+        tags: text(row, "tags")?,
+        committed_at: get(row, "committed_at")?,
+        committer_name: text(row, "committer_name")?,
+        committer_email: text(row, "committer_email")?,
+        planned_at: get(row, "planned_at")?,
+        planner_name: text(row, "planner_name")?,
+        planner_email: text(row, "planner_email")?,
+    })
+}
+
+/// The columns shared by the `changes` and `events` tables, in the order of
+/// [`CHANGE_COLUMNS`]
+fn change_params<'a>(
+    change: &'a FullChange,
+    project: &'a str,
+    note: &'a str,
+    committer: &'a Planner,
+) -> Vec<Param<'a>> {
+    vec![
+        Param::text(&change.id),
+        Param::text(&change.change.name),
+        Param::text(project),
+        Param::text(note),
+        Param::Time(Utc::now()),
+        Param::text(&committer.name),
+        Param::text(&committer.email),
+        Param::Time(change.change.date),
+        Param::text(&change.change.planner.name),
+        Param::text(&change.change.planner.email),
+    ]
+}
+
+/// Columns bound by [`change_params`]
+const CHANGE_COLUMNS: &str = "change_id, change, project, note, \
+    committed_at, committer_name, committer_email, \
+    planned_at, planner_name, planner_email";
+
+/// `@P1, @P2, …` for `count` parameters
+fn placeholders(count: usize) -> String {
+    (1..=count)
+        .map(|n| format!("@P{n}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl RegistryStore for SqlServer {
+    fn registry_version(&self) -> BoxFuture<'_, anyhow::Result<Option<f32>>> {
+        async move {
+            if !self.table_exists("releases").await? {
+                return Ok(None);
+            }
+            let rows = self
+                .fetch(Query::new(self.qualified(
+                    "select top 1 version from releases order by version desc",
+                )))
+                .await?;
+            rows.first().map(|row| get(row, "version")).transpose()
+        }
+        .boxed()
+    }
+
+    fn insert_release<'a>(
+        &'a self,
+        version: f32,
+        installer: &'a Planner,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            let mut query = Query::new(self.qualified(
+                "insert into releases (
+                    version, installed_at, installer_name, installer_email
+                ) values (@P1, @P2, @P3, @P4)",
+            ));
+            query.bind(version);
+            query.bind(Utc::now());
+            query.bind(installer.name.as_str());
+            query.bind(installer.email.as_str());
+            self.execute(query).await
+        }
+        .boxed()
+    }
+
+    fn begin(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        self.batch("begin transaction").boxed()
+    }
+
+    fn commit(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        self.batch("commit").boxed()
+    }
+
+    fn rollback(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        // A failed statement already rolled back the transaction with
+        // `xact_abort` on
+        self.batch("if @@trancount > 0 rollback").boxed()
+    }
+
+    fn is_transient(&self, error: &anyhow::Error) -> bool {
+        match error.downcast_ref::<tiberius::error::Error>() {
+            Some(tiberius::error::Error::Server(error)) => TRANSIENT_ERRORS.contains(&error.code()),
+            _ => false,
+        }
+    }
+
+    fn in_target_session(&self) -> bool {
+        self.in_target_session
+    }
+
+    fn fetch_projects(&self) -> BoxFuture<'_, anyhow::Result<Vec<ProjectRow>>> {
+        async move {
+            let rows = self
+                .fetch(Query::new(self.qualified("select * from projects")))
+                .await?;
+            rows.iter().map(project_row).collect()
+        }
+        .boxed()
+    }
+
+    fn insert_project<'a>(
+        &'a self,
+        project: &'a str,
+        uri: Option<&'a str>,
+        committer: &'a Planner,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            let mut query = Query::new(self.qualified(
+                "insert into projects (
+                    project, uri, created_at, creator_name, creator_email
+                ) values (@P1, @P2, @P3, @P4, @P5)",
+            ));
+            query.bind(project);
+            query.bind(uri);
+            query.bind(Utc::now());
+            query.bind(committer.name.as_str());
+            query.bind(committer.email.as_str());
+            self.execute(query).await
+        }
+        .boxed()
+    }
+
+    fn fetch_changes(&self) -> BoxFuture<'_, anyhow::Result<Vec<ChangeRow>>> {
+        async move {
+            let rows = self
+                .fetch(Query::new(self.qualified("select * from changes")))
+                .await?;
+            rows.iter().map(change_row).collect()
+        }
+        .boxed()
+    }
+
+    fn insert_change<'a>(
+        &'a self,
+        change: &'a FullChange,
+        script_hash: &'a str,
+        project: &'a str,
+        committer: &'a Planner,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            let mut params = change_params(change, project, &change.change.note, committer);
+            params.push(Param::text(script_hash));
+            let sql = format!(
+                "insert into changes ({CHANGE_COLUMNS}, script_hash) values ({})",
+                placeholders(params.len())
+            );
+            self.insert_rows(&sql, vec![params]).await
+        }
+        .boxed()
+    }
+
+    fn insert_tags<'a>(
+        &'a self,
+        change: &'a FullChange,
+        project: &'a str,
+        uri: Option<&'a str>,
+        committer: &'a Planner,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            let ids: Vec<_> = change
+                .tags
+                .iter()
+                .map(|tag| {
+                    (
+                        tag.id(project, uri, &change.id),
+                        format!("@{}", tag.name),
+                        tag,
+                    )
+                })
+                .collect();
+            let rows = ids
+                .iter()
+                .map(|(tag_id, name, tag)| {
+                    vec![
+                        Param::text(tag_id),
+                        Param::text(name),
+                        Param::text(project),
+                        Param::text(&change.id),
+                        Param::text(&tag.note),
+                        Param::Time(Utc::now()),
+                        Param::text(&committer.name),
+                        Param::text(&committer.email),
+                        Param::Time(tag.date),
+                        Param::text(&tag.planner.name),
+                        Param::text(&tag.planner.email),
+                    ]
+                })
+                .collect();
+            self.insert_rows(
+                "insert into tags (
+                    tag_id, tag, project, change_id, note,
+                    committed_at, committer_name, committer_email,
+                    planned_at, planner_name, planner_email
+                ) values (@P1, @P2, @P3, @P4, @P5, @P6, @P7, @P8, @P9, @P10, @P11)",
+                rows,
+            )
+            .await
+        }
+        .boxed()
+    }
+
+    fn fetch_tags(&self) -> BoxFuture<'_, anyhow::Result<Vec<TagRow>>> {
+        async move {
+            let rows = self
+                .fetch(Query::new(self.qualified("select * from tags")))
+                .await?;
+            rows.iter().map(tag_row).collect()
+        }
+        .boxed()
+    }
+
+    fn insert_dependencies<'a>(
+        &'a self,
+        change_id: &'a str,
+        dependencies: &'a [DependencyRow],
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            let rows = dependencies
+                .iter()
+                .map(|dependency| {
+                    vec![
+                        Param::text(change_id),
+                        Param::text(dependency.kind),
+                        Param::text(&dependency.dependency),
+                        Param::Text(dependency.dependency_id.as_deref()),
+                    ]
+                })
+                .collect();
+            self.insert_rows(
+                "insert into dependencies (
+                    change_id, type, dependency, dependency_id
+                ) values (@P1, @P2, @P3, @P4)",
+                rows,
+            )
+            .await
+        }
+        .boxed()
+    }
+
+    fn fetch_dependencies(&self) -> BoxFuture<'_, anyhow::Result<Vec<DeployedDependencyRow>>> {
+        async move {
+            let rows = self
+                .fetch(Query::new(self.qualified("select * from dependencies")))
+                .await?;
+            rows.iter().map(dependency_row).collect()
+        }
+        .boxed()
+    }
+
+    fn changes_requiring<'a>(
+        &'a self,
+        change_id: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<ChangeRow>>> {
+        async move {
+            let mut query = Query::new(self.qualified(
+                "select c.* from changes c
+                join dependencies d on d.change_id = c.change_id
+                where d.type = 'require' and d.dependency_id = @P1",
+            ));
+            query.bind(change_id);
+            self.fetch(query).await?.iter().map(change_row).collect()
+        }
+        .boxed()
+    }
+
+    fn delete_change<'a>(&'a self, change_id: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            for table in ["dependencies", "tags", "changes"] {
+                let mut query = Query::new(
+                    self.qualified(&format!("delete from {table} where change_id = @P1"))
+                        .into_owned(),
+                );
+                query.bind(change_id);
+                self.execute(query).await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn delete_project<'a>(&'a self, project: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            for statement in [
+                "delete from dependencies where change_id in (
+                    select change_id from changes where project = @P1
+                )",
+                "delete from tags where project = @P1",
+                "delete from changes where project = @P1",
+                "delete from events where project = @P1",
+                "delete from projects where project = @P1",
+            ] {
+                let mut query = Query::new(self.qualified(statement));
+                query.bind(project);
+                self.execute(query).await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn add_event<'a>(
+        &'a self,
+        event_type: Event,
+        change: &'a FullChange,
+        note: Option<&'a str>,
+        project: &'a str,
+        committer: &'a Planner,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            let event = event_name(event_type);
+            let lists = registry::event_lists(change);
+            let note = note.unwrap_or(&change.change.note);
+            let mut params = vec![Param::text(&event)];
+            params.extend(change_params(change, project, note, committer));
+            params.extend(lists.iter().map(|list| Param::text(list)));
+            let sql = format!(
+                "insert into events (
+                    event, {CHANGE_COLUMNS}, requires, conflicts, tags
+                ) values ({})",
+                placeholders(params.len())
+            );
+            self.insert_rows(&sql, vec![params]).await
+        }
+        .boxed()
+    }
+
+    fn fetch_events<'a>(
+        &'a self,
+        query: &'a EventQuery,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<EventRow>>> {
+        async move {
+            // SQL Server has no regular expressions, so patterns are matched
+            // here, and with them the rows to skip and the limit
+            let pattern = |pattern: &Option<String>| pattern.as_deref().map(Regex::new).transpose();
+            let project_pattern = pattern(&query.project_pattern)?;
+            let change_pattern = pattern(&query.change_pattern)?;
+            let committer_pattern = pattern(&query.committer_pattern)?;
+            let matches = |pattern: &Option<Regex>, value: &str| {
+                pattern
+                    .as_ref()
+                    .is_none_or(|pattern| pattern.is_match(value))
+            };
+
+            let mut params = Vec::new();
+            let mut bind = |param| {
+                params.push(param);
+                format!("@P{}", params.len())
+            };
+            let mut sql = "select * from events where 1 = 1".to_string();
+            if let Some(project) = &query.project {
+                sql.push_str(&format!(" and project = {}", bind(Param::text(project))));
+            }
+            let event_names: Vec<_> = query.event_types.iter().map(|e| event_name(*e)).collect();
+            if !event_names.is_empty() {
+                let names: Vec<_> = event_names
+                    .iter()
+                    .map(|name| bind(Param::text(name)))
+                    .collect();
+                sql.push_str(&format!(" and event in ({})", names.join(", ")));
+            }
+            if let Some(since) = query.since {
+                sql.push_str(&format!(
+                    " and committed_at >= {}",
+                    bind(Param::Time(since))
+                ));
+            }
+            if let Some(until) = query.until {
+                sql.push_str(&format!(" and committed_at < {}", bind(Param::Time(until))));
+            }
+            if let Some(after) = &query.after {
+                // T-SQL can't compare rows like `(committed_at, change_id) < (…)`
+                let op = if query.reverse { ">" } else { "<" };
+                let committed_at = bind(Param::Time(after.committed_at));
+                let change_id = bind(Param::text(&after.change_id));
+                sql.push_str(&format!(
+                    " and (committed_at {op} {committed_at} \
+                    or committed_at = {committed_at} and change_id {op} {change_id})"
+                ));
+            }
+            sql.push_str(if query.reverse {
+                " order by committed_at asc, change_id asc"
+            } else {
+                " order by committed_at desc, change_id desc"
+            });
+
+            let mut statement = Query::new(self.qualified(&sql).into_owned());
+            for param in params {
+                param.bind_to(&mut statement);
+            }
+            let mut events = Vec::new();
+            for row in &self.fetch(statement).await? {
+                let event = event_row(row)?;
+                if matches(&project_pattern, &event.project)
+                    && matches(&change_pattern, &event.change)
+                    && matches(&committer_pattern, &event.committer_name)
+                {
+                    events.push(event);
+                }
+            }
+            let skip = usize::try_from(query.skip).unwrap_or(usize::MAX);
+            let limit = query.limit.map_or(usize::MAX, |limit| {
+                usize::try_from(limit).unwrap_or(usize::MAX)
+            });
+            Ok(events.into_iter().skip(skip).take(limit).collect())
+        }
+        .boxed()
+    }
+
+    fn deployed_change_tags<'a>(
+        &'a self,
+        change_id: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<String>> {
+        async move {
+            let mut query = Query::new(self.qualified(
+                "select top 1 tags from events
+                where change_id = @P1 and event = 'deploy'
+                order by committed_at desc",
+            ));
+            query.bind(change_id);
+            let rows = self.fetch(query).await?;
+            Ok(match rows.first() {
+                Some(row) => text(row, "tags")?,
+                None => String::new(),
+            })
+        }
+        .boxed()
+    }
+
+    fn import_rows<'a>(&'a self, dump: &'a RegistryDump) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            self.insert_rows(
+                "insert into projects (
+                    project, uri, created_at, creator_name, creator_email
+                ) values (@P1, @P2, @P3, @P4, @P5)",
+                dump.projects
+                    .iter()
+                    .map(|row| {
+                        vec![
+                            Param::text(&row.project),
+                            Param::Text(row.uri.as_deref()),
+                            Param::Time(row.created_at),
+                            Param::text(&row.creator_name),
+                            Param::text(&row.creator_email),
+                        ]
+                    })
+                    .collect(),
+            )
+            .await?;
+            self.insert_rows(
+                "insert into changes (
+                    change_id, script_hash, change, project, note,
+                    committed_at, committer_name, committer_email,
+                    planned_at, planner_name, planner_email
+                ) values (@P1, @P2, @P3, @P4, @P5, @P6, @P7, @P8, @P9, @P10, @P11)",
+                dump.changes
+                    .iter()
+                    .map(|row| {
+                        vec![
+                            Param::text(&row.change_id),
+                            Param::Text(row.script_hash.as_deref()),
+                            Param::text(&row.change),
+                            Param::text(&row.project),
+                            Param::text(&row.note),
+                            Param::Time(row.committed_at),
+                            Param::text(&row.committer_name),
+                            Param::text(&row.committer_email),
+                            Param::Time(row.planned_at),
+                            Param::text(&row.planner_name),
+                            Param::text(&row.planner_email),
+                        ]
+                    })
+                    .collect(),
+            )
+            .await?;
+            self.insert_rows(
+                "insert into tags (
+                    tag_id, tag, project, change_id, note,
+                    committed_at, committer_name, committer_email,
+                    planned_at, planner_name, planner_email
+                ) values (@P1, @P2, @P3, @P4, @P5, @P6, @P7, @P8, @P9, @P10, @P11)",
+                dump.tags
+                    .iter()
+                    .map(|row| {
+                        vec![
+                            Param::text(&row.tag_id),
+                            Param::text(&row.tag),
+                            Param::text(&row.project),
+                            Param::text(&row.change_id),
+                            Param::text(&row.note),
+                            Param::Time(row.committed_at),
+                            Param::text(&row.committer_name),
+                            Param::text(&row.committer_email),
+                            Param::Time(row.planned_at),
+                            Param::text(&row.planner_name),
+                            Param::text(&row.planner_email),
+                        ]
+                    })
+                    .collect(),
+            )
+            .await?;
+            self.insert_rows(
+                "insert into dependencies (
+                    change_id, type, dependency, dependency_id
+                ) values (@P1, @P2, @P3, @P4)",
+                dump.dependencies
+                    .iter()
+                    .map(|row| {
+                        vec![
+                            Param::text(&row.change_id),
+                            Param::text(&row.kind),
+                            Param::text(&row.dependency),
+                            Param::Text(row.dependency_id.as_deref()),
+                        ]
+                    })
+                    .collect(),
+            )
+            .await?;
+            let event_names: Vec<_> = dump
+                .events
+                .iter()
+                .map(|row| event_name(row.event))
+                .collect();
+            self.insert_rows(
+                "insert into events (
+                    event, change_id, change, project, note, requires, conflicts, tags,
+                    committed_at, committer_name, committer_email,
+                    planned_at, planner_name, planner_email
+                ) values (
+                    @P1, @P2, @P3, @P4, @P5, @P6, @P7, @P8, @P9, @P10, @P11, @P12, @P13, @P14
+                )",
+                dump.events
+                    .iter()
+                    .zip(&event_names)
+                    .map(|(row, event)| {
+                        vec![
+                            Param::text(event),
+                            Param::text(&row.change_id),
+                            Param::text(&row.change),
+                            Param::text(&row.project),
+                            Param::text(&row.note),
+                            Param::text(&row.requires),
+                            Param::text(&row.conflicts),
+                            Param::text(&row.tags),
+                            Param::Time(row.committed_at),
+                            Param::text(&row.committer_name),
+                            Param::text(&row.committer_email),
+                            Param::Time(row.planned_at),
+                            Param::text(&row.planner_name),
+                            Param::text(&row.planner_email),
+                        ]
+                    })
+                    .collect(),
+            )
+            .await
+        }
+        .boxed()
+    }
+}
+
+impl Engine for SqlServer {
+    fn identifier_quote(&self) -> char {
+        '"'
+    }
+
+    fn registry_schema(&self) -> Cow<'static, str> {
+        self.registry_ddl(include_str!("../registry_schema_mssql.sql"))
+    }
+
+    fn releases_schema(&self) -> Cow<'static, str> {
+        self.registry_ddl(include_str!("../registry_releases_mssql.sql"))
+    }
+
+    fn registry_upgrades(&self) -> Vec<(f32, Cow<'static, str>)> {
+        // Timestamps always had full precision here, so 1.1 only records the release
+        vec![(1.1, "".into())]
+    }
+
+    fn registry_teardown(&self) -> Cow<'static, str> {
+        self.registry_ddl(
+            "drop table :\"registry\".dependencies, :\"registry\".tags, :\"registry\".events, \
+            :\"registry\".changes, :\"registry\".projects, :\"registry\".releases;",
+        )
+    }
+
+    fn lock(&self, timeout: Duration) -> BoxFuture<'_, anyhow::Result<bool>> {
+        async move {
+            let mut query = Query::new(
+                "declare @result int;
+                exec @result = sp_getapplock
+                    @Resource = @P1, @LockMode = 'Exclusive', @LockOwner = 'Session',
+                    @LockTimeout = @P2;
+                select @result as result",
+            );
+            query.bind(LOCK_RESOURCE);
+            query.bind(i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX));
+            let rows = self.fetch(query).await?;
+            let result: i32 = match rows.first() {
+                Some(row) => get(row, "result")?,
+                None => bail!("sp_getapplock returned no result"),
+            };
+            // 0 when granted right away, 1 after waiting
+            Ok(result >= 0)
+        }
+        .boxed()
+    }
+
+    fn unlock(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        async move {
+            let mut query = Query::new(
+                "if applock_mode('public', @P1, 'Session') <> 'NoLock'
+                exec sp_releaseapplock @Resource = @P1, @LockOwner = 'Session'",
+            );
+            query.bind(LOCK_RESOURCE);
+            self.execute(query).await
+        }
+        .boxed()
+    }
+
+    fn transactional_ddl(&self) -> bool {
+        true
+    }
+
+    fn registry_in_session(&self, schema: &str) -> Option<Box<dyn Engine>> {
+        Some(Box::new(Self {
+            client: self.client.clone(),
+            registry_schema: Some(schema.to_string()),
+            in_target_session: true,
+            _tunnel: self._tunnel.clone(),
+        }))
+    }
+
+    fn run_script<'a>(&'a self, sql: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            for (index, batch) in split_batches(sql).into_iter().enumerate() {
+                interrupt::check()?;
+                let mut client = self.client.lock().await;
+                let result = async { client.simple_query(batch.sql).await?.into_results().await };
+                result.await.map_err(|source| Error::Statement {
+                    index: index + 1,
+                    line: batch.line,
+                    statement: batch.sql.to_string(),
+                    sqlstate: source.code().map(|code| code.to_string()),
+                    source: Box::new(source),
+                })?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn registry_exists(&self) -> BoxFuture<'_, anyhow::Result<bool>> {
+        self.table_exists("changes").boxed()
+    }
+
+    fn schema_exists<'a>(&'a self, schema_name: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        async move {
+            let mut query = Query::new("select name from sys.schemas where name = @P1");
+            query.bind(schema_name);
+            Ok(!self.fetch(query).await?.is_empty())
+        }
+        .boxed()
+    }
+
+    fn create_schema<'a>(&'a self, schema_name: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            // `create schema` has to be alone in its batch
+            let mut query = Query::new(
+                "if schema_id(@P1) is null
+                begin
+                    declare @sql nvarchar(max) = N'create schema ' + quotename(@P1);
+                    exec sp_executesql @sql;
+                end",
+            );
+            query.bind(schema_name);
+            self.execute(query).await
+        }
+        .boxed()
+    }
+
+    fn drop_schema<'a>(&'a self, schema_name: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            // SQL Server only drops empty schemas, so drop the foreign keys
+            // referencing its tables, then what it contains
+            let mut query = Query::new(
+                "declare @sql nvarchar(max) = N'';
+                select @sql += N'alter table ' + quotename(schema_name(t.schema_id)) + N'.'
+                    + quotename(t.name) + N' drop constraint ' + quotename(f.name) + N';'
+                from sys.foreign_keys f
+                join sys.tables t on t.object_id = f.parent_object_id
+                join sys.tables r on r.object_id = f.referenced_object_id
+                where r.schema_id = schema_id(@P1);
+                select @sql += N'drop '
+                    + case
+                        when type = 'U' then N'table'
+                        when type = 'V' then N'view'
+                        when type = 'P' then N'procedure'
+                        else N'function'
+                    end
+                    + N' ' + quotename(@P1) + N'.' + quotename(name) + N';'
+                from sys.objects
+                where schema_id = schema_id(@P1) and type in ('U', 'V', 'P', 'FN', 'IF', 'TF');
+                set @sql += N'drop schema ' + quotename(@P1) + N';';
+                exec sp_executesql @sql",
+            );
+            query.bind(schema_name);
+            self.execute(query).await
+        }
+        .boxed()
+    }
+
+    fn fetch_strings<'a>(&'a self, sql: &'a str) -> BoxFuture<'a, anyhow::Result<Vec<String>>> {
+        async move {
+            let mut client = self.client.lock().await;
+            let rows = client.simple_query(sql).await?.into_first_result().await?;
+            rows.iter()
+                .map(|row| {
+                    row.try_get::<&str, _>(0)?
+                        .map(str::to_string)
+                        .ok_or_else(|| anyhow!("null in the results of {sql}"))
+                })
+                .collect()
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_ddl() {
+        let sql = include_str!("../registry_schema_mssql.sql");
+        let schema = registry_ddl(sql, "sqitch");
+        assert!(schema.contains("IF OBJECT_ID(QUOTENAME(N'sqitch') + N'.changes', N'U') IS NULL"));
+        assert!(schema.contains("CREATE TABLE \"sqitch\".changes ("));
+        assert!(schema.contains("REFERENCES \"sqitch\".changes (change_id)"));
+        assert!(!schema.contains(":\"registry\"") && !schema.contains(":'registry'"));
+        // A single batch, so that it runs as one
+        assert_eq!(split_batches(&schema).len(), 1);
+
+        let schema = registry_ddl(sql, "it's \"odd\"");
+        assert!(schema.contains("QUOTENAME(N'it''s \"odd\"')"));
+        assert!(schema.contains("CREATE TABLE \"it's \"\"odd\"\"\".projects ("));
+    }
+
+    #[test]
+    fn test_placeholders() {
+        assert_eq!(placeholders(3), "@P1, @P2, @P3");
+    }
+}
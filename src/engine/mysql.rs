@@ -0,0 +1,858 @@
+use std::{borrow::Cow, sync::Arc, time::Duration};
+
+use futures::{future::BoxFuture, FutureExt};
+use sqlx::{
+    mysql::{
+        MySqlConnectOptions, MySqlConnection, MySqlDatabaseError, MySqlPool, MySqlPoolOptions,
+        MySqlSslMode,
+    },
+    Executor,
+};
+
+use super::{execute_script_in_transactions, ClientConfig, Engine, SslMode, Timeouts};
+use crate::{
+    change::Planner,
+    plan::FullChange,
+    registry::{
+        ChangeRow, DependencyRow, DeployedDependencyRow, Event, EventQuery, EventRow, ProjectRow,
+        RegistryDump, RegistryStore, TagRow,
+    },
+    script::quote_ident,
+    tunnel::Tunnel,
+};
+
+/// Name of the lock taken by sqitch, scoped to the current database
+const LOCK_NAME: &str = "concat('sqitch working on ', database())";
+
+/// Error numbers after which the registry transaction can be run again:
+/// deadlock, lock wait timeout and lost connection
+const TRANSIENT_ERRORS: [u16; 3] = [1213, 1205, 2013];
+
+/// Tables of the registry, which its SQL names right after one of
+/// [`TABLE_KEYWORDS`]
+const REGISTRY_TABLES: [&str; 6] = [
+    "releases",
+    "projects",
+    "changes",
+    "tags",
+    "dependencies",
+    "events",
+];
+
+/// Words coming before a table name in the registry SQL, and never before a
+/// column name
+const TABLE_KEYWORDS: [&str; 6] = ["from", "into", "join", "TABLE", "EXISTS", "REFERENCES"];
+
+/// MySQL keeps the registry in a database of its own, or among the tables of
+/// the target database with their names prefixed
+pub struct MySql {
+    /// Holds a single connection, so that locks and transactions of the
+    /// session apply to every query
+    pool: MySqlPool,
+    /// Prepended to the names of the registry tables and their constraints
+    prefix: String,
+    /// SSH tunnel the connection goes through, open as long as this is
+    _tunnel: Option<Arc<Tunnel>>,
+}
+
+impl MySql {
+    pub async fn connect(config: &ClientConfig, database: Option<&str>) -> anyhow::Result<Self> {
+        let mut options = MySqlConnectOptions::new()
+            .host(&config.hostname)
+            .port(config.port);
+        let database = database.unwrap_or(&config.db);
+        if !database.is_empty() {
+            options = options.database(database);
+        }
+        if let Some(password) = &config.password {
+            options = options.password(password);
+        }
+        if !config.username.is_empty() {
+            options = options.username(&config.username);
+        }
+        if let Some(charset) = &config.charset {
+            options = options.charset(charset);
+        }
+        if let Some(socket) = &config.socket {
+            options = options.socket(socket);
+        }
+        let tls = &config.tls;
+        if let Some(ssl_mode) = tls.ssl_mode {
+            options = options.ssl_mode(match ssl_mode {
+                SslMode::Disabled => MySqlSslMode::Disabled,
+                SslMode::Preferred => MySqlSslMode::Preferred,
+                SslMode::Required => MySqlSslMode::Required,
+                SslMode::VerifyCa => MySqlSslMode::VerifyCa,
+                SslMode::VerifyIdentity => MySqlSslMode::VerifyIdentity,
+            });
+        }
+        if let Some(ssl_ca) = &tls.ssl_ca {
+            options = options.ssl_ca(ssl_ca);
+        }
+        if let Some(ssl_cert) = &tls.ssl_cert {
+            options = options.ssl_client_cert(ssl_cert);
+        }
+        if let Some(ssl_key) = &tls.ssl_key {
+            options = options.ssl_client_key(ssl_key);
+        }
+        let mut pool_options = MySqlPoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None);
+        if let Some(connect_timeout) = config.timeouts.connect_timeout {
+            pool_options = pool_options.acquire_timeout(Duration::from_secs(connect_timeout));
+        }
+        let timeouts = config.timeouts;
+        pool_options = pool_options.after_connect(move |connection, _| {
+            async move { set_timeouts(connection, timeouts).await }.boxed()
+        });
+        let pool = pool_options.connect_with(options).await?;
+        pool.execute("select 1").await?;
+        Ok(Self {
+            pool,
+            prefix: String::new(),
+            _tunnel: None,
+        })
+    }
+
+    /// Keep `tunnel` open while connected through it
+    pub fn with_tunnel(self, tunnel: Option<Arc<Tunnel>>) -> Self {
+        Self {
+            _tunnel: tunnel,
+            ..self
+        }
+    }
+
+    /// Keep the registry in the tables of the connected database whose names
+    /// start with `prefix`
+    pub fn with_prefix(self, prefix: &str) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            ..self
+        }
+    }
+
+    /// Whether the registry table `name` exists in the connected database
+    async fn table_exists(&self, name: &str) -> anyhow::Result<bool> {
+        let tables = sqlx::query(
+            "
+            select table_name
+            from information_schema.tables
+            where table_schema = database() and table_name = ?",
+        )
+        .bind(format!("{}{name}", self.prefix))
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(!tables.is_empty())
+    }
+
+    /// Registry SQL with the table names prefixed
+    fn prefixed<'a>(&self, sql: &'a str) -> Cow<'a, str> {
+        prefix_tables(sql, &self.prefix)
+    }
+}
+
+/// Apply the statement and lock wait timeouts to the session
+async fn set_timeouts(connection: &mut MySqlConnection, timeouts: Timeouts) -> sqlx::Result<()> {
+    if let Some(seconds) = timeouts.lock_wait_timeout {
+        // Metadata locks, as taken by schema changes, then row locks
+        connection
+            .execute(
+                format!(
+                    "set session lock_wait_timeout = {seconds}, \
+                    innodb_lock_wait_timeout = {seconds}"
+                )
+                .as_str(),
+            )
+            .await?;
+    }
+    if let Some(seconds) = timeouts.statement_timeout {
+        let (version,): (String,) = sqlx::query_as("select version()")
+            .fetch_one(&mut *connection)
+            .await?;
+        let statement = if version.contains("MariaDB") {
+            format!("set session max_statement_time = {seconds}")
+        } else {
+            format!("set session max_execution_time = {}", seconds * 1000)
+        };
+        connection.execute(statement.as_str()).await?;
+    }
+    Ok(())
+}
+
+fn prefix_tables<'a>(sql: &'a str, prefix: &str) -> Cow<'a, str> {
+    if prefix.is_empty() {
+        return Cow::Borrowed(sql);
+    }
+    // Constraint names are unique per database, like table names
+    let mut sql = sql.replace("CONSTRAINT `", &format!("CONSTRAINT `{prefix}"));
+    for table in REGISTRY_TABLES {
+        for keyword in TABLE_KEYWORDS {
+            sql = sql.replace(
+                &format!("{keyword} `{table}`"),
+                &format!("{keyword} `{prefix}{table}`"),
+            );
+        }
+    }
+    Cow::Owned(sql)
+}
+
+impl RegistryStore for MySql {
+    fn registry_version(&self) -> BoxFuture<'_, anyhow::Result<Option<f32>>> {
+        async move {
+            if !self.table_exists("releases").await? {
+                return Ok(None);
+            }
+            let version: Option<(f32,)> = sqlx::query_as(
+                &self.prefixed("select `version` from `releases` order by `version` desc limit 1"),
+            )
+            .fetch_optional(&self.pool)
+            .await?;
+            Ok(version.map(|(version,)| version))
+        }
+        .boxed()
+    }
+
+    fn insert_release<'a>(
+        &'a self,
+        version: f32,
+        installer: &'a Planner,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            sqlx::query(&self.prefixed(
+                "insert into `releases` (
+                    `version`, `installed_at`, `installer_name`, `installer_email`
+                ) values (?, ?, ?, ?)",
+            ))
+            .bind(version)
+            .bind(chrono::Utc::now())
+            .bind(&installer.name)
+            .bind(&installer.email)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn begin(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        async move {
+            self.pool.execute("start transaction").await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn commit(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        async move {
+            self.pool.execute("commit").await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn rollback(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        async move {
+            self.pool.execute("rollback").await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn is_transient(&self, error: &anyhow::Error) -> bool {
+        match error.downcast_ref::<sqlx::Error>() {
+            // The connection was lost, the pool opens a new one
+            Some(sqlx::Error::Io(_)) => true,
+            Some(sqlx::Error::Database(error)) => error
+                .try_downcast_ref::<MySqlDatabaseError>()
+                .is_some_and(|error| TRANSIENT_ERRORS.contains(&error.number())),
+            _ => false,
+        }
+    }
+
+    fn in_target_session(&self) -> bool {
+        false
+    }
+
+    fn fetch_projects(&self) -> BoxFuture<'_, anyhow::Result<Vec<ProjectRow>>> {
+        async move {
+            Ok(sqlx::query_as(&self.prefixed("select * from `projects`"))
+                .fetch_all(&self.pool)
+                .await?)
+        }
+        .boxed()
+    }
+
+    fn insert_project<'a>(
+        &'a self,
+        project: &'a str,
+        uri: Option<&'a str>,
+        committer: &'a Planner,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            sqlx::query(&self.prefixed(
+                "insert into `projects` (
+                    `project`, `uri`, `created_at`, `creator_name`, `creator_email`
+                ) values (?, ?, ?, ?, ?)",
+            ))
+            .bind(project)
+            .bind(uri)
+            .bind(chrono::Utc::now())
+            .bind(&committer.name)
+            .bind(&committer.email)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn fetch_changes(&self) -> BoxFuture<'_, anyhow::Result<Vec<ChangeRow>>> {
+        async move {
+            Ok(sqlx::query_as(&self.prefixed("select * from `changes`"))
+                .fetch_all(&self.pool)
+                .await?)
+        }
+        .boxed()
+    }
+
+    fn insert_change<'a>(
+        &'a self,
+        change: &'a FullChange,
+        script_hash: &'a str,
+        project: &'a str,
+        committer: &'a Planner,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            let sql = self.prefixed(
+                "insert into `changes` (
+                    `change_id`, `change`, `project`, `note`,
+                    `committed_at`, `committer_name`, `committer_email`,
+                    `planned_at`, `planner_name`, `planner_email`,
+                    `script_hash`
+                ) values (
+                    ?, ?, ?, ?,
+                    ?, ?, ?,
+                    ?, ?, ?,
+                    ?
+                )",
+            );
+            let query = sqlx::query(&sql);
+            bind_change!(query, change, project, committer)
+                .bind(script_hash)
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn fetch_tags(&self) -> BoxFuture<'_, anyhow::Result<Vec<TagRow>>> {
+        async move {
+            Ok(sqlx::query_as(&self.prefixed("select * from `tags`"))
+                .fetch_all(&self.pool)
+                .await?)
+        }
+        .boxed()
+    }
+
+    fn insert_tags<'a>(
+        &'a self,
+        change: &'a FullChange,
+        project: &'a str,
+        uri: Option<&'a str>,
+        committer: &'a Planner,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            for tag in &change.tags {
+                sqlx::query(&self.prefixed(
+                    "insert into `tags` (
+                        `tag_id`, `tag`, `project`, `change_id`, `note`,
+                        `committed_at`, `committer_name`, `committer_email`,
+                        `planned_at`, `planner_name`, `planner_email`
+                    ) values (
+                        ?, ?, ?, ?, ?,
+                        ?, ?, ?,
+                        ?, ?, ?
+                    )",
+                ))
+                .bind(tag.id(project, uri, &change.id))
+                .bind(format!("@{}", tag.name))
+                .bind(project)
+                .bind(&change.id)
+                .bind(&tag.note)
+                // Committer
+                .bind(chrono::Utc::now())
+                .bind(&committer.name)
+                .bind(&committer.email)
+                // Planner
+                .bind(tag.date)
+                .bind(&tag.planner.name)
+                .bind(&tag.planner.email)
+                .execute(&self.pool)
+                .await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn insert_dependencies<'a>(
+        &'a self,
+        change_id: &'a str,
+        dependencies: &'a [DependencyRow],
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            for dependency in dependencies {
+                sqlx::query(&self.prefixed(
+                    "insert into `dependencies` (
+                        `change_id`, `type`, `dependency`, `dependency_id`
+                    ) values (?, ?, ?, ?)",
+                ))
+                .bind(change_id)
+                .bind(dependency.kind)
+                .bind(&dependency.dependency)
+                .bind(&dependency.dependency_id)
+                .execute(&self.pool)
+                .await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn fetch_dependencies(&self) -> BoxFuture<'_, anyhow::Result<Vec<DeployedDependencyRow>>> {
+        async move {
+            Ok(
+                sqlx::query_as(&self.prefixed("select * from `dependencies`"))
+                    .fetch_all(&self.pool)
+                    .await?,
+            )
+        }
+        .boxed()
+    }
+
+    fn changes_requiring<'a>(
+        &'a self,
+        change_id: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<ChangeRow>>> {
+        async move {
+            Ok(sqlx::query_as(&self.prefixed(
+                "select c.* from `changes` c
+                join `dependencies` d on d.`change_id` = c.`change_id`
+                where d.`type` = 'require' and d.`dependency_id` = ?",
+            ))
+            .bind(change_id)
+            .fetch_all(&self.pool)
+            .await?)
+        }
+        .boxed()
+    }
+
+    fn delete_change<'a>(&'a self, change_id: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            for table in ["dependencies", "tags", "changes"] {
+                sqlx::query(&self.prefixed(&format!("delete from `{table}` where change_id = ?")))
+                    .bind(change_id)
+                    .execute(&self.pool)
+                    .await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn delete_project<'a>(&'a self, project: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            for statement in [
+                "delete from `dependencies` where `change_id` in (
+                    select `change_id` from `changes` where `project` = ?
+                )",
+                "delete from `tags` where `project` = ?",
+                "delete from `changes` where `project` = ?",
+                "delete from `events` where `project` = ?",
+                "delete from `projects` where `project` = ?",
+            ] {
+                sqlx::query(&self.prefixed(statement))
+                    .bind(project)
+                    .execute(&self.pool)
+                    .await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn add_event<'a>(
+        &'a self,
+        event_type: Event,
+        change: &'a FullChange,
+        note: Option<&'a str>,
+        project: &'a str,
+        committer: &'a Planner,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            let sql = self.prefixed(
+                "insert into `events` (
+                    `event`, `change_id`, `change`, `project`, `note`,
+                    `committed_at`, `committer_name`, `committer_email`,
+                    `planned_at`, `planner_name`, `planner_email`,
+                    `requires`, `conflicts`, `tags`
+                ) values (
+                    ?, ?, ?, ?, ?,
+                    ?, ?, ?,
+                    ?, ?, ?,
+                    ?, ?, ?
+                )",
+            );
+            let query = sqlx::query(&sql).bind(event_type);
+            let note = note.unwrap_or(&change.change.note);
+            let query = bind_change!(query, change, project, committer, note);
+            bind_event_lists!(query, change).execute(&self.pool).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn fetch_events<'a>(
+        &'a self,
+        query: &'a EventQuery,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<EventRow>>> {
+        async move {
+            let mut builder =
+                sqlx::QueryBuilder::new(self.prefixed("select * from `events` where true"));
+            if let Some(project) = &query.project {
+                builder.push(" and `project` = ").push_bind(project);
+            }
+            for (column, pattern) in [
+                ("project", &query.project_pattern),
+                ("change", &query.change_pattern),
+                ("committer_name", &query.committer_pattern),
+            ] {
+                if let Some(pattern) = pattern {
+                    builder
+                        .push(format_args!(" and `{column}` regexp "))
+                        .push_bind(pattern);
+                }
+            }
+            if !query.event_types.is_empty() {
+                builder.push(" and `event` in (");
+                let mut separated = builder.separated(", ");
+                for event_type in &query.event_types {
+                    separated.push_bind(*event_type);
+                }
+                builder.push(")");
+            }
+            if let Some(since) = query.since {
+                builder.push(" and `committed_at` >= ").push_bind(since);
+            }
+            if let Some(until) = query.until {
+                builder.push(" and `committed_at` < ").push_bind(until);
+            }
+            if let Some(after) = &query.after {
+                builder
+                    .push(if query.reverse {
+                        " and (`committed_at`, `change_id`) > ("
+                    } else {
+                        " and (`committed_at`, `change_id`) < ("
+                    })
+                    .push_bind(after.committed_at)
+                    .push(", ")
+                    .push_bind(&after.change_id)
+                    .push(")");
+            }
+            builder.push(if query.reverse {
+                " order by `committed_at` asc, `change_id` asc"
+            } else {
+                " order by `committed_at` desc, `change_id` desc"
+            });
+            // MySQL has no offset without a limit
+            if query.limit.is_some() || query.skip > 0 {
+                builder
+                    .push(" limit ")
+                    .push_bind(query.limit.unwrap_or(u64::MAX));
+            }
+            if query.skip > 0 {
+                builder.push(" offset ").push_bind(query.skip);
+            }
+            Ok(builder.build_query_as().fetch_all(&self.pool).await?)
+        }
+        .boxed()
+    }
+
+    fn deployed_change_tags<'a>(
+        &'a self,
+        change_id: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<String>> {
+        async move {
+            let tags: Option<(String,)> = sqlx::query_as(&self.prefixed(
+                "select `tags` from `events`
+                where `change_id` = ? and `event` = 'deploy'
+                order by `committed_at` desc
+                limit 1",
+            ))
+            .bind(change_id)
+            .fetch_optional(&self.pool)
+            .await?;
+            Ok(tags.map(|(tags,)| tags).unwrap_or_default())
+        }
+        .boxed()
+    }
+
+    fn import_rows<'a>(&'a self, dump: &'a RegistryDump) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            for row in &dump.projects {
+                sqlx::query(
+                    &self.prefixed("insert into `projects` (
+                        `project`, `uri`, `created_at`, `creator_name`, `creator_email`
+                    ) values (?, ?, ?, ?, ?)"),
+                )
+                .bind(&row.project)
+                .bind(&row.uri)
+                .bind(row.created_at)
+                .bind(&row.creator_name)
+                .bind(&row.creator_email)
+                .execute(&self.pool)
+                .await?;
+            }
+            for row in &dump.changes {
+                sqlx::query(
+                    &self.prefixed("insert into `changes` (
+                        `change_id`, `script_hash`, `change`, `project`, `note`,
+                        `committed_at`, `committer_name`, `committer_email`,
+                        `planned_at`, `planner_name`, `planner_email`
+                    ) values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"),
+                )
+                .bind(&row.change_id)
+                .bind(&row.script_hash)
+                .bind(&row.change)
+                .bind(&row.project)
+                .bind(&row.note)
+                .bind(row.committed_at)
+                .bind(&row.committer_name)
+                .bind(&row.committer_email)
+                .bind(row.planned_at)
+                .bind(&row.planner_name)
+                .bind(&row.planner_email)
+                .execute(&self.pool)
+                .await?;
+            }
+            for row in &dump.tags {
+                sqlx::query(
+                    &self.prefixed("insert into `tags` (
+                        `tag_id`, `tag`, `project`, `change_id`, `note`,
+                        `committed_at`, `committer_name`, `committer_email`,
+                        `planned_at`, `planner_name`, `planner_email`
+                    ) values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"),
+                )
+                .bind(&row.tag_id)
+                .bind(&row.tag)
+                .bind(&row.project)
+                .bind(&row.change_id)
+                .bind(&row.note)
+                .bind(row.committed_at)
+                .bind(&row.committer_name)
+                .bind(&row.committer_email)
+                .bind(row.planned_at)
+                .bind(&row.planner_name)
+                .bind(&row.planner_email)
+                .execute(&self.pool)
+                .await?;
+            }
+            for row in &dump.dependencies {
+                sqlx::query(
+                    &self.prefixed("insert into `dependencies` (
+                        `change_id`, `type`, `dependency`, `dependency_id`
+                    ) values (?, ?, ?, ?)"),
+                )
+                .bind(&row.change_id)
+                .bind(&row.kind)
+                .bind(&row.dependency)
+                .bind(&row.dependency_id)
+                .execute(&self.pool)
+                .await?;
+            }
+            for row in &dump.events {
+                sqlx::query(
+                    &self.prefixed("insert into `events` (
+                        `event`, `change_id`, `change`, `project`, `note`, `requires`, `conflicts`, `tags`,
+                        `committed_at`, `committer_name`, `committer_email`,
+                        `planned_at`, `planner_name`, `planner_email`
+                    ) values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"),
+                )
+                .bind(row.event)
+                .bind(&row.change_id)
+                .bind(&row.change)
+                .bind(&row.project)
+                .bind(&row.note)
+                .bind(&row.requires)
+                .bind(&row.conflicts)
+                .bind(&row.tags)
+                .bind(row.committed_at)
+                .bind(&row.committer_name)
+                .bind(&row.committer_email)
+                .bind(row.planned_at)
+                .bind(&row.planner_name)
+                .bind(&row.planner_email)
+                .execute(&self.pool)
+                .await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+impl Engine for MySql {
+    fn identifier_quote(&self) -> char {
+        '`'
+    }
+
+    fn registry_schema(&self) -> Cow<'static, str> {
+        self.prefixed(include_str!("../registry_schema.sql"))
+    }
+
+    fn releases_schema(&self) -> Cow<'static, str> {
+        self.prefixed(include_str!("../registry_releases.sql"))
+    }
+
+    fn registry_upgrades(&self) -> Vec<(f32, Cow<'static, str>)> {
+        vec![(
+            1.1,
+            // Keep fractional seconds of commit times
+            self.prefixed(
+                "ALTER TABLE `releases` MODIFY `installed_at` datetime(6) NOT NULL;
+                ALTER TABLE `projects` MODIFY `created_at` datetime(6) NOT NULL;
+                ALTER TABLE `changes` MODIFY `committed_at` datetime(6) NOT NULL;
+                ALTER TABLE `events` MODIFY `committed_at` datetime(6) NOT NULL;
+                ALTER TABLE `tags` MODIFY `committed_at` datetime(6) NOT NULL;",
+            ),
+        )]
+    }
+
+    fn registry_teardown(&self) -> Cow<'static, str> {
+        // Referencing tables first
+        self.prefixed(
+            "DROP TABLE `dependencies`;
+            DROP TABLE `tags`;
+            DROP TABLE `events`;
+            DROP TABLE `changes`;
+            DROP TABLE `projects`;
+            DROP TABLE `releases`;",
+        )
+    }
+
+    fn lock(&self, timeout: Duration) -> BoxFuture<'_, anyhow::Result<bool>> {
+        async move {
+            let (locked,): (i64,) = sqlx::query_as(&format!(
+                "select cast(coalesce(get_lock({LOCK_NAME}, ?), 0) as signed)"
+            ))
+            .bind(timeout.as_secs())
+            .fetch_one(&self.pool)
+            .await?;
+            Ok(locked == 1)
+        }
+        .boxed()
+    }
+
+    fn unlock(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        async move {
+            sqlx::query(&format!("select release_lock({LOCK_NAME})"))
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn registry_in_session(&self, _schema: &str) -> Option<Box<dyn Engine>> {
+        None
+    }
+
+    fn transactional_ddl(&self) -> bool {
+        // DDL statements commit the current transaction implicitly
+        false
+    }
+
+    fn run_script<'a>(&'a self, sql: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move { execute_script_in_transactions(&self.pool, sql).await }.boxed()
+    }
+
+    fn registry_exists(&self) -> BoxFuture<'_, anyhow::Result<bool>> {
+        self.table_exists("changes").boxed()
+    }
+
+    fn schema_exists<'a>(&'a self, schema_name: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        async move {
+            let rows = sqlx::query(
+                "
+                select schema_name
+                from information_schema.schemata
+                where schema_name = ?",
+            )
+            .bind(schema_name)
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(!rows.is_empty())
+        }
+        .boxed()
+    }
+
+    fn create_schema<'a>(&'a self, schema_name: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            let schema = quote_ident(schema_name, '`');
+            self.pool
+                .execute(format!("create schema if not exists {schema}").as_str())
+                .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn drop_schema<'a>(&'a self, schema_name: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            let schema = quote_ident(schema_name, '`');
+            self.pool
+                .execute(format!("drop schema {schema}").as_str())
+                .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn fetch_strings<'a>(&'a self, sql: &'a str) -> BoxFuture<'a, anyhow::Result<Vec<String>>> {
+        async move {
+            let rows: Vec<(String,)> = sqlx::query_as(sql).fetch_all(&self.pool).await?;
+            Ok(rows.into_iter().map(|(value,)| value).collect())
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_tables() {
+        let schema = prefix_tables(include_str!("../registry_schema.sql"), "quitch_");
+        assert!(schema.contains("CREATE TABLE IF NOT EXISTS `quitch_changes`"));
+        assert!(schema.contains("CONSTRAINT `quitch_tags_ibfk_1`"));
+        assert!(schema.contains("REFERENCES `quitch_changes` (`change_id`)"));
+        for table in REGISTRY_TABLES {
+            for keyword in TABLE_KEYWORDS {
+                assert!(!schema.contains(&format!("{keyword} `{table}`")));
+            }
+        }
+
+        // Columns named like tables are left alone
+        assert_eq!(
+            prefix_tables("select `tags` from `events` e join `tags` t", "quitch_"),
+            "select `tags` from `quitch_events` e join `quitch_tags` t"
+        );
+        assert!(matches!(
+            prefix_tables("select * from `changes`", ""),
+            Cow::Borrowed(_)
+        ));
+    }
+}
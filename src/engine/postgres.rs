@@ -0,0 +1,924 @@
+use std::{
+    borrow::Cow,
+    future::Future,
+    sync::{Arc, LazyLock},
+    time::{Duration, Instant},
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use regex::Regex;
+use sqlx::{
+    postgres::{PgConnectOptions, PgPool, PgPoolOptions, PgSslMode},
+    Executor,
+};
+use tracing::warn;
+
+use super::{execute_script, ClientConfig, Engine, SslMode};
+use crate::{
+    change::Planner,
+    error::Error,
+    plan::FullChange,
+    registry::{
+        ChangeRow, DependencyRow, DeployedDependencyRow, Event, EventQuery, EventRow, ProjectRow,
+        RegistryDump, RegistryStore, TagRow,
+    },
+    script::quote_ident,
+    tunnel::Tunnel,
+};
+
+/// How many times a script is run before a serialization failure is reported
+const MAX_ATTEMPTS: u32 = 5;
+
+/// SQLSTATE of a transaction aborted because of concurrent transactions
+const SERIALIZATION_FAILURE: &str = "40001";
+
+/// SQLSTATE of a transaction aborted to break a deadlock
+const DEADLOCK_DETECTED: &str = "40P01";
+
+/// Advisory lock key used by sqitch
+const LOCK_KEY: i64 = 75474063;
+
+/// How often to try taking a lock held by someone else
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// PostgreSQL keeps the registry in a schema of the target database.
+///
+/// Also used for CockroachDB, which speaks the same protocol.
+pub struct Postgres {
+    /// Holds a single connection, so that locks and transactions of the
+    /// session apply to every query
+    pool: PgPool,
+    /// CockroachDB aborts contended transactions far more often than other
+    /// databases and expects clients to retry them
+    retry_serialization_failures: bool,
+    /// Schema of a registry used from the target's session, whose search path
+    /// is the target's, so its tables are qualified with the schema
+    registry_schema: Option<String>,
+    /// SSH tunnel the connection goes through, open as long as this is
+    _tunnel: Option<Arc<Tunnel>>,
+}
+
+impl Postgres {
+    pub async fn connect(
+        config: &ClientConfig,
+        schema: Option<&str>,
+        retry_serialization_failures: bool,
+    ) -> anyhow::Result<Self> {
+        let mut options = PgConnectOptions::new()
+            .host(&config.hostname)
+            .port(config.port);
+        if !config.db.is_empty() {
+            options = options.database(&config.db);
+        }
+        if let Some(password) = &config.password {
+            options = options.password(password);
+        }
+        if !config.username.is_empty() {
+            options = options.username(&config.username);
+        }
+        if let Some(schema) = schema {
+            // Quoted like the schema was when it was created, so that
+            // uppercase letters and commas in the name are kept
+            options = options.options([("search_path", quote_ident(schema, '"'))]);
+        }
+        if let Some(charset) = &config.charset {
+            options = options.options([("client_encoding", charset)]);
+        }
+        let timeouts = &config.timeouts;
+        for (setting, seconds) in [
+            ("statement_timeout", timeouts.statement_timeout),
+            ("lock_timeout", timeouts.lock_wait_timeout),
+        ] {
+            if let Some(seconds) = seconds {
+                options = options.options([(setting, format!("{seconds}s"))]);
+            }
+        }
+        if let Some(socket) = &config.socket {
+            options = options.socket(socket);
+        }
+        let tls = &config.tls;
+        if let Some(ssl_mode) = tls.ssl_mode {
+            options = options.ssl_mode(match ssl_mode {
+                SslMode::Disabled => PgSslMode::Disable,
+                SslMode::Preferred => PgSslMode::Prefer,
+                SslMode::Required => PgSslMode::Require,
+                SslMode::VerifyCa => PgSslMode::VerifyCa,
+                SslMode::VerifyIdentity => PgSslMode::VerifyFull,
+            });
+        }
+        if let Some(ssl_ca) = &tls.ssl_ca {
+            options = options.ssl_root_cert(ssl_ca);
+        }
+        if let Some(ssl_cert) = &tls.ssl_cert {
+            options = options.ssl_client_cert(ssl_cert);
+        }
+        if let Some(ssl_key) = &tls.ssl_key {
+            options = options.ssl_client_key(ssl_key);
+        }
+        let mut pool_options = PgPoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None);
+        if let Some(connect_timeout) = config.timeouts.connect_timeout {
+            pool_options = pool_options.acquire_timeout(Duration::from_secs(connect_timeout));
+        }
+        let pool = pool_options.connect_with(options).await?;
+        pool.execute("select 1").await?;
+        Ok(Self {
+            pool,
+            retry_serialization_failures,
+            registry_schema: None,
+            _tunnel: None,
+        })
+    }
+
+    /// Keep `tunnel` open while connected through it
+    pub fn with_tunnel(self, tunnel: Option<Arc<Tunnel>>) -> Self {
+        Self {
+            _tunnel: tunnel,
+            ..self
+        }
+    }
+
+    /// Whether the registry table `name` exists in the registry schema
+    async fn table_exists(&self, name: &str) -> anyhow::Result<bool> {
+        let tables = sqlx::query(
+            "
+            select table_name
+            from information_schema.tables
+            where table_schema = coalesce($1, current_schema()) and table_name = $2",
+        )
+        .bind(&self.registry_schema)
+        .bind(name)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(!tables.is_empty())
+    }
+
+    /// Registry SQL with the table names qualified, when used from the
+    /// target's session
+    fn qualified<'a>(&self, sql: &'a str) -> Cow<'a, str> {
+        match &self.registry_schema {
+            Some(schema) => qualify_tables(sql, schema),
+            None => Cow::Borrowed(sql),
+        }
+    }
+
+    /// Run a script, stopping at the first failed statement
+    async fn try_run_script(&self, sql: &str) -> anyhow::Result<()> {
+        let result = execute_script(&self.pool, sql).await;
+        if result.is_err() {
+            // Leave the connection usable if the script opened a transaction
+            self.pool.execute("rollback").await?;
+        }
+        result
+    }
+}
+
+/// Registry SQL with the registry tables it reads or writes qualified with
+/// `schema`
+pub(super) fn qualify_tables<'a>(sql: &'a str, schema: &str) -> Cow<'a, str> {
+    static TABLES: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"\b(from|into|join) (releases|projects|changes|events|tags|dependencies)\b")
+            .expect("valid regex")
+    });
+    let schema = quote_ident(schema, '"').replace('$', "$$");
+    TABLES.replace_all(sql, format!("$1 {schema}.$2"))
+}
+
+fn is_serialization_failure(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<Error>(),
+        Some(Error::Statement { sqlstate: Some(code), .. }) if code == SERIALIZATION_FAILURE
+    )
+}
+
+/// Run `operation` until it succeeds, fails with an error that is not
+/// retryable or fails `max_attempts` times, waiting longer after every attempt.
+async fn with_retries<T, E, F, Fut>(
+    max_attempts: u32,
+    is_retryable: impl Fn(&E) -> bool,
+    mut operation: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 1;
+    loop {
+        match operation().await {
+            Err(error) if attempt < max_attempts && is_retryable(&error) => {
+                warn!("attempt {attempt} of {max_attempts} failed, retrying");
+                tokio::time::sleep(Duration::from_millis(100) * 2u32.pow(attempt - 1)).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+impl RegistryStore for Postgres {
+    fn registry_version(&self) -> BoxFuture<'_, anyhow::Result<Option<f32>>> {
+        async move {
+            if !self.table_exists("releases").await? {
+                return Ok(None);
+            }
+            let version: Option<(f32,)> = sqlx::query_as(
+                &self.qualified("select version from releases order by version desc limit 1"),
+            )
+            .fetch_optional(&self.pool)
+            .await?;
+            Ok(version.map(|(version,)| version))
+        }
+        .boxed()
+    }
+
+    fn insert_release<'a>(
+        &'a self,
+        version: f32,
+        installer: &'a Planner,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            sqlx::query(&self.qualified(
+                "insert into releases (
+                    version, installed_at, installer_name, installer_email
+                ) values ($1, $2, $3, $4)",
+            ))
+            .bind(version)
+            .bind(chrono::Utc::now())
+            .bind(&installer.name)
+            .bind(&installer.email)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn begin(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        async move {
+            self.pool.execute("begin").await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn commit(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        async move {
+            self.pool.execute("commit").await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn rollback(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        async move {
+            self.pool.execute("rollback").await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn is_transient(&self, error: &anyhow::Error) -> bool {
+        match error.downcast_ref::<sqlx::Error>() {
+            // The connection was lost, the pool opens a new one
+            Some(sqlx::Error::Io(_)) => true,
+            Some(sqlx::Error::Database(error)) => matches!(
+                error.code().as_deref(),
+                Some(SERIALIZATION_FAILURE | DEADLOCK_DETECTED)
+            ),
+            _ => false,
+        }
+    }
+
+    fn in_target_session(&self) -> bool {
+        self.registry_schema.is_some()
+    }
+
+    fn fetch_projects(&self) -> BoxFuture<'_, anyhow::Result<Vec<ProjectRow>>> {
+        async move {
+            Ok(sqlx::query_as(&self.qualified("select * from projects"))
+                .fetch_all(&self.pool)
+                .await?)
+        }
+        .boxed()
+    }
+
+    fn insert_project<'a>(
+        &'a self,
+        project: &'a str,
+        uri: Option<&'a str>,
+        committer: &'a Planner,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            sqlx::query(&self.qualified(
+                "insert into projects (
+                    project, uri, created_at, creator_name, creator_email
+                ) values ($1, $2, $3, $4, $5)",
+            ))
+            .bind(project)
+            .bind(uri)
+            .bind(chrono::Utc::now())
+            .bind(&committer.name)
+            .bind(&committer.email)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn fetch_changes(&self) -> BoxFuture<'_, anyhow::Result<Vec<ChangeRow>>> {
+        async move {
+            Ok(sqlx::query_as(&self.qualified("select * from changes"))
+                .fetch_all(&self.pool)
+                .await?)
+        }
+        .boxed()
+    }
+
+    fn insert_change<'a>(
+        &'a self,
+        change: &'a FullChange,
+        script_hash: &'a str,
+        project: &'a str,
+        committer: &'a Planner,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            let sql = self.qualified(
+                "insert into changes (
+                    change_id, change, project, note,
+                    committed_at, committer_name, committer_email,
+                    planned_at, planner_name, planner_email,
+                    script_hash
+                ) values (
+                    $1, $2, $3, $4,
+                    $5, $6, $7,
+                    $8, $9, $10,
+                    $11
+                )",
+            );
+            let query = sqlx::query(&sql);
+            bind_change!(query, change, project, committer)
+                .bind(script_hash)
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn fetch_tags(&self) -> BoxFuture<'_, anyhow::Result<Vec<TagRow>>> {
+        async move {
+            Ok(sqlx::query_as(&self.qualified("select * from tags"))
+                .fetch_all(&self.pool)
+                .await?)
+        }
+        .boxed()
+    }
+
+    fn insert_tags<'a>(
+        &'a self,
+        change: &'a FullChange,
+        project: &'a str,
+        uri: Option<&'a str>,
+        committer: &'a Planner,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            for tag in &change.tags {
+                sqlx::query(&self.qualified(
+                    "insert into tags (
+                        tag_id, tag, project, change_id, note,
+                        committed_at, committer_name, committer_email,
+                        planned_at, planner_name, planner_email
+                    ) values (
+                        $1, $2, $3, $4, $5,
+                        $6, $7, $8,
+                        $9, $10, $11
+                    )",
+                ))
+                .bind(tag.id(project, uri, &change.id))
+                .bind(format!("@{}", tag.name))
+                .bind(project)
+                .bind(&change.id)
+                .bind(&tag.note)
+                // Committer
+                .bind(chrono::Utc::now())
+                .bind(&committer.name)
+                .bind(&committer.email)
+                // Planner
+                .bind(tag.date)
+                .bind(&tag.planner.name)
+                .bind(&tag.planner.email)
+                .execute(&self.pool)
+                .await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn insert_dependencies<'a>(
+        &'a self,
+        change_id: &'a str,
+        dependencies: &'a [DependencyRow],
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            for dependency in dependencies {
+                sqlx::query(&self.qualified(
+                    "insert into dependencies (
+                        change_id, type, dependency, dependency_id
+                    ) values ($1, $2, $3, $4)",
+                ))
+                .bind(change_id)
+                .bind(dependency.kind)
+                .bind(&dependency.dependency)
+                .bind(&dependency.dependency_id)
+                .execute(&self.pool)
+                .await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn fetch_dependencies(&self) -> BoxFuture<'_, anyhow::Result<Vec<DeployedDependencyRow>>> {
+        async move {
+            Ok(
+                sqlx::query_as(&self.qualified("select * from dependencies"))
+                    .fetch_all(&self.pool)
+                    .await?,
+            )
+        }
+        .boxed()
+    }
+
+    fn changes_requiring<'a>(
+        &'a self,
+        change_id: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<ChangeRow>>> {
+        async move {
+            Ok(sqlx::query_as(&self.qualified(
+                "select c.* from changes c
+                join dependencies d on d.change_id = c.change_id
+                where d.type = 'require' and d.dependency_id = $1",
+            ))
+            .bind(change_id)
+            .fetch_all(&self.pool)
+            .await?)
+        }
+        .boxed()
+    }
+
+    fn delete_change<'a>(&'a self, change_id: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            for table in ["dependencies", "tags", "changes"] {
+                sqlx::query(&self.qualified(&format!("delete from {table} where change_id = $1")))
+                    .bind(change_id)
+                    .execute(&self.pool)
+                    .await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn delete_project<'a>(&'a self, project: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            for statement in [
+                "delete from dependencies where change_id in (
+                    select change_id from changes where project = $1
+                )",
+                "delete from tags where project = $1",
+                "delete from changes where project = $1",
+                "delete from events where project = $1",
+                "delete from projects where project = $1",
+            ] {
+                sqlx::query(&self.qualified(statement))
+                    .bind(project)
+                    .execute(&self.pool)
+                    .await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn add_event<'a>(
+        &'a self,
+        event_type: Event,
+        change: &'a FullChange,
+        note: Option<&'a str>,
+        project: &'a str,
+        committer: &'a Planner,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            let sql = self.qualified(
+                "insert into events (
+                    event, change_id, change, project, note,
+                    committed_at, committer_name, committer_email,
+                    planned_at, planner_name, planner_email,
+                    requires, conflicts, tags
+                ) values (
+                    $1, $2, $3, $4, $5,
+                    $6, $7, $8,
+                    $9, $10, $11,
+                    $12, $13, $14
+                )",
+            );
+            let query = sqlx::query(&sql).bind(event_type);
+            let note = note.unwrap_or(&change.change.note);
+            let query = bind_change!(query, change, project, committer, note);
+            bind_event_lists!(query, change).execute(&self.pool).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn fetch_events<'a>(
+        &'a self,
+        query: &'a EventQuery,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<EventRow>>> {
+        async move {
+            let mut builder =
+                sqlx::QueryBuilder::new(self.qualified("select * from events where true"));
+            if let Some(project) = &query.project {
+                builder.push(" and project = ").push_bind(project);
+            }
+            for (column, pattern) in [
+                ("project", &query.project_pattern),
+                ("change", &query.change_pattern),
+                ("committer_name", &query.committer_pattern),
+            ] {
+                if let Some(pattern) = pattern {
+                    builder
+                        .push(format_args!(" and {column} ~ "))
+                        .push_bind(pattern);
+                }
+            }
+            if !query.event_types.is_empty() {
+                builder.push(" and event in (");
+                let mut separated = builder.separated(", ");
+                for event_type in &query.event_types {
+                    separated.push_bind(*event_type);
+                }
+                builder.push(")");
+            }
+            if let Some(since) = query.since {
+                builder.push(" and committed_at >= ").push_bind(since);
+            }
+            if let Some(until) = query.until {
+                builder.push(" and committed_at < ").push_bind(until);
+            }
+            if let Some(after) = &query.after {
+                builder
+                    .push(if query.reverse {
+                        " and (committed_at, change_id) > ("
+                    } else {
+                        " and (committed_at, change_id) < ("
+                    })
+                    .push_bind(after.committed_at)
+                    .push(", ")
+                    .push_bind(&after.change_id)
+                    .push(")");
+            }
+            builder.push(if query.reverse {
+                " order by committed_at asc, change_id asc"
+            } else {
+                " order by committed_at desc, change_id desc"
+            });
+            if let Some(limit) = query.limit {
+                builder.push(" limit ").push_bind(limit as i64);
+            }
+            if query.skip > 0 {
+                builder.push(" offset ").push_bind(query.skip as i64);
+            }
+            Ok(builder.build_query_as().fetch_all(&self.pool).await?)
+        }
+        .boxed()
+    }
+
+    fn deployed_change_tags<'a>(
+        &'a self,
+        change_id: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<String>> {
+        async move {
+            let tags: Option<(String,)> = sqlx::query_as(&self.qualified(
+                "select tags from events
+                where change_id = $1 and event = 'deploy'
+                order by committed_at desc
+                limit 1",
+            ))
+            .bind(change_id)
+            .fetch_optional(&self.pool)
+            .await?;
+            Ok(tags.map(|(tags,)| tags).unwrap_or_default())
+        }
+        .boxed()
+    }
+
+    fn import_rows<'a>(&'a self, dump: &'a RegistryDump) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            for row in &dump.projects {
+                sqlx::query(&self.qualified(
+                    "insert into projects (
+                        project, uri, created_at, creator_name, creator_email
+                    ) values ($1, $2, $3, $4, $5)",
+                ))
+                .bind(&row.project)
+                .bind(&row.uri)
+                .bind(row.created_at)
+                .bind(&row.creator_name)
+                .bind(&row.creator_email)
+                .execute(&self.pool)
+                .await?;
+            }
+            for row in &dump.changes {
+                sqlx::query(&self.qualified(
+                    "insert into changes (
+                        change_id, script_hash, change, project, note,
+                        committed_at, committer_name, committer_email,
+                        planned_at, planner_name, planner_email
+                    ) values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+                ))
+                .bind(&row.change_id)
+                .bind(&row.script_hash)
+                .bind(&row.change)
+                .bind(&row.project)
+                .bind(&row.note)
+                .bind(row.committed_at)
+                .bind(&row.committer_name)
+                .bind(&row.committer_email)
+                .bind(row.planned_at)
+                .bind(&row.planner_name)
+                .bind(&row.planner_email)
+                .execute(&self.pool)
+                .await?;
+            }
+            for row in &dump.tags {
+                sqlx::query(&self.qualified(
+                    "insert into tags (
+                        tag_id, tag, project, change_id, note,
+                        committed_at, committer_name, committer_email,
+                        planned_at, planner_name, planner_email
+                    ) values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+                ))
+                .bind(&row.tag_id)
+                .bind(&row.tag)
+                .bind(&row.project)
+                .bind(&row.change_id)
+                .bind(&row.note)
+                .bind(row.committed_at)
+                .bind(&row.committer_name)
+                .bind(&row.committer_email)
+                .bind(row.planned_at)
+                .bind(&row.planner_name)
+                .bind(&row.planner_email)
+                .execute(&self.pool)
+                .await?;
+            }
+            for row in &dump.dependencies {
+                sqlx::query(&self.qualified(
+                    "insert into dependencies (
+                        change_id, type, dependency, dependency_id
+                    ) values ($1, $2, $3, $4)",
+                ))
+                .bind(&row.change_id)
+                .bind(&row.kind)
+                .bind(&row.dependency)
+                .bind(&row.dependency_id)
+                .execute(&self.pool)
+                .await?;
+            }
+            for row in &dump.events {
+                sqlx::query(&self.qualified(
+                    "insert into events (
+                        event, change_id, change, project, note, requires, conflicts, tags,
+                        committed_at, committer_name, committer_email,
+                        planned_at, planner_name, planner_email
+                    ) values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)",
+                ))
+                .bind(row.event)
+                .bind(&row.change_id)
+                .bind(&row.change)
+                .bind(&row.project)
+                .bind(&row.note)
+                .bind(&row.requires)
+                .bind(&row.conflicts)
+                .bind(&row.tags)
+                .bind(row.committed_at)
+                .bind(&row.committer_name)
+                .bind(&row.committer_email)
+                .bind(row.planned_at)
+                .bind(&row.planner_name)
+                .bind(&row.planner_email)
+                .execute(&self.pool)
+                .await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+impl Engine for Postgres {
+    fn identifier_quote(&self) -> char {
+        '"'
+    }
+
+    fn registry_schema(&self) -> Cow<'static, str> {
+        include_str!("../registry_schema_pg.sql").into()
+    }
+
+    fn releases_schema(&self) -> Cow<'static, str> {
+        include_str!("../registry_releases_pg.sql").into()
+    }
+
+    fn registry_upgrades(&self) -> Vec<(f32, Cow<'static, str>)> {
+        // Timestamps always had full precision here, so 1.1 only records the release
+        vec![(1.1, "".into())]
+    }
+
+    fn registry_teardown(&self) -> Cow<'static, str> {
+        "drop table dependencies, tags, events, changes, projects, releases;".into()
+    }
+
+    fn lock(&self, timeout: Duration) -> BoxFuture<'_, anyhow::Result<bool>> {
+        async move {
+            let deadline = Instant::now() + timeout;
+            loop {
+                let (locked,): (bool,) = sqlx::query_as("select pg_try_advisory_lock($1)")
+                    .bind(LOCK_KEY)
+                    .fetch_one(&self.pool)
+                    .await?;
+                if locked {
+                    return Ok(true);
+                }
+                if Instant::now() >= deadline {
+                    return Ok(false);
+                }
+                tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+            }
+        }
+        .boxed()
+    }
+
+    fn unlock(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        async move {
+            sqlx::query("select pg_advisory_unlock($1)")
+                .bind(LOCK_KEY)
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn transactional_ddl(&self) -> bool {
+        // Scripts are rolled back before retrying a serialization failure,
+        // which would also end an outer transaction
+        !self.retry_serialization_failures
+    }
+
+    fn registry_in_session(&self, schema: &str) -> Option<Box<dyn Engine>> {
+        Some(Box::new(Self {
+            pool: self.pool.clone(),
+            retry_serialization_failures: self.retry_serialization_failures,
+            registry_schema: Some(schema.to_string()),
+            _tunnel: self._tunnel.clone(),
+        }))
+    }
+
+    fn run_script<'a>(&'a self, sql: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            if self.retry_serialization_failures {
+                with_retries(MAX_ATTEMPTS, is_serialization_failure, || {
+                    self.try_run_script(sql)
+                })
+                .await?;
+                return Ok(());
+            }
+            execute_script(&self.pool, sql).await
+        }
+        .boxed()
+    }
+
+    fn registry_exists(&self) -> BoxFuture<'_, anyhow::Result<bool>> {
+        self.table_exists("changes").boxed()
+    }
+
+    fn schema_exists<'a>(&'a self, schema_name: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        async move {
+            let rows = sqlx::query(
+                "
+                select schema_name
+                from information_schema.schemata
+                where schema_name = $1",
+            )
+            .bind(schema_name)
+            .fetch_all(&self.pool)
+            .await?;
+            Ok(!rows.is_empty())
+        }
+        .boxed()
+    }
+
+    fn create_schema<'a>(&'a self, schema_name: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            let schema = quote_ident(schema_name, '"');
+            self.pool
+                .execute(format!("create schema if not exists {schema}").as_str())
+                .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn drop_schema<'a>(&'a self, schema_name: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            let schema = quote_ident(schema_name, '"');
+            self.pool
+                .execute(format!("drop schema {schema} cascade").as_str())
+                .await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn fetch_strings<'a>(&'a self, sql: &'a str) -> BoxFuture<'a, anyhow::Result<Vec<String>>> {
+        async move {
+            let rows: Vec<(String,)> = sqlx::query_as(sql).fetch_all(&self.pool).await?;
+            Ok(rows.into_iter().map(|(value,)| value).collect())
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    #[test]
+    fn test_qualify_tables() {
+        assert_eq!(
+            qualify_tables(
+                "select c.* from changes c join dependencies d on d.change_id = c.change_id",
+                "sqitch"
+            ),
+            "select c.* from \"sqitch\".changes c \
+            join \"sqitch\".dependencies d on d.change_id = c.change_id"
+        );
+        assert_eq!(
+            qualify_tables("select tags from events where project = $1", "My$1"),
+            "select tags from \"My$1\".events where project = $1"
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_until_success() {
+        let attempts = Cell::new(0);
+        let result = with_retries(
+            3,
+            |_: &&str| true,
+            || async {
+                attempts.set(attempts.get() + 1);
+                if attempts.get() < 2 {
+                    Err("aborted")
+                } else {
+                    Ok(attempts.get())
+                }
+            },
+        )
+        .await;
+        assert_eq!(result, Ok(2));
+    }
+
+    #[tokio::test]
+    async fn stops_on_other_errors_and_after_max_attempts() {
+        let attempts = Cell::new(0);
+        let result: Result<(), _> = with_retries(
+            3,
+            |e: &&str| *e == "aborted",
+            || async {
+                attempts.set(attempts.get() + 1);
+                Err("syntax error")
+            },
+        )
+        .await;
+        assert_eq!((result, attempts.get()), (Err("syntax error"), 1));
+
+        attempts.set(0);
+        let result: Result<(), _> = with_retries(
+            2,
+            |_: &&str| true,
+            || async {
+                attempts.set(attempts.get() + 1);
+                Err("aborted")
+            },
+        )
+        .await;
+        assert_eq!((result, attempts.get()), (Err("aborted"), 2));
+    }
+}
@@ -0,0 +1,142 @@
+//! Errors that programs using quitch can tell apart.
+//!
+//! Functions of the library return [`anyhow::Error`], so that errors keep the
+//! context of what quitch was doing. The errors worth telling apart are an
+//! [`Error`] somewhere in that chain, found with [`Error::of`].
+
+use crate::failure::Failure;
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// A line of the plan file that doesn't parse
+    #[error("line {line} of the plan")]
+    Plan {
+        /// Line of the plan file, starting from 1
+        line: usize,
+        #[source]
+        source: anyhow::Error,
+    },
+    /// Could not connect to the target or the registry
+    #[error(transparent)]
+    Connection(anyhow::Error),
+    /// The registry doesn't match the plan or this version of quitch
+    #[error(transparent)]
+    Mismatch(anyhow::Error),
+    /// A statement of a script failed
+    #[error("statement {index} at line {line} failed: {source}\n{statement}")]
+    Statement {
+        /// Position of the statement in the script, counting from 1
+        index: usize,
+        /// Line the statement starts on
+        line: usize,
+        statement: String,
+        /// Error code reported by the database, if any: the SQLSTATE, or the
+        /// error number on SQL Server
+        sqlstate: Option<String>,
+        /// Error of the driver
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// A script failed other than in one of its statements, e.g. because the
+    /// connection was lost
+    #[error(transparent)]
+    Script(anyhow::Error),
+    /// Not an error as such, but the command ended without doing anything
+    #[error("{0}")]
+    NothingToDo(String),
+    /// Timed out waiting for another instance working on the target
+    #[error(transparent)]
+    Locked(anyhow::Error),
+    /// Stopped by Ctrl-C
+    #[error("interrupted")]
+    Interrupted,
+}
+
+impl Error {
+    /// The first quitch error in the chain of an error
+    pub fn of(error: &anyhow::Error) -> Option<&Self> {
+        error.chain().find_map(|error| error.downcast_ref::<Self>())
+    }
+
+    /// Failure of a statement, with its SQLSTATE if the database reported one
+    pub fn statement(index: usize, line: usize, statement: &str, source: sqlx::Error) -> Self {
+        let sqlstate = source
+            .as_database_error()
+            .and_then(|error| error.code())
+            .map(|code| code.into_owned());
+        Self::Statement {
+            index,
+            line,
+            statement: statement.to_string(),
+            sqlstate,
+            source: Box::new(source),
+        }
+    }
+
+    /// Kind of failure, deciding the exit code; none for the generic ones
+    pub fn failure(&self) -> Option<Failure> {
+        match self {
+            Self::Plan { .. } => None,
+            Self::Connection(_) => Some(Failure::Connection),
+            Self::Mismatch(_) => Some(Failure::Mismatch),
+            Self::Statement { .. } | Self::Script(_) => Some(Failure::Script),
+            Self::NothingToDo(_) => Some(Failure::NothingToDo),
+            Self::Locked(_) => Some(Failure::Locked),
+            Self::Interrupted => Some(Failure::Interrupted),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn test_statement_error() {
+        let error = anyhow::Error::from(Error::statement(
+            2,
+            3,
+            "select oops",
+            sqlx::Error::RowNotFound,
+        ))
+        .context("deploying flips");
+        let Some(Error::Statement {
+            statement,
+            sqlstate,
+            ..
+        }) = Error::of(&error)
+        else {
+            panic!("not a statement error: {error:?}");
+        };
+        assert_eq!(statement, "select oops");
+        assert_eq!(*sqlstate, None);
+        assert_eq!(
+            format!("{error:#}"),
+            format!(
+                "deploying flips: statement 2 at line 3 failed: {0}\nselect oops: {0}",
+                sqlx::Error::RowNotFound
+            )
+        );
+        assert_eq!(Failure::of(&error), Some(Failure::Script));
+    }
+
+    #[test]
+    fn test_plan_error() {
+        let error = Err::<(), _>(anyhow::anyhow!("column 5: missing name"))
+            .map_err(|source| Error::Plan { line: 7, source })
+            .context("reading sqitch.plan")
+            .unwrap_err();
+        assert!(matches!(
+            Error::of(&error),
+            Some(Error::Plan { line: 7, .. })
+        ));
+        assert_eq!(Failure::of(&error), None);
+        assert_eq!(
+            format!("{error:#}"),
+            "reading sqitch.plan: line 7 of the plan: column 5: missing name"
+        );
+    }
+}
@@ -0,0 +1,105 @@
+//! Kinds of failures that automation tells apart by the exit code
+
+use std::fmt;
+
+use crate::error::Error;
+
+/// Exit codes of quitch, shown at the end of `--help`
+pub const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0  Success
+  1  Usage error, or any failure not listed below
+  2  Could not connect to the target or the registry
+  3  The registry doesn't match the plan or this version of quitch
+  4  A deploy or revert script failed
+  5  Nothing to do, e.g. no changes to deploy or revert
+  6  Timed out waiting for another instance working on the target
+130  Interrupted with Ctrl-C";
+
+/// What went wrong, for the failures that get their own exit code
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    Connection,
+    Mismatch,
+    Script,
+    /// Not an error as such, but scripts may want to know nothing happened
+    NothingToDo,
+    Locked,
+    /// Stopped by Ctrl-C, exiting with the code shells use for SIGINT
+    Interrupted,
+}
+
+impl Failure {
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Self::Connection => 2,
+            Self::Mismatch => 3,
+            Self::Script => 4,
+            Self::NothingToDo => 5,
+            Self::Locked => 6,
+            Self::Interrupted => 130,
+        }
+    }
+
+    /// Kind of failure of an error, none for the generic failures
+    pub fn of(error: &anyhow::Error) -> Option<Self> {
+        Error::of(error).and_then(Error::failure)
+    }
+
+    /// An error ending the command early without anything going wrong
+    pub fn nothing_to_do(message: impl fmt::Display) -> anyhow::Error {
+        Error::NothingToDo(message.to_string()).into()
+    }
+}
+
+/// Tag the error of a result with the kind of failure it is. Errors that are
+/// already a quitch [`Error`] keep their kind.
+pub trait Classify<T> {
+    fn classify(self, failure: Failure) -> anyhow::Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> Classify<T> for Result<T, E> {
+    fn classify(self, failure: Failure) -> anyhow::Result<T> {
+        self.map_err(|error| {
+            let error = error.into();
+            if error.is::<Error>() {
+                return error;
+            }
+            match failure {
+                Failure::Connection => Error::Connection(error),
+                Failure::Mismatch => Error::Mismatch(error),
+                Failure::Script => Error::Script(error),
+                Failure::NothingToDo => Error::NothingToDo(format!("{error:#}")),
+                Failure::Locked => Error::Locked(error),
+                Failure::Interrupted => Error::Interrupted,
+            }
+            .into()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, Context};
+
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let error = Err::<(), _>(anyhow!("refused").context("connecting"))
+            .classify(Failure::Connection)
+            .context("deploying")
+            .unwrap_err();
+        assert_eq!(Failure::of(&error), Some(Failure::Connection));
+        assert_eq!(format!("{error:#}"), "deploying: connecting: refused");
+
+        assert_eq!(Failure::of(&anyhow!("oops")), None);
+        let nothing = Failure::nothing_to_do("Nothing to deploy");
+        assert_eq!(Failure::of(&nothing), Some(Failure::NothingToDo));
+        assert_eq!(nothing.to_string(), "Nothing to deploy");
+
+        // The kind of an error that already has one is kept
+        let error = Err::<(), _>(nothing).classify(Failure::Script).unwrap_err();
+        assert_eq!(Failure::of(&error), Some(Failure::NothingToDo));
+    }
+}
@@ -0,0 +1,233 @@
+//! Git repositories holding plans.
+//!
+//! With `--record-git`, the commit a deploy or revert was made from goes at the
+//! end of the notes of events, as the registry has no place for it. It is
+//! written as `Git-Commit:` and `Git-Branch:` trailers like those of git commit
+//! messages, and `quitch log` shows them apart from the note.
+//!
+//! `quitch checkout` reads the plan of another branch before switching to it.
+
+use std::path::Path;
+
+use anyhow::{bail, Context};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GitCommit {
+    pub sha: String,
+    /// None when HEAD is detached, as in most CI checkouts
+    pub branch: Option<String>,
+}
+
+const COMMIT_TRAILER: &str = "Git-Commit: ";
+const BRANCH_TRAILER: &str = "Git-Branch: ";
+
+impl GitCommit {
+    /// The commit checked out in the repository containing `dir`
+    pub async fn current(dir: &Path) -> anyhow::Result<Self> {
+        let sha = git(dir, &["rev-parse", "HEAD"])
+            .await
+            .with_context(|| format!("no commit checked out in {}", dir.display()))?;
+        let branch = git(dir, &["symbolic-ref", "--quiet", "--short", "HEAD"])
+            .await
+            .ok();
+        Ok(Self {
+            sha: sha.trim().to_string(),
+            branch: branch.map(|branch| branch.trim().to_string()),
+        })
+    }
+
+    /// A note followed by the trailers of this commit
+    pub fn annotate(&self, note: &str) -> String {
+        let mut annotated = if note.is_empty() {
+            String::new()
+        } else {
+            format!("{note}\n\n")
+        };
+        annotated.push_str(COMMIT_TRAILER);
+        annotated.push_str(&self.sha);
+        if let Some(branch) = &self.branch {
+            annotated.push('\n');
+            annotated.push_str(BRANCH_TRAILER);
+            annotated.push_str(branch);
+        }
+        annotated
+    }
+
+    /// Split an event note into the note itself and the commit it was
+    /// annotated with, if any
+    pub fn split_note(note: &str) -> (&str, Option<Self>) {
+        let (rest, trailers) = match note.rfind(&format!("\n\n{COMMIT_TRAILER}")) {
+            Some(start) => (&note[..start], &note[start + 2..]),
+            None if note.starts_with(COMMIT_TRAILER) => ("", note),
+            None => return (note, None),
+        };
+        let mut lines = trailers.lines();
+        let sha = lines
+            .next()
+            .and_then(|line| line.strip_prefix(COMMIT_TRAILER));
+        let branch = lines.next().map(|line| line.strip_prefix(BRANCH_TRAILER));
+        match (sha, branch, lines.next()) {
+            (Some(sha), None, None) => (rest, Some(Self::new(sha, None))),
+            (Some(sha), Some(Some(branch)), None) => (rest, Some(Self::new(sha, Some(branch)))),
+            _ => (note, None),
+        }
+    }
+
+    fn new(sha: &str, branch: Option<&str>) -> Self {
+        Self {
+            sha: sha.to_string(),
+            branch: branch.map(String::from),
+        }
+    }
+}
+
+/// Contents of a file as of a branch or other revision
+pub async fn read_at(path: &Path, revision: &str) -> anyhow::Result<String> {
+    let (dir, name) = split_path(path)?;
+    git(dir, &["show", &format!("{revision}:./{name}")]).await
+}
+
+/// Check out a branch or other revision in the repository containing `dir`
+pub async fn checkout(dir: &Path, revision: &str) -> anyhow::Result<()> {
+    git(dir, &["checkout", "--quiet", revision]).await?;
+    Ok(())
+}
+
+/// Directory of a file, for `git -C`, and its name in there
+fn split_path(path: &Path) -> anyhow::Result<(&Path, String)> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("{} is not a file name git can read", path.display()))?;
+    Ok((dir, name.to_string()))
+}
+
+/// Run git in a directory, returning its output
+async fn git(dir: &Path, args: &[&str]) -> anyhow::Result<String> {
+    let output = tokio::process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .await
+        .context("could not run git")?;
+    if !output.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_annotate() {
+        let commit = GitCommit::new("0123abcd", Some("main"));
+        let annotated = commit.annotate("Add flips");
+        assert_eq!(
+            annotated,
+            "Add flips\n\nGit-Commit: 0123abcd\nGit-Branch: main"
+        );
+        assert_eq!(
+            GitCommit::split_note(&annotated),
+            ("Add flips", Some(commit))
+        );
+
+        let detached = GitCommit::new("0123abcd", None);
+        let annotated = detached.annotate("");
+        assert_eq!(annotated, "Git-Commit: 0123abcd");
+        assert_eq!(GitCommit::split_note(&annotated), ("", Some(detached)));
+    }
+
+    #[test]
+    fn test_split_note_without_trailers() {
+        for note in [
+            "",
+            "Add flips",
+            "Add flips\n\nGit-Commit: 0123abcd\nnot a trailer",
+        ] {
+            assert_eq!(GitCommit::split_note(note), (note, None));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_current() {
+        let dir = std::env::temp_dir().join(format!("quitch-git-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .arg("-C")
+                .arg(&dir)
+                .args(args)
+                .status()
+                .unwrap();
+            assert!(status.success(), "git {args:?} failed");
+        };
+        git(&["init", "--quiet", "--initial-branch", "trunk"]);
+        assert!(GitCommit::current(&dir).await.is_err());
+        git(&[
+            "-c",
+            "user.name=Jane",
+            "-c",
+            "user.email=jane@example.com",
+            "commit",
+            "--quiet",
+            "--allow-empty",
+            "--message",
+            "Empty",
+        ]);
+
+        let commit = GitCommit::current(&dir).await.unwrap();
+        assert_eq!(commit.sha.len(), 40);
+        assert_eq!(commit.branch.as_deref(), Some("trunk"));
+
+        git(&["checkout", "--quiet", "--detach"]);
+        let detached = GitCommit::current(&dir).await.unwrap();
+        assert_eq!(detached, GitCommit::new(&commit.sha, None));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_at_and_checkout() {
+        let dir = std::env::temp_dir().join(format!("quitch-git-checkout-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("db")).unwrap();
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .args(["-C", dir.to_str().unwrap()])
+                .args(["-c", "user.name=Jane", "-c", "user.email=jane@example.com"])
+                .args(args)
+                .status()
+                .unwrap();
+            assert!(status.success(), "git {args:?} failed");
+        };
+        let plan = dir.join("db").join("sqitch.plan");
+        git(&["init", "--quiet", "--initial-branch", "trunk"]);
+        std::fs::write(&plan, "%project=flipr\n").unwrap();
+        git(&["add", "."]);
+        git(&["commit", "--quiet", "--message", "Start"]);
+        git(&["checkout", "--quiet", "-b", "feature"]);
+        std::fs::write(
+            &plan,
+            "%project=flipr\n\nflips 2024-03-07T03:19:34Z Jane <jane@example.com>\n",
+        )
+        .unwrap();
+        git(&["commit", "--quiet", "--all", "--message", "Add flips"]);
+
+        assert_eq!(read_at(&plan, "trunk").await.unwrap(), "%project=flipr\n");
+        assert!(read_at(&plan, "missing").await.is_err());
+
+        checkout(&dir, "trunk").await.unwrap();
+        assert_eq!(std::fs::read_to_string(&plan).unwrap(), "%project=flipr\n");
+        let error = checkout(&dir, "missing").await.unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("git checkout --quiet missing failed: "));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+//! Hooks: scripts in the `hooks` directory next to `deploy` and `revert`,
+//! run before and after changes are deployed or reverted, e.g. to refresh
+//! grants or clear caches.
+//!
+//! Each [`Hook`] has its own directory, such as `hooks/after_deploy`. Its
+//! `.sql` files run on the target like change scripts, with the same
+//! variables, and its other executable files run as programs, with the changes
+//! described in `QUITCH_*` environment variables. Hooks run in order of their
+//! file names, and the first one that fails stops the deploy or revert.
+
+use std::path::Path;
+
+use crate::plan::FullChange;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hook {
+    /// Before each change is deployed
+    BeforeDeploy,
+    /// After each change is deployed
+    AfterDeploy,
+    BeforeRevert,
+    AfterRevert,
+    /// Once before the changes of a deploy, with all of them
+    BeforeDeployRun,
+    AfterDeployRun,
+    BeforeRevertRun,
+    AfterRevertRun,
+}
+
+impl Hook {
+    /// Name of the directory in `hooks` holding the scripts of this hook
+    pub fn dir_name(self) -> &'static str {
+        match self {
+            Self::BeforeDeploy => "before_deploy",
+            Self::AfterDeploy => "after_deploy",
+            Self::BeforeRevert => "before_revert",
+            Self::AfterRevert => "after_revert",
+            Self::BeforeDeployRun => "before_deploy_run",
+            Self::AfterDeployRun => "after_deploy_run",
+            Self::BeforeRevertRun => "before_revert_run",
+            Self::AfterRevertRun => "after_revert_run",
+        }
+    }
+
+    /// Environment of executable hooks.
+    ///
+    /// `QUITCH_CHANGES` lists the names of the changes being deployed or
+    /// reverted, in the order they are, and hooks of a single change also get
+    /// its name, ID, note and tags.
+    pub fn env(self, project: &str, changes: &[&FullChange]) -> Vec<(&'static str, String)> {
+        let names: Vec<_> = changes.iter().map(|change| change.name()).collect();
+        let mut env = vec![
+            ("QUITCH_HOOK", self.dir_name().to_string()),
+            ("QUITCH_PROJECT", project.to_string()),
+            ("QUITCH_CHANGES", names.join(" ")),
+        ];
+        if let [change] = changes {
+            let tags: Vec<_> = change
+                .tags
+                .iter()
+                .map(|tag| format!("@{}", tag.name))
+                .collect();
+            env.extend([
+                ("QUITCH_CHANGE", change.name().to_string()),
+                ("QUITCH_CHANGE_ID", change.id.clone()),
+                ("QUITCH_CHANGE_NOTE", change.change.note.clone()),
+                ("QUITCH_CHANGE_TAGS", tags.join(" ")),
+            ]);
+        }
+        env
+    }
+}
+
+/// Whether a hook file runs on the target rather than as a program
+pub fn is_sql(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "sql")
+}
+
+/// Whether a file can run as a program. Files that can't, such as a README,
+/// are skipped.
+#[cfg(unix)]
+pub fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::metadata(path)
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+pub fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::tests::example_with_tag;
+
+    #[test]
+    fn test_env() {
+        let plan = example_with_tag();
+        let changes: Vec<_> = plan.full_changes().iter().collect();
+        let env = Hook::AfterDeploy.env("quitch", &changes[..1]);
+        let value = |name| {
+            env.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(value("QUITCH_HOOK"), Some("after_deploy"));
+        assert_eq!(value("QUITCH_PROJECT"), Some("quitch"));
+        assert_eq!(value("QUITCH_CHANGE"), Some(changes[0].name()));
+        assert_eq!(value("QUITCH_CHANGE_ID"), Some(changes[0].id.as_str()));
+        assert_eq!(value("QUITCH_CHANGE_TAGS"), Some("@v1.0"));
+
+        let env = Hook::BeforeRevertRun.env("quitch", &changes);
+        assert_eq!(env.len(), 3);
+        assert_eq!(
+            env[2].1,
+            format!("{} {}", changes[0].name(), changes[1].name())
+        );
+    }
+}
@@ -0,0 +1,66 @@
+//! Ctrl-C while changes are being deployed or reverted.
+//!
+//! Killing quitch in the middle of a change would leave the registry out of
+//! step with the target. Instead, the statement that is running finishes, the
+//! change fails with a `fail` event like any other failure, and the lock on
+//! the target is released before quitch exits. Pressing Ctrl-C again exits
+//! right away.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tracing::warn;
+
+use crate::{error::Error, failure::Failure};
+
+/// Set while a [`Guard`] is alive
+static GUARDED: AtomicBool = AtomicBool::new(false);
+
+/// Set by Ctrl-C while guarded, and left set so that nothing else starts
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Handle Ctrl-C for the rest of the process: exit right away, unless a
+/// [`Guard`] is alive, in which case the work stops at the next statement.
+///
+/// Only the binary installs the handler, so that programs using the library
+/// keep their own.
+pub fn install() {
+    tokio::spawn(async {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            if !GUARDED.load(Ordering::SeqCst) || INTERRUPTED.swap(true, Ordering::SeqCst) {
+                std::process::exit(Failure::Interrupted.exit_code().into());
+            }
+            warn!("Interrupted, stopping after the current statement; press Ctrl-C again to exit right away");
+        }
+    });
+}
+
+/// Keeps Ctrl-C from exiting until dropped
+#[must_use]
+pub struct Guard(());
+
+impl Guard {
+    pub fn new() -> Self {
+        GUARDED.store(true, Ordering::SeqCst);
+        Self(())
+    }
+}
+
+impl Default for Guard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        GUARDED.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Fail if Ctrl-C was pressed, checked before each statement and change
+pub fn check() -> anyhow::Result<()> {
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        return Err(Error::Interrupted.into());
+    }
+    Ok(())
+}
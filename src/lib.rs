@@ -0,0 +1,72 @@
+//! A reimplementation of parts of [Sqitch](https://sqitch.org), the database
+//! change management tool.
+//!
+//! The `quitch` binary is a thin CLI over this library, so anything it does
+//! can also be driven from code, e.g. to deploy migrations on startup:
+//!
+//! ```no_run
+//! use quitch::{
+//!     commands::{deploy, parse_connection_string, CommonArgs, ExecutionArgs, RegistryLocation},
+//!     failure::Failure,
+//!     output::Format,
+//!     script::ScriptDirs,
+//! };
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! let common_args = CommonArgs {
+//!     registry: RegistryLocation::Name("sqitch".into()),
+//!     plan_file: "db/sqitch.plan".into(),
+//!     script_dirs: ScriptDirs::next_to_plan("db/sqitch.plan"),
+//!     connection_options: parse_connection_string("db:pg://app:secret@localhost/app")?,
+//! };
+//! match deploy(common_args, ExecutionArgs::default(), None, Format::Text).await {
+//!     Err(error) if Failure::of(&error) != Some(Failure::NothingToDo) => return Err(error),
+//!     _ => {}
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The building blocks are available too: [`Plan`] parses plan files, an
+//! [`Engine`] is a connection to a target or its registry, whose tables are
+//! described in [`registry`], and a [`Deployer`] deploys and reverts changes
+//! while keeping the registry up to date.
+//!
+//! Errors are [`anyhow::Error`]s with the context of what failed. Those worth
+//! handling, such as a failed statement with its SQLSTATE, are an [`Error`]
+//! found with [`Error::of`].
+
+pub mod change;
+pub mod change_ref;
+pub mod commands;
+pub mod config;
+pub mod credentials;
+pub mod deployer;
+pub mod doctor;
+pub mod embed;
+pub mod engine;
+pub mod error;
+pub mod failure;
+pub mod git;
+pub mod hook;
+pub mod interrupt;
+pub mod lint;
+pub mod merge;
+pub mod notify;
+pub mod offline;
+pub mod output;
+pub mod plan;
+pub mod registry;
+pub mod scaffold;
+pub mod script;
+pub mod squash;
+pub mod tag;
+pub mod tunnel;
+
+pub use self::{
+    change::Change,
+    deployer::Deployer,
+    engine::{ClientConfig, Engine, EngineKind},
+    error::Error,
+    plan::Plan,
+};
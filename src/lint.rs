@@ -0,0 +1,281 @@
+//! Checks for mistakes in a plan, run by `quitch plan lint` before they reach
+//! a database.
+//!
+//! Lines are checked one by one first, so that a plan that doesn't parse still
+//! gets all of its problems reported. Dependencies and scripts are only
+//! checked once the plan parses.
+
+use std::{collections::HashMap, fmt::Display};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{
+    change::{invalid_name, Change},
+    change_ref::ChangeRef,
+    plan::Plan,
+    registry::dependency_rows,
+    script::{ScriptDirs, ScriptKind},
+    tag::Tag,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found in the plan
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Diagnostic {
+    /// Line of the plan file, starting from 1
+    pub line: usize,
+    pub severity: Severity,
+    /// Short name of the check that failed, such as `duplicate-change`
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn error(line: usize, code: &'static str, message: impl Display) -> Self {
+        Self {
+            line,
+            severity: Severity::Error,
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    fn warning(line: usize, code: &'static str, message: impl Display) -> Self {
+        Self {
+            line,
+            severity: Severity::Warning,
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Check the contents of a plan file, with scripts looked up in `script_dirs`
+pub fn lint(plan_string: &str, script_dirs: &ScriptDirs) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    // Line of each change, in plan order
+    let mut change_lines = Vec::new();
+    // Line of the last instance of each change and the number of tags then
+    let mut changes_seen: HashMap<String, (usize, usize)> = HashMap::new();
+    let mut tags_seen: HashMap<String, usize> = HashMap::new();
+    let mut last_date: Option<DateTime<Utc>> = None;
+
+    for (idx, line) in plan_string.lines().enumerate() {
+        let line_number = idx + 1;
+        if line.starts_with('%') || line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        let (name, date) = if line.starts_with('@') {
+            let tag = match Tag::parse_line(line) {
+                Ok(tag) => tag,
+                Err(error) => {
+                    diagnostics.push(Diagnostic::error(line_number, "syntax", error));
+                    continue;
+                }
+            };
+            if change_lines.is_empty() {
+                diagnostics.push(Diagnostic::error(
+                    line_number,
+                    "syntax",
+                    format!("tag @{} must follow a change", tag.name),
+                ));
+            }
+            if let Some(first_line) = tags_seen.insert(tag.name.clone(), line_number) {
+                diagnostics.push(Diagnostic::error(
+                    line_number,
+                    "duplicate-tag",
+                    format!("tag @{} is already on line {first_line}", tag.name),
+                ));
+            }
+            if let Some(reason) = invalid_name(&tag.name, true) {
+                diagnostics.push(Diagnostic::error(
+                    line_number,
+                    "invalid-name",
+                    format!("tag name @{} {reason}", tag.name),
+                ));
+            }
+            (format!("tag @{}", tag.name), tag.date)
+        } else {
+            let change = match Change::parse_line(line) {
+                Ok(change) => change,
+                Err(error) => {
+                    diagnostics.push(Diagnostic::error(line_number, "syntax", error));
+                    continue;
+                }
+            };
+            change_lines.push(line_number);
+            if let Some((previous_line, tag_count)) =
+                changes_seen.insert(change.name.clone(), (line_number, tags_seen.len()))
+            {
+                if tag_count == tags_seen.len() {
+                    diagnostics.push(Diagnostic::error(
+                        line_number,
+                        "duplicate-change",
+                        format!(
+                            "change {} is already on line {previous_line} without a tag in between",
+                            change.name
+                        ),
+                    ));
+                }
+            }
+            if let Some(reason) = invalid_name(&change.name, false) {
+                diagnostics.push(Diagnostic::error(
+                    line_number,
+                    "invalid-name",
+                    format!("change name {} {reason}", change.name),
+                ));
+            }
+            (format!("change {}", change.name), change.date)
+        };
+        if let Some(previous) = last_date.filter(|previous| date < *previous) {
+            diagnostics.push(Diagnostic::warning(
+                line_number,
+                "timestamp-order",
+                format!(
+                    "{name} is dated {} before the line above, dated {}",
+                    date.to_rfc3339(),
+                    previous.to_rfc3339()
+                ),
+            ));
+        }
+        last_date = Some(last_date.map_or(date, |previous| previous.max(date)));
+    }
+
+    if let Ok(plan) = Plan::parse(plan_string) {
+        lint_plan(&plan, script_dirs, &change_lines, &mut diagnostics);
+    }
+    diagnostics.sort_by_key(|diagnostic| diagnostic.line);
+    diagnostics
+}
+
+/// Checks that need the whole plan: dependencies and scripts
+fn lint_plan(
+    plan: &Plan,
+    script_dirs: &ScriptDirs,
+    change_lines: &[usize],
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let changes = plan.full_changes();
+    for (change, &line_number) in changes.iter().zip(change_lines) {
+        // Changes of other projects can only be checked against a registry
+        for dependency in dependency_rows(plan, change, &[]) {
+            let name = &dependency.dependency;
+            if name
+                .split_once(':')
+                .is_some_and(|(project, _)| project != plan.project())
+            {
+                continue;
+            }
+            let known = match dependency.kind {
+                "require" => dependency.dependency_id.is_some(),
+                _ => {
+                    let own_name = name.split_once(':').map_or(name.as_str(), |(_, name)| name);
+                    own_name
+                        .parse::<ChangeRef>()
+                        .is_ok_and(|reference| reference.resolve(changes).is_ok())
+                }
+            };
+            if !known {
+                let message = match dependency.kind {
+                    "require" => format!(
+                        "{} requires {name}, which is not earlier in the plan",
+                        change.name()
+                    ),
+                    _ => format!(
+                        "{} conflicts with {name}, which is not in the plan",
+                        change.name()
+                    ),
+                };
+                diagnostics.push(Diagnostic::error(
+                    line_number,
+                    "unknown-dependency",
+                    message,
+                ));
+            }
+        }
+
+        // Verify scripts are optional
+        for kind in [ScriptKind::Deploy, ScriptKind::Revert] {
+            let path = script_dirs.change_script_path(kind, change);
+            if !path.exists() {
+                diagnostics.push(Diagnostic::error(
+                    line_number,
+                    "missing-script",
+                    format!(
+                        "{} script of {} is missing: {}",
+                        kind.dir_name(),
+                        change.name(),
+                        path.display()
+                    ),
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lint() {
+        let plan_string = "%syntax-version=1.0.0\n\
+            %project=quitch\n\
+            \n\
+            users 2024-03-07T03:19:34Z Jane <jane@example.com>\n\
+            flips [users !missing] 2024-03-06T00:00:00Z Jane <jane@example.com>\n\
+            users 2024-03-08T00:00:00Z Jane <jane@example.com>\n\
+            -bad 2024-03-09T00:00:00Z Jane <jane@example.com>\n\
+            broken 2024-03-09\n";
+        let script_dirs = ScriptDirs::next_to_plan("/nonexistent/sqitch.plan");
+        let codes: Vec<_> = lint(plan_string, &script_dirs)
+            .into_iter()
+            .map(|d| (d.line, d.code))
+            .collect();
+        assert_eq!(
+            codes,
+            [
+                (5, "timestamp-order"),
+                (6, "duplicate-change"),
+                (7, "invalid-name"),
+                (8, "syntax"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lint_dependencies_and_scripts() {
+        let dir = std::env::temp_dir().join(format!("quitch-lint-{}", std::process::id()));
+        for kind in ["deploy", "revert"] {
+            std::fs::create_dir_all(dir.join(kind)).unwrap();
+            std::fs::write(dir.join(kind).join("users.sql"), "").unwrap();
+        }
+        std::fs::write(dir.join("deploy").join("flips.sql"), "").unwrap();
+        let plan_string = "%project=quitch\n\
+            \n\
+            users [other:accounts] 2024-03-07T03:19:34Z Jane <jane@example.com>\n\
+            flips [users quitch:nope !gone] 2024-03-08T00:00:00Z Jane <jane@example.com>\n";
+        let diagnostics = lint(plan_string, &ScriptDirs::in_top_dir(&dir));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages.len(), 3, "{messages:?}");
+        assert_eq!(
+            messages[0],
+            "flips requires quitch:nope, which is not earlier in the plan"
+        );
+        assert_eq!(
+            messages[1],
+            "flips conflicts with gone, which is not in the plan"
+        );
+        assert!(messages[2].starts_with("revert script of flips is missing: "));
+        assert!(diagnostics.iter().all(|d| d.line == 4));
+    }
+}
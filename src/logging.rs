@@ -0,0 +1,104 @@
+//! Messages about what quitch is doing, written to stderr
+
+use std::fmt;
+
+use tracing::{field::Field, Event, Level, Subscriber};
+use tracing_subscriber::{
+    field::Visit,
+    fmt::{format::Writer, FmtContext, FormatEvent, FormatFields},
+    registry::LookupSpan,
+};
+
+/// How log messages are written
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Just the messages, for people
+    #[default]
+    Text,
+    /// One JSON object per message with its level, time and fields
+    Json,
+}
+
+/// Most detailed level shown, starting from info and moving by `verbosity`
+pub fn max_level(verbosity: i16) -> Level {
+    match verbosity {
+        ..=-2 => Level::ERROR,
+        -1 => Level::WARN,
+        0 => Level::INFO,
+        1 => Level::DEBUG,
+        2.. => Level::TRACE,
+    }
+}
+
+/// Send log messages to stderr for the rest of the run
+pub fn init(verbosity: i16, format: LogFormat) {
+    let builder = tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_max_level(max_level(verbosity));
+    match format {
+        LogFormat::Text => builder.event_format(Plain).init(),
+        LogFormat::Json => builder.json().flatten_event(true).init(),
+    }
+}
+
+/// Formats an event as its message alone, prefixed for warnings and errors
+/// the way quitch always printed them
+struct Plain;
+
+impl<S, N> FormatEvent<S, N> for Plain
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut message = Message(String::new());
+        event.record(&mut message);
+        writeln!(writer, "{}{}", prefix(*event.metadata().level()), message.0)
+    }
+}
+
+fn prefix(level: Level) -> &'static str {
+    match level {
+        Level::ERROR => "Error: ",
+        Level::WARN => "Warning: ",
+        _ => "",
+    }
+}
+
+/// Picks the message out of an event's fields
+struct Message(String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{value:?}");
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.0 = value.to_string();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_max_level() {
+        assert_eq!(max_level(0), Level::INFO);
+        assert_eq!(max_level(1), Level::DEBUG);
+        assert_eq!(max_level(5), Level::TRACE);
+        assert_eq!(max_level(-1), Level::WARN);
+        assert_eq!(max_level(-3), Level::ERROR);
+        assert_eq!(prefix(Level::WARN), "Warning: ");
+        assert_eq!(prefix(Level::INFO), "");
+    }
+}
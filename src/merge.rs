@@ -0,0 +1,248 @@
+//! Three-way merges of plan files, run by `quitch plan merge` as a git merge
+//! driver.
+//!
+//! A textual merge of two branches that both added changes at the end of the
+//! plan conflicts, and resolving it by hand easily leaves a plan that no longer
+//! parses. Here, when both sides only added entries after the base, the
+//! entries added by theirs go after those added by ours. The changes of theirs
+//! get new IDs, as their parent is now the last change of ours, so they must be
+//! reverted from databases they were deployed to before the merge.
+//!
+//! Anything else, such as both sides editing the same change, adding changes
+//! or tags with the same name, or a result that doesn't parse, is left to the
+//! user between conflict markers.
+
+use std::collections::HashSet;
+
+use indexmap::IndexMap;
+
+use crate::plan::{Entry, Plan};
+
+/// Result of merging plans
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Merge {
+    /// The merged plan file, with conflict markers if `conflicted`
+    pub contents: String,
+    pub conflicted: bool,
+}
+
+/// Merge the changes from `base` to `theirs` into `ours`
+pub fn merge(base: &str, ours: &str, theirs: &str) -> anyhow::Result<Merge> {
+    let [base, ours, theirs] = [base, ours, theirs].map(Plan::parse);
+    let (base, ours, theirs) = (base?, ours?, theirs?);
+    let pragmas = merge_pragmas(base.pragmas(), ours.pragmas(), theirs.pragmas())?;
+    let [base, ours, theirs] = [&base, &ours, &theirs].map(Plan::entries);
+
+    if let Some(entries) = merge_entries(base, ours, theirs) {
+        let contents = format_plan(&pragmas, entries.iter().map(Entry::format_line));
+        // Reworks and dependencies may no longer line up
+        if Plan::parse(&contents).is_ok() {
+            return Ok(Merge {
+                contents,
+                conflicted: false,
+            });
+        }
+    }
+
+    let shared = common_prefix(ours, theirs);
+    let shared_end = common_prefix(ours[shared..].iter().rev(), theirs[shared..].iter().rev());
+    let lines =
+        |entries: &[Entry]| -> Vec<String> { entries.iter().map(Entry::format_line).collect() };
+    let before = lines(&ours[..shared]);
+    let after = lines(&ours[ours.len() - shared_end..]);
+    let conflict = [
+        vec!["<<<<<<< ours".to_string()],
+        lines(&ours[shared..ours.len() - shared_end]),
+        vec!["=======".to_string()],
+        lines(&theirs[shared..theirs.len() - shared_end]),
+        vec![">>>>>>> theirs".to_string()],
+    ]
+    .concat();
+    Ok(Merge {
+        contents: format_plan(&pragmas, [before, conflict, after].concat().into_iter()),
+        conflicted: true,
+    })
+}
+
+/// Pragmas changed on either side, failing if both changed the same one
+fn merge_pragmas(
+    base: &IndexMap<String, String>,
+    ours: &IndexMap<String, String>,
+    theirs: &IndexMap<String, String>,
+) -> anyhow::Result<IndexMap<String, String>> {
+    let mut merged = IndexMap::new();
+    for key in ours.keys().chain(theirs.keys()).chain(base.keys()) {
+        if merged.contains_key(key) {
+            continue;
+        }
+        let [base_value, ours_value, theirs_value] = [base, ours, theirs].map(|p| p.get(key));
+        let value = if ours_value == theirs_value || theirs_value == base_value {
+            ours_value
+        } else if ours_value == base_value {
+            theirs_value
+        } else {
+            anyhow::bail!("both sides changed the %{key} pragma");
+        };
+        if let Some(value) = value {
+            merged.insert(key.clone(), value.clone());
+        }
+    }
+    Ok(merged)
+}
+
+/// Entries of a clean merge, if there is one
+fn merge_entries(base: &[Entry], ours: &[Entry], theirs: &[Entry]) -> Option<Vec<Entry>> {
+    if ours == theirs || theirs == base {
+        return Some(ours.to_vec());
+    }
+    if ours == base {
+        return Some(theirs.to_vec());
+    }
+    if !ours.starts_with(base) || !theirs.starts_with(base) {
+        return None;
+    }
+    // Entries both sides added in the same place only need to be added once
+    let shared = common_prefix(ours, theirs);
+    let (added_by_ours, added_by_theirs) = (&ours[shared..], &theirs[shared..]);
+    // A tag added right after the shared changes would move onto a change of ours
+    let first_added = added_by_theirs
+        .iter()
+        .find(|entry| matches!(entry, Entry::Change(_) | Entry::Tag(_)));
+    if matches!(first_added, Some(Entry::Tag(_))) {
+        return None;
+    }
+    let names = |entries: &[Entry]| -> HashSet<String> {
+        entries
+            .iter()
+            .filter_map(|entry| match entry {
+                Entry::Change(change) => Some(change.name.clone()),
+                Entry::Tag(tag) => Some(format!("@{}", tag.name)),
+                Entry::Blank | Entry::Comment(_) => None,
+            })
+            .collect()
+    };
+    if !names(added_by_ours).is_disjoint(&names(added_by_theirs)) {
+        return None;
+    }
+    Some([ours, added_by_theirs].concat())
+}
+
+/// Number of items the two sequences start with
+fn common_prefix<T: PartialEq>(
+    a: impl IntoIterator<Item = T>,
+    b: impl IntoIterator<Item = T>,
+) -> usize {
+    a.into_iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+fn format_plan(pragmas: &IndexMap<String, String>, lines: impl Iterator<Item = String>) -> String {
+    pragmas
+        .iter()
+        .map(|(key, value)| format!("%{key}={value}"))
+        .chain(lines)
+        .map(|line| line + "\n")
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "\
+%syntax-version=1.0.0
+%project=flipr
+
+users 2024-03-07T03:19:34Z Jane <jane@example.com> # Add users
+";
+
+    fn with(lines: &str) -> String {
+        format!("{BASE}{lines}")
+    }
+
+    const FLIPS: &str = "flips [users] 2024-03-08T00:00:00Z Jane <jane@example.com> # Add flips\n";
+    const LIKES: &str = "likes [users] 2024-03-09T00:00:00Z Joe <joe@example.com> # Add likes\n";
+
+    #[test]
+    fn test_merge_appended_changes() {
+        let merged = merge(BASE, &with(FLIPS), &with(LIKES)).unwrap();
+        assert_eq!(
+            merged,
+            Merge {
+                contents: with(&format!("{FLIPS}{LIKES}")),
+                conflicted: false,
+            }
+        );
+        let plan = Plan::parse(&merged.contents).unwrap();
+        let changes = plan.full_changes();
+        assert_eq!(changes[2].parent.as_ref(), Some(&changes[1].id));
+    }
+
+    #[test]
+    fn test_merge_one_side() {
+        for (ours, theirs) in [
+            (with(FLIPS), BASE.to_string()),
+            (BASE.to_string(), with(FLIPS)),
+        ] {
+            let merged = merge(BASE, &ours, &theirs).unwrap();
+            assert_eq!(merged.contents, with(FLIPS));
+            assert!(!merged.conflicted);
+        }
+        let same = merge(BASE, &with(FLIPS), &with(FLIPS)).unwrap();
+        assert_eq!(same.contents, with(FLIPS));
+    }
+
+    #[test]
+    fn test_merge_pragmas() {
+        let theirs = with(LIKES).replace(
+            "%project=flipr\n",
+            "%project=flipr\n%uri=https://example.com/\n",
+        );
+        let merged = merge(BASE, &with(FLIPS), &theirs).unwrap();
+        assert!(merged
+            .contents
+            .starts_with("%syntax-version=1.0.0\n%project=flipr\n%uri=https://example.com/\n"));
+
+        let ours = BASE.replace("flipr", "flips");
+        let theirs = BASE.replace("flipr", "likes");
+        assert_eq!(
+            merge(BASE, &ours, &theirs).unwrap_err().to_string(),
+            "both sides changed the %project pragma"
+        );
+    }
+
+    #[test]
+    fn test_merge_same_name() {
+        let theirs = FLIPS.replace("Jane <jane@example.com>", "Joe <joe@example.com>");
+        let merged = merge(BASE, &with(FLIPS), &with(&theirs)).unwrap();
+        assert!(merged.conflicted);
+        assert_eq!(
+            merged.contents,
+            with(&format!(
+                "<<<<<<< ours\n{FLIPS}=======\n{theirs}>>>>>>> theirs\n"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_merge_tag_of_shared_change() {
+        let tag = "@v1.0 2024-03-09T00:00:00Z Joe <joe@example.com> # Release\n";
+        let merged = merge(BASE, &with(FLIPS), &with(tag)).unwrap();
+        assert!(merged.conflicted);
+    }
+
+    #[test]
+    fn test_merge_edited_change() {
+        let ours = BASE.replace("Add users", "Add the users");
+        let theirs = with(LIKES).replace("Add users", "Add all users");
+        let merged = merge(BASE, &ours, &theirs).unwrap();
+        assert!(merged.conflicted);
+        assert_eq!(
+            merged.contents,
+            format!(
+                "%syntax-version=1.0.0\n%project=flipr\n\n<<<<<<< ours\n{}=======\n{}{LIKES}>>>>>>> theirs\n",
+                "users 2024-03-07T03:19:34Z Jane <jane@example.com> # Add the users\n",
+                "users 2024-03-07T03:19:34Z Jane <jane@example.com> # Add all users\n",
+            )
+        );
+    }
+}
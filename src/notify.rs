@@ -0,0 +1,368 @@
+//! Webhooks told about each deploy, revert and rebase, e.g. to post to Slack.
+//!
+//! Webhooks come from `[webhook "name"]` sections of the config:
+//!
+//! ```ini
+//! [webhook "slack"]
+//!     url = https://hooks.slack.com/services/T000/B000/XXXX
+//!     on = failure
+//! ```
+//!
+//! Each gets a JSON [`Notification`] POSTed to its URL after the run. A
+//! webhook that can't be reached is only warned about, so that it doesn't
+//! change the outcome of the run.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Serialize;
+use tracing::{info, warn};
+use url::Url;
+
+use crate::{deployer::Deployer, engine::ClientConfig, failure::Failure, output::ChangeOutcome};
+
+/// How long to wait for a webhook to respond
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many redirects to follow before giving up on a webhook
+const MAX_REDIRECTS: usize = 5;
+
+/// Which runs a webhook is told about
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NotifyOn {
+    #[default]
+    Always,
+    Success,
+    Failure,
+}
+
+impl std::str::FromStr for NotifyOn {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "always" => Self::Always,
+            "success" => Self::Success,
+            "failure" => Self::Failure,
+            _ => bail!("expected always, success or failure, got {s}"),
+        })
+    }
+}
+
+/// An HTTP endpoint told about runs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Webhook {
+    /// Name of its `[webhook]` section
+    pub name: String,
+    /// Where to POST, with `{project}`, `{target}`, `{action}` and `{status}`
+    /// replaced by those of the run
+    pub url: String,
+    pub on: NotifyOn,
+}
+
+impl Webhook {
+    fn wants(&self, notification: &Notification) -> bool {
+        match self.on {
+            NotifyOn::Always => true,
+            NotifyOn::Success => notification.status == Status::Success,
+            NotifyOn::Failure => notification.status == Status::Failure,
+        }
+    }
+
+    /// The URL with the placeholders of the template filled in
+    fn url_for(&self, notification: &Notification) -> String {
+        let encode = |value: &str| utf8_percent_encode(value, NON_ALPHANUMERIC).to_string();
+        self.url
+            .replace(
+                "{project}",
+                &encode(notification.project.as_deref().unwrap_or_default()),
+            )
+            .replace("{target}", &encode(&notification.target))
+            .replace("{action}", notification.action)
+            .replace("{status}", notification.status.as_str())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Success,
+    Failure,
+}
+
+impl Status {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Success => "success",
+            Self::Failure => "failure",
+        }
+    }
+}
+
+/// What webhooks are sent about a run
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Notification {
+    /// One line summary, shown by chat services such as Slack
+    pub text: String,
+    /// None if the plan couldn't be read
+    pub project: Option<String>,
+    /// The target's URI, without the password
+    pub target: String,
+    /// `deploy`, `revert` or `rebase`
+    pub action: &'static str,
+    pub status: Status,
+    /// Changes deployed or reverted, including the one that failed
+    pub changes: Vec<ChangeOutcome>,
+    /// Seconds the run took
+    pub duration: f64,
+    pub error: Option<String>,
+}
+
+/// A deploy, revert or rebase that webhooks are told about when it ends
+#[derive(Clone, Debug)]
+pub struct Run {
+    action: &'static str,
+    target: String,
+    webhooks: Vec<Webhook>,
+    started: Instant,
+}
+
+impl Run {
+    pub fn start(action: &'static str, target: &ClientConfig, webhooks: Vec<Webhook>) -> Self {
+        Self {
+            action,
+            target: target.to_string(),
+            webhooks,
+            started: Instant::now(),
+        }
+    }
+
+    /// Tell the webhooks how the run went. Log-only runs and runs that had
+    /// nothing to do aren't reported.
+    pub async fn finish(&self, deployer: Option<&Deployer>, result: &anyhow::Result<()>) {
+        if self.webhooks.is_empty()
+            || deployer.is_some_and(|deployer| deployer.log_only)
+            || result
+                .as_ref()
+                .is_err_and(|error| Failure::of(error) == Some(Failure::NothingToDo))
+        {
+            return;
+        }
+        let changes = deployer
+            .map(|deployer| deployer.outcomes.lock().unwrap().clone())
+            .unwrap_or_default();
+        let notification = self.notification(
+            deployer.map(|deployer| deployer.plan.project().to_string()),
+            changes,
+            result.as_ref().err(),
+        );
+        notify(&self.webhooks, &notification).await;
+    }
+
+    fn notification(
+        &self,
+        project: Option<String>,
+        changes: Vec<ChangeOutcome>,
+        error: Option<&anyhow::Error>,
+    ) -> Notification {
+        let error = error.map(|error| format!("{error:#}"));
+        let what = match &project {
+            Some(project) => format!("{} of {project} to {}", self.action, self.target),
+            None => format!("{} to {}", self.action, self.target),
+        };
+        let text = match &error {
+            Some(error) => format!("quitch {what} failed: {error}"),
+            None => {
+                let plural = if changes.len() == 1 { "" } else { "s" };
+                format!("quitch {what} succeeded, {} change{plural}", changes.len())
+            }
+        };
+        Notification {
+            text,
+            project,
+            target: self.target.clone(),
+            action: self.action,
+            status: if error.is_some() {
+                Status::Failure
+            } else {
+                Status::Success
+            },
+            changes,
+            duration: self.started.elapsed().as_secs_f64(),
+            error,
+        }
+    }
+}
+
+/// POST the notification to each webhook that wants it
+pub async fn notify(webhooks: &[Webhook], notification: &Notification) {
+    let body = match serde_json::to_string(notification) {
+        Ok(body) => body,
+        Err(error) => return warn!("failed to serialize the notification: {error}"),
+    };
+    for webhook in webhooks
+        .iter()
+        .filter(|webhook| webhook.wants(notification))
+    {
+        match post_json(&webhook.url_for(notification), body.clone()).await {
+            Ok(()) => info!("Notified webhook {}", webhook.name),
+            Err(error) => warn!("failed to notify webhook {}: {error:#}", webhook.name),
+        }
+    }
+}
+
+/// POST a JSON body, failing unless the response has a 2xx status
+pub async fn post_json(url: &str, body: String) -> anyhow::Result<()> {
+    let url = Url::parse(url).with_context(|| format!("invalid webhook URL {url}"))?;
+    tokio::task::spawn_blocking(move || post_json_blocking(&url, &body)).await?
+}
+
+/// POST the body again to wherever a webhook redirects, unlike ureq, which
+/// turns it into a GET or gives up
+fn post_json_blocking(url: &Url, body: &str) -> anyhow::Result<()> {
+    // Proxies come from `HTTPS_PROXY`, `NO_PROXY` and the like
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .timeout_global(Some(TIMEOUT))
+        .http_status_as_error(false)
+        .max_redirects(0)
+        .user_agent(concat!("quitch/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .into();
+    let mut url = url.clone();
+    for _ in 0..=MAX_REDIRECTS {
+        if !matches!(url.scheme(), "http" | "https") {
+            bail!(
+                "unsupported webhook scheme {}, use http or https",
+                url.scheme()
+            );
+        }
+        let response = agent
+            .post(url.as_str())
+            .content_type("application/json")
+            .send(body)?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let location = response
+            .headers()
+            .get("location")
+            .and_then(|location| location.to_str().ok());
+        match location {
+            Some(location) if status.is_redirection() => {
+                url = url
+                    .join(location)
+                    .with_context(|| format!("invalid redirect to {location}"))?;
+            }
+            _ => bail!("{status}"),
+        }
+    }
+    bail!("more than {MAX_REDIRECTS} redirects")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::output::Action;
+
+    fn notification() -> Notification {
+        let run = Run {
+            action: "deploy",
+            target: "pg://app@localhost:5432/app".to_string(),
+            webhooks: Vec::new(),
+            started: Instant::now(),
+        };
+        let changes = vec![ChangeOutcome {
+            id: "da41a550".to_string(),
+            name: "users".to_string(),
+            action: Action::Deploy,
+            event: None,
+            error: None,
+        }];
+        run.notification(Some("quitch".to_string()), changes, None)
+    }
+
+    #[test]
+    fn test_notification() {
+        let notification = notification();
+        assert_eq!(
+            notification.text,
+            "quitch deploy of quitch to pg://app@localhost:5432/app succeeded, 1 change"
+        );
+        assert_eq!(notification.status, Status::Success);
+
+        let webhook = Webhook {
+            name: "ci".to_string(),
+            url: "https://ci.example.com/hooks/{project}/{action}?status={status}&target={target}"
+                .to_string(),
+            on: NotifyOn::Failure,
+        };
+        assert_eq!(
+            webhook.url_for(&notification),
+            "https://ci.example.com/hooks/quitch/deploy?status=success\
+            &target=pg%3A%2F%2Fapp%40localhost%3A5432%2Fapp"
+        );
+        assert!(!webhook.wants(&notification));
+    }
+
+    /// Answer one request with `response`, giving the port and the request
+    fn serve_once(response: String) -> (u16, std::thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            while !request.ends_with(b"}") {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream.write_all(response.as_bytes()).unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (port, server)
+    }
+
+    #[tokio::test]
+    async fn test_post_json() {
+        let (port, server) = serve_once("HTTP/1.1 204 No Content\r\n\r\n".to_string());
+        post_json(
+            &format!("http://127.0.0.1:{port}/hooks?key=1"),
+            r#"{"text":"hi"}"#.to_string(),
+        )
+        .await
+        .unwrap();
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /hooks?key=1 HTTP/1.1\r\n"));
+        assert!(request
+            .to_lowercase()
+            .contains(&format!("\r\nhost: 127.0.0.1:{port}\r\n")));
+        assert!(request.ends_with("\r\n\r\n{\"text\":\"hi\"}"));
+
+        let (moved_port, moved) = serve_once("HTTP/1.1 204 No Content\r\n\r\n".to_string());
+        let (port, _) = serve_once(format!(
+            "HTTP/1.1 308 Permanent Redirect\r\n\
+            Location: http://127.0.0.1:{moved_port}/moved\r\n\
+            Content-Length: 0\r\n\r\n"
+        ));
+        post_json(&format!("http://127.0.0.1:{port}/"), "{}".to_string())
+            .await
+            .unwrap();
+        assert!(moved
+            .join()
+            .unwrap()
+            .starts_with("POST /moved HTTP/1.1\r\n"));
+
+        let (port, _) = serve_once("HTTP/1.1 404 Not Found\r\n\r\n".to_string());
+        let error = post_json(&format!("http://127.0.0.1:{port}/"), "{}".to_string())
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "404 Not Found");
+    }
+}
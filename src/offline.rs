@@ -0,0 +1,306 @@
+//! Deploy scripts combined into a single file, to be applied without quitch
+
+use std::{collections::HashMap, fmt::Write};
+
+use crate::{
+    change::Planner,
+    git::GitCommit,
+    plan::{FullChange, Plan},
+    registry::{dependency_rows, event_lists, Event},
+    script::{script_hash, substitute_variables},
+};
+
+/// Quote a string as a MySQL string literal
+pub fn quote_literal(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('\'');
+    for ch in s.chars() {
+        match ch {
+            '\'' => quoted.push_str("\\'"),
+            '\\' => quoted.push_str("\\\\"),
+            '\0' => quoted.push_str("\\0"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            ch => quoted.push(ch),
+        }
+    }
+    quoted.push('\'');
+    quoted
+}
+
+/// Where the combined script records deployed changes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegistryTables<'a> {
+    /// Tables of the registry schema
+    Schema(&'a str),
+    /// Tables in the target database with names starting with the prefix
+    Prefix(&'a str),
+}
+
+impl RegistryTables<'_> {
+    /// Quoted name of a registry table
+    fn table(self, name: &str) -> String {
+        match self {
+            Self::Schema(schema) => format!("`{schema}`.`{name}`"),
+            Self::Prefix(prefix) => format!("`{prefix}{name}`"),
+        }
+    }
+}
+
+/// Registry statements recording a change as deployed
+fn registry_statements(
+    registry: RegistryTables,
+    plan: &Plan,
+    change: &FullChange,
+    script: &str,
+    committer: &Planner,
+    git_commit: Option<&GitCommit>,
+) -> String {
+    let change_id = quote_literal(&change.id);
+    let script_hash = quote_literal(&script_hash(script.as_bytes()));
+    let name = quote_literal(change.name());
+    let project = quote_literal(plan.project());
+    let note = quote_literal(&change.change.note);
+    let planned_at = quote_literal(&change.change.date.format("%F %T").to_string());
+    let planner = format!(
+        "{}, {}",
+        quote_literal(&change.change.planner.name),
+        quote_literal(&change.change.planner.email)
+    );
+    let committer = format!(
+        "{}, {}",
+        quote_literal(&committer.name),
+        quote_literal(&committer.email)
+    );
+    let event_note = quote_literal(&git_commit.map_or_else(
+        || change.change.note.clone(),
+        |commit| commit.annotate(&change.change.note),
+    ));
+    let event = quote_literal(&Event::Deploy.to_string().to_lowercase());
+    let [requires, conflicts, tags] = event_lists(change).map(|list| quote_literal(&list));
+    let [changes_table, dependencies_table, tags_table, events_table] =
+        ["changes", "dependencies", "tags", "events"].map(|name| registry.table(name));
+    let mut statements = format!(
+        "insert into {changes_table} (\n    \
+            `change_id`, `script_hash`, `change`, `project`, `note`,\n    \
+            `committed_at`, `committer_name`, `committer_email`,\n    \
+            `planned_at`, `planner_name`, `planner_email`\n\
+        ) values (\n    \
+            {change_id}, {script_hash}, {name}, {project}, {note},\n    \
+            utc_timestamp(6), {committer},\n    \
+            {planned_at}, {planner}\n\
+        );\n"
+    );
+    // Changes of other projects can't be looked up without the registry
+    for dependency in dependency_rows(plan, change, &[]) {
+        let kind = quote_literal(dependency.kind);
+        let name = quote_literal(&dependency.dependency);
+        let id = dependency
+            .dependency_id
+            .as_deref()
+            .map_or("null".to_string(), quote_literal);
+        writeln!(
+            &mut statements,
+            "insert into {dependencies_table} (`change_id`, `type`, `dependency`, `dependency_id`) \
+            values ({change_id}, {kind}, {name}, {id});"
+        )
+        .expect("always succeeds");
+    }
+    for tag in &change.tags {
+        let tag_id = quote_literal(&tag.id(plan.project(), plan.uri(), &change.id));
+        let tag_name = quote_literal(&format!("@{}", tag.name));
+        let tag_note = quote_literal(&tag.note);
+        let tag_planned_at = quote_literal(&tag.date.format("%F %T").to_string());
+        let tag_planner = format!(
+            "{}, {}",
+            quote_literal(&tag.planner.name),
+            quote_literal(&tag.planner.email)
+        );
+        write!(
+            &mut statements,
+            "insert into {tags_table} (\n    \
+                `tag_id`, `tag`, `project`, `change_id`, `note`,\n    \
+                `committed_at`, `committer_name`, `committer_email`,\n    \
+                `planned_at`, `planner_name`, `planner_email`\n\
+            ) values (\n    \
+                {tag_id}, {tag_name}, {project}, {change_id}, {tag_note},\n    \
+                utc_timestamp(6), {committer},\n    \
+                {tag_planned_at}, {tag_planner}\n\
+            );\n"
+        )
+        .expect("always succeeds");
+    }
+    write!(
+        &mut statements,
+        "insert into {events_table} (\n    \
+            `event`, `change_id`, `change`, `project`, `note`,\n    \
+            `requires`, `conflicts`, `tags`,\n    \
+            `committed_at`, `committer_name`, `committer_email`,\n    \
+            `planned_at`, `planner_name`, `planner_email`\n\
+        ) values (\n    \
+            {event}, {change_id}, {name}, {project}, {event_note},\n    \
+            {requires}, {conflicts}, {tags},\n    \
+            utc_timestamp(6), {committer},\n    \
+            {planned_at}, {planner}\n\
+        );\n"
+    )
+    .expect("always succeeds");
+    statements
+}
+
+/// Combine deploy scripts of changes with the registry bookkeeping for each of them.
+///
+/// `scripts` are the deploy scripts of `changes`, in the same order, and
+/// `git_commit` is recorded in the notes of their events.
+pub fn combined_deploy_script(
+    registry: RegistryTables,
+    plan: &Plan,
+    changes: &[FullChange],
+    scripts: &[String],
+    variables: &HashMap<String, String>,
+    committer: &Planner,
+    git_commit: Option<&GitCommit>,
+) -> String {
+    let project = plan.project();
+    let mut combined = String::new();
+    writeln!(
+        &mut combined,
+        "-- Deploy {} changes of {project}, generated by quitch",
+        changes.len()
+    )
+    .expect("always succeeds");
+    match registry {
+        RegistryTables::Schema(schema) => writeln!(
+            &mut combined,
+            "-- The registry schema `{schema}` must already exist"
+        ),
+        RegistryTables::Prefix(prefix) => writeln!(
+            &mut combined,
+            "-- The registry tables `{prefix}*` must already exist in the target database"
+        ),
+    }
+    .expect("always succeeds");
+    let projects_table = registry.table("projects");
+    let uri = plan.uri().map_or("null".to_string(), quote_literal);
+    write!(
+        &mut combined,
+        "\ninsert ignore into {projects_table} (\n    \
+            `project`, `uri`, `created_at`, `creator_name`, `creator_email`\n\
+        ) values (\n    \
+            {}, {uri}, utc_timestamp(6), {}, {}\n\
+        );\n",
+        quote_literal(project),
+        quote_literal(&committer.name),
+        quote_literal(&committer.email)
+    )
+    .expect("always succeeds");
+    for (change, script) in changes.iter().zip(scripts) {
+        write!(
+            &mut combined,
+            "\n-- Deploy {project}:{} ({})\n\n{}",
+            change.name(),
+            change.id,
+            substitute_variables(script, variables, '`')
+        )
+        .expect("always succeeds");
+        if !script.ends_with('\n') {
+            combined.push('\n');
+        }
+        combined.push('\n');
+        combined.push_str(&registry_statements(
+            registry, plan, change, script, committer, git_commit,
+        ));
+    }
+    combined
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::tests::{example, example_with_tag};
+
+    #[test]
+    fn test_quote_literal() {
+        assert_eq!(quote_literal("plain"), "'plain'");
+        assert_eq!(quote_literal("it's"), "'it\\'s'");
+        assert_eq!(quote_literal("a\\b\nc"), "'a\\\\b\\nc'");
+    }
+
+    #[test]
+    fn test_combined_deploy_script() {
+        let plan = example();
+        let changes: Vec<_> = plan.full_changes().to_vec();
+        let scripts = vec!["create table a (id int);".to_string(), String::new()];
+        let jane = Planner::new("Jane", "jane@example.com");
+        let combined = combined_deploy_script(
+            RegistryTables::Schema("sqitch"),
+            &plan,
+            &changes,
+            &scripts,
+            &HashMap::new(),
+            &jane,
+            None,
+        );
+        assert!(combined.starts_with("-- Deploy 2 changes of quitch, generated by quitch\n"));
+        assert!(combined.contains("insert ignore into `sqitch`.`projects`"));
+        assert!(combined.contains(
+            "\n-- Deploy quitch:change_name (da41a550b0cba5bd3dffbf645032a98ae1136da5)\n\n\
+            create table a (id int);\n\n\
+            insert into `sqitch`.`changes` (\n"
+        ));
+        assert_eq!(combined.matches("insert into `sqitch`.`events`").count(), 2);
+        assert_eq!(
+            combined
+                .matches("utc_timestamp(6), 'Jane', 'jane@example.com'")
+                .count(),
+            5
+        );
+        assert!(combined.contains("'2024-03-07 03:19:34'"));
+        assert!(combined.contains(&format!(
+            "'da41a550b0cba5bd3dffbf645032a98ae1136da5', '{}',",
+            script_hash(b"create table a (id int);")
+        )));
+    }
+
+    #[test]
+    fn test_combined_deploy_script_prefix() {
+        let plan = example();
+        let changes: Vec<_> = plan.full_changes().to_vec();
+        let combined = combined_deploy_script(
+            RegistryTables::Prefix("quitch_"),
+            &plan,
+            &changes,
+            &[String::new(), String::new()],
+            &HashMap::new(),
+            &Planner::new("Jane", "jane@example.com"),
+            None,
+        );
+        assert!(combined.contains("-- The registry tables `quitch_*` must already exist"));
+        assert!(combined.contains("insert ignore into `quitch_projects`"));
+        assert_eq!(combined.matches("insert into `quitch_events`").count(), 2);
+        assert!(!combined.contains("`sqitch`"));
+    }
+
+    #[test]
+    fn test_combined_deploy_script_tags() {
+        let plan = example_with_tag();
+        let changes: Vec<_> = plan.full_changes()[..1].to_vec();
+        let combined = combined_deploy_script(
+            RegistryTables::Schema("sqitch"),
+            &plan,
+            &changes,
+            &[String::new()],
+            &HashMap::new(),
+            &Planner::new("Jane", "jane@example.com"),
+            Some(&GitCommit {
+                sha: "0123abcd".to_string(),
+                branch: None,
+            }),
+        );
+        assert!(combined.contains("insert into `sqitch`.`tags`"));
+        assert!(combined.contains("'@v1.0', 'quitch', 'da41a550b0cba5bd3dffbf645032a98ae1136da5'"));
+        assert!(combined.contains(
+            "'change_name', 'quitch', 'A description of the change\\n\\nGit-Commit: 0123abcd',"
+        ));
+    }
+}
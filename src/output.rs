@@ -0,0 +1,737 @@
+//! Human and machine readable command output
+
+use std::{
+    io::{IsTerminal, Write},
+    process::{Child, Stdio},
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use itertools::Itertools;
+use serde::Serialize;
+
+use crate::{
+    change::format_line_date,
+    git::GitCommit,
+    plan::FullChange,
+    registry::{ChangeRow, Event, EventRow},
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    #[default]
+    Text,
+    Json,
+    /// `log` only: everything recorded with each event
+    Full,
+    /// `log` only: also the project, planner and tags of each event
+    Long,
+    /// `log` only: same as text
+    Medium,
+    /// `log` only: one line per event
+    Oneline,
+}
+
+impl Format {
+    /// Whether the format is one of those of sqitch's `log`, which other
+    /// commands don't have
+    pub fn log_only(self) -> bool {
+        matches!(self, Self::Full | Self::Long | Self::Medium | Self::Oneline)
+    }
+}
+
+/// Print a result as JSON on stdout
+pub fn print_json(value: &impl Serialize) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+/// Environment variables naming the pager of `log`, in order
+const PAGER_VARS: [&str; 3] = ["QUITCH_PAGER", "SQITCH_PAGER", "PAGER"];
+
+/// Pager command from the environment, `less` by default, or none when set
+/// to nothing or `cat`
+fn pager_command(var: impl Fn(&str) -> Option<String>) -> Option<String> {
+    let command = PAGER_VARS
+        .into_iter()
+        .find_map(var)
+        .unwrap_or_else(|| "less".to_string());
+    let command = command.trim();
+    (!command.is_empty() && command != "cat").then(|| command.to_string())
+}
+
+/// Output of `log`: stdout, or a pager reading it if stdout is a terminal,
+/// the way git does. The pager starts with the first write, and is waited
+/// for when dropped.
+#[derive(Debug)]
+pub struct Pager {
+    paged: bool,
+    pager: Option<Child>,
+}
+
+impl Pager {
+    pub fn new(paged: bool) -> Self {
+        Self {
+            paged: paged && std::io::stdout().is_terminal(),
+            pager: None,
+        }
+    }
+
+    /// Write `text`, returning false once the pager was quit and nothing more
+    /// needs writing
+    pub fn write(&mut self, text: &str) -> anyhow::Result<bool> {
+        if std::mem::take(&mut self.paged) {
+            self.pager = pager_command(|var| std::env::var(var).ok()).and_then(spawn_pager);
+        }
+        match &mut self.pager {
+            // The pager may be quit before reading everything
+            Some(pager) => Ok(pager
+                .stdin
+                .as_mut()
+                .expect("piped")
+                .write_all(text.as_bytes())
+                .is_ok()),
+            None => {
+                std::io::stdout().lock().write_all(text.as_bytes())?;
+                Ok(true)
+            }
+        }
+    }
+}
+
+impl Drop for Pager {
+    fn drop(&mut self) {
+        if let Some(pager) = &mut self.pager {
+            // Closing its input lets the pager know there is no more
+            drop(pager.stdin.take());
+            let _ = pager.wait();
+        }
+    }
+}
+
+/// Run `command` to page what is written to its stdin, or nothing if it
+/// can't be run
+fn spawn_pager(command: String) -> Option<Child> {
+    let mut words = command.split_whitespace();
+    let mut pager = std::process::Command::new(words.next().expect("not empty"));
+    pager.args(words).stdin(Stdio::piped());
+    if std::env::var_os("LESS").is_none() {
+        // Quit if it fits on the screen, keep colors and the screen
+        pager.env("LESS", "FRX");
+    }
+    pager.spawn().ok()
+}
+
+/// A JSON array printed an item at a time, formatted as [`print_json`]
+/// formats a whole one
+#[derive(Debug, Default)]
+pub struct JsonArray {
+    len: usize,
+}
+
+impl JsonArray {
+    /// Text of the next item, with the punctuation before it
+    pub fn item(&mut self, value: &impl Serialize) -> anyhow::Result<String> {
+        let json = serde_json::to_string_pretty(value)?;
+        let before = if self.len == 0 { "[\n" } else { ",\n" };
+        self.len += 1;
+        Ok(format!(
+            "{before}{}",
+            json.lines().map(|line| format!("  {line}")).join("\n")
+        ))
+    }
+
+    /// Text closing the array, and the line
+    pub fn end(&self) -> &'static str {
+        if self.len == 0 {
+            "[]\n"
+        } else {
+            "\n]\n"
+        }
+    }
+}
+
+fn format_timestamp(timestamp: DateTime<Utc>) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// A change from the plan as shown by `quitch plan`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PlanChangeOutput {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub name: String,
+    pub date: String,
+    pub planner: String,
+    pub note: String,
+    pub tags: Vec<String>,
+}
+
+impl PlanChangeOutput {
+    pub fn new(change: &FullChange, show_id: bool) -> Self {
+        Self {
+            id: show_id.then(|| change.id.clone()),
+            name: change.name().to_string(),
+            date: format_line_date(change.change.date).to_string(),
+            planner: change.change.planner.to_string(),
+            note: change.change.note.clone(),
+            tags: change.tags.iter().map(|t| format!("@{}", t.name)).collect(),
+        }
+    }
+
+    fn format_oneline(&self) -> String {
+        let mut line = self.name.clone();
+        if let Some(id) = &self.id {
+            line = format!("{id} {line}");
+        }
+        if !self.tags.is_empty() {
+            line = format!("{line} ({})", self.tags.join(", "));
+        }
+        line
+    }
+}
+
+/// What a command did to a change
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Deploy,
+    Revert,
+}
+
+/// A change deployed or reverted by a command
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ChangeOutcome {
+    pub id: String,
+    pub name: String,
+    pub action: Action,
+    /// Event written to the registry, none in log-only mode
+    pub event: Option<Event>,
+    /// Why the change failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Result of `quitch deploy`, `revert` or `rebase`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct RunOutput {
+    pub log_only: bool,
+    /// Changes in the order they were deployed or reverted
+    pub changes: Vec<ChangeOutcome>,
+    /// Error the command stopped at
+    pub error: Option<String>,
+}
+
+/// Result of `quitch deploy --tenants-query` for one tenant
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TenantRunOutput {
+    pub tenant: String,
+    #[serde(flatten)]
+    pub run: RunOutput,
+}
+
+/// A deployed change as shown by `quitch status`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DeployedChangeOutput {
+    pub id: String,
+    pub name: String,
+    pub tags: Vec<String>,
+    pub deployed_at: String,
+    pub deployed_by: String,
+}
+
+impl DeployedChangeOutput {
+    /// `tags` are as stored in an event, separated by spaces
+    pub fn new(row: &ChangeRow, tags: &str) -> Self {
+        Self {
+            id: row.change_id.clone(),
+            name: row.change.clone(),
+            tags: tags.split_whitespace().map(String::from).collect(),
+            deployed_at: format_timestamp(row.committed_at),
+            deployed_by: format!("{} <{}>", row.committer_name, row.committer_email),
+        }
+    }
+}
+
+/// State of a target as shown by `quitch status`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct StatusOutput {
+    pub database: String,
+    pub project: String,
+    pub last_deployed: Option<DeployedChangeOutput>,
+    /// Number of planned changes that are deployed
+    pub deployed: usize,
+    /// Number of planned changes that aren't deployed yet
+    pub undeployed: usize,
+    /// Names of the planned changes that aren't deployed yet, in plan order
+    pub undeployed_changes: Vec<String>,
+    /// Number of deployed changes whose deploy script was modified since
+    pub modified: usize,
+    pub modified_changes: Vec<ModifiedChangeOutput>,
+    /// Changes in the registry that don't match the plan
+    pub unknown: Vec<UnknownChangeOutput>,
+}
+
+impl StatusOutput {
+    /// What keeps the target from matching the plan, like `2 pending,
+    /// 1 unknown, 1 modified`, or nothing if it is up to date
+    pub fn summary(&self) -> Option<String> {
+        let counts = [
+            (self.undeployed, "pending"),
+            (self.unknown.len(), "unknown"),
+            (self.modified, "modified"),
+        ];
+        let summary = counts
+            .into_iter()
+            .filter(|(count, _)| *count > 0)
+            .map(|(count, what)| format!("{count} {what}"))
+            .join(", ");
+        (!summary.is_empty()).then_some(summary)
+    }
+}
+
+/// A deployed change whose deploy script is no longer the one deployed
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ModifiedChangeOutput {
+    pub id: String,
+    pub name: String,
+    /// Path of the deploy script, which may be gone
+    pub script: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct UnknownChangeOutput {
+    pub id: String,
+    pub name: String,
+    /// How the change departs from the plan
+    pub reason: String,
+}
+
+/// An event as shown by `quitch log`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct EventOutput {
+    pub event: Event,
+    pub change_id: String,
+    pub change: String,
+    pub project: String,
+    pub requires: Vec<String>,
+    pub conflicts: Vec<String>,
+    pub tags: Vec<String>,
+    pub planner: String,
+    pub planned_at: String,
+    pub committer: String,
+    pub committed_at: String,
+    /// Note of the event, without the git trailers
+    pub note: String,
+    /// Git commit recorded with `--record-git`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub git_commit: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub git_branch: Option<String>,
+}
+
+impl EventOutput {
+    pub fn new(row: &EventRow) -> Self {
+        let (note, commit) = GitCommit::split_note(&row.note);
+        Self {
+            event: row.event,
+            change_id: row.change_id.clone(),
+            change: row.change.clone(),
+            project: row.project.clone(),
+            // Written by `event_lists`
+            requires: split_list(&row.requires, ','),
+            conflicts: split_list(&row.conflicts, ','),
+            tags: split_list(&row.tags, ' '),
+            planner: format!("{} <{}>", row.planner_name, row.planner_email),
+            planned_at: format_timestamp(row.planned_at),
+            committer: format!("{} <{}>", row.committer_name, row.committer_email),
+            committed_at: format_timestamp(row.committed_at),
+            note: note.to_string(),
+            git_branch: commit.as_ref().and_then(|commit| commit.branch.clone()),
+            git_commit: commit.map(|commit| commit.sha),
+        }
+    }
+
+    /// The event in one of the text formats of `log`, ending with a newline
+    pub fn format(&self, format: Format) -> String {
+        if format == Format::Oneline {
+            let subject = self.note.lines().next().unwrap_or_default();
+            let line = format!(
+                "{} {} {}:{} {subject}",
+                self.change_id,
+                self.event.to_string().to_lowercase(),
+                self.project,
+                self.change
+            );
+            return line.trim_end().to_string() + "\n";
+        }
+        let detailed = matches!(format, Format::Full | Format::Long);
+        let mut lines = vec![if detailed && !self.tags.is_empty() {
+            format!(
+                "{} {} ({})",
+                self.event,
+                self.change_id,
+                self.tags.join(", ")
+            )
+        } else {
+            format!("{} {}", self.event, self.change_id)
+        }];
+        lines.push(format!("Name:      {}", self.change));
+        if detailed {
+            lines.push(format!("Project:   {}", self.project));
+        }
+        if format == Format::Full {
+            for (label, changes) in [
+                ("Requires: ", &self.requires),
+                ("Conflicts:", &self.conflicts),
+            ] {
+                if !changes.is_empty() {
+                    lines.push(format!("{label} {}", changes.join(", ")));
+                }
+            }
+        }
+        if detailed {
+            lines.push(format!("Planner:   {}", self.planner));
+        }
+        if format == Format::Full {
+            lines.push(format!("Planned:   {}", self.planned_at));
+        }
+        lines.push(format!("Committer: {}", self.committer));
+        match format {
+            Format::Full => lines.push(format!("Committed: {}", self.committed_at)),
+            Format::Long => {}
+            _ => lines.push(format!("Date:      {}", self.committed_at)),
+        }
+        if let Some(commit) = &self.git_commit {
+            lines.push(match &self.git_branch {
+                Some(branch) => format!("Git:       {commit} ({branch})"),
+                None => format!("Git:       {commit}"),
+            });
+        }
+        if !self.note.is_empty() {
+            lines.push(String::new());
+            lines.extend(self.note.lines().map(|line| format!("    {line}")));
+        }
+        lines.push(String::new());
+        lines.into_iter().map(|line| line + "\n").collect()
+    }
+}
+
+/// Items of a list column of the `events` table
+fn split_list(list: &str, separator: char) -> Vec<String> {
+    list.split(separator)
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// One change per line, with its tags
+pub fn format_plan_oneline(changes: &[PlanChangeOutput]) -> String {
+    changes
+        .iter()
+        .map(PlanChangeOutput::format_oneline)
+        .map(|line| line + "\n")
+        .collect()
+}
+
+/// Changes as a table with aligned columns
+pub fn format_plan_table(changes: &[PlanChangeOutput]) -> String {
+    let show_ids = changes.iter().any(|c| c.id.is_some());
+    let header: Vec<String> = show_ids
+        .then_some("ID")
+        .into_iter()
+        .chain(["Name", "Date", "Planner", "Tags", "Note"])
+        .map(String::from)
+        .collect();
+    let rows = changes.iter().map(|c| {
+        c.id.iter()
+            .cloned()
+            .chain([
+                c.name.clone(),
+                c.date.clone(),
+                c.planner.clone(),
+                c.tags.join(" "),
+                // Keep multiline notes on one row
+                c.note.replace('\n', "\\n"),
+            ])
+            .collect::<Vec<_>>()
+    });
+    let table: Vec<Vec<String>> = std::iter::once(header).chain(rows).collect();
+
+    let widths: Vec<usize> = (0..table[0].len())
+        .map(|column| {
+            table
+                .iter()
+                .map(|row| row[column].chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    table
+        .iter()
+        .map(|row| {
+            let line = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{cell:width$}"))
+                .join("  ");
+            line.trim_end().to_string() + "\n"
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example() -> Vec<PlanChangeOutput> {
+        vec![
+            PlanChangeOutput {
+                id: None,
+                name: "users".into(),
+                date: "2024-03-07T03:19:34Z".into(),
+                planner: "Jane <jane@example.com>".into(),
+                note: "Add users".into(),
+                tags: vec!["@v1.0".into()],
+            },
+            PlanChangeOutput {
+                id: None,
+                name: "posts".into(),
+                date: "2024-03-10T00:04:24Z".into(),
+                planner: "Jane <jane@example.com>".into(),
+                note: String::new(),
+                tags: vec![],
+            },
+        ]
+    }
+
+    #[test]
+    fn test_format_plan_oneline() {
+        assert_eq!(format_plan_oneline(&example()), "users (@v1.0)\nposts\n");
+    }
+
+    #[test]
+    fn test_format_plan_table() {
+        assert_eq!(
+            format_plan_table(&example()),
+            "\
+Name   Date                  Planner                  Tags   Note
+users  2024-03-07T03:19:34Z  Jane <jane@example.com>  @v1.0  Add users
+posts  2024-03-10T00:04:24Z  Jane <jane@example.com>
+"
+        );
+    }
+
+    #[test]
+    fn test_plan_json_skips_missing_id() {
+        let json = serde_json::to_value(&example()[1]).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "name": "posts",
+                "date": "2024-03-10T00:04:24Z",
+                "planner": "Jane <jane@example.com>",
+                "note": "",
+                "tags": [],
+            })
+        );
+    }
+
+    #[test]
+    fn test_event_output_git_commit() {
+        let date = DateTime::UNIX_EPOCH;
+        let row = EventRow {
+            event: Event::Deploy,
+            change_id: "abc".into(),
+            change: "users".into(),
+            project: "quitch".into(),
+            note: "Add users\n\nGit-Commit: 0123abcd\nGit-Branch: main".into(),
+            requires: String::new(),
+            conflicts: String::new(),
+            tags: String::new(),
+            committed_at: date,
+            committer_name: "Jane".into(),
+            committer_email: "jane@example.com".into(),
+            planned_at: date,
+            planner_name: "Jane".into(),
+            planner_email: "jane@example.com".into(),
+        };
+        let event = EventOutput::new(&row);
+        assert_eq!(event.note, "Add users");
+        assert_eq!(event.git_commit.as_deref(), Some("0123abcd"));
+        assert_eq!(event.git_branch.as_deref(), Some("main"));
+
+        let event = EventOutput::new(&EventRow {
+            note: "Add users".into(),
+            ..row
+        });
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["note"], "Add users");
+        assert!(json.get("git_commit").is_none());
+    }
+
+    #[test]
+    fn test_event_formats() {
+        let date = DateTime::UNIX_EPOCH;
+        let event = EventOutput::new(&EventRow {
+            event: Event::Deploy,
+            change_id: "abc".into(),
+            change: "posts".into(),
+            project: "quitch".into(),
+            note: "Add posts\nwith authors".into(),
+            requires: "users,roles".into(),
+            conflicts: String::new(),
+            tags: "@v1.0 @v1.1".into(),
+            committed_at: date,
+            committer_name: "Jane".into(),
+            committer_email: "jane@example.com".into(),
+            planned_at: date,
+            planner_name: "John".into(),
+            planner_email: "john@example.com".into(),
+        });
+        assert_eq!(
+            event.format(Format::Oneline),
+            "abc deploy quitch:posts Add posts\n"
+        );
+        assert_eq!(event.format(Format::Medium), event.format(Format::Text));
+        assert_eq!(
+            event.format(Format::Text),
+            "\
+Deploy abc
+Name:      posts
+Committer: Jane <jane@example.com>
+Date:      1970-01-01T00:00:00Z
+
+    Add posts
+    with authors
+
+"
+        );
+        assert_eq!(
+            event.format(Format::Long),
+            "\
+Deploy abc (@v1.0, @v1.1)
+Name:      posts
+Project:   quitch
+Planner:   John <john@example.com>
+Committer: Jane <jane@example.com>
+
+    Add posts
+    with authors
+
+"
+        );
+        assert_eq!(
+            event.format(Format::Full),
+            "\
+Deploy abc (@v1.0, @v1.1)
+Name:      posts
+Project:   quitch
+Requires:  users, roles
+Planner:   John <john@example.com>
+Planned:   1970-01-01T00:00:00Z
+Committer: Jane <jane@example.com>
+Committed: 1970-01-01T00:00:00Z
+
+    Add posts
+    with authors
+
+"
+        );
+    }
+
+    #[test]
+    fn test_pager_command() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |var: &str| {
+                vars.iter()
+                    .find(|(name, _)| *name == var)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+        assert_eq!(pager_command(env(&[])).as_deref(), Some("less"));
+        assert_eq!(
+            pager_command(env(&[("PAGER", "more"), ("SQITCH_PAGER", "less -S")])).as_deref(),
+            Some("less -S")
+        );
+        assert_eq!(pager_command(env(&[("QUITCH_PAGER", "cat")])), None);
+        assert_eq!(pager_command(env(&[("PAGER", "")])), None);
+    }
+
+    #[test]
+    fn test_status_summary() {
+        let mut status = StatusOutput {
+            database: "flipr".into(),
+            project: "flipr".into(),
+            last_deployed: None,
+            deployed: 1,
+            undeployed: 0,
+            undeployed_changes: vec![],
+            modified: 0,
+            modified_changes: vec![],
+            unknown: vec![],
+        };
+        assert_eq!(status.summary(), None);
+        status.modified = 1;
+        assert_eq!(status.summary().as_deref(), Some("1 modified"));
+        status.undeployed = 2;
+        status.unknown.push(UnknownChangeOutput {
+            id: "abc".into(),
+            name: "legacy".into(),
+            reason: "is not in the plan".into(),
+        });
+        assert_eq!(
+            status.summary().as_deref(),
+            Some("2 pending, 1 unknown, 1 modified")
+        );
+    }
+
+    #[test]
+    fn test_json_array() {
+        let items = [
+            serde_json::json!({"change": "users", "tags": ["@v1"]}),
+            serde_json::json!({"change": "flips", "tags": []}),
+        ];
+        for len in 0..=items.len() {
+            let mut array = JsonArray::default();
+            let mut text = String::new();
+            for item in &items[..len] {
+                text += &array.item(item).unwrap();
+            }
+            text += array.end();
+            assert_eq!(
+                text,
+                serde_json::to_string_pretty(&items[..len]).unwrap() + "\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_run_output_json() {
+        let output = RunOutput {
+            log_only: false,
+            changes: vec![ChangeOutcome {
+                id: "abc".into(),
+                name: "users".into(),
+                action: Action::Revert,
+                event: Some(Event::Fail),
+                error: Some("script failed".into()),
+            }],
+            error: Some("script failed".into()),
+        };
+        assert_eq!(
+            serde_json::to_value(&output).unwrap(),
+            serde_json::json!({
+                "log_only": false,
+                "changes": [{
+                    "id": "abc",
+                    "name": "users",
+                    "action": "revert",
+                    "event": "fail",
+                    "error": "script failed",
+                }],
+                "error": "script failed",
+            })
+        );
+    }
+}
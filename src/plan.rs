@@ -1,87 +1,332 @@
+use std::collections::HashMap;
+
 use indexmap::IndexMap;
-use itertools::Itertools;
 
-use crate::change::Change;
+use crate::{change::Change, error::Error, tag::Tag};
+
+/// Plan syntax version written by quitch, the latest one sqitch knows
+pub const SYNTAX_VERSION: &str = "1.0.0";
+
+/// Check the `%syntax-version` pragma of a plan.
+///
+/// Plans from older sqitch versions say `1.0.0-b2` or have no pragma at all,
+/// and all of them share the syntax of 1.0.0.
+fn check_syntax_version(version: Option<&str>) -> anyhow::Result<()> {
+    let Some(version) = version else {
+        return Ok(());
+    };
+    let major = version.split(['.', '-']).next().unwrap_or(version);
+    if major != "1" {
+        anyhow::bail!(
+            "unsupported plan syntax version {version}, quitch reads plans of version {SYNTAX_VERSION}"
+        );
+    }
+    Ok(())
+}
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Plan {
-    project: String,
-    changes: Vec<Change>,
+    /// Pragmas such as `project` and `uri`, in the order they were written
+    pragmas: IndexMap<String, String>,
+    entries: Vec<Entry>,
+    /// Computed from the entries every time they change
+    resolved: Resolved,
+}
+
+/// The changes of a plan with their IDs, so that they are hashed only once
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Resolved {
+    changes: Vec<FullChange>,
+    /// Index of the latest instance of each change name
+    by_name: HashMap<String, usize>,
+    by_id: HashMap<String, usize>,
+}
+
+/// A line in the plan after the pragmas, in plan order
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Entry {
+    Change(Change),
+    /// Tags apply to the closest preceding change
+    Tag(Tag),
+    /// An empty line, kept when writing the plan back
+    Blank,
+    /// A line starting with `#`, holding the text after it
+    Comment(String),
+}
+
+impl Entry {
+    pub fn format_line(&self) -> String {
+        match self {
+            Self::Change(change) => change.format_line(),
+            Self::Tag(tag) => tag.format_line(),
+            Self::Blank => String::new(),
+            Self::Comment(comment) => format!("#{comment}"),
+        }
+    }
 }
 
 impl Plan {
+    /// Create an empty plan for a new project
+    pub fn new(project: String) -> Self {
+        Self {
+            pragmas: IndexMap::from([
+                ("syntax-version".to_string(), SYNTAX_VERSION.to_string()),
+                ("project".to_string(), project),
+            ]),
+            entries: vec![Entry::Blank],
+            resolved: Resolved::default(),
+        }
+    }
+
     pub fn project(&self) -> &str {
-        &self.project
+        self.pragmas.get("project").map_or("", String::as_str)
+    }
+
+    /// URI from the `%uri` pragma, identifying the project across registries
+    pub fn uri(&self) -> Option<&str> {
+        self.pragmas.get("uri").map(String::as_str)
+    }
+
+    /// Pragmas such as `project` and `uri`, in the order they were written
+    pub fn pragmas(&self) -> &IndexMap<String, String> {
+        &self.pragmas
+    }
+
+    /// Lines after the pragmas, in plan order
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
     }
 
     pub fn is_empty(&self) -> bool {
-        self.changes.is_empty()
+        self.changes().next().is_none()
     }
 
-    pub fn parse(plan_string: &str) -> anyhow::Result<Self> {
-        let lines = plan_string.lines();
-        if lines.clone().next() != Some("%syntax-version=1.0.0") {
-            anyhow::bail!("Unsupported sqitch plan syntax");
+    pub fn changes(&self) -> impl Iterator<Item = &Change> + '_ {
+        self.entries.iter().filter_map(|entry| match entry {
+            Entry::Change(change) => Some(change),
+            _ => None,
+        })
+    }
+
+    pub fn tags(&self) -> impl Iterator<Item = &Tag> + '_ {
+        self.entries.iter().filter_map(|entry| match entry {
+            Entry::Tag(tag) => Some(tag),
+            _ => None,
+        })
+    }
+
+    /// Append a change to the plan.
+    ///
+    /// A change already in the plan can only be added again after a tag, as a
+    /// rework of it.
+    pub fn add_change(&mut self, change: Change) -> anyhow::Result<()> {
+        self.entries.push(Entry::Change(change));
+        if let Err(error) = self.refresh() {
+            self.entries.pop();
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    /// Remove the latest instance of a change from the plan along with its tags
+    pub fn remove_change(&mut self, name: &str) -> anyhow::Result<Change> {
+        let Some(change_idx) = self
+            .entries
+            .iter()
+            .rposition(|entry| matches!(entry, Entry::Change(change) if change.name == name))
+        else {
+            anyhow::bail!("change {name} does not exist in the plan");
+        };
+        let previous = self.entries.clone();
+        let Entry::Change(change) = self.entries.remove(change_idx) else {
+            unreachable!("found above");
+        };
+        // Blank lines stay where they are
+        let mut idx = change_idx;
+        while let Some(entry) = self.entries.get(idx) {
+            match entry {
+                Entry::Change(_) => break,
+                Entry::Tag(_) => {
+                    self.entries.remove(idx);
+                }
+                _ => idx += 1,
+            }
+        }
+        if let Err(error) = self.refresh() {
+            self.entries = previous;
+            return Err(error);
+        }
+        Ok(change)
+    }
+
+    /// The plan with other lines after the pragmas, failing if they don't make
+    /// a valid plan
+    pub fn with_entries(&self, entries: Vec<Entry>) -> anyhow::Result<Self> {
+        let mut plan = Self {
+            pragmas: self.pragmas.clone(),
+            entries,
+            resolved: Resolved::default(),
+        };
+        plan.refresh()?;
+        Ok(plan)
+    }
+
+    /// Append a tag on the last change in the plan
+    pub fn add_tag(&mut self, tag: Tag) -> anyhow::Result<()> {
+        if self.is_empty() {
+            anyhow::bail!("cannot tag an empty plan");
+        }
+        if self.tags().any(|t| t.name == tag.name) {
+            anyhow::bail!("tag @{} already exists in the plan", tag.name);
         }
+        self.entries.push(Entry::Tag(tag));
+        self.refresh()
+    }
 
-        // There are three types of lines:
-        // - Meta lines that start with %
+    pub fn parse(plan_string: &str) -> anyhow::Result<Self> {
+        // There are five types of lines:
+        // - Pragma lines that start with %
+        // - Comment lines that start with #
+        // - Tag lines that start with @
         // - Change lines
         // - Empty lines
+        let mut pragmas = IndexMap::new();
+        let mut entries = Vec::new();
+        for (idx, line) in plan_string.lines().enumerate() {
+            let line_error = |source| Error::Plan {
+                line: idx + 1,
+                source,
+            };
+            if let Some(pragma) = line.strip_prefix('%') {
+                let (key, value) = pragma.split_once('=').unwrap_or((pragma, ""));
+                pragmas.insert(key.trim().to_string(), value.trim().to_string());
+            } else if line.trim().is_empty() {
+                entries.push(Entry::Blank);
+            } else if let Some(comment) = line.trim_start().strip_prefix('#') {
+                entries.push(Entry::Comment(comment.to_string()));
+            } else if line.starts_with('@') {
+                entries.push(Entry::Tag(Tag::parse_line(line).map_err(line_error)?));
+            } else {
+                entries.push(Entry::Change(Change::parse_line(line).map_err(line_error)?));
+            }
+        }
+        check_syntax_version(pragmas.get("syntax-version").map(String::as_str))?;
+        let first = entries
+            .iter()
+            .find(|entry| matches!(entry, Entry::Change(_) | Entry::Tag(_)));
+        if let Some(Entry::Tag(tag)) = first {
+            anyhow::bail!("tag @{} must follow a change", tag.name);
+        }
+
+        let mut plan = Plan {
+            pragmas,
+            entries,
+            resolved: Resolved::default(),
+        };
+        plan.refresh()?;
+        Ok(plan)
+    }
 
-        // Parse meta lines
-        let meta_entries: IndexMap<&str, &str> = lines
-            .clone()
-            .filter_map(|line| line.strip_prefix('%'))
-            .map(|line| {
-                let mut parts = line.splitn(2, '=');
-                let key = parts
-                    .next()
-                    .expect("splitn always returns at least one element");
-                let value = parts.next().unwrap_or("");
-                (key, value)
-            })
-            .collect();
-        let project = meta_entries
-            .get("project")
-            .map_or_else(String::new, |s| s.to_string());
-
-        // Change lines are lines that aren't meta lines or empty
-        let changes: Vec<Change> = lines
-            .filter(|line| !line.is_empty() && !line.starts_with('%'))
-            .map(Change::parse_line)
-            .try_collect()?;
-
-        Ok(Plan { project, changes })
-    }
-
-    #[cfg(test)]
+    /// Names of the tags between each change and the next change with the same name.
+    ///
+    /// Scripts of a reworked change are suffixed with one of these tags. Fails if
+    /// a change is duplicated without a tag in between.
+    fn rework_tags(&self) -> anyhow::Result<Vec<Vec<String>>> {
+        let mut rework_tags = Vec::new();
+        let mut tag_names: Vec<&str> = Vec::new();
+        // Index of the last instance of each change and the number of tags at that point
+        let mut last_seen: HashMap<&str, (usize, usize)> = HashMap::new();
+        for entry in &self.entries {
+            match entry {
+                Entry::Change(change) => {
+                    let change_idx = rework_tags.len();
+                    if let Some((prev_idx, tags_seen)) =
+                        last_seen.insert(&change.name, (change_idx, tag_names.len()))
+                    {
+                        let tags_between = &tag_names[tags_seen..];
+                        if tags_between.is_empty() {
+                            anyhow::bail!(
+                                "change {} is duplicated without a tag in between",
+                                change.name
+                            );
+                        }
+                        rework_tags[prev_idx] =
+                            tags_between.iter().map(|t| t.to_string()).collect();
+                    }
+                    rework_tags.push(Vec::new());
+                }
+                Entry::Tag(tag) => tag_names.push(&tag.name),
+                Entry::Blank | Entry::Comment(_) => {}
+            }
+        }
+        Ok(rework_tags)
+    }
+
+    /// The plan file contents, with pragmas first and every other line,
+    /// comments included, where it was
     pub fn format(&self) -> String {
-        use std::iter::once;
-
-        let meta_lines = vec![
-            "%syntax-version=1.0.0".to_string(),
-            format!("%project={}", self.project),
-        ];
-        let change_lines = self.changes.iter().map(Change::format_line);
-        meta_lines
-            .into_iter()
-            .chain(once(String::new()))
-            .chain(change_lines)
-            .chain(once(String::new()))
-            .join("\n")
-    }
-
-    pub fn full_changes(&self) -> impl Iterator<Item = FullChange> + '_ {
+        let pragma_lines = self
+            .pragmas
+            .iter()
+            .map(|(key, value)| format!("%{key}={value}"));
+        let entry_lines = self.entries.iter().map(Entry::format_line);
+        pragma_lines
+            .chain(entry_lines)
+            .map(|line| line + "\n")
+            .collect()
+    }
+
+    /// Changes in plan order, with their IDs and tags
+    pub fn full_changes(&self) -> &[FullChange] {
+        &self.resolved.changes
+    }
+
+    /// The latest instance of a change with this name
+    pub fn change_by_name(&self, name: &str) -> Option<&FullChange> {
+        let idx = *self.resolved.by_name.get(name)?;
+        Some(&self.resolved.changes[idx])
+    }
+
+    pub fn change_by_id(&self, id: &str) -> Option<&FullChange> {
+        Some(&self.resolved.changes[self.change_index(id)?])
+    }
+
+    /// Position of a change in [`Self::full_changes`]
+    pub fn change_index(&self, id: &str) -> Option<usize> {
+        self.resolved.by_id.get(id).copied()
+    }
+
+    /// Compute the changes again after the entries changed, failing if they
+    /// no longer make a valid plan
+    fn refresh(&mut self) -> anyhow::Result<()> {
+        let mut rework_tags = self.rework_tags()?.into_iter();
+        let mut resolved = Resolved::default();
         let mut parent_id = None;
-        self.changes.iter().map(move |change| {
-            let change_id = change.id(&self.project, parent_id.clone());
-            FullChange {
+        let mut entries = self.entries.iter().peekable();
+        while let Some(entry) = entries.next() {
+            let Entry::Change(change) = entry else {
+                continue;
+            };
+            let mut tags = Vec::new();
+            while let Some(entry) = entries.next_if(|entry| !matches!(entry, Entry::Change(_))) {
+                if let Entry::Tag(tag) = entry {
+                    tags.push(tag.clone());
+                }
+            }
+            let change_id = change.id(self.project(), self.uri(), parent_id.clone());
+            let idx = resolved.changes.len();
+            resolved.by_name.insert(change.name.clone(), idx);
+            resolved.by_id.insert(change_id.clone(), idx);
+            resolved.changes.push(FullChange {
                 change: change.clone(),
                 id: change_id.clone(),
                 parent: parent_id.replace(change_id),
-            }
-        })
+                tags,
+                rework_tags: rework_tags.next().expect("one per change"),
+            });
+        }
+        self.resolved = resolved;
+        Ok(())
     }
 }
 
@@ -90,36 +335,64 @@ pub struct FullChange {
     pub change: Change,
     pub id: String,
     pub parent: Option<String>,
+    /// Tags applied to this change
+    pub tags: Vec<Tag>,
+    /// Tags between this change and its next rework, empty if it wasn't reworked
+    pub rework_tags: Vec<String>,
 }
 impl FullChange {
     pub fn name(&self) -> &str {
         &self.change.name
     }
+
+    /// Candidate script file names without the extension, in order of preference.
+    ///
+    /// Scripts of reworked changes are named `name@tag` after one of the rework tags.
+    pub fn script_names(&self) -> Vec<String> {
+        if self.rework_tags.is_empty() {
+            vec![self.name().to_string()]
+        } else {
+            self.rework_tags
+                .iter()
+                .map(|tag| format!("{}@{tag}", self.name()))
+                .collect()
+        }
+    }
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use std::str::FromStr;
 
     use chrono::DateTime;
 
-    use crate::change::tests::example as example_change;
+    use crate::change::{tests::example as example_change, Planner};
 
     use super::*;
 
     pub fn example() -> Plan {
-        Plan {
-            project: "quitch".into(),
-            changes: vec![
-                example_change(),
-                Change {
+        let mut plan = Plan {
+            pragmas: IndexMap::from([
+                ("syntax-version".into(), "1.0.0".into()),
+                ("project".into(), "quitch".into()),
+            ]),
+            entries: vec![
+                Entry::Blank,
+                Entry::Change(example_change()),
+                Entry::Change(Change {
                     date: DateTime::from_str("2024-03-10T00:04:24Z").unwrap(),
+                    written_date: None,
                     name: "change_num2".into(),
                     note: "Second change".into(),
-                    planner: "Ruslan Fadeev <github@kinrany.dev>".into(),
-                },
+                    planner: Planner::new("Ruslan Fadeev", "github@kinrany.dev"),
+                    requires: Vec::new(),
+                    conflicts: Vec::new(),
+                }),
             ],
-        }
+            resolved: Resolved::default(),
+        };
+        plan.refresh().unwrap();
+        plan
     }
 
     pub static EXAMPLE_STRING: &str = "\
@@ -142,10 +415,29 @@ mod tests {
         assert_eq!(plan, example());
     }
 
+    #[test]
+    fn test_parse_uri() {
+        let plan_string = "%syntax-version=1.0.0\n\
+            %project=quitch\n\
+            %uri=https://github.com/Kinrany/quitch\n\
+            \n";
+        let plan = Plan::parse(plan_string).unwrap();
+        assert_eq!(plan.uri(), Some("https://github.com/Kinrany/quitch"));
+        assert_eq!(plan.format(), plan_string);
+    }
+
+    #[test]
+    fn test_format_new() {
+        assert_eq!(
+            Plan::new("quitch".into()).format(),
+            "%syntax-version=1.0.0\n%project=quitch\n\n"
+        );
+    }
+
     #[test]
     fn test_full_changes() {
         let plan = example();
-        let full_changes: Vec<_> = plan.full_changes().collect();
+        let full_changes: Vec<_> = plan.full_changes().to_vec();
         assert_eq!(
             full_changes,
             vec![
@@ -153,18 +445,286 @@ mod tests {
                     change: example_change(),
                     id: "da41a550b0cba5bd3dffbf645032a98ae1136da5".into(),
                     parent: None,
+                    tags: vec![],
+                    rework_tags: vec![],
                 },
                 FullChange {
                     change: Change {
                         date: DateTime::from_str("2024-03-10T00:04:24Z").unwrap(),
+                        written_date: None,
                         name: "change_num2".into(),
                         note: "Second change".into(),
-                        planner: "Ruslan Fadeev <github@kinrany.dev>".into(),
+                        planner: Planner::new("Ruslan Fadeev", "github@kinrany.dev"),
+                        requires: Vec::new(),
+                        conflicts: Vec::new(),
                     },
                     id: "2959791f9fb4db4c322a9fdf121215d5e8a6a601".into(),
-                    parent: Some("da41a550b0cba5bd3dffbf645032a98ae1136da5".into())
+                    parent: Some("da41a550b0cba5bd3dffbf645032a98ae1136da5".into()),
+                    tags: vec![],
+                    rework_tags: vec![],
                 }
             ]
         );
     }
+
+    pub fn example_with_tag() -> Plan {
+        let mut plan = example();
+        plan.entries
+            .insert(2, Entry::Tag(crate::tag::tests::example()));
+        plan.refresh().unwrap();
+        plan
+    }
+
+    #[test]
+    fn test_parse_with_tag() {
+        let plan_string = format!(
+            "%syntax-version=1.0.0\n\
+            %project=quitch\n\
+            \n\
+            {}\n\
+            {}\n\
+            change_num2 2024-03-10T00:04:24Z Ruslan Fadeev <github@kinrany.dev> # Second change\n",
+            crate::change::tests::EXAMPLE_LINE,
+            crate::tag::tests::EXAMPLE_LINE,
+        );
+        let plan = Plan::parse(&plan_string).unwrap();
+        assert_eq!(plan, example_with_tag());
+    }
+
+    #[test]
+    fn test_syntax_versions() {
+        for header in [
+            "%syntax-version=1.0.0\n",
+            "%syntax-version=1.0.0-b2\n",
+            "% syntax-version = 1.0.0-b1\n",
+            "",
+        ] {
+            let plan_string = EXAMPLE_STRING.replace("%syntax-version=1.0.0\n", header);
+            let plan = Plan::parse(&plan_string).unwrap();
+            assert_eq!(plan.entries, example().entries, "{header}");
+        }
+        let error = Plan::parse(&EXAMPLE_STRING.replace("1.0.0", "2.0.0")).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "unsupported plan syntax version 2.0.0, quitch reads plans of version 1.0.0"
+        );
+    }
+
+    #[test]
+    fn test_uri_changes_ids() {
+        let with_uri = Plan::parse(&EXAMPLE_STRING.replace(
+            "%project=quitch\n",
+            "%project=quitch\n%uri=https://example.com/quitch\n",
+        ))
+        .unwrap();
+        assert_eq!(with_uri.uri(), Some("https://example.com/quitch"));
+        let parsed = Plan::parse(&with_uri.format()).unwrap();
+        assert_eq!(parsed, with_uri);
+
+        let ids = |plan: &Plan| {
+            plan.full_changes()
+                .iter()
+                .map(|c| c.id.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_ne!(ids(&with_uri), ids(&example()));
+    }
+
+    #[test]
+    fn test_format_plus_parse_with_tag() {
+        let plan_string = example_with_tag().format();
+        let plan = Plan::parse(&plan_string).unwrap();
+        assert_eq!(plan, example_with_tag());
+    }
+
+    #[test]
+    fn test_parse_tag_before_changes() {
+        let plan_string = format!(
+            "%syntax-version=1.0.0\n%project=quitch\n\n{}\n",
+            crate::tag::tests::EXAMPLE_LINE,
+        );
+        assert!(Plan::parse(&plan_string).is_err());
+    }
+
+    #[test]
+    fn test_parse_error_position() {
+        let plan_string = EXAMPLE_STRING.replace("kinrany.dev> # Second", "kinrany.dev # Second");
+        let error = Plan::parse(&plan_string).unwrap_err();
+        assert_eq!(
+            format!("{error:#}"),
+            "line 5 of the plan: column 48: missing > after the planner email"
+        );
+        assert!(matches!(
+            Error::of(&error),
+            Some(Error::Plan { line: 5, .. })
+        ));
+    }
+
+    #[test]
+    fn test_full_changes_with_tag() {
+        let full_changes: Vec<_> = example_with_tag().full_changes().to_vec();
+        assert_eq!(full_changes[0].tags, vec![crate::tag::tests::example()]);
+        assert!(full_changes[1].tags.is_empty());
+
+        // Tags don't affect change IDs
+        let untagged: Vec<_> = example().full_changes().to_vec();
+        assert_eq!(full_changes[0].id, untagged[0].id);
+        assert_eq!(full_changes[1].id, untagged[1].id);
+        assert_eq!(full_changes[1].parent, untagged[1].parent);
+    }
+
+    #[test]
+    fn test_add_tag() {
+        let mut plan = example();
+        let mut tag = crate::tag::tests::example();
+        plan.add_tag(tag.clone()).unwrap();
+        assert_eq!(plan.tags().count(), 1);
+
+        // Duplicate tag names are rejected
+        tag.note = "Again".into();
+        assert!(plan.add_tag(tag.clone()).is_err());
+
+        // Empty plans cannot be tagged
+        assert!(Plan::new("quitch".into()).add_tag(tag).is_err());
+    }
+
+    pub fn example_with_rework() -> Plan {
+        let mut plan = example_with_tag();
+        plan.add_change(Change {
+            date: DateTime::from_str("2024-03-11T00:00:00Z").unwrap(),
+            written_date: None,
+            note: "Reworked".into(),
+            ..example_change()
+        })
+        .unwrap();
+        plan
+    }
+
+    #[test]
+    fn test_format_plus_parse_with_rework() {
+        let plan_string = example_with_rework().format();
+        let plan = Plan::parse(&plan_string).unwrap();
+        assert_eq!(plan, example_with_rework());
+    }
+
+    #[test]
+    fn test_parse_duplicate_without_tag() {
+        let mut plan = example();
+        plan.entries.push(Entry::Change(example_change()));
+        assert!(Plan::parse(&plan.format()).is_err());
+    }
+
+    #[test]
+    fn test_full_changes_with_rework() {
+        let full_changes: Vec<_> = example_with_rework().full_changes().to_vec();
+        assert_eq!(full_changes.len(), 3);
+        assert_eq!(full_changes[0].rework_tags, vec!["v1.0".to_string()]);
+        assert_eq!(full_changes[0].script_names(), vec!["change_name@v1.0"]);
+        assert!(full_changes[1].rework_tags.is_empty());
+        assert!(full_changes[2].rework_tags.is_empty());
+        assert_eq!(full_changes[2].script_names(), vec!["change_name"]);
+        assert_ne!(full_changes[0].id, full_changes[2].id);
+        assert_eq!(full_changes[2].parent.as_ref(), Some(&full_changes[1].id));
+    }
+
+    #[test]
+    fn test_change_lookup() {
+        let plan = example_with_rework();
+        let full_changes = plan.full_changes();
+        assert_eq!(plan.change_by_name("change_name"), full_changes.get(2));
+        assert_eq!(plan.change_by_name("change_num2"), full_changes.get(1));
+        assert_eq!(plan.change_by_id(&full_changes[0].id), full_changes.first());
+        assert_eq!(plan.change_index(&full_changes[1].id), Some(1));
+        assert_eq!(plan.change_by_name("missing"), None);
+        assert_eq!(plan.change_index("missing"), None);
+    }
+
+    #[test]
+    fn test_format_keeps_layout() {
+        let plan_string = "%project=quitch\n\
+            %syntax-version=1.0.0\n\
+            %custom=value\n\
+            \n\
+            # Schema\n\
+            change_name 2024-03-07T03:19:34Z Ruslan Fadeev <github@kinrany.dev>\n\
+            #\n\
+            \n\
+            @v1.0 2024-03-08T10:00:00Z Ruslan Fadeev <github@kinrany.dev> # First release\n\
+            \n\
+            \n\
+            change_num2 2024-03-10T00:04:24Z Ruslan Fadeev <github@kinrany.dev> # Second change\n";
+        let plan = Plan::parse(plan_string).unwrap();
+        assert_eq!(plan.format(), plan_string);
+
+        let changes: Vec<_> = plan.full_changes().to_vec();
+        assert_eq!(changes[0].tags, [crate::tag::tests::example()]);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(plan.entries[1], Entry::Comment(" Schema".into()),);
+    }
+
+    #[test]
+    fn test_add_and_remove_change() {
+        let mut plan = example_with_tag();
+        let reworked = Change {
+            date: DateTime::from_str("2024-03-11T00:00:00Z").unwrap(),
+            ..example_change()
+        };
+        plan.add_change(reworked.clone()).unwrap();
+        assert_eq!(plan.full_changes().first().unwrap().rework_tags, ["v1.0"]);
+
+        // Reworking again needs another tag
+        let error = plan.add_change(reworked.clone()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "change change_name is duplicated without a tag in between"
+        );
+
+        assert_eq!(plan.remove_change("change_name").unwrap(), reworked);
+        assert_eq!(plan, example_with_tag());
+
+        assert!(plan.remove_change("missing").is_err());
+
+        // The tag goes with the change, which would leave the rework untagged
+        let mut plan = example();
+        plan.add_tag(crate::tag::tests::example()).unwrap();
+        plan.add_change(reworked).unwrap();
+        let before = plan.clone();
+        assert!(plan.remove_change("change_num2").is_err());
+        assert_eq!(plan, before);
+
+        let mut plan = example_with_tag();
+        assert_eq!(plan.remove_change("change_name").unwrap(), example_change());
+        assert!(plan.tags().next().is_none());
+        assert_eq!(
+            plan.format(),
+            "%syntax-version=1.0.0\n\
+            %project=quitch\n\
+            \n\
+            change_num2 2024-03-10T00:04:24Z Ruslan Fadeev <github@kinrany.dev> # Second change\n"
+        );
+    }
+
+    /// Every plan in `testdata/sqitch-ids` has its change IDs as sqitch
+    /// computes them in a `.ids` file next to it, written by `regenerate.sh`
+    #[test]
+    fn test_sqitch_change_ids() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/sqitch-ids");
+        let mut checked = 0;
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|ext| ext != "plan") {
+                continue;
+            }
+            let plan = Plan::parse(&std::fs::read_to_string(&path).unwrap()).unwrap();
+            let ids: String = plan
+                .full_changes()
+                .iter()
+                .map(|change| format!("{} {}\n", change.id, change.name()))
+                .collect();
+            let expected = std::fs::read_to_string(path.with_extension("ids")).unwrap();
+            assert_eq!(ids, expected, "{}", path.display());
+            checked += 1;
+        }
+        assert!(checked > 0);
+    }
 }
@@ -1,6 +1,22 @@
+//! Rows of the Sqitch registry tables, where they are stored and the checks
+//! quitch makes on them
+
+use std::{collections::HashSet, fmt::Display};
+
+use anyhow::bail;
 use chrono::{DateTime, Utc};
+use futures::{future::BoxFuture, stream::BoxStream, StreamExt, TryStreamExt};
+use itertools::Itertools;
+
+use crate::{
+    change::Planner,
+    change_ref::ChangeRef,
+    plan::{FullChange, Plan},
+};
+
+pub mod memory;
 
-#[derive(Clone, Debug, sqlx::FromRow)]
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize, sqlx::FromRow)]
 pub struct ChangeRow {
     pub change_id: String,
     pub script_hash: Option<String>,
@@ -15,3 +31,763 @@ pub struct ChangeRow {
     pub planner_name: String,
     pub planner_email: String,
 }
+
+/// Type of an event in the `events` table
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+    sqlx::Type,
+)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum Event {
+    Deploy,
+    Fail,
+    Merge,
+    Revert,
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Deploy => "Deploy",
+            Self::Fail => "Fail",
+            Self::Merge => "Merge",
+            Self::Revert => "Revert",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize, sqlx::FromRow)]
+pub struct EventRow {
+    pub event: Event,
+    pub change_id: String,
+    /// Name of the change
+    pub change: String,
+    pub project: String,
+    pub note: String,
+    pub requires: String,
+    pub conflicts: String,
+    pub tags: String,
+    pub committed_at: DateTime<Utc>,
+    pub committer_name: String,
+    pub committer_email: String,
+    pub planned_at: DateTime<Utc>,
+    pub planner_name: String,
+    pub planner_email: String,
+}
+
+/// Which events [`RegistryStore::fetch_events`] returns.
+///
+/// Patterns are regular expressions matched by the database, as sqitch does,
+/// so their syntax is that of the engine. SQL Server has none, so quitch
+/// matches them there with the syntax of the `regex` crate.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EventQuery {
+    /// Only events of this project
+    pub project: Option<String>,
+    /// Only events of projects whose names match this pattern
+    pub project_pattern: Option<String>,
+    /// Only events of changes whose names match this pattern
+    pub change_pattern: Option<String>,
+    /// Only events whose committer name matches this pattern
+    pub committer_pattern: Option<String>,
+    /// Only events of these types, or of any type if empty
+    pub event_types: Vec<Event>,
+    /// Only events committed at or after this time
+    pub since: Option<DateTime<Utc>>,
+    /// Only events committed before this time
+    pub until: Option<DateTime<Utc>>,
+    /// Number of matching events to leave out before the first returned
+    pub skip: u64,
+    pub limit: Option<u64>,
+    /// Oldest first
+    pub reverse: bool,
+    /// Only events after this one in the order of the query, to page through
+    /// them without counting the ones already seen like `skip` does
+    pub after: Option<EventCursor>,
+}
+
+/// Position of an event among the others: events are ordered by when they
+/// were committed, then by change ID, which together are their key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EventCursor {
+    pub committed_at: DateTime<Utc>,
+    pub change_id: String,
+}
+
+impl EventCursor {
+    pub fn of(event: &EventRow) -> Self {
+        Self {
+            committed_at: event.committed_at,
+            change_id: event.change_id.clone(),
+        }
+    }
+}
+
+impl EventQuery {
+    /// Every event of `project`, newest first
+    pub fn project(project: &str) -> Self {
+        Self {
+            project: Some(project.to_string()),
+            ..Self::default()
+        }
+    }
+}
+
+/// A row of the `tags` table
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize, sqlx::FromRow)]
+pub struct TagRow {
+    pub tag_id: String,
+    /// Name of the tag, with the leading `@`
+    pub tag: String,
+    pub project: String,
+    /// ID of the tagged change
+    pub change_id: String,
+    pub note: String,
+    pub committed_at: DateTime<Utc>,
+    pub committer_name: String,
+    pub committer_email: String,
+    pub planned_at: DateTime<Utc>,
+    pub planner_name: String,
+    pub planner_email: String,
+}
+
+/// A row of the `dependencies` table, see [`DependencyRow`] for inserting them
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize, sqlx::FromRow)]
+pub struct DeployedDependencyRow {
+    /// ID of the change that has the dependency
+    pub change_id: String,
+    /// `require` or `conflict`
+    #[sqlx(rename = "type")]
+    pub kind: String,
+    /// Name of the other change
+    pub dependency: String,
+    /// ID of the required change, none for conflicts
+    pub dependency_id: Option<String>,
+}
+
+/// A row of the `projects` table
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize, sqlx::FromRow)]
+pub struct ProjectRow {
+    pub project: String,
+    pub uri: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub creator_name: String,
+    pub creator_email: String,
+}
+
+/// Contents of the registry tables, as written by `quitch registry export`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct RegistryDump {
+    /// Release of the registry the rows were read from
+    pub registry_version: f32,
+    pub projects: Vec<ProjectRow>,
+    pub changes: Vec<ChangeRow>,
+    pub tags: Vec<TagRow>,
+    pub dependencies: Vec<DeployedDependencyRow>,
+    /// Oldest first
+    pub events: Vec<EventRow>,
+}
+
+/// Operations on the registry tables.
+///
+/// Implemented by every [`Engine`](crate::engine::Engine), and by
+/// [`memory::MemoryRegistry`] to test command logic without a database.
+pub trait RegistryStore: Send + Sync {
+    /// Latest release recorded in the registry, none without a `releases` table
+    fn registry_version(&self) -> BoxFuture<'_, anyhow::Result<Option<f32>>>;
+
+    /// Record that the registry was brought to `version` by `installer`
+    fn insert_release<'a>(
+        &'a self,
+        version: f32,
+        installer: &'a Planner,
+    ) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    fn begin(&self) -> BoxFuture<'_, anyhow::Result<()>>;
+
+    fn commit(&self) -> BoxFuture<'_, anyhow::Result<()>>;
+
+    fn rollback(&self) -> BoxFuture<'_, anyhow::Result<()>>;
+
+    /// Whether `error`, returned by the methods above, is a transient failure
+    /// such as a deadlock, after which the transaction can be run again
+    fn is_transient(&self, error: &anyhow::Error) -> bool;
+
+    /// Whether the registry is used from the session of the target, so that
+    /// its transactions are the target's
+    fn in_target_session(&self) -> bool;
+
+    fn fetch_projects(&self) -> BoxFuture<'_, anyhow::Result<Vec<ProjectRow>>>;
+
+    fn insert_project<'a>(
+        &'a self,
+        project: &'a str,
+        uri: Option<&'a str>,
+        committer: &'a Planner,
+    ) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    fn fetch_changes(&self) -> BoxFuture<'_, anyhow::Result<Vec<ChangeRow>>>;
+
+    /// Record a deployed change along with the hash of its deploy script
+    fn insert_change<'a>(
+        &'a self,
+        change: &'a FullChange,
+        script_hash: &'a str,
+        project: &'a str,
+        committer: &'a Planner,
+    ) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    /// Record the tags of a deployed change, with IDs depending on the project
+    /// and its URI
+    fn insert_tags<'a>(
+        &'a self,
+        change: &'a FullChange,
+        project: &'a str,
+        uri: Option<&'a str>,
+        committer: &'a Planner,
+    ) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    fn fetch_tags(&self) -> BoxFuture<'_, anyhow::Result<Vec<TagRow>>>;
+
+    fn insert_dependencies<'a>(
+        &'a self,
+        change_id: &'a str,
+        dependencies: &'a [DependencyRow],
+    ) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    fn fetch_dependencies(&self) -> BoxFuture<'_, anyhow::Result<Vec<DeployedDependencyRow>>>;
+
+    /// Deployed changes of any project that require the change
+    fn changes_requiring<'a>(
+        &'a self,
+        change_id: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<ChangeRow>>>;
+
+    /// Remove a change along with its tags and dependencies
+    fn delete_change<'a>(&'a self, change_id: &'a str) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    /// Remove a project along with its changes, tags, dependencies and events
+    fn delete_project<'a>(&'a self, project: &'a str) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    /// Record an event of a change, with `note` in place of the change note
+    /// if given
+    fn add_event<'a>(
+        &'a self,
+        event_type: Event,
+        change: &'a FullChange,
+        note: Option<&'a str>,
+        project: &'a str,
+        committer: &'a Planner,
+    ) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    /// Events matching `query`, newest first unless it is reversed
+    fn fetch_events<'a>(
+        &'a self,
+        query: &'a EventQuery,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<EventRow>>>;
+
+    /// Tags of a change according to its latest deploy event.
+    fn deployed_change_tags<'a>(
+        &'a self,
+        change_id: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<String>>;
+
+    /// Insert the rows of an exported registry as they are
+    fn import_rows<'a>(&'a self, dump: &'a RegistryDump) -> BoxFuture<'a, anyhow::Result<()>>;
+}
+
+/// Check a plan's project against the projects already in the registry.
+///
+/// Returns whether the project still has to be registered. A registry used by
+/// other projects is only shared with `force`.
+pub fn check_project(
+    projects: &[ProjectRow],
+    project: &str,
+    uri: Option<&str>,
+    force: bool,
+) -> anyhow::Result<bool> {
+    if let Some(row) = projects.iter().find(|row| row.project == project) {
+        if let (Some(registered), Some(uri)) = (&row.uri, uri) {
+            if registered != uri {
+                bail!(
+                    "project {project} is registered with URI {registered}, but the plan has {uri}"
+                );
+            }
+        }
+        return Ok(false);
+    }
+    if !projects.is_empty() && !force {
+        let others = projects.iter().map(|row| &row.project).join(", ");
+        bail!("the registry belongs to other projects ({others}), use --force to add {project}");
+    }
+    Ok(true)
+}
+
+/// Sqitch registry layout that quitch creates and expects, as recorded in the
+/// `releases` table
+pub const REGISTRY_VERSION: f32 = 1.1;
+
+/// Check the latest release recorded in a registry before using it
+pub fn check_registry_version(version: Option<f32>) -> anyhow::Result<()> {
+    match version {
+        None => bail!("the registry has no recorded release, run `quitch upgrade`"),
+        Some(version) if version < REGISTRY_VERSION => bail!(
+            "the registry is at version {version}, run `quitch upgrade` to bring it to {REGISTRY_VERSION}"
+        ),
+        Some(version) if version > REGISTRY_VERSION => bail!(
+            "the registry is at version {version}, which is newer than {REGISTRY_VERSION} supported by this quitch"
+        ),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Upgrade scripts still to be applied to a registry at `version`, in order
+pub fn pending_upgrades<S>(upgrades: &[(f32, S)], version: f32) -> impl Iterator<Item = &(f32, S)> {
+    upgrades.iter().filter(move |(to, _)| *to > version)
+}
+
+/// A row of the `dependencies` table
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DependencyRow {
+    /// `require` or `conflict`
+    pub kind: &'static str,
+    /// Name of the other change
+    pub dependency: String,
+    /// ID of the required change, none for conflicts
+    pub dependency_id: Option<String>,
+}
+
+/// Dependencies of a change, with required changes resolved to the latest
+/// instance of that name earlier in the plan, or for changes of other projects
+/// written as `project:change`, to the latest instance in `deployed`.
+pub fn dependency_rows(
+    plan: &Plan,
+    change: &FullChange,
+    deployed: &[ChangeRow],
+) -> Vec<DependencyRow> {
+    let all_changes = plan.full_changes();
+    let earlier = &all_changes[..plan.change_index(&change.id).unwrap_or(all_changes.len())];
+    let requires = change.change.requires.iter().map(|name| DependencyRow {
+        kind: "require",
+        dependency: name.clone(),
+        dependency_id: match split_project(plan, name) {
+            (None, name) => name
+                .parse::<ChangeRef>()
+                .ok()
+                .and_then(|reference| reference.resolve(earlier).ok())
+                .map(|c| c.id.clone()),
+            (Some(project), name) => {
+                other_project_change(deployed, project, name).map(|row| row.change_id.clone())
+            }
+        },
+    });
+    let conflicts = change.change.conflicts.iter().map(|name| DependencyRow {
+        kind: "conflict",
+        dependency: name.clone(),
+        dependency_id: None,
+    });
+    requires.chain(conflicts).collect()
+}
+
+/// Whether a change requires changes of other projects, which are looked up
+/// in the registry
+pub fn requires_other_projects(plan: &Plan, change: &FullChange) -> bool {
+    change
+        .change
+        .requires
+        .iter()
+        .any(|name| split_project(plan, name).0.is_some())
+}
+
+/// Split the optional `project:` prefix off a dependency, leaving none for the
+/// plan's own project
+pub(crate) fn split_project<'a>(plan: &Plan, dependency: &'a str) -> (Option<&'a str>, &'a str) {
+    match dependency.split_once(':') {
+        Some((project, name)) if project != plan.project() => (Some(project), name),
+        Some((_, name)) => (None, name),
+        None => (None, dependency),
+    }
+}
+
+/// Latest deployed change of another project with this name or ID
+fn other_project_change<'a>(
+    deployed: &'a [ChangeRow],
+    project: &str,
+    name_or_id: &str,
+) -> Option<&'a ChangeRow> {
+    deployed
+        .iter()
+        .filter(|row| row.project == project)
+        .filter(|row| row.change_id == name_or_id || row.change == name_or_id)
+        .max_by_key(|row| row.committed_at)
+}
+
+/// Check that the changes required by each change about to be deployed are
+/// deployed before it, and that the changes it conflicts with are not.
+///
+/// `deployed` holds the changes of every project in the registry.
+pub fn check_requires(
+    plan: &Plan,
+    deployed: &[ChangeRow],
+    changes: &[FullChange],
+) -> anyhow::Result<()> {
+    let mut deployed_ids: HashSet<_> = deployed.iter().map(|row| row.change_id.clone()).collect();
+    let mut deployed_names: HashSet<_> = deployed
+        .iter()
+        .map(|row| (row.project.clone(), row.change.clone()))
+        .collect();
+    for change in changes {
+        for dependency in dependency_rows(plan, change, deployed) {
+            let name = &dependency.dependency;
+            let (project, own_name) = split_project(plan, name);
+            match (dependency.kind, &dependency.dependency_id, project) {
+                ("require", Some(id), _) if deployed_ids.contains(id) => {}
+                ("require", _, Some(project)) => bail!(
+                    "{} requires {name}, which is not deployed in project {project}",
+                    change.name()
+                ),
+                ("require", Some(_), None) => {
+                    bail!("{} requires {name}, which is not deployed", change.name())
+                }
+                ("require", None, None) => bail!(
+                    "{} requires {name}, which is not earlier in the plan",
+                    change.name()
+                ),
+                _ => {
+                    let project = project.unwrap_or(plan.project()).to_string();
+                    let own_name = own_name.split('@').next().unwrap_or(own_name);
+                    if deployed_names.contains(&(project, own_name.to_string())) {
+                        bail!("{} conflicts with {name}, which is deployed", change.name());
+                    }
+                }
+            }
+        }
+        deployed_ids.insert(change.id.clone());
+        deployed_names.insert((plan.project().to_string(), change.name().to_string()));
+    }
+    Ok(())
+}
+
+/// Values of the `requires`, `conflicts` and `tags` columns of an event,
+/// formatted the way sqitch writes them
+pub fn event_lists(change: &FullChange) -> [String; 3] {
+    [
+        change.change.requires.join(","),
+        change.change.conflicts.join(","),
+        change
+            .tags
+            .iter()
+            .map(|tag| format!("@{}", tag.name))
+            .join(" "),
+    ]
+}
+
+/// Number of events [`events`] fetches at a time
+pub const EVENT_PAGE_SIZE: u64 = 500;
+
+/// Events matching `query` like [`RegistryStore::fetch_events`] returns them,
+/// but fetched `page_size` at a time as the stream is read, so that long
+/// histories are never all in memory.
+pub fn events(
+    registry: &dyn RegistryStore,
+    query: EventQuery,
+    page_size: u64,
+) -> BoxStream<'_, anyhow::Result<EventRow>> {
+    futures::stream::try_unfold(Some(query), move |query| async move {
+        let Some(mut query) = query else {
+            return anyhow::Ok(None);
+        };
+        let page_limit = query.limit.map_or(page_size, |limit| limit.min(page_size));
+        if page_limit == 0 {
+            return Ok(None);
+        }
+        let page = registry
+            .fetch_events(&EventQuery {
+                limit: Some(page_limit),
+                ..query.clone()
+            })
+            .await?;
+        let Some(last) = page.last() else {
+            return Ok(None);
+        };
+        // The cursor replaces the skip after the first page
+        query.after = Some(EventCursor::of(last));
+        query.skip = 0;
+        query.limit = query.limit.map(|limit| limit - page.len() as u64);
+        let next = (page.len() as u64 == page_limit).then_some(query);
+        Ok(Some((futures::stream::iter(page).map(Ok), next)))
+    })
+    .try_flatten()
+    .boxed()
+}
+
+/// Read every table of the registry, for `quitch registry export`
+pub async fn export_registry(registry: &dyn RegistryStore) -> anyhow::Result<RegistryDump> {
+    let projects = registry.fetch_projects().await?;
+    let mut events = Vec::new();
+    for project in &projects {
+        events.extend(
+            registry
+                .fetch_events(&EventQuery {
+                    reverse: true,
+                    ..EventQuery::project(&project.project)
+                })
+                .await?,
+        );
+    }
+    Ok(RegistryDump {
+        registry_version: REGISTRY_VERSION,
+        changes: registry.fetch_changes().await?,
+        tags: registry.fetch_tags().await?,
+        dependencies: registry.fetch_dependencies().await?,
+        projects,
+        events,
+    })
+}
+
+/// Fill a registry with no projects yet with the rows of an export
+pub async fn import_registry(
+    registry: &dyn RegistryStore,
+    dump: &RegistryDump,
+) -> anyhow::Result<()> {
+    if dump.registry_version != REGISTRY_VERSION {
+        bail!(
+            "the export is of a registry at version {}, but this quitch writes version \
+            {REGISTRY_VERSION}; export it again with the same version of quitch",
+            dump.registry_version
+        );
+    }
+    let existing = registry.fetch_projects().await?;
+    if !existing.is_empty() {
+        bail!(
+            "the registry already has projects {}, import into a new one",
+            existing.iter().map(|row| &row.project).join(", ")
+        );
+    }
+    registry.begin().await?;
+    if let Err(error) = registry.import_rows(dump).await {
+        let _ = registry.rollback().await;
+        return Err(error);
+    }
+    registry.commit().await
+}
+
+/// Note of a `fail` event: the change note followed by the error
+pub fn fail_note(change_note: &str, action: &str, error: &anyhow::Error) -> String {
+    let failure = format!("Failed to {action}: {error:#}");
+    if change_note.is_empty() {
+        failure
+    } else {
+        format!("{change_note}\n\n{failure}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::tests::{example, example_with_tag};
+
+    #[test]
+    fn test_dependency_rows() {
+        let plan = example();
+        let mut changes: Vec<_> = plan.full_changes().to_vec();
+        changes[1].change.requires = vec!["change_name".into(), "missing".into()];
+        changes[1].change.conflicts = vec!["other".into()];
+        assert_eq!(
+            dependency_rows(&plan, &changes[1], &[]),
+            [
+                DependencyRow {
+                    kind: "require",
+                    dependency: "change_name".into(),
+                    dependency_id: Some(changes[0].id.clone()),
+                },
+                DependencyRow {
+                    kind: "require",
+                    dependency: "missing".into(),
+                    dependency_id: None,
+                },
+                DependencyRow {
+                    kind: "conflict",
+                    dependency: "other".into(),
+                    dependency_id: None,
+                },
+            ]
+        );
+        assert_eq!(
+            event_lists(&changes[1]),
+            ["change_name,missing".to_string(), "other".into(), "".into()]
+        );
+    }
+
+    fn project_row(project: &str, uri: Option<&str>) -> ProjectRow {
+        ProjectRow {
+            project: project.into(),
+            uri: uri.map(Into::into),
+            created_at: DateTime::from_timestamp(0, 0).unwrap(),
+            creator_name: "quitch".into(),
+            creator_email: "quitch@quitch".into(),
+        }
+    }
+
+    #[test]
+    fn test_check_project() {
+        // New registry
+        assert!(check_project(&[], "quitch", None, false).unwrap());
+
+        // Already registered, with or without a URI
+        let registered = [project_row("quitch", Some("https://example.com/quitch"))];
+        assert!(!check_project(&registered, "quitch", None, false).unwrap());
+        assert!(!check_project(
+            &registered,
+            "quitch",
+            Some("https://example.com/quitch"),
+            false
+        )
+        .unwrap());
+        assert!(check_project(
+            &registered,
+            "quitch",
+            Some("https://example.com/other"),
+            true
+        )
+        .is_err());
+
+        // Registry of another project
+        let other = [project_row("other", None)];
+        assert!(check_project(&other, "quitch", None, false).is_err());
+        assert!(check_project(&other, "quitch", None, true).unwrap());
+    }
+
+    #[test]
+    fn test_registry_version() {
+        assert!(check_registry_version(Some(REGISTRY_VERSION)).is_ok());
+        assert!(check_registry_version(None).is_err());
+        assert!(check_registry_version(Some(1.0)).is_err());
+        assert!(check_registry_version(Some(1.2)).is_err());
+
+        static UPGRADES: [(f32, &str); 2] = [(1.0, "to 1.0"), (1.1, "to 1.1")];
+        let pending: Vec<_> = pending_upgrades(&UPGRADES, 1.0).collect();
+        assert_eq!(pending, [&(1.1, "to 1.1")]);
+        assert_eq!(pending_upgrades(&UPGRADES, 1.1).count(), 0);
+    }
+
+    #[test]
+    fn test_event_tags() {
+        let changes: Vec<_> = example_with_tag().full_changes().to_vec();
+        assert_eq!(event_lists(&changes[0])[2], "@v1.0");
+    }
+
+    #[test]
+    fn test_check_requires() {
+        let plan = example();
+        let mut changes: Vec<_> = plan.full_changes().to_vec();
+        changes[1].change.requires = vec!["change_name".into()];
+        assert!(check_requires(&plan, &[], &changes).is_ok());
+        assert!(check_requires(&plan, &[], &changes[1..]).is_err());
+
+        let deployed = ChangeRow {
+            change_id: changes[0].id.clone(),
+            script_hash: None,
+            change: changes[0].name().to_string(),
+            project: plan.project().to_string(),
+            note: String::new(),
+            committed_at: DateTime::from_timestamp(0, 0).unwrap(),
+            committer_name: "quitch".into(),
+            committer_email: "quitch@quitch".into(),
+            planned_at: changes[0].change.date,
+            planner_name: changes[0].change.planner.name.clone(),
+            planner_email: changes[0].change.planner.email.clone(),
+        };
+        assert!(check_requires(&plan, std::slice::from_ref(&deployed), &changes[1..]).is_ok());
+
+        // Changes of other projects are looked up among the deployed changes
+        changes[1].change.requires = vec!["platform:roles".into()];
+        let error = check_requires(&plan, std::slice::from_ref(&deployed), &changes).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "change_num2 requires platform:roles, which is not deployed in project platform"
+        );
+        let roles = ChangeRow {
+            change_id: "0000000000000000000000000000000000000000".into(),
+            change: "roles".into(),
+            project: "platform".into(),
+            ..deployed.clone()
+        };
+        assert!(check_requires(&plan, std::slice::from_ref(&roles), &changes).is_ok());
+        assert_eq!(
+            dependency_rows(&plan, &changes[1], std::slice::from_ref(&roles))[0].dependency_id,
+            Some(roles.change_id.clone())
+        );
+        assert!(requires_other_projects(&plan, &changes[1]));
+        assert!(!requires_other_projects(&plan, &changes[0]));
+
+        changes[1].change.requires = vec!["missing".into()];
+        assert!(check_requires(&plan, &[], &changes).is_err());
+
+        changes[1].change.requires = Vec::new();
+        changes[1].change.conflicts = vec!["change_name".into()];
+        let error = check_requires(&plan, &[deployed], &changes[1..]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "change_num2 conflicts with change_name, which is deployed"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_export_and_import() {
+        let source = memory::MemoryRegistry::new();
+        let committer = Planner::new("Jane", "jane@example.com");
+        let plan = example_with_tag();
+        source
+            .insert_project("quitch", None, &committer)
+            .await
+            .unwrap();
+        let change = &plan.full_changes()[0];
+        source
+            .insert_change(change, "hash", "quitch", &committer)
+            .await
+            .unwrap();
+        source
+            .insert_tags(change, "quitch", None, &committer)
+            .await
+            .unwrap();
+        source
+            .add_event(Event::Deploy, change, None, "quitch", &committer)
+            .await
+            .unwrap();
+
+        let exported = serde_json::to_string(&export_registry(&source).await.unwrap()).unwrap();
+        let dump: RegistryDump = serde_json::from_str(&exported).unwrap();
+        let target = memory::MemoryRegistry::new();
+        import_registry(&target, &dump).await.unwrap();
+        let reexported = serde_json::to_string(&export_registry(&target).await.unwrap()).unwrap();
+        assert_eq!(reexported, exported);
+        assert_eq!(dump.tags[0].tag, "@v1.0");
+        assert_eq!(dump.events[0].event, Event::Deploy);
+
+        let error = import_registry(&target, &dump).await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "the registry already has projects quitch, import into a new one"
+        );
+        let older = RegistryDump {
+            registry_version: 1.0,
+            ..dump
+        };
+        let error = import_registry(&memory::MemoryRegistry::new(), &older)
+            .await
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("the export is of a registry at version 1, "));
+    }
+}
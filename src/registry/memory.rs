@@ -0,0 +1,625 @@
+//! A registry kept in memory, for testing command logic without a database
+
+use std::sync::Mutex;
+
+use anyhow::bail;
+use chrono::Utc;
+use futures::{future::BoxFuture, FutureExt};
+use regex::Regex;
+
+use super::{
+    event_lists, ChangeRow, DependencyRow, DeployedDependencyRow, Event, EventQuery, EventRow,
+    ProjectRow, RegistryDump, RegistryStore, TagRow, REGISTRY_VERSION,
+};
+use crate::{change::Planner, plan::FullChange};
+
+/// Contents of the registry tables
+#[derive(Clone, Debug, Default)]
+struct Tables {
+    releases: Vec<f32>,
+    projects: Vec<ProjectRow>,
+    changes: Vec<ChangeRow>,
+    tags: Vec<TagRow>,
+    dependencies: Vec<DeployedDependencyRow>,
+    /// In the order they were added
+    events: Vec<EventRow>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    tables: Tables,
+    /// Tables as they were when the open transaction began
+    transaction: Option<Tables>,
+}
+
+/// A registry that lives as long as the value, starting out as freshly created
+#[derive(Debug)]
+pub struct MemoryRegistry {
+    state: Mutex<State>,
+}
+
+impl MemoryRegistry {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                tables: Tables {
+                    releases: vec![REGISTRY_VERSION],
+                    ..Tables::default()
+                },
+                transaction: None,
+            }),
+        }
+    }
+
+    fn with_tables<T>(&self, f: impl FnOnce(&mut Tables) -> T) -> T {
+        f(&mut self.state.lock().unwrap().tables)
+    }
+}
+
+impl Default for MemoryRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RegistryStore for MemoryRegistry {
+    fn registry_version(&self) -> BoxFuture<'_, anyhow::Result<Option<f32>>> {
+        let version = self.with_tables(|tables| tables.releases.last().copied());
+        async move { Ok(version) }.boxed()
+    }
+
+    fn insert_release<'a>(
+        &'a self,
+        version: f32,
+        _installer: &'a Planner,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        self.with_tables(|tables| tables.releases.push(version));
+        async { Ok(()) }.boxed()
+    }
+
+    fn begin(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        async move {
+            let mut state = self.state.lock().unwrap();
+            if state.transaction.is_some() {
+                bail!("a transaction is already open");
+            }
+            state.transaction = Some(state.tables.clone());
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn commit(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        async move {
+            match self.state.lock().unwrap().transaction.take() {
+                Some(_) => Ok(()),
+                None => bail!("no transaction to commit"),
+            }
+        }
+        .boxed()
+    }
+
+    fn rollback(&self) -> BoxFuture<'_, anyhow::Result<()>> {
+        async move {
+            let mut state = self.state.lock().unwrap();
+            match state.transaction.take() {
+                Some(tables) => {
+                    state.tables = tables;
+                    Ok(())
+                }
+                None => bail!("no transaction to roll back"),
+            }
+        }
+        .boxed()
+    }
+
+    fn is_transient(&self, _error: &anyhow::Error) -> bool {
+        false
+    }
+
+    fn in_target_session(&self) -> bool {
+        false
+    }
+
+    fn fetch_projects(&self) -> BoxFuture<'_, anyhow::Result<Vec<ProjectRow>>> {
+        let projects = self.with_tables(|tables| tables.projects.clone());
+        async move { Ok(projects) }.boxed()
+    }
+
+    fn insert_project<'a>(
+        &'a self,
+        project: &'a str,
+        uri: Option<&'a str>,
+        committer: &'a Planner,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        self.with_tables(|tables| {
+            tables.projects.push(ProjectRow {
+                project: project.to_string(),
+                uri: uri.map(Into::into),
+                created_at: Utc::now(),
+                creator_name: committer.name.clone(),
+                creator_email: committer.email.clone(),
+            })
+        });
+        async { Ok(()) }.boxed()
+    }
+
+    fn fetch_changes(&self) -> BoxFuture<'_, anyhow::Result<Vec<ChangeRow>>> {
+        let changes = self.with_tables(|tables| tables.changes.clone());
+        async move { Ok(changes) }.boxed()
+    }
+
+    fn insert_change<'a>(
+        &'a self,
+        change: &'a FullChange,
+        script_hash: &'a str,
+        project: &'a str,
+        committer: &'a Planner,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        async move {
+            self.with_tables(|tables| {
+                if tables.changes.iter().any(|row| row.change_id == change.id) {
+                    bail!("change {} is already deployed", change.id);
+                }
+                tables.changes.push(ChangeRow {
+                    change_id: change.id.clone(),
+                    script_hash: Some(script_hash.to_string()),
+                    change: change.name().to_string(),
+                    project: project.to_string(),
+                    note: change.change.note.clone(),
+                    committed_at: Utc::now(),
+                    committer_name: committer.name.clone(),
+                    committer_email: committer.email.clone(),
+                    planned_at: change.change.date,
+                    planner_name: change.change.planner.name.clone(),
+                    planner_email: change.change.planner.email.clone(),
+                });
+                Ok(())
+            })
+        }
+        .boxed()
+    }
+
+    fn fetch_tags(&self) -> BoxFuture<'_, anyhow::Result<Vec<TagRow>>> {
+        let tags = self.with_tables(|tables| tables.tags.clone());
+        async move { Ok(tags) }.boxed()
+    }
+
+    fn insert_tags<'a>(
+        &'a self,
+        change: &'a FullChange,
+        project: &'a str,
+        uri: Option<&'a str>,
+        committer: &'a Planner,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        self.with_tables(|tables| {
+            for tag in &change.tags {
+                tables.tags.push(TagRow {
+                    tag_id: tag.id(project, uri, &change.id),
+                    tag: format!("@{}", tag.name),
+                    project: project.to_string(),
+                    change_id: change.id.clone(),
+                    note: tag.note.clone(),
+                    committed_at: Utc::now(),
+                    committer_name: committer.name.clone(),
+                    committer_email: committer.email.clone(),
+                    planned_at: tag.date,
+                    planner_name: tag.planner.name.clone(),
+                    planner_email: tag.planner.email.clone(),
+                });
+            }
+        });
+        async { Ok(()) }.boxed()
+    }
+
+    fn insert_dependencies<'a>(
+        &'a self,
+        change_id: &'a str,
+        dependencies: &'a [DependencyRow],
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        self.with_tables(|tables| {
+            for dependency in dependencies {
+                tables.dependencies.push(DeployedDependencyRow {
+                    change_id: change_id.to_string(),
+                    kind: dependency.kind.to_string(),
+                    dependency: dependency.dependency.clone(),
+                    dependency_id: dependency.dependency_id.clone(),
+                });
+            }
+        });
+        async { Ok(()) }.boxed()
+    }
+
+    fn fetch_dependencies(&self) -> BoxFuture<'_, anyhow::Result<Vec<DeployedDependencyRow>>> {
+        let dependencies = self.with_tables(|tables| tables.dependencies.clone());
+        async move { Ok(dependencies) }.boxed()
+    }
+
+    fn changes_requiring<'a>(
+        &'a self,
+        change_id: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<ChangeRow>>> {
+        let changes = self.with_tables(|tables| {
+            tables
+                .changes
+                .iter()
+                .filter(|row| {
+                    tables.dependencies.iter().any(|dependency| {
+                        dependency.change_id == row.change_id
+                            && dependency.kind == "require"
+                            && dependency.dependency_id.as_deref() == Some(change_id)
+                    })
+                })
+                .cloned()
+                .collect()
+        });
+        async move { Ok(changes) }.boxed()
+    }
+
+    fn delete_change<'a>(&'a self, change_id: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        self.with_tables(|tables| {
+            tables.dependencies.retain(|row| row.change_id != change_id);
+            tables.tags.retain(|row| row.change_id != change_id);
+            tables.changes.retain(|row| row.change_id != change_id);
+        });
+        async { Ok(()) }.boxed()
+    }
+
+    fn delete_project<'a>(&'a self, project: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        self.with_tables(|tables| {
+            let change_ids: Vec<_> = tables
+                .changes
+                .iter()
+                .filter(|row| row.project == project)
+                .map(|row| row.change_id.clone())
+                .collect();
+            tables
+                .dependencies
+                .retain(|row| !change_ids.contains(&row.change_id));
+            tables.tags.retain(|row| row.project != project);
+            tables.changes.retain(|row| row.project != project);
+            tables.events.retain(|row| row.project != project);
+            tables.projects.retain(|row| row.project != project);
+        });
+        async { Ok(()) }.boxed()
+    }
+
+    fn add_event<'a>(
+        &'a self,
+        event_type: Event,
+        change: &'a FullChange,
+        note: Option<&'a str>,
+        project: &'a str,
+        committer: &'a Planner,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        let [requires, conflicts, tags] = event_lists(change);
+        self.with_tables(|tables| {
+            tables.events.push(EventRow {
+                event: event_type,
+                change_id: change.id.clone(),
+                change: change.name().to_string(),
+                project: project.to_string(),
+                note: note.unwrap_or(&change.change.note).to_string(),
+                requires,
+                conflicts,
+                tags,
+                committed_at: Utc::now(),
+                committer_name: committer.name.clone(),
+                committer_email: committer.email.clone(),
+                planned_at: change.change.date,
+                planner_name: change.change.planner.name.clone(),
+                planner_email: change.change.planner.email.clone(),
+            })
+        });
+        async { Ok(()) }.boxed()
+    }
+
+    fn fetch_events<'a>(
+        &'a self,
+        query: &'a EventQuery,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<EventRow>>> {
+        async move {
+            let project_pattern = compile(&query.project_pattern)?;
+            let change_pattern = compile(&query.change_pattern)?;
+            let committer_pattern = compile(&query.committer_pattern)?;
+            let matches = |pattern: &Option<Regex>, value: &str| {
+                pattern
+                    .as_ref()
+                    .is_none_or(|pattern| pattern.is_match(value))
+            };
+            let mut events: Vec<_> = self.with_tables(|tables| {
+                tables
+                    .events
+                    .iter()
+                    .filter(|row| {
+                        query
+                            .project
+                            .as_ref()
+                            .is_none_or(|project| row.project == *project)
+                            && matches(&project_pattern, &row.project)
+                            && matches(&change_pattern, &row.change)
+                            && matches(&committer_pattern, &row.committer_name)
+                            && (query.event_types.is_empty()
+                                || query.event_types.contains(&row.event))
+                            && query.since.is_none_or(|since| row.committed_at >= since)
+                            && query.until.is_none_or(|until| row.committed_at < until)
+                            && query.after.as_ref().is_none_or(|after| {
+                                let position = (&row.committed_at, &row.change_id);
+                                let after = (&after.committed_at, &after.change_id);
+                                if query.reverse {
+                                    position > after
+                                } else {
+                                    position < after
+                                }
+                            })
+                    })
+                    .cloned()
+                    .collect()
+            });
+            // Newest first unless reversed, like the database engines
+            events.sort_by(|a, b| {
+                (a.committed_at, &a.change_id).cmp(&(b.committed_at, &b.change_id))
+            });
+            if !query.reverse {
+                events.reverse();
+            }
+            let limit = query.limit.map_or(usize::MAX, |limit| limit as usize);
+            Ok(events
+                .into_iter()
+                .skip(query.skip as usize)
+                .take(limit)
+                .collect())
+        }
+        .boxed()
+    }
+
+    fn deployed_change_tags<'a>(
+        &'a self,
+        change_id: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<String>> {
+        let tags = self.with_tables(|tables| {
+            tables
+                .events
+                .iter()
+                .rev()
+                .find(|row| row.change_id == change_id && row.event == Event::Deploy)
+                .map(|row| row.tags.clone())
+                .unwrap_or_default()
+        });
+        async move { Ok(tags) }.boxed()
+    }
+
+    fn import_rows<'a>(&'a self, dump: &'a RegistryDump) -> BoxFuture<'a, anyhow::Result<()>> {
+        self.with_tables(|tables| {
+            tables.projects.extend(dump.projects.iter().cloned());
+            tables.changes.extend(dump.changes.iter().cloned());
+            tables.tags.extend(dump.tags.iter().cloned());
+            tables
+                .dependencies
+                .extend(dump.dependencies.iter().cloned());
+            tables.events.extend(dump.events.iter().cloned());
+        });
+        async { Ok(()) }.boxed()
+    }
+}
+
+/// A pattern of an [`EventQuery`], in place of the regular expressions of
+/// the database engines
+fn compile(pattern: &Option<String>) -> anyhow::Result<Option<Regex>> {
+    Ok(pattern.as_deref().map(Regex::new).transpose()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{plan::tests::example_with_tag, registry::events};
+
+    #[tokio::test]
+    async fn rollback_undoes_the_transaction() {
+        let registry = MemoryRegistry::new();
+        let changes: Vec<_> = example_with_tag().full_changes().to_vec();
+        let committer = Planner::new("Jane", "jane@example.com");
+        registry
+            .insert_change(&changes[0], "hash", "quitch", &committer)
+            .await
+            .unwrap();
+        registry
+            .add_event(Event::Deploy, &changes[0], None, "quitch", &committer)
+            .await
+            .unwrap();
+
+        registry.begin().await.unwrap();
+        registry.delete_change(&changes[0].id).await.unwrap();
+        assert!(registry.fetch_changes().await.unwrap().is_empty());
+        registry.rollback().await.unwrap();
+
+        let rows = registry.fetch_changes().await.unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].script_hash.as_deref(), Some("hash"));
+        assert_eq!(rows[0].planner_name, "Ruslan Fadeev");
+        assert_eq!(rows[0].planner_email, "github@kinrany.dev");
+        assert_eq!(rows[0].committer_name, "Jane");
+        assert_eq!(rows[0].committer_email, "jane@example.com");
+        assert_eq!(
+            registry.deployed_change_tags(&changes[0].id).await.unwrap(),
+            "@v1.0"
+        );
+        assert!(registry.commit().await.is_err());
+        assert_eq!(
+            registry.registry_version().await.unwrap(),
+            Some(REGISTRY_VERSION)
+        );
+    }
+
+    #[tokio::test]
+    async fn delete_project_keeps_other_projects() {
+        let registry = MemoryRegistry::new();
+        let changes: Vec<_> = example_with_tag().full_changes().to_vec();
+        let committer = Planner::new("Jane", "jane@example.com");
+        for (project, change) in [("quitch", &changes[0]), ("other", &changes[1])] {
+            registry
+                .insert_project(project, None, &committer)
+                .await
+                .unwrap();
+            registry
+                .insert_change(change, "hash", project, &committer)
+                .await
+                .unwrap();
+            registry
+                .insert_tags(change, project, None, &committer)
+                .await
+                .unwrap();
+            registry
+                .add_event(Event::Deploy, change, None, project, &committer)
+                .await
+                .unwrap();
+        }
+
+        registry.delete_project("quitch").await.unwrap();
+        let projects = registry.fetch_projects().await.unwrap();
+        assert_eq!(projects.len(), 1);
+        assert_eq!(projects[0].project, "other");
+        assert_eq!(registry.fetch_changes().await.unwrap().len(), 1);
+        assert!(registry.fetch_tags().await.unwrap().is_empty());
+        assert!(registry
+            .fetch_events(&EventQuery::project("quitch"))
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn fetch_events_filters() {
+        let registry = MemoryRegistry::new();
+        let changes = example_with_tag().full_changes().to_vec();
+        let jane = Planner::new("Jane", "jane@example.com");
+        let john = Planner::new("John", "john@example.com");
+        for (change, committer) in changes.iter().zip([&jane, &john]) {
+            registry
+                .add_event(Event::Deploy, change, None, "quitch", committer)
+                .await
+                .unwrap();
+        }
+        registry
+            .add_event(Event::Revert, &changes[1], None, "quitch", &jane)
+            .await
+            .unwrap();
+        let names = |events: Vec<EventRow>| {
+            events
+                .into_iter()
+                .map(|event| format!("{} {}", event.event, event.change))
+                .collect::<Vec<_>>()
+        };
+        let fetch = async |query: EventQuery| names(registry.fetch_events(&query).await.unwrap());
+        let all = EventQuery::project("quitch");
+
+        let newest_first = fetch(all.clone()).await;
+        assert_eq!(newest_first.len(), 3);
+        assert_eq!(
+            fetch(EventQuery {
+                skip: 1,
+                limit: Some(1),
+                ..all.clone()
+            })
+            .await,
+            newest_first[1..2]
+        );
+        assert_eq!(
+            fetch(EventQuery {
+                committer_pattern: Some("^Jo".to_string()),
+                ..all.clone()
+            })
+            .await,
+            [format!("Deploy {}", changes[1].name())]
+        );
+        assert_eq!(
+            fetch(EventQuery {
+                project_pattern: Some("quit".to_string()),
+                event_types: vec![Event::Revert],
+                ..EventQuery::default()
+            })
+            .await,
+            [format!("Revert {}", changes[1].name())]
+        );
+        let later = Utc::now() + chrono::Duration::hours(1);
+        assert!(fetch(EventQuery {
+            since: Some(later),
+            ..all.clone()
+        })
+        .await
+        .is_empty());
+        assert_eq!(
+            fetch(EventQuery {
+                until: Some(later),
+                ..all.clone()
+            })
+            .await,
+            newest_first
+        );
+        assert!(registry
+            .fetch_events(&EventQuery {
+                change_pattern: Some("(".to_string()),
+                ..all
+            })
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn events_are_fetched_in_pages() {
+        use futures::TryStreamExt;
+
+        let registry = MemoryRegistry::new();
+        let changes = example_with_tag().full_changes().to_vec();
+        let committer = Planner::new("Jane", "jane@example.com");
+        for event in [Event::Deploy, Event::Revert, Event::Deploy] {
+            for change in &changes {
+                registry
+                    .add_event(event, change, None, "quitch", &committer)
+                    .await
+                    .unwrap();
+            }
+        }
+        // Events committed at once are told apart by change ID
+        let now = Utc::now();
+        registry.with_tables(|tables| {
+            for row in &mut tables.events[..changes.len()] {
+                row.committed_at = now;
+            }
+        });
+        let keys = |events: Vec<EventRow>| {
+            events
+                .into_iter()
+                .map(|event| (event.committed_at, event.change_id))
+                .collect::<Vec<_>>()
+        };
+
+        let all = EventQuery::project("quitch");
+        for query in [
+            all.clone(),
+            EventQuery {
+                reverse: true,
+                ..all.clone()
+            },
+            EventQuery {
+                skip: 1,
+                limit: Some(4),
+                ..all.clone()
+            },
+            EventQuery {
+                limit: Some(2),
+                ..all.clone()
+            },
+        ] {
+            let fetched = registry.fetch_events(&query).await.unwrap();
+            for page_size in [1, 2, 4, 10] {
+                let streamed: Vec<_> = events(&registry, query.clone(), page_size)
+                    .try_collect()
+                    .await
+                    .unwrap();
+                assert_eq!(keys(streamed), keys(fetched.clone()), "{query:?}");
+            }
+        }
+        assert_eq!(registry.fetch_events(&all).await.unwrap().len(), 6);
+    }
+}
@@ -0,0 +1,10 @@
+-- Kept apart from registry_schema.sql so that `quitch upgrade` can add it to
+-- registries created before releases were recorded
+
+CREATE TABLE IF NOT EXISTS `releases` (
+  `version` float(4,1) NOT NULL COMMENT 'Version of the Sqitch registry.',
+  `installed_at` datetime(6) NOT NULL COMMENT 'Date the registry release was installed.',
+  `installer_name` varchar(255) NOT NULL COMMENT 'Name of the user who installed the registry release.',
+  `installer_email` varchar(255) NOT NULL COMMENT 'Email address of the user who installed the registry release.',
+  PRIMARY KEY (`version`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb3 COLLATE=utf8mb3_general_ci COMMENT='Sqitch registry releases.';
@@ -0,0 +1,9 @@
+-- Kept apart from registry_schema_mssql.sql like registry_releases_pg.sql
+
+IF OBJECT_ID(QUOTENAME(N:'registry') + N'.releases', N'U') IS NULL
+  CREATE TABLE :"registry".releases (
+    version         REAL          NOT NULL PRIMARY KEY,
+    installed_at    DATETIME2     NOT NULL DEFAULT SYSUTCDATETIME(),
+    installer_name  NVARCHAR(255) NOT NULL,
+    installer_email NVARCHAR(255) NOT NULL
+  );
@@ -0,0 +1,11 @@
+-- Kept apart from registry_schema_pg.sql so that `quitch upgrade` can add it to
+-- registries created before releases were recorded
+
+CREATE TABLE IF NOT EXISTS releases (
+  version         REAL        PRIMARY KEY,
+  installed_at    TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp(),
+  installer_name  TEXT        NOT NULL,
+  installer_email TEXT        NOT NULL
+);
+
+COMMENT ON TABLE releases IS 'Sqitch registry releases.';
@@ -1,6 +1,16 @@
 -- Generated by DBeaver from a database created by sqitch
 
-CREATE TABLE `changes` (
+CREATE TABLE IF NOT EXISTS `projects` (
+  `project` varchar(255) NOT NULL COMMENT 'Unique Name of a project.',
+  `uri` varchar(255) DEFAULT NULL COMMENT 'Optional project URI',
+  `created_at` datetime(6) NOT NULL COMMENT 'Date the project was added to the database.',
+  `creator_name` varchar(255) NOT NULL COMMENT 'Name of the user who added the project.',
+  `creator_email` varchar(255) NOT NULL COMMENT 'Email address of the user who added the project.',
+  PRIMARY KEY (`project`),
+  UNIQUE KEY `uri` (`uri`)
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb3 COLLATE=utf8mb3_general_ci COMMENT='Sqitch projects deployed to this database.';
+
+CREATE TABLE IF NOT EXISTS `changes` (
   `change_id` varchar(40) NOT NULL COMMENT 'Change primary key.',
   `script_hash` varchar(40) DEFAULT NULL COMMENT 'Deploy script SHA-1 hash.',
   `change` varchar(255) NOT NULL COMMENT 'Name of a deployed change.',
@@ -16,7 +26,7 @@ CREATE TABLE `changes` (
   UNIQUE KEY `project` (`project`,`script_hash`)
 ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb3 COLLATE=utf8mb3_general_ci COMMENT='Tracks the changes currently deployed to the database.';
 
-CREATE TABLE `events` (
+CREATE TABLE IF NOT EXISTS `events` (
   `event` enum('deploy','fail','merge','revert') NOT NULL COMMENT 'Type of event.',
   `change_id` varchar(40) NOT NULL COMMENT 'Change ID.',
   `change` varchar(255) NOT NULL COMMENT 'Change name.',
@@ -25,7 +35,7 @@ CREATE TABLE `events` (
   `requires` text NOT NULL COMMENT 'List of the names of required changes.',
   `conflicts` text NOT NULL COMMENT 'List of the names of conflicting changes.',
   `tags` text NOT NULL COMMENT 'List of tags associated with the change.',
-  `committed_at` datetime NOT NULL COMMENT 'Date the event was committed.',
+  `committed_at` datetime(6) NOT NULL COMMENT 'Date the event was committed.',
   `committer_name` varchar(255) NOT NULL COMMENT 'Name of the user who committed the event.',
   `committer_email` varchar(255) NOT NULL COMMENT 'Email address of the user who committed the event.',
   `planned_at` datetime NOT NULL COMMENT 'Date the event was added to the plan.',
@@ -33,3 +43,32 @@ CREATE TABLE `events` (
   `planner_email` varchar(255) NOT NULL COMMENT 'Email address of the user who plan planned the change.',
   PRIMARY KEY (`change_id`,`committed_at`)
 ) ENGINE=InnoDB DEFAULT CHARSET=utf8mb3 COLLATE=utf8mb3_general_ci COMMENT='Contains full history of all deployment events.';
+
+CREATE TABLE IF NOT EXISTS `tags` (
+  `tag_id` varchar(40) NOT NULL COMMENT 'Tag primary key.',
+  `tag` varchar(255) NOT NULL COMMENT 'Project-unique tag name.',
+  `project` varchar(255) NOT NULL COMMENT 'Name of the Sqitch project to which the tag belongs.',
+  `change_id` varchar(40) NOT NULL COMMENT 'ID of last change deployed before the tag was applied.',
+  `note` varchar(255) NOT NULL DEFAULT '' COMMENT 'Description of the tag.',
+  `committed_at` datetime(6) NOT NULL COMMENT 'Date the tag was applied to the database.',
+  `committer_name` varchar(255) NOT NULL COMMENT 'Name of the user who applied the tag.',
+  `committer_email` varchar(255) NOT NULL COMMENT 'Email address of the user who applied the tag.',
+  `planned_at` datetime NOT NULL COMMENT 'Date the tag was added to the plan.',
+  `planner_name` varchar(255) NOT NULL COMMENT 'Name of the user who planed the tag.',
+  `planner_email` varchar(255) NOT NULL COMMENT 'Email address of the user who planned the tag.',
+  PRIMARY KEY (`tag_id`),
+  UNIQUE KEY `project` (`project`,`tag`),
+  KEY `change_id` (`change_id`),
+  CONSTRAINT `tags_ibfk_1` FOREIGN KEY (`change_id`) REFERENCES `changes` (`change_id`) ON UPDATE CASCADE
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb3 COLLATE=utf8mb3_general_ci COMMENT='Tracks the tags currently applied to the database.';
+
+CREATE TABLE IF NOT EXISTS `dependencies` (
+  `change_id` varchar(40) NOT NULL COMMENT 'ID of the depending change.',
+  `type` varchar(8) NOT NULL COMMENT 'Type of dependency.',
+  `dependency` varchar(255) NOT NULL COMMENT 'Dependency name.',
+  `dependency_id` varchar(40) DEFAULT NULL COMMENT 'Change ID the dependency resolves to.',
+  PRIMARY KEY (`change_id`,`dependency`),
+  KEY `dependency_id` (`dependency_id`),
+  CONSTRAINT `dependencies_ibfk_1` FOREIGN KEY (`change_id`) REFERENCES `changes` (`change_id`) ON DELETE CASCADE ON UPDATE CASCADE,
+  CONSTRAINT `dependencies_ibfk_2` FOREIGN KEY (`dependency_id`) REFERENCES `changes` (`change_id`) ON UPDATE CASCADE
+) ENGINE=InnoDB DEFAULT CHARSET=utf8mb3 COLLATE=utf8mb3_general_ci COMMENT='Tracks the currently satisfied dependencies.';
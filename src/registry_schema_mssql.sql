@@ -0,0 +1,80 @@
+-- Adapted from registry_schema_pg.sql for SQL Server, with the registry schema
+-- filled in for the `registry` placeholders. Unique columns that may be null
+-- get filtered indexes, since a UNIQUE constraint allows a single null.
+
+IF OBJECT_ID(QUOTENAME(N:'registry') + N'.projects', N'U') IS NULL
+BEGIN
+  CREATE TABLE :"registry".projects (
+    project         NVARCHAR(255) NOT NULL PRIMARY KEY,
+    uri             NVARCHAR(255) NULL,
+    created_at      DATETIME2     NOT NULL DEFAULT SYSUTCDATETIME(),
+    creator_name    NVARCHAR(255) NOT NULL,
+    creator_email   NVARCHAR(255) NOT NULL
+  );
+  CREATE UNIQUE INDEX projects_uri ON :"registry".projects (uri) WHERE uri IS NOT NULL;
+END;
+
+IF OBJECT_ID(QUOTENAME(N:'registry') + N'.changes', N'U') IS NULL
+BEGIN
+  CREATE TABLE :"registry".changes (
+    change_id       NVARCHAR(40)  NOT NULL PRIMARY KEY,
+    script_hash     NVARCHAR(40)  NULL,
+    change          NVARCHAR(255) NOT NULL,
+    project         NVARCHAR(255) NOT NULL,
+    note            NVARCHAR(MAX) NOT NULL DEFAULT '',
+    committed_at    DATETIME2     NOT NULL DEFAULT SYSUTCDATETIME(),
+    committer_name  NVARCHAR(255) NOT NULL,
+    committer_email NVARCHAR(255) NOT NULL,
+    planned_at      DATETIME2     NOT NULL,
+    planner_name    NVARCHAR(255) NOT NULL,
+    planner_email   NVARCHAR(255) NOT NULL
+  );
+  CREATE UNIQUE INDEX changes_script_hash ON :"registry".changes (project, script_hash)
+    WHERE script_hash IS NOT NULL;
+END;
+
+IF OBJECT_ID(QUOTENAME(N:'registry') + N'.events', N'U') IS NULL
+  CREATE TABLE :"registry".events (
+    event           NVARCHAR(6)   NOT NULL CHECK (event IN ('deploy', 'fail', 'merge', 'revert')),
+    change_id       NVARCHAR(40)  NOT NULL,
+    change          NVARCHAR(255) NOT NULL,
+    project         NVARCHAR(255) NOT NULL,
+    note            NVARCHAR(MAX) NOT NULL DEFAULT '',
+    requires        NVARCHAR(MAX) NOT NULL DEFAULT '',
+    conflicts       NVARCHAR(MAX) NOT NULL DEFAULT '',
+    tags            NVARCHAR(MAX) NOT NULL DEFAULT '',
+    committed_at    DATETIME2     NOT NULL DEFAULT SYSUTCDATETIME(),
+    committer_name  NVARCHAR(255) NOT NULL,
+    committer_email NVARCHAR(255) NOT NULL,
+    planned_at      DATETIME2     NOT NULL,
+    planner_name    NVARCHAR(255) NOT NULL,
+    planner_email   NVARCHAR(255) NOT NULL,
+    PRIMARY KEY (change_id, committed_at)
+  );
+
+IF OBJECT_ID(QUOTENAME(N:'registry') + N'.tags', N'U') IS NULL
+  CREATE TABLE :"registry".tags (
+    tag_id          NVARCHAR(40)  NOT NULL PRIMARY KEY,
+    tag             NVARCHAR(255) NOT NULL,
+    project         NVARCHAR(255) NOT NULL,
+    change_id       NVARCHAR(40)  NOT NULL REFERENCES :"registry".changes (change_id) ON UPDATE CASCADE,
+    note            NVARCHAR(MAX) NOT NULL DEFAULT '',
+    committed_at    DATETIME2     NOT NULL DEFAULT SYSUTCDATETIME(),
+    committer_name  NVARCHAR(255) NOT NULL,
+    committer_email NVARCHAR(255) NOT NULL,
+    planned_at      DATETIME2     NOT NULL,
+    planner_name    NVARCHAR(255) NOT NULL,
+    planner_email   NVARCHAR(255) NOT NULL,
+    UNIQUE (project, tag)
+  );
+
+-- SQL Server refuses a second cascading reference to changes, so renaming a
+-- required change isn't cascaded to dependency_id
+IF OBJECT_ID(QUOTENAME(N:'registry') + N'.dependencies', N'U') IS NULL
+  CREATE TABLE :"registry".dependencies (
+    change_id       NVARCHAR(40)  NOT NULL REFERENCES :"registry".changes (change_id) ON UPDATE CASCADE ON DELETE CASCADE,
+    type            NVARCHAR(8)   NOT NULL,
+    dependency      NVARCHAR(255) NOT NULL,
+    dependency_id   NVARCHAR(40)  NULL REFERENCES :"registry".changes (change_id),
+    PRIMARY KEY (change_id, dependency)
+  );
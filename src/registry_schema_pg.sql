@@ -0,0 +1,75 @@
+-- Adapted from the sqitch PostgreSQL registry to match registry_schema.sql
+
+CREATE TABLE IF NOT EXISTS projects (
+  project         TEXT        PRIMARY KEY,
+  uri             TEXT        NULL UNIQUE,
+  created_at      TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp(),
+  creator_name    TEXT        NOT NULL,
+  creator_email   TEXT        NOT NULL
+);
+
+COMMENT ON TABLE projects IS 'Sqitch projects deployed to this database.';
+
+CREATE TABLE IF NOT EXISTS changes (
+  change_id       TEXT        PRIMARY KEY,
+  script_hash     TEXT        NULL,
+  change          TEXT        NOT NULL,
+  project         TEXT        NOT NULL,
+  note            TEXT        NOT NULL DEFAULT '',
+  committed_at    TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp(),
+  committer_name  TEXT        NOT NULL,
+  committer_email TEXT        NOT NULL,
+  planned_at      TIMESTAMPTZ NOT NULL,
+  planner_name    TEXT        NOT NULL,
+  planner_email   TEXT        NOT NULL,
+  UNIQUE (project, script_hash)
+);
+
+COMMENT ON TABLE changes IS 'Tracks the changes currently deployed to the database.';
+
+CREATE TABLE IF NOT EXISTS events (
+  event           TEXT        NOT NULL CHECK (event IN ('deploy', 'fail', 'merge', 'revert')),
+  change_id       TEXT        NOT NULL,
+  change          TEXT        NOT NULL,
+  project         TEXT        NOT NULL,
+  note            TEXT        NOT NULL DEFAULT '',
+  requires        TEXT        NOT NULL DEFAULT '',
+  conflicts       TEXT        NOT NULL DEFAULT '',
+  tags            TEXT        NOT NULL DEFAULT '',
+  committed_at    TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp(),
+  committer_name  TEXT        NOT NULL,
+  committer_email TEXT        NOT NULL,
+  planned_at      TIMESTAMPTZ NOT NULL,
+  planner_name    TEXT        NOT NULL,
+  planner_email   TEXT        NOT NULL,
+  PRIMARY KEY (change_id, committed_at)
+);
+
+COMMENT ON TABLE events IS 'Contains full history of all deployment events.';
+
+CREATE TABLE IF NOT EXISTS tags (
+  tag_id          TEXT        PRIMARY KEY,
+  tag             TEXT        NOT NULL,
+  project         TEXT        NOT NULL,
+  change_id       TEXT        NOT NULL REFERENCES changes(change_id) ON UPDATE CASCADE,
+  note            TEXT        NOT NULL DEFAULT '',
+  committed_at    TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp(),
+  committer_name  TEXT        NOT NULL,
+  committer_email TEXT        NOT NULL,
+  planned_at      TIMESTAMPTZ NOT NULL,
+  planner_name    TEXT        NOT NULL,
+  planner_email   TEXT        NOT NULL,
+  UNIQUE (project, tag)
+);
+
+COMMENT ON TABLE tags IS 'Tracks the tags currently applied to the database.';
+
+CREATE TABLE IF NOT EXISTS dependencies (
+  change_id       TEXT        NOT NULL REFERENCES changes(change_id) ON UPDATE CASCADE ON DELETE CASCADE,
+  type            TEXT        NOT NULL,
+  dependency      TEXT        NOT NULL,
+  dependency_id   TEXT        NULL REFERENCES changes(change_id) ON UPDATE CASCADE,
+  PRIMARY KEY (change_id, dependency)
+);
+
+COMMENT ON TABLE dependencies IS 'Tracks the currently satisfied dependencies.';
@@ -0,0 +1,137 @@
+//! Stub scripts for newly added changes
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context as _;
+
+use crate::{change::Change, script::ScriptKind};
+
+/// Built-in stub script, like sqitch's for `engine`, its sqitch name such as
+/// `pg`
+pub fn script_stub(kind: ScriptKind, engine: &str, project: &str, change: &str) -> String {
+    let (verb, placeholder, end) = match kind {
+        ScriptKind::Deploy => ("Deploy", "-- XXX Add DDLs here.", "COMMIT;"),
+        ScriptKind::Revert => ("Revert", "-- XXX Add DDLs here.", "COMMIT;"),
+        ScriptKind::Verify => ("Verify", "-- XXX Add verifications here.", "ROLLBACK;"),
+    };
+    let preposition = match kind {
+        ScriptKind::Deploy => "to",
+        ScriptKind::Revert | ScriptKind::Verify => "from",
+    };
+    format!(
+        "-- {verb} {project}:{change} {preposition} {engine}\n\nBEGIN;\n\n{placeholder}\n\n{end}\n"
+    )
+}
+
+/// Path to a user template for scripts of this kind, in the same
+/// `etc/templates/<kind>/<engine>.tmpl` layout sqitch uses
+pub fn template_path(plan_file: &str, kind: ScriptKind, engine: &str) -> PathBuf {
+    let plan_dir = Path::new(plan_file).parent().expect("plan_dir");
+    plan_dir
+        .join("etc")
+        .join("templates")
+        .join(kind.dir_name())
+        .join(format!("{engine}.tmpl"))
+}
+
+/// Render a Tera template of a stub script.
+///
+/// The template can use `change`, `project`, `requires` and `conflicts`.
+pub fn render_template(template: &str, project: &str, change: &Change) -> anyhow::Result<String> {
+    let mut context = tera::Context::new();
+    context.insert("change", &change.name);
+    context.insert("project", project);
+    context.insert("requires", &change.requires);
+    context.insert("conflicts", &change.conflicts);
+    Ok(tera::Tera::one_off(template, &context, false)?)
+}
+
+/// Stub script for a new change, rendered from the user template if there is
+/// one and built in otherwise
+pub async fn change_stub(
+    plan_file: &str,
+    kind: ScriptKind,
+    engine: &str,
+    project: &str,
+    change: &Change,
+) -> anyhow::Result<String> {
+    let path = template_path(plan_file, kind, engine);
+    match tokio::fs::read_to_string(&path).await {
+        Ok(template) => render_template(&template, project, change)
+            .with_context(|| format!("failed to render {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Ok(script_stub(kind, engine, project, &change.name))
+        }
+        Err(e) => Err(anyhow::anyhow!("failed to read {}: {e}", path.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deploy_stub() {
+        assert_eq!(
+            script_stub(ScriptKind::Deploy, "mysql", "quitch", "users"),
+            "-- Deploy quitch:users to mysql\n\
+            \n\
+            BEGIN;\n\
+            \n\
+            -- XXX Add DDLs here.\n\
+            \n\
+            COMMIT;\n"
+        );
+    }
+
+    #[test]
+    fn test_verify_stub() {
+        let stub = script_stub(ScriptKind::Verify, "pg", "quitch", "users");
+        assert!(stub.starts_with("-- Verify quitch:users from pg\n"));
+        assert!(stub.ends_with("ROLLBACK;\n"));
+    }
+
+    #[test]
+    fn test_render_template() {
+        let mut change = crate::change::tests::example();
+        change.requires = vec!["roles".into(), "schema".into()];
+        change.conflicts = vec!["old_users".into()];
+        let template = "-- Deploy {{ project }}:{{ change }}\n\
+            {% for name in requires %}-- requires: {{ name }}\n{% endfor %}\
+            {% for name in conflicts %}-- conflicts: {{ name }}\n{% endfor %}";
+        assert_eq!(
+            render_template(template, "quitch", &change).unwrap(),
+            "-- Deploy quitch:change_name\n\
+            -- requires: roles\n\
+            -- requires: schema\n\
+            -- conflicts: old_users\n"
+        );
+        assert!(render_template("{{ missing }}", "quitch", &change).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_change_stub_falls_back_to_built_in() {
+        let dir = std::env::temp_dir().join(format!("quitch-scaffold-{}", std::process::id()));
+        let plan_file = dir.join("sqitch.plan");
+        let plan_file = plan_file.to_str().unwrap();
+        let change = crate::change::tests::example();
+
+        let stub = change_stub(plan_file, ScriptKind::Deploy, "pg", "quitch", &change)
+            .await
+            .unwrap();
+        assert_eq!(
+            stub,
+            script_stub(ScriptKind::Deploy, "pg", "quitch", "change_name")
+        );
+
+        let template = template_path(plan_file, ScriptKind::Deploy, "pg");
+        assert!(template.ends_with("etc/templates/deploy/pg.tmpl"));
+        std::fs::create_dir_all(template.parent().unwrap()).unwrap();
+        std::fs::write(&template, "-- {{ project }}:{{ change }}\n").unwrap();
+        let stub = change_stub(plan_file, ScriptKind::Deploy, "pg", "quitch", &change)
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(stub, "-- quitch:change_name\n");
+    }
+}
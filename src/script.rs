@@ -0,0 +1,660 @@
+//! Deploy, revert and verify scripts
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::plan::FullChange;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScriptKind {
+    Deploy,
+    Revert,
+    Verify,
+}
+
+impl ScriptKind {
+    pub const ALL: [Self; 3] = [Self::Deploy, Self::Revert, Self::Verify];
+
+    /// Name of the directory containing scripts of this kind
+    pub fn dir_name(self) -> &'static str {
+        match self {
+            Self::Deploy => "deploy",
+            Self::Revert => "revert",
+            Self::Verify => "verify",
+        }
+    }
+}
+
+/// SHA-1 of the raw script contents, as stored in `changes.script_hash`
+pub fn script_hash(script: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(script);
+    base16ct::lower::encode_string(&hasher.finalize())
+}
+
+/// Directories of the deploy, revert and verify scripts, and of hooks
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScriptDirs {
+    pub deploy: PathBuf,
+    pub revert: PathBuf,
+    pub verify: PathBuf,
+    /// Holds a directory per [`Hook`](crate::hook::Hook)
+    pub hooks: PathBuf,
+}
+
+impl ScriptDirs {
+    /// `deploy`, `revert`, `verify` and `hooks` in `top_dir`
+    pub fn in_top_dir(top_dir: &Path) -> Self {
+        Self {
+            deploy: top_dir.join(ScriptKind::Deploy.dir_name()),
+            revert: top_dir.join(ScriptKind::Revert.dir_name()),
+            verify: top_dir.join(ScriptKind::Verify.dir_name()),
+            hooks: top_dir.join("hooks"),
+        }
+    }
+
+    /// Directories next to the plan file, used when `top_dir` isn't set
+    pub fn next_to_plan(plan_file: &str) -> Self {
+        Self::in_top_dir(Path::new(plan_file).parent().expect("plan_dir"))
+    }
+
+    pub fn dir(&self, kind: ScriptKind) -> &Path {
+        match kind {
+            ScriptKind::Deploy => &self.deploy,
+            ScriptKind::Revert => &self.revert,
+            ScriptKind::Verify => &self.verify,
+        }
+    }
+
+    pub fn script_path(&self, kind: ScriptKind, change_name: &str) -> PathBuf {
+        self.dir(kind).join(format!("{change_name}.sql"))
+    }
+
+    /// Path to a script of a change from the plan, taking reworks into account.
+    ///
+    /// Like sqitch, picks the first candidate name that has a deploy script.
+    pub fn change_script_path(&self, kind: ScriptKind, change: &FullChange) -> PathBuf {
+        let script_names = change.script_names();
+        let script_name = script_names
+            .iter()
+            .find(|name| self.script_path(ScriptKind::Deploy, name).exists())
+            .unwrap_or(&script_names[0]);
+        self.script_path(kind, script_name)
+    }
+}
+
+/// Replace placeholders of defined variables in a script, the way psql and
+/// SQL*Plus do.
+///
+/// `:name` and `&name` become the value as is, `:'name'` a string literal and
+/// `:"name"` an identifier quoted with `identifier_quote`. String literals,
+/// quoted identifiers, comments, `::` casts and undefined variables are left
+/// alone.
+pub fn substitute_variables(
+    script: &str,
+    variables: &HashMap<String, String>,
+    identifier_quote: char,
+) -> String {
+    let mut substituted = String::with_capacity(script.len());
+    let mut rest = script;
+    while let Some(c) = rest.chars().next() {
+        let len = match c {
+            '-' if rest.starts_with("--") => rest.find('\n').unwrap_or(rest.len()),
+            '/' if rest.starts_with("/*") => rest.find("*/").map_or(rest.len(), |end| end + 2),
+            '\'' | '"' | '`' => quoted_len(rest),
+            ':' if rest.starts_with("::") => 2,
+            ':' | '&' => match placeholder(rest, variables, identifier_quote) {
+                Some((len, value)) => {
+                    substituted.push_str(&value);
+                    rest = &rest[len..];
+                    continue;
+                }
+                None => 1,
+            },
+            _ => c.len_utf8(),
+        };
+        substituted.push_str(&rest[..len]);
+        rest = &rest[len..];
+    }
+    substituted
+}
+
+/// Quote an identifier such as a schema name with `quote`, doubling the quotes
+/// in it, which is how both MySQL and PostgreSQL escape them
+pub fn quote_ident(name: &str, quote: char) -> String {
+    let escaped = name.replace(quote, &format!("{quote}{quote}"));
+    format!("{quote}{escaped}{quote}")
+}
+
+/// Length of the quoted string or identifier at the start of `text`
+fn quoted_len(text: &str) -> usize {
+    let quote = text.chars().next().expect("starts with a quote");
+    let mut chars = text.char_indices().skip(1).peekable();
+    while let Some((i, c)) = chars.next() {
+        if c == '\\' {
+            chars.next();
+        } else if c == quote && chars.next_if(|&(_, after)| after == quote).is_none() {
+            return i + 1;
+        }
+    }
+    text.len()
+}
+
+/// Length and replacement of a placeholder at the start of `text`
+fn placeholder(
+    text: &str,
+    variables: &HashMap<String, String>,
+    identifier_quote: char,
+) -> Option<(usize, String)> {
+    let name_len = |text: &str| {
+        text.find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .unwrap_or(text.len())
+    };
+
+    if let Some(after) = text.strip_prefix(':') {
+        for (open, quote_char) in [('\'', '\''), ('"', identifier_quote)] {
+            let Some(quoted) = after.strip_prefix(open) else {
+                continue;
+            };
+            let name = &quoted[..name_len(quoted)];
+            if quoted[name.len()..].starts_with(open) {
+                let value = variables.get(name)?;
+                // String literals escape quotes the same way
+                return Some((name.len() + 3, quote_ident(value, quote_char)));
+            }
+        }
+    }
+    // SQL*Plus also accepts `&&name`
+    let sigils = if text.starts_with("&&") { 2 } else { 1 };
+    let after = &text[sigils..];
+    let name = &after[..name_len(after)];
+    let value = variables.get(name)?;
+    Some((sigils + name.len(), value.clone()))
+}
+
+/// A statement of a script
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Statement<'a> {
+    /// Line the statement starts on, counting from 1
+    pub line: usize,
+    /// Text of the statement without the terminating semicolon, or of the
+    /// batch without the `GO` line
+    pub sql: &'a str,
+}
+
+impl Statement<'_> {
+    /// Whether MySQL commits the current transaction before running the
+    /// statement, as it does for schema changes and transaction control.
+    ///
+    /// Goes by the first keyword only, so a few statements such as `create
+    /// temporary table` count although they don't commit.
+    pub fn commits_implicitly(&self) -> bool {
+        const KEYWORDS: &[&str] = &[
+            "alter", "analyze", "begin", "commit", "create", "drop", "flush", "grant", "load",
+            "lock", "optimize", "rename", "repair", "revoke", "start", "truncate", "unlock",
+        ];
+        let keyword = first_word(self.sql);
+        KEYWORDS
+            .iter()
+            .any(|candidate| keyword.eq_ignore_ascii_case(candidate))
+    }
+}
+
+/// First word of a statement after whitespace and comments
+fn first_word(sql: &str) -> &str {
+    let mut rest = sql.trim_start();
+    loop {
+        if rest.starts_with("--") || rest.starts_with('#') {
+            rest = rest.split_once('\n').map_or("", |(_, after)| after);
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            rest = comment.split_once("*/").map_or("", |(_, after)| after);
+        } else {
+            break;
+        }
+        rest = rest.trim_start();
+    }
+    let end = rest
+        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .unwrap_or(rest.len());
+    &rest[..end]
+}
+
+/// Split a script into statements on semicolons outside of string literals,
+/// quoted identifiers, comments and dollar-quoted bodies.
+///
+/// Like the `mysql` client, a `DELIMITER` line changes what ends statements
+/// from there on, so that procedure and trigger bodies can contain semicolons.
+/// Statements consisting only of comments are skipped, the same as the
+/// database does.
+pub fn split_statements(script: &str) -> Vec<Statement<'_>> {
+    let mut statements = Vec::new();
+    let mut delimiter = ";";
+    let mut start = None;
+    let mut line = 1;
+    let mut chars = script.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let rest = &script[i..];
+        match c {
+            '-' if rest.starts_with("--") => {
+                // Leave the newline to be counted
+                while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+                continue;
+            }
+            '/' if rest.starts_with("/*") => {
+                chars.next();
+                while let Some((j, c)) = chars.next() {
+                    if c == '\n' {
+                        line += 1;
+                    } else if script[j..].starts_with("*/") {
+                        chars.next();
+                        break;
+                    }
+                }
+                continue;
+            }
+            _ => {}
+        }
+        if c.is_whitespace() {
+            if c == '\n' {
+                line += 1;
+            }
+            continue;
+        }
+        if start.is_none() {
+            if let Some(new_delimiter) = delimiter_directive(rest) {
+                delimiter = new_delimiter;
+                while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+                continue;
+            }
+        }
+        if rest.starts_with(delimiter) {
+            while chars.next_if(|&(j, _)| j < i + delimiter.len()).is_some() {}
+            if let Some((start, start_line)) = start.take() {
+                statements.push(Statement {
+                    line: start_line,
+                    sql: script[start..i].trim_end(),
+                });
+            }
+            continue;
+        }
+        start.get_or_insert((i, line));
+        match c {
+            '\'' | '"' | '`' => {
+                while let Some((_, next)) = chars.next() {
+                    match next {
+                        '\\' => {
+                            if let Some((_, '\n')) = chars.next() {
+                                line += 1;
+                            }
+                        }
+                        '\n' => line += 1,
+                        // A doubled quote stands for the quote itself
+                        _ if next == c && chars.next_if(|&(_, after)| after == c).is_none() => {
+                            break
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            // A `$` inside an identifier such as `a$b$c` doesn't start a quote
+            '$' if !script[..i]
+                .ends_with(|c: char| c == '_' || c == '$' || c.is_alphanumeric()) =>
+            {
+                if let Some(tag) = dollar_quote_tag(rest) {
+                    let end = rest[tag.len()..]
+                        .find(tag)
+                        .map_or(rest.len(), |end| tag.len() * 2 + end);
+                    line += rest[..end].matches('\n').count();
+                    while chars.next_if(|&(j, _)| j < i + end).is_some() {}
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Some((start, start_line)) = start {
+        statements.push(Statement {
+            line: start_line,
+            sql: script[start..].trim_end(),
+        });
+    }
+    statements
+}
+
+/// Split a T-SQL script into batches on `GO` lines, as `sqlcmd` does.
+///
+/// `GO` may be followed by how many times to run the batch, and by a comment.
+/// Lines inside string literals, quoted identifiers and comments don't end a
+/// batch, and batches consisting only of comments are skipped.
+pub fn split_batches(script: &str) -> Vec<Statement<'_>> {
+    let mut batches = Vec::new();
+    let mut start = None;
+    let mut line = 1;
+    // Block comments nest in T-SQL
+    let mut comment_depth = 0;
+    // Closing quote of the string literal or identifier the line starts in
+    let mut quote = None;
+    let mut offset = 0;
+    for text in script.split_inclusive('\n') {
+        let count = (comment_depth == 0 && quote.is_none())
+            .then(|| go_count(text))
+            .flatten();
+        if let Some(count) = count {
+            if let Some((start, start_line)) = start.take() {
+                let batch = Statement {
+                    line: start_line,
+                    sql: script[start..offset].trim_end(),
+                };
+                batches.extend(std::iter::repeat_n(batch, count));
+            }
+        } else {
+            let mut chars = text.char_indices().peekable();
+            while let Some((i, c)) = chars.next() {
+                let rest = &text[i..];
+                if let Some(close) = quote {
+                    // A doubled quote stands for the quote itself
+                    if c == close && chars.next_if(|&(_, after)| after == close).is_none() {
+                        quote = None;
+                    }
+                    continue;
+                }
+                if comment_depth > 0 {
+                    if rest.starts_with("*/") {
+                        chars.next();
+                        comment_depth -= 1;
+                    } else if rest.starts_with("/*") {
+                        chars.next();
+                        comment_depth += 1;
+                    }
+                    continue;
+                }
+                match c {
+                    '-' if rest.starts_with("--") => break,
+                    '/' if rest.starts_with("/*") => {
+                        chars.next();
+                        comment_depth = 1;
+                        continue;
+                    }
+                    _ if c.is_whitespace() => continue,
+                    _ => {}
+                }
+                start.get_or_insert((offset + i, line));
+                quote = match c {
+                    '\'' | '"' => Some(c),
+                    '[' => Some(']'),
+                    _ => None,
+                };
+            }
+        }
+        offset += text.len();
+        line += 1;
+    }
+    if let Some((start, start_line)) = start {
+        batches.push(Statement {
+            line: start_line,
+            sql: script[start..].trim_end(),
+        });
+    }
+    batches
+}
+
+/// How many times to run the batch ended by `line`, if it is a `GO` line
+fn go_count(line: &str) -> Option<usize> {
+    let line = line.split_once("--").map_or(line, |(code, _)| code).trim();
+    let after = line
+        .get(..2)
+        .filter(|keyword| keyword.eq_ignore_ascii_case("go"))
+        .map(|_| &line[2..])?;
+    if after.is_empty() {
+        return Some(1);
+    }
+    if !after.starts_with(char::is_whitespace) {
+        return None;
+    }
+    after.trim_start().parse().ok().filter(|&count| count > 0)
+}
+
+/// Delimiter set by a `DELIMITER` line at the start of `text`
+fn delimiter_directive(text: &str) -> Option<&str> {
+    let line = &text[..text.find('\n').unwrap_or(text.len())];
+    let (keyword, delimiter) = line.split_once(char::is_whitespace)?;
+    let delimiter = delimiter.trim();
+    (keyword.eq_ignore_ascii_case("delimiter") && !delimiter.is_empty()).then_some(delimiter)
+}
+
+/// Opening tag of a PostgreSQL dollar-quoted string at the start of `text`,
+/// such as `$$` or `$body$`
+fn dollar_quote_tag(text: &str) -> Option<&str> {
+    let end = text[1..].find('$')? + 2;
+    let tag = &text[1..end - 1];
+    let valid = tag
+        .chars()
+        .enumerate()
+        .all(|(i, c)| c == '_' || c.is_alphabetic() || (i > 0 && c.is_ascii_digit()));
+    valid.then(|| &text[..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script_hash() {
+        assert_eq!(
+            script_hash(b"select 1;\n"),
+            "005c6eb7364156e6b0d158d8b2767a24f1ce6611"
+        );
+    }
+
+    #[test]
+    fn test_split_statements() {
+        let script = "-- Deploy users\n\
+            \n\
+            create table users (name text default 'a;b''c');\n\
+            /* multi-line;\n\
+            comment */ insert into users values (\"x;\");\n\
+            create function f() returns int as $body$ select 1; $body$ language sql;\n\
+            -- trailing comment;\n";
+        assert_eq!(
+            split_statements(script),
+            [
+                Statement {
+                    line: 3,
+                    sql: "create table users (name text default 'a;b''c')"
+                },
+                Statement {
+                    line: 5,
+                    sql: "insert into users values (\"x;\")"
+                },
+                Statement {
+                    line: 6,
+                    sql: "create function f() returns int as $body$ select 1; $body$ language sql"
+                },
+            ]
+        );
+        assert_eq!(
+            split_statements("select 'it\\'s'; select $1"),
+            [
+                Statement {
+                    line: 1,
+                    sql: "select 'it\\'s'"
+                },
+                Statement {
+                    line: 1,
+                    sql: "select $1"
+                },
+            ]
+        );
+        assert!(split_statements("  -- nothing\n").is_empty());
+        assert_eq!(
+            split_statements("create table a$b$c (id int); insert into quitch$changes values (1);"),
+            [
+                Statement {
+                    line: 1,
+                    sql: "create table a$b$c (id int)"
+                },
+                Statement {
+                    line: 1,
+                    sql: "insert into quitch$changes values (1)"
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_statements_delimiter() {
+        let script = "DELIMITER //\n\
+            create procedure p() begin select 1; select 2; end//\n\
+            delimiter ;\n\
+            call p();\n";
+        assert_eq!(
+            split_statements(script),
+            [
+                Statement {
+                    line: 2,
+                    sql: "create procedure p() begin select 1; select 2; end"
+                },
+                Statement {
+                    line: 4,
+                    sql: "call p()"
+                },
+            ]
+        );
+        let script = "delimiter $$\ncreate trigger t before insert on x for each row begin set new.a = 1; end$$\n";
+        assert_eq!(
+            split_statements(script)[0].sql,
+            "create trigger t before insert on x for each row begin set new.a = 1; end"
+        );
+    }
+
+    #[test]
+    fn test_split_batches() {
+        let script = "-- Deploy users\n\
+            create table users (name nvarchar(10) default 'a;\n\
+            GO');\n\
+            insert into users values ('x');\n\
+            go\n\
+            /* GO\n\
+            /* nested */ GO\n\
+            */ create procedure [go\n\
+            go] as select 1;\n\
+            GO 2 -- twice\n\
+            goto done;\n\
+            GO\n\
+            -- only a comment\n\
+            GO\n";
+        assert_eq!(
+            split_batches(script),
+            [
+                Statement {
+                    line: 2,
+                    sql: "create table users (name nvarchar(10) default 'a;\n\
+                        GO');\n\
+                        insert into users values ('x');"
+                },
+                Statement {
+                    line: 8,
+                    sql: "create procedure [go\ngo] as select 1;"
+                },
+                Statement {
+                    line: 8,
+                    sql: "create procedure [go\ngo] as select 1;"
+                },
+                Statement {
+                    line: 11,
+                    sql: "goto done;"
+                },
+            ]
+        );
+        assert_eq!(
+            split_batches("select 1"),
+            [Statement {
+                line: 1,
+                sql: "select 1"
+            }]
+        );
+        assert!(split_batches("GO\n  -- nothing\n").is_empty());
+    }
+
+    #[test]
+    fn test_commits_implicitly() {
+        let statement = |sql| Statement { line: 1, sql };
+        for sql in [
+            "create table t (id int)",
+            "-- Add the column\nALTER TABLE t ADD COLUMN a INT",
+            "/* old */ drop table t",
+            "start transaction",
+        ] {
+            assert!(statement(sql).commits_implicitly(), "{sql}");
+        }
+        for sql in [
+            "insert into t values (1)",
+            "update t set a = 1",
+            "# create\nselect 1",
+            "created",
+        ] {
+            assert!(!statement(sql).commits_implicitly(), "{sql}");
+        }
+    }
+
+    #[test]
+    fn test_substitute_variables() {
+        let variables = HashMap::from([
+            ("schema".to_string(), "app".to_string()),
+            ("owner".to_string(), "o'neil".to_string()),
+        ]);
+        let substitute = |script| substitute_variables(script, &variables, '"');
+        assert_eq!(
+            substitute("create schema :schema; create table &schema.t (id int);"),
+            "create schema app; create table app.t (id int);"
+        );
+        assert_eq!(
+            substitute("comment on schema :\"schema\" is :'owner';"),
+            "comment on schema \"app\" is 'o''neil';"
+        );
+        assert_eq!(substitute("select &&schema"), "select app");
+        // Left alone
+        for script in [
+            "select 1::int, :missing, ':schema', \":schema\"",
+            "-- :schema\nselect 1 /* &schema */",
+            "select a && b",
+        ] {
+            assert_eq!(substitute(script), script);
+        }
+    }
+
+    #[test]
+    fn test_quote_ident() {
+        assert_eq!(quote_ident("sqitch", '`'), "`sqitch`");
+        assert_eq!(quote_ident("my`db", '`'), "`my``db`");
+        assert_eq!(quote_ident("My \"Schema\"", '"'), "\"My \"\"Schema\"\"\"");
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn quote_ident_round_trips(
+            name in "\\PC*",
+            quote in proptest::sample::select(vec!['`', '"']),
+        ) {
+            let quoted = quote_ident(&name, quote);
+            let inner = &quoted[1..quoted.len() - 1];
+            let doubled = format!("{quote}{quote}");
+            // A quote that isn't doubled would end the identifier early
+            proptest::prop_assert!(!inner.replace(&doubled, "").contains(quote));
+            proptest::prop_assert_eq!(inner.replace(&doubled, &quote.to_string()), name);
+        }
+
+        #[test]
+        fn quoted_ident_is_one_token(
+            // Scripts are scanned with backslash escapes, as MySQL strings have
+            name in "[^\\\\]*",
+            quote in proptest::sample::select(vec!['`', '"']),
+        ) {
+            let quoted = quote_ident(&name, quote);
+            proptest::prop_assert_eq!(quoted_len(&format!("{quoted}; drop")), quoted.len());
+        }
+    }
+}
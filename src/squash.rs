@@ -0,0 +1,256 @@
+//! Squashing the first changes of a plan into one baseline change, for `quitch
+//! plan squash`.
+//!
+//! Deploying years of changes one by one makes every fresh database slow to
+//! set up. The baseline runs the scripts of the changes it replaces one after
+//! the other, so fresh databases end up the same with a single change. The
+//! changes after it get new IDs, as their parent is now the baseline, so the
+//! registries of databases deployed before the squash are rewritten by `quitch
+//! registry squash`, which matches the plans from before and after with
+//! [`match_changes`].
+
+use anyhow::bail;
+
+use crate::{
+    change::Change,
+    change_ref::ChangeRef,
+    plan::{Entry, FullChange, Plan},
+    registry::split_project,
+};
+
+/// Replace the changes of `plan` up to the one at index `through` with
+/// `baseline`.
+///
+/// The tags of the squashed changes move to the baseline, along with their
+/// dependencies on other projects. Later changes that required squashed
+/// changes require the baseline instead.
+pub fn squash(plan: &Plan, through: usize, mut baseline: Change) -> anyhow::Result<Plan> {
+    let changes = plan.full_changes();
+    let (squashed, kept) = changes.split_at(through + 1);
+    if changes.iter().any(|change| change.name() == baseline.name) {
+        bail!("change {} already exists in the plan", baseline.name);
+    }
+    for change in squashed {
+        for (dependencies, baseline_dependencies) in [
+            (&change.change.requires, &mut baseline.requires),
+            (&change.change.conflicts, &mut baseline.conflicts),
+        ] {
+            for dependency in dependencies {
+                if split_project(plan, dependency).0.is_some()
+                    && !baseline_dependencies.contains(dependency)
+                {
+                    baseline_dependencies.push(dependency.clone());
+                }
+            }
+        }
+    }
+
+    let mut entries = Vec::new();
+    let mut kept = kept.iter();
+    // Number of changes seen so far
+    let mut seen = 0;
+    for entry in plan.entries() {
+        match entry {
+            Entry::Change(_) if seen <= through => {
+                if seen == 0 {
+                    entries.push(Entry::Change(baseline.clone()));
+                }
+                seen += 1;
+            }
+            Entry::Change(_) => {
+                let change = kept.next().expect("one entry per change");
+                entries.push(Entry::Change(rewrite_dependencies(
+                    plan,
+                    change,
+                    through,
+                    &baseline.name,
+                )?));
+                seen += 1;
+            }
+            Entry::Tag(_) => entries.push(entry.clone()),
+            // Lines between squashed changes go with them
+            Entry::Blank | Entry::Comment(_) if seen > 0 && seen <= through => {}
+            Entry::Blank | Entry::Comment(_) => entries.push(entry.clone()),
+        }
+    }
+    plan.with_entries(entries)
+}
+
+/// A change after the squashed ones, requiring the baseline in place of the
+/// squashed changes it required
+fn rewrite_dependencies(
+    plan: &Plan,
+    change: &FullChange,
+    through: usize,
+    baseline: &str,
+) -> anyhow::Result<Change> {
+    let changes = plan.full_changes();
+    let earlier = &changes[..plan.change_index(&change.id).expect("in the plan")];
+    // Index of the change a dependency of the project names, like deploys
+    // resolve it
+    let resolve = |dependency: &str, changes: &[FullChange]| match split_project(plan, dependency) {
+        (None, name) => name
+            .parse::<ChangeRef>()
+            .ok()
+            .and_then(|reference| reference.resolve_index(changes).ok()),
+        (Some(_), _) => None,
+    };
+
+    let mut rewritten = change.change.clone();
+    rewritten.requires.clear();
+    for dependency in &change.change.requires {
+        let dependency = match resolve(dependency, earlier) {
+            Some(idx) if idx <= through => baseline,
+            _ => dependency,
+        };
+        if !rewritten.requires.iter().any(|name| name == dependency) {
+            rewritten.requires.push(dependency.to_string());
+        }
+    }
+    for dependency in &change.change.conflicts {
+        if resolve(dependency, changes).is_some_and(|idx| idx <= through) {
+            bail!(
+                "{} conflicts with {dependency}, which would be squashed into {baseline}",
+                change.name()
+            );
+        }
+    }
+    Ok(rewritten)
+}
+
+/// Pair each change of `before`, a plan before a squash, with its change in
+/// `after`, the plan after it: the baseline for the squashed changes, and
+/// the same change for the others.
+///
+/// Only the names of the changes after the baseline are compared, as their
+/// dependencies may have been rewritten, so `before` must really be the
+/// plan that was squashed.
+pub fn match_changes<'a>(
+    before: &'a Plan,
+    after: &'a Plan,
+) -> anyhow::Result<Vec<(&'a FullChange, &'a FullChange)>> {
+    let (old, new) = (before.full_changes(), after.full_changes());
+    let Some((baseline, kept)) = new.split_first() else {
+        bail!("the plan is empty");
+    };
+    let squashed = (old.len() + 1)
+        .checked_sub(new.len())
+        .filter(|squashed| *squashed > 0 && before.project() == after.project());
+    let Some(squashed) = squashed else {
+        bail!("the plan before the squash must have more changes of the same project");
+    };
+    if old.iter().any(|change| change.name() == baseline.name()) {
+        bail!("the plan before the squash already has {}", baseline.name());
+    }
+    if let Some((old, new)) = old[squashed..]
+        .iter()
+        .zip(kept)
+        .find(|(old, new)| old.name() != new.name())
+    {
+        bail!(
+            "{} of the plan before the squash doesn't match {} of the plan after it",
+            old.name(),
+            new.name()
+        );
+    }
+    Ok(old[..squashed]
+        .iter()
+        .map(|old| (old, baseline))
+        .chain(old[squashed..].iter().zip(kept))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAN: &str = "\
+%syntax-version=1.0.0
+%project=flipr
+
+# Users
+users 2024-03-07T03:19:34Z Jane <jane@example.com> # Add users
+@v1.0 2024-03-08T00:00:00Z Jane <jane@example.com> # Release
+
+flips [users other:accounts] 2024-03-09T00:00:00Z Jane <jane@example.com> # Add flips
+@v2.0 2024-03-10T00:00:00Z Jane <jane@example.com> # Release
+
+likes [users flips@v2.0] 2024-03-11T00:00:00Z Joe <joe@example.com> # Add likes
+";
+
+    fn baseline() -> Change {
+        Change::parse_line("baseline 2024-03-12T00:00:00Z Jane <jane@example.com> # Baseline")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_squash() {
+        let plan = Plan::parse(PLAN).unwrap();
+        let squashed = squash(&plan, 1, baseline()).unwrap();
+        assert_eq!(
+            squashed.format(),
+            "\
+%syntax-version=1.0.0
+%project=flipr
+
+# Users
+baseline [other:accounts] 2024-03-12T00:00:00Z Jane <jane@example.com> # Baseline
+@v1.0 2024-03-08T00:00:00Z Jane <jane@example.com> # Release
+@v2.0 2024-03-10T00:00:00Z Jane <jane@example.com> # Release
+
+likes [baseline] 2024-03-11T00:00:00Z Joe <joe@example.com> # Add likes
+"
+        );
+        let changes = squashed.full_changes();
+        assert_eq!(changes[1].parent.as_ref(), Some(&changes[0].id));
+
+        let matched = match_changes(&plan, &squashed).unwrap();
+        let names: Vec<_> = matched
+            .iter()
+            .map(|(old, new)| (old.name(), new.name()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("users", "baseline"),
+                ("flips", "baseline"),
+                ("likes", "likes")
+            ]
+        );
+        assert!(match_changes(&squashed, &plan).is_err());
+        assert!(match_changes(&squashed, &squashed).is_err());
+        assert!(match_changes(
+            &Plan::parse(&PLAN.replace("likes", "tweets")).unwrap(),
+            &squashed
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_squash_everything() {
+        let plan = Plan::parse(PLAN).unwrap();
+        let squashed = squash(&plan, 2, baseline()).unwrap();
+        assert_eq!(squashed.full_changes().len(), 1);
+        assert_eq!(squashed.tags().count(), 2);
+    }
+
+    #[test]
+    fn test_squash_invalid() {
+        let plan = Plan::parse(PLAN).unwrap();
+        let users = Change {
+            name: "users".to_string(),
+            ..baseline()
+        };
+        assert!(squash(&plan, 0, users).is_err());
+
+        let conflicting = Plan::parse(&format!(
+            "{PLAN}tweets [!users] 2024-03-12T00:00:00Z Joe <joe@example.com>\n"
+        ))
+        .unwrap();
+        let error = squash(&conflicting, 1, baseline()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "tweets conflicts with users, which would be squashed into baseline"
+        );
+    }
+}
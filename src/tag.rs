@@ -0,0 +1,138 @@
+use chrono::{DateTime, Utc};
+
+use crate::change::{
+    canonical_uri, format_line_date, format_line_note, object_id, written_date, LineFields, Planner,
+};
+
+/// A tag on the change preceding it in the plan
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tag {
+    /// Name of the tag without the leading `@`
+    pub name: String,
+    pub note: String,
+    pub date: DateTime<Utc>,
+    /// Date as written in the plan when it isn't in UTC
+    pub written_date: Option<String>,
+    pub planner: Planner,
+}
+
+impl Tag {
+    pub fn format(
+        &self,
+        project: &str,
+        uri: Option<&str>,
+        change_id: &str,
+    ) -> Result<String, std::fmt::Error> {
+        use std::fmt::Write;
+
+        let mut s = String::new();
+        writeln!(&mut s, "project {}", project)?;
+        if let Some(uri) = uri {
+            writeln!(&mut s, "uri {}", canonical_uri(uri))?;
+        }
+        writeln!(&mut s, "tag @{}", self.name)?;
+        writeln!(&mut s, "change {}", change_id)?;
+        writeln!(&mut s, "planner {}", self.planner)?;
+        write!(&mut s, "date {}", format_line_date(self.date))?;
+        if !self.note.is_empty() {
+            write!(&mut s, "\n\n{}", self.note)?;
+        }
+        Ok(s)
+    }
+
+    /// Tag IDs depend on the ID of the tagged change, but not the other way around
+    pub fn id(&self, project: &str, uri: Option<&str>, change_id: &str) -> String {
+        let tag_str = self
+            .format(project, uri, change_id)
+            .expect("always succeeds");
+        object_id("tag", &tag_str)
+    }
+
+    pub fn parse_line(tag: &str) -> anyhow::Result<Self> {
+        let LineFields {
+            name,
+            requires,
+            conflicts,
+            date,
+            written_date,
+            planner,
+            note,
+        } = LineFields::parse(tag)?;
+        let Some(name) = name.strip_prefix('@') else {
+            anyhow::bail!("tag lines must start with @");
+        };
+        if !requires.is_empty() || !conflicts.is_empty() {
+            anyhow::bail!("tag @{name} cannot have dependencies");
+        }
+        Ok(Self {
+            name: name.to_string(),
+            note,
+            date,
+            written_date,
+            planner,
+        })
+    }
+
+    pub fn format_line(&self) -> String {
+        format!(
+            "@{} {} {}{}",
+            self.name,
+            written_date(self.date, self.written_date.as_deref()),
+            self.planner,
+            format_line_note(&self.note),
+        )
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    pub fn example() -> Tag {
+        Tag {
+            date: DateTime::from_str("2024-03-08T10:00:00Z").unwrap(),
+            written_date: None,
+            name: "v1.0".into(),
+            note: "First release".into(),
+            planner: Planner::new("Ruslan Fadeev", "github@kinrany.dev"),
+        }
+    }
+
+    pub static EXAMPLE_LINE: &str = "@v1.0 \
+        2024-03-08T10:00:00Z \
+        Ruslan Fadeev <github@kinrany.dev> \
+        # First release";
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(Tag::parse_line(EXAMPLE_LINE).unwrap(), example());
+    }
+
+    #[test]
+    fn test_format_line() {
+        assert_eq!(example().format_line(), EXAMPLE_LINE);
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(
+            example()
+                .format("quitch", None, "da41a550b0cba5bd3dffbf645032a98ae1136da5")
+                .unwrap(),
+            "project quitch\n\
+            tag @v1.0\n\
+            change da41a550b0cba5bd3dffbf645032a98ae1136da5\n\
+            planner Ruslan Fadeev <github@kinrany.dev>\n\
+            date 2024-03-08T10:00:00Z\n\
+            \n\
+            First release"
+        );
+    }
+
+    #[test]
+    fn test_parse_line_without_at() {
+        assert!(Tag::parse_line("v1.0 2024-03-08T10:00:00Z someone").is_err());
+    }
+}
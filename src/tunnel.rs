@@ -0,0 +1,286 @@
+//! SSH tunnels to targets only reachable through a jump host.
+//!
+//! The tunnel is an `ssh -L` run in the background, so that `~/.ssh/config`,
+//! the agent and known hosts apply as they do in a shell. The connection to
+//! the database is then made to the forwarded local port.
+
+use std::{
+    borrow::Cow,
+    ffi::OsString,
+    path::PathBuf,
+    process::Stdio,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    process::{Child, ChildStderr, Command},
+};
+use tracing::{info, warn};
+
+use crate::engine::ClientConfig;
+
+/// Seconds to wait for the tunnel to open, unless the target sets
+/// `connect-timeout`
+const OPEN_TIMEOUT: u64 = 30;
+
+/// How often to check whether the forwarded port accepts connections
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Times to pick another port when another process took the last one before
+/// ssh could listen on it
+const PORT_ATTEMPTS: u32 = 3;
+
+/// Jump host to reach the target database through
+#[derive(Clone, Debug, Default, PartialEq, Eq, clap::Args)]
+#[clap(rename_all = "kebab-case")]
+pub struct SshOptions {
+    /// Open an SSH tunnel to this host, or host:port, and connect to the
+    /// database through it
+    #[clap(long, value_name = "HOST")]
+    pub ssh_host: Option<String>,
+    /// User to log in to the SSH host as
+    #[clap(long, value_name = "USER")]
+    pub ssh_user: Option<String>,
+    /// Private key to log in to the SSH host with
+    #[clap(long, value_name = "FILE")]
+    pub ssh_key: Option<PathBuf>,
+}
+
+impl SshOptions {
+    /// Fill in the options missing from `self` with those from `other`
+    pub fn or(self, other: Self) -> Self {
+        Self {
+            ssh_host: self.ssh_host.or(other.ssh_host),
+            ssh_user: self.ssh_user.or(other.ssh_user),
+            ssh_key: self.ssh_key.or(other.ssh_key),
+        }
+    }
+}
+
+/// A running `ssh -L`, killed when dropped
+#[derive(Debug)]
+pub struct Tunnel {
+    _ssh: Child,
+    port: u16,
+}
+
+/// Open a tunnel if `config` sets `ssh-host`, returning the config to connect
+/// through it with. The tunnel closes once every clone of it is dropped, so
+/// whatever connects through it must hold on to it.
+pub async fn open(
+    config: &ClientConfig,
+) -> anyhow::Result<(Cow<'_, ClientConfig>, Option<Arc<Tunnel>>)> {
+    if config.ssh.ssh_host.is_none() {
+        return Ok((Cow::Borrowed(config), None));
+    }
+    let tunnel = Tunnel::open(config).await?;
+    let config = ClientConfig {
+        hostname: "127.0.0.1".to_string(),
+        port: tunnel.port,
+        socket: None,
+        ssh: SshOptions::default(),
+        ..config.clone()
+    };
+    Ok((Cow::Owned(config), Some(Arc::new(tunnel))))
+}
+
+impl Tunnel {
+    async fn open(config: &ClientConfig) -> anyhow::Result<Self> {
+        let ssh_host = config.ssh.ssh_host.as_deref().unwrap_or_default();
+        for attempt in 1..=PORT_ATTEMPTS {
+            if let Some(tunnel) = Self::open_on_free_port(config).await? {
+                return Ok(tunnel);
+            }
+            if attempt < PORT_ATTEMPTS {
+                info!("The port picked for the SSH tunnel was taken, picking another");
+            }
+        }
+        bail!(
+            "could not open an SSH tunnel through {ssh_host}: the ports picked for it were taken \
+            by other processes"
+        )
+    }
+
+    /// Open the tunnel on a port the OS says is free, none if another process
+    /// took the port before ssh could listen on it
+    async fn open_on_free_port(config: &ClientConfig) -> anyhow::Result<Option<Self>> {
+        let ssh_host = config.ssh.ssh_host.as_deref().unwrap_or_default();
+        let port = std::net::TcpListener::bind(("127.0.0.1", 0))?
+            .local_addr()?
+            .port();
+        let mut ssh = Command::new("ssh")
+            .args(ssh_args(config, port)?)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .context("could not run ssh")?;
+        let stderr = tokio::spawn(forward_stderr(
+            ssh.stderr
+                .take()
+                .context("could not read the errors of ssh")?,
+        ));
+
+        let timeout = config.timeouts.connect_timeout.unwrap_or(OPEN_TIMEOUT);
+        let deadline = Instant::now() + Duration::from_secs(timeout);
+        // ssh only listens once it has logged in and set up the forwarding
+        while tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .is_err()
+        {
+            if let Some(status) = ssh.try_wait()? {
+                // ssh closes stderr on exit, so this has everything it wrote
+                if port_taken(&stderr.await.unwrap_or_default()) {
+                    return Ok(None);
+                }
+                bail!("could not open an SSH tunnel through {ssh_host}: ssh {status}");
+            }
+            if Instant::now() > deadline {
+                bail!("timed out after {timeout} seconds opening an SSH tunnel through {ssh_host}");
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        info!(
+            "Opened an SSH tunnel to {} through {ssh_host}",
+            destination(config)
+        );
+        Ok(Some(Self { _ssh: ssh, port }))
+    }
+}
+
+/// Log what ssh writes to stderr as warnings, returning all of it once ssh
+/// exits
+async fn forward_stderr(stderr: ChildStderr) -> String {
+    let mut output = String::new();
+    let mut lines = BufReader::new(stderr).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        warn!("ssh: {line}");
+        output.push_str(&line);
+        output.push('\n');
+    }
+    output
+}
+
+/// Whether ssh failed because the local port to forward was already in use
+fn port_taken(stderr: &str) -> bool {
+    stderr.contains("Address already in use") || stderr.contains("cannot listen to port")
+}
+
+/// Where the SSH host forwards connections to: the target's socket or its
+/// hostname and port, as seen from the SSH host
+fn destination(config: &ClientConfig) -> String {
+    match &config.socket {
+        Some(socket) => socket.to_string_lossy().into_owned(),
+        None => format!("{}:{}", config.hostname, config.port),
+    }
+}
+
+/// Arguments of `ssh` to forward `local_port` to the target of `config`
+fn ssh_args(config: &ClientConfig, local_port: u16) -> anyhow::Result<Vec<OsString>> {
+    let SshOptions {
+        ssh_host,
+        ssh_user,
+        ssh_key,
+    } = &config.ssh;
+    let ssh_host = ssh_host.as_deref().unwrap_or_default();
+    let (host, ssh_port) = match ssh_host.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => {
+            let port: u16 = port
+                .parse()
+                .with_context(|| format!("invalid port in ssh-host {ssh_host}"))?;
+            (host, Some(port))
+        }
+        _ => (ssh_host, None),
+    };
+    if host.is_empty() || host.starts_with('-') {
+        bail!("invalid ssh-host {ssh_host:?}");
+    }
+
+    let mut args: Vec<OsString> = vec![
+        "-N".into(),
+        // Fail instead of prompting for passwords or host keys nobody sees
+        "-o".into(),
+        "BatchMode=yes".into(),
+        "-o".into(),
+        "ExitOnForwardFailure=yes".into(),
+        "-L".into(),
+        format!("127.0.0.1:{local_port}:{}", destination(config)).into(),
+    ];
+    if let Some(port) = ssh_port {
+        args.extend(["-p".into(), port.to_string().into()]);
+    }
+    if let Some(user) = ssh_user {
+        args.extend(["-l".into(), user.into()]);
+    }
+    if let Some(key) = ssh_key {
+        args.extend(["-o".into(), "IdentitiesOnly=yes".into()]);
+        args.extend(["-i".into(), key.into()]);
+    }
+    args.extend(["--".into(), host.into()]);
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::parse_connection_string;
+
+    fn args(target: &str) -> anyhow::Result<Vec<String>> {
+        let config = parse_connection_string(target)?;
+        Ok(ssh_args(&config, 40000)?
+            .into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect())
+    }
+
+    #[test]
+    fn test_ssh_args() {
+        assert_eq!(
+            args("mysql://db.internal/app?ssh-host=bastion&ssh-user=deploy&ssh-key=%2Fkeys%2Fid")
+                .unwrap(),
+            [
+                "-N",
+                "-o",
+                "BatchMode=yes",
+                "-o",
+                "ExitOnForwardFailure=yes",
+                "-L",
+                "127.0.0.1:40000:db.internal:3306",
+                "-l",
+                "deploy",
+                "-o",
+                "IdentitiesOnly=yes",
+                "-i",
+                "/keys/id",
+                "--",
+                "bastion",
+            ]
+        );
+        let args = args("mysql://localhost/app?ssh-host=bastion:2222&socket=%2Frun%2Fmysqld.sock")
+            .unwrap();
+        assert!(args.contains(&"127.0.0.1:40000:/run/mysqld.sock".to_string()));
+        assert!(args.ends_with(&["-p", "2222", "--", "bastion"].map(String::from)));
+    }
+
+    #[test]
+    fn test_port_taken() {
+        assert!(port_taken(
+            "bind [127.0.0.1]:40000: Address already in use\n\
+            channel_setup_fwd_listener_tcpip: cannot listen to port: 40000\n\
+            Could not request local forwarding.\n"
+        ));
+        assert!(!port_taken(
+            "ssh: connect to host bastion port 22: Connection refused\n"
+        ));
+    }
+
+    #[test]
+    fn test_ssh_args_invalid_host() {
+        assert!(args("mysql://localhost/app?ssh-host=bastion:ssh").is_err());
+        assert!(args("mysql://localhost/app?ssh-host=-oProxyCommand=x").is_err());
+    }
+}
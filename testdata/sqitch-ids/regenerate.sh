@@ -0,0 +1,14 @@
+#!/bin/sh
+
+# Write the change IDs sqitch computes for each fixture plan into a `.ids` file
+# next to it, one `<id> <name>` line per change, and the version of sqitch
+# that computed them into `sqitch-version`:
+#
+#     cd testdata/sqitch-ids && ./regenerate.sh
+
+set -eu
+
+sqitch --version > sqitch-version
+for plan in *.plan; do
+    sqitch --plan-file "$plan" plan --no-headers --format 'format:%H %n' > "${plan%.plan}.ids"
+done